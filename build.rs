@@ -0,0 +1,33 @@
+use std::process::Command;
+
+fn main() {
+    // Builds outside of a git checkout (e.g. in Docker) can pass the commit in the environment
+    let git_sha = std::env::var("IHACDN_GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|sha| sha.trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=IHACDN_GIT_SHA={}",
+        git_sha.as_deref().unwrap_or("unknown")
+    );
+
+    // Reproducible builds set `SOURCE_DATE_EPOCH`
+    let build_timestamp = std::env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string()
+    });
+    println!("cargo:rustc-env=IHACDN_BUILD_TIMESTAMP={build_timestamp}");
+
+    println!("cargo:rerun-if-env-changed=IHACDN_GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
@@ -0,0 +1,321 @@
+//! Abuse scoring per IP and the automatic temporary bans, kept in Redis.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin_keys,
+    config::IhaCdnAbuseConfig,
+    error::{ErrorCode, IhaCdnError},
+    notifier::{extract_ip_address, notify_ban},
+    state::SharedState,
+    store::RedisConnection,
+};
+
+/// How many ban keys are fetched at once while listing them.
+const SCAN_BATCH: usize = 500;
+
+/// A kind of abusive request.
+#[derive(Debug, Clone, Copy)]
+pub enum AbuseSignal {
+    /// An upload with a blocked extension or content type.
+    BlockedType,
+    /// An upload over the size limit.
+    Oversized,
+    /// A request for a missing entry.
+    NotFound,
+}
+
+impl AbuseSignal {
+    /// The signal of an error response, if it is one.
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "blocked_type" => Some(AbuseSignal::BlockedType),
+            "payload_too_large" => Some(AbuseSignal::Oversized),
+            "not_found" => Some(AbuseSignal::NotFound),
+            _ => None,
+        }
+    }
+
+    fn weight(&self, config: &IhaCdnAbuseConfig) -> f64 {
+        match self {
+            AbuseSignal::BlockedType => config.blocked_type_weight,
+            AbuseSignal::Oversized => config.oversized_weight,
+            AbuseSignal::NotFound => config.not_found_weight,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AbuseSignal::BlockedType => "blocked_type",
+            AbuseSignal::Oversized => "oversized",
+            AbuseSignal::NotFound => "not_found",
+        }
+    }
+}
+
+/// A temporary ban of an IP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: String,
+    /// The signal that reached the threshold.
+    pub reason: String,
+    /// UNIX timestamp.
+    pub banned_at: i64,
+    /// UNIX timestamp.
+    pub until: i64,
+    /// How many times the IP was banned recently, including this ban.
+    pub strikes: u32,
+}
+
+fn score_key(prefix: &str, ip: &str) -> String {
+    format!("{prefix}:abuse:score:{ip}")
+}
+
+fn strikes_key(prefix: &str, ip: &str) -> String {
+    format!("{prefix}:abuse:strikes:{ip}")
+}
+
+fn ban_key(prefix: &str, ip: &str) -> String {
+    format!("{prefix}:abuse:ban:{ip}")
+}
+
+/// The duration of a ban, doubling with every strike.
+fn ban_duration(config: &IhaCdnAbuseConfig, strikes: u32) -> u64 {
+    let factor = 1u64
+        .checked_shl(strikes.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    config
+        .ban_duration
+        .saturating_mul(factor)
+        .min(config.max_ban_duration)
+}
+
+/// The active ban of an IP, if any.
+pub async fn active_ban(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    ip: &str,
+) -> RedisResult<Option<Ban>> {
+    let ban = redis::cmd("GET")
+        .arg(ban_key(prefix, ip))
+        .query_async::<Option<String>>(connection)
+        .await?;
+    Ok(ban.and_then(|ban| serde_json::from_str(&ban).ok()))
+}
+
+/// Add a signal to the score of an IP, banning it once the score reaches the threshold.
+///
+/// Returns the new ban, if any.
+pub async fn record_signal(
+    config: &IhaCdnAbuseConfig,
+    connection: &mut RedisConnection,
+    prefix: &str,
+    ip: &str,
+    signal: AbuseSignal,
+) -> RedisResult<Option<Ban>> {
+    let now = chrono::Utc::now();
+    let strikes = redis::cmd("EVAL")
+        .arg(SCORE_SCRIPT)
+        .arg(2)
+        .arg(score_key(prefix, ip))
+        .arg(strikes_key(prefix, ip))
+        .arg(now.timestamp_millis() as f64 / 1000.0)
+        .arg(signal.weight(config))
+        .arg(config.decay_per_minute / 60.0)
+        .arg(config.threshold)
+        .arg(config.strike_memory)
+        .query_async::<u32>(connection)
+        .await?;
+    if strikes == 0 {
+        return Ok(None);
+    }
+
+    let duration = ban_duration(config, strikes);
+    let ban = Ban {
+        ip: ip.to_string(),
+        reason: signal.as_str().to_string(),
+        banned_at: now.timestamp(),
+        until: now.timestamp() + duration as i64,
+        strikes,
+    };
+    redis::cmd("SET")
+        .arg(ban_key(prefix, ip))
+        .arg(serde_json::to_string(&ban).unwrap())
+        .arg("EX")
+        .arg(duration)
+        .exec_async(connection)
+        .await?;
+    Ok(Some(ban))
+}
+
+/// Every active ban, the soonest to end first.
+pub async fn list_bans(connection: &mut RedisConnection, prefix: &str) -> RedisResult<Vec<Ban>> {
+    let mut bans = vec![];
+    let mut cursor: u64 = 0;
+    loop {
+        let (next_cursor, keys) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(ban_key(prefix, "*"))
+            .arg("COUNT")
+            .arg(SCAN_BATCH)
+            .query_async::<(u64, Vec<String>)>(connection)
+            .await?;
+
+        if !keys.is_empty() {
+            let values = redis::cmd("MGET")
+                .arg(&keys)
+                .query_async::<Vec<Option<String>>>(connection)
+                .await?;
+            bans.extend(
+                values
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|ban| serde_json::from_str::<Ban>(&ban).ok()),
+            );
+        }
+
+        cursor = next_cursor;
+        if cursor == 0 {
+            break;
+        }
+    }
+    bans.sort_by_key(|ban| ban.until);
+    Ok(bans)
+}
+
+/// Lift the ban of an IP and forget its score and strikes, returns whether it was banned.
+pub async fn lift_ban(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    ip: &str,
+) -> RedisResult<bool> {
+    let (removed,) = redis::pipe()
+        .cmd("DEL")
+        .arg(ban_key(prefix, ip))
+        .cmd("DEL")
+        .arg(score_key(prefix, ip))
+        .ignore()
+        .cmd("DEL")
+        .arg(strikes_key(prefix, ip))
+        .ignore()
+        .query_async::<(i64,)>(connection)
+        .await?;
+    Ok(removed > 0)
+}
+
+/// Whether the secret is a valid admin key, admins are never banned.
+async fn is_admin(state: &SharedState, secret: &str) -> bool {
+    matches!(admin_keys::identify(state, secret).await, Ok(Some(_)))
+}
+
+/// Middleware rejecting banned IPs and scoring abusive requests.
+pub async fn enforce(
+    State(state): State<Arc<SharedState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.config.abuse.enable {
+        return next.run(request).await;
+    }
+    let Some(ip) = extract_ip_address(request.headers()).first().copied() else {
+        return next.run(request).await;
+    };
+    let ip = ip.to_string();
+    let admin_secret = request
+        .headers()
+        .get("x-admin-key")
+        .and_then(|key| key.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    match state.make_connection().await {
+        Ok(mut connection) => {
+            match active_ban(&mut connection, &state.config.key_prefix, &ip).await {
+                Ok(Some(ban)) if !is_admin(&state, &admin_secret).await => {
+                    return IhaCdnError::Banned { until: ban.until }.into_response();
+                }
+                Ok(_) => (),
+                Err(e) => tracing::error!("Failed to check the ban of {}: {}", ip, e),
+            }
+        }
+        Err(e) => tracing::error!("Failed to check the ban of {}: {}", ip, e),
+    }
+
+    let response = next.run(request).await;
+
+    let Some(signal) = response
+        .extensions()
+        .get::<ErrorCode>()
+        .and_then(|ErrorCode(code)| AbuseSignal::from_code(code))
+    else {
+        return response;
+    };
+    if is_admin(&state, &admin_secret).await {
+        return response;
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = score(&state, &ip, signal).await {
+            tracing::error!("Failed to record abuse of {}: {}", ip, e);
+        }
+    });
+    response
+}
+
+async fn score(state: &Arc<SharedState>, ip: &str, signal: AbuseSignal) -> RedisResult<()> {
+    let mut connection = state.make_connection().await?;
+    if let Some(ban) = record_signal(
+        &state.config.abuse,
+        &mut connection,
+        &state.config.key_prefix,
+        ip,
+        signal,
+    )
+    .await?
+    {
+        tracing::warn!(
+            "Banned {} until {} for {} (strike {})",
+            ban.ip,
+            ban.until,
+            ban.reason,
+            ban.strikes
+        );
+        notify_ban(&ban, &state.config);
+    }
+    Ok(())
+}
+
+/// Add `ARGV[2]` to the leaking score in `KEYS[1]`, returns the strikes in `KEYS[2]` once
+/// the score reaches `ARGV[4]`, 0 otherwise.
+///
+/// The score drains by `ARGV[3]` per second since its last update at `ARGV[1]`.
+const SCORE_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local decay = tonumber(ARGV[3])
+local current = redis.call("HMGET", KEYS[1], "score", "updated_at")
+local score = tonumber(current[1]) or 0
+local updated_at = tonumber(current[2]) or now
+score = math.max(0, score - (now - updated_at) * decay) + tonumber(ARGV[2])
+if score >= tonumber(ARGV[4]) then
+    redis.call("DEL", KEYS[1])
+    local strikes = redis.call("INCR", KEYS[2])
+    redis.call("EXPIRE", KEYS[2], ARGV[5])
+    return strikes
+end
+redis.call("HSET", KEYS[1], "score", tostring(score), "updated_at", tostring(now))
+if decay > 0 then
+    redis.call("EXPIRE", KEYS[1], math.ceil(score / decay) + 1)
+else
+    redis.call("EXPIRE", KEYS[1], ARGV[5])
+end
+return 0
+"#;
@@ -0,0 +1,231 @@
+//! The access log, a JSON record of every request kept apart from the tracing logs.
+//!
+//! Log filters never drop it, it is the trail used to answer abuse reports.
+
+use std::{io::Write, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, header},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use tracing_appender::{
+    non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+
+use crate::{
+    config::{AccessLogRotation, AccessLogSink, IhaCdnAccessLogConfig},
+    notifier::extract_ip_address,
+    sanitize::split_id_path,
+    state::SharedState,
+};
+
+/// The entry a response is about, for routes without its ID in the path, e.g. uploads.
+#[derive(Debug, Clone)]
+pub struct LoggedEntry(pub String);
+
+/// Where the records go.
+pub enum AccessLog {
+    Disabled,
+    /// Written by a background thread, the guard flushes it when the server stops.
+    File {
+        writer: NonBlocking,
+        _guard: WorkerGuard,
+    },
+    Redis {
+        stream: String,
+        max_len: Option<u64>,
+    },
+}
+
+impl AccessLog {
+    pub fn open(config: &IhaCdnAccessLogConfig) -> Result<Self, String> {
+        if !config.enable {
+            return Ok(AccessLog::Disabled);
+        }
+
+        match config.sink {
+            AccessLogSink::File => {
+                let rotation = match config.rotation {
+                    AccessLogRotation::Hourly => Rotation::HOURLY,
+                    AccessLogRotation::Daily => Rotation::DAILY,
+                    AccessLogRotation::Never => Rotation::NEVER,
+                };
+                let mut builder = RollingFileAppender::builder()
+                    .rotation(rotation)
+                    .filename_prefix("access")
+                    .filename_suffix("log");
+                if let Some(max_files) = config.max_files {
+                    builder = builder.max_log_files(max_files);
+                }
+                let appender = builder
+                    .build(&config.directory)
+                    .map_err(|e| e.to_string())?;
+                // Blocks rather than dropping records when the writer falls behind
+                let (writer, guard) = NonBlockingBuilder::default().lossy(false).finish(appender);
+                Ok(AccessLog::File {
+                    writer,
+                    _guard: guard,
+                })
+            }
+            AccessLogSink::Redis => Ok(AccessLog::Redis {
+                stream: config.stream.clone(),
+                max_len: config.max_len,
+            }),
+        }
+    }
+}
+
+/// What is recorded of a request.
+#[derive(Debug, Serialize)]
+struct AccessRecord {
+    /// RFC 3339, in UTC.
+    timestamp: String,
+    host: String,
+    method: String,
+    path: String,
+    /// `upload` or `download` for requests storing or reading an entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<&'static str>,
+    /// The entry the request was about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    status: u16,
+    /// The `Content-Length` of the request, the size of uploads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_in: Option<u64>,
+    /// The `Content-Length` of the response, unknown for streams of unknown length.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_out: Option<u64>,
+    /// Until the response headers were ready, in milliseconds.
+    duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+}
+
+/// Middleware recording every request in the access log.
+pub async fn record(
+    State(state): State<Arc<SharedState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(*state.access_log, AccessLog::Disabled) {
+        return next.run(request).await;
+    }
+
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let headers = request.headers().clone();
+    // HTTP/2 requests carry the host in the URI instead
+    let host = header_string(&headers, header::HOST)
+        .or_else(|| request.uri().host().map(str::to_string))
+        .unwrap_or_default();
+    // Routes of entries start with the ID
+    let path_id = request
+        .extensions()
+        .get::<MatchedPath>()
+        .filter(|matched| matched.as_str().starts_with("/{id_path}"))
+        .and_then(|_| path.trim_start_matches('/').split('/').next())
+        .and_then(split_id_path)
+        .map(|(id, _)| id);
+
+    let response = next.run(request).await;
+
+    let uploaded = response
+        .extensions()
+        .get::<LoggedEntry>()
+        .map(|LoggedEntry(id)| id.clone());
+    let action = match (&uploaded, &path_id) {
+        (Some(_), _) => Some("upload"),
+        (None, Some(_)) if method == axum::http::Method::GET && response.status().is_success() => {
+            Some("download")
+        }
+        _ => None,
+    };
+    let record = AccessRecord {
+        timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        host,
+        method: method.to_string(),
+        path,
+        action,
+        id: uploaded.or(path_id),
+        status: response.status().as_u16(),
+        bytes_in: content_length(&headers),
+        bytes_out: content_length(response.headers()),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        ip: extract_ip_address(&headers)
+            .first()
+            .map(|ip| ip.to_string()),
+        user_agent: header_string(&headers, header::USER_AGENT),
+    };
+    write_record(&state, record);
+
+    response
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Write a record, failures are only logged.
+fn write_record(state: &Arc<SharedState>, record: AccessRecord) {
+    match &*state.access_log {
+        AccessLog::Disabled => {}
+        AccessLog::File { writer, .. } => {
+            let mut line = serde_json::to_vec(&record).unwrap();
+            line.push(b'\n');
+            // The writer only queues the line for its thread
+            if let Err(e) = writer.clone().write_all(&line) {
+                tracing::error!("Failed to write the access log: {}", e);
+            }
+        }
+        AccessLog::Redis { stream, max_len } => {
+            let mut cmd = redis::cmd("XADD");
+            cmd.arg(stream);
+            if let Some(max_len) = max_len {
+                // Approximate trimming is a lot cheaper for Redis
+                cmd.arg("MAXLEN").arg("~").arg(*max_len);
+            }
+            cmd.arg("*");
+            if let serde_json::Value::Object(fields) = serde_json::to_value(&record).unwrap() {
+                for (field, value) in fields {
+                    let value = match value {
+                        serde_json::Value::String(value) => value,
+                        value => value.to_string(),
+                    };
+                    cmd.arg(field).arg(value);
+                }
+            }
+
+            let state = Arc::clone(state);
+            tokio::spawn(async move {
+                let mut connection = match state.make_connection().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        tracing::error!("Failed to connect to Redis for the access log: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = cmd.query_async::<String>(&mut connection).await {
+                    tracing::error!("Failed to write the access log: {}", e);
+                }
+            });
+        }
+    }
+}
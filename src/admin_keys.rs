@@ -0,0 +1,179 @@
+//! Rotation of admin keys, kept in Redis so it applies without editing the config.
+//!
+//! A rotation adds a pending key that works alongside the current one, promoting it
+//! revokes the configured key until the config is changed.
+
+use std::collections::HashMap;
+
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{AdminIdentity, constant_time_eq},
+    error::IhaCdnError,
+    state::{SharedState, hash_token},
+    store::RedisConnection,
+};
+
+/// The rotation state of an admin key, only hashes of the keys are stored.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeyRotation {
+    /// The hash of the configured key that was replaced by [`KeyRotation::promoted`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retired: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    promoted: Option<String>,
+    /// The new key, accepted alongside the current one until it is promoted or revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending: Option<String>,
+}
+
+impl KeyRotation {
+    /// Whether a promoted key replaces `configured`.
+    ///
+    /// A promotion only applies to the key it replaced, changing the config ends it.
+    pub fn is_promoted(&self, configured: &str) -> bool {
+        self.promoted.is_some() && self.retired.as_deref() == Some(&hash_token(configured))
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    fn accepts(&self, configured: &str, secret: &str) -> bool {
+        let hashed = hash_token(secret);
+        let current = if self.is_promoted(configured) {
+            self.promoted.as_deref() == Some(&hashed)
+        } else {
+            constant_time_eq(secret, configured)
+        };
+        current || self.pending.as_deref() == Some(&hashed)
+    }
+}
+
+fn rotations_key(prefix: &str) -> String {
+    format!("{prefix}:admin:rotation")
+}
+
+/// The rotation state of every admin key that was ever rotated, by name.
+pub async fn load_rotations(
+    connection: &mut RedisConnection,
+    prefix: &str,
+) -> RedisResult<HashMap<String, KeyRotation>> {
+    let rotations = redis::cmd("HGETALL")
+        .arg(rotations_key(prefix))
+        .query_async::<HashMap<String, String>>(connection)
+        .await?;
+    Ok(rotations
+        .into_iter()
+        .filter_map(|(name, rotation)| Some((name, serde_json::from_str(&rotation).ok()?)))
+        .collect())
+}
+
+async fn load_rotation(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    name: &str,
+) -> RedisResult<KeyRotation> {
+    let rotation = redis::cmd("HGET")
+        .arg(rotations_key(prefix))
+        .arg(name)
+        .query_async::<Option<String>>(connection)
+        .await?;
+    Ok(rotation
+        .and_then(|rotation| serde_json::from_str(&rotation).ok())
+        .unwrap_or_default())
+}
+
+async fn save_rotation(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    name: &str,
+    rotation: &KeyRotation,
+) -> RedisResult<()> {
+    redis::cmd("HSET")
+        .arg(rotations_key(prefix))
+        .arg(name)
+        .arg(serde_json::to_string(rotation).unwrap())
+        .exec_async(connection)
+        .await
+}
+
+/// Find the admin key a secret belongs to, including the pending and promoted keys.
+pub async fn identify(
+    state: &SharedState,
+    secret: &str,
+) -> Result<Option<AdminIdentity>, IhaCdnError> {
+    if secret.is_empty() {
+        return Ok(None);
+    }
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let rotations = load_rotations(&mut connection, &state.config.key_prefix)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: "admin keys".to_string(),
+            source,
+        })?;
+
+    Ok(state
+        .config
+        .admin_credentials()
+        .find(
+            |(identity, configured)| match rotations.get(&identity.name) {
+                Some(rotation) => rotation.accepts(configured, secret),
+                None => constant_time_eq(secret, configured),
+            },
+        )
+        .map(|(identity, _)| identity))
+}
+
+/// Start rotating the admin key `name`, `key` works alongside the current key until promoted.
+///
+/// Rotating again replaces the pending key.
+pub async fn rotate(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    name: &str,
+    key: &str,
+) -> RedisResult<()> {
+    let mut rotation = load_rotation(connection, prefix, name).await?;
+    rotation.pending = Some(hash_token(key));
+    save_rotation(connection, prefix, name, &rotation).await
+}
+
+/// Make the pending key the only key of `name`, revoking the `configured` one.
+///
+/// Returns whether there was a pending key.
+pub async fn promote(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    name: &str,
+    configured: &str,
+) -> RedisResult<bool> {
+    let mut rotation = load_rotation(connection, prefix, name).await?;
+    let Some(pending) = rotation.pending.take() else {
+        return Ok(false);
+    };
+    rotation.retired = Some(hash_token(configured));
+    rotation.promoted = Some(pending);
+    save_rotation(connection, prefix, name, &rotation).await?;
+    Ok(true)
+}
+
+/// Drop the pending key of `name`, returns whether there was one.
+pub async fn revoke(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    name: &str,
+) -> RedisResult<bool> {
+    let mut rotation = load_rotation(connection, prefix, name).await?;
+    if rotation.pending.take().is_none() {
+        return Ok(false);
+    }
+    save_rotation(connection, prefix, name, &rotation).await?;
+    Ok(true)
+}
@@ -0,0 +1,85 @@
+//! Scanning uploads for malware with clamd, before they are stored.
+
+use std::{path::Path, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::IhaCdnAntivirusConfig;
+
+/// The size of the chunks streamed to clamd.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What clamd found in an upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// The name of the signature that matched, e.g. `Eicar-Test-Signature`.
+    Infected(String),
+    /// The scan could not be done, with the reason.
+    Failed(String),
+}
+
+/// Stream a file to clamd with `INSTREAM` and read its verdict.
+///
+/// Returns [`ScanVerdict::Clean`] if scanning is disabled.
+pub async fn scan_file(config: &IhaCdnAntivirusConfig, path: &Path) -> ScanVerdict {
+    if !config.enable {
+        return ScanVerdict::Clean;
+    }
+
+    let timeout = Duration::from_secs(config.timeout);
+    let scan = async {
+        match config.address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(socket) => scan_with(tokio::net::UnixStream::connect(socket).await?, path).await,
+            #[cfg(not(unix))]
+            Some(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+            None => scan_with(tokio::net::TcpStream::connect(&config.address).await?, path).await,
+        }
+    };
+    let reply = match tokio::time::timeout(timeout, scan).await {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(e)) => return ScanVerdict::Failed(e.to_string()),
+        Err(_) => {
+            return ScanVerdict::Failed(format!("timed out after {} seconds", config.timeout));
+        }
+    };
+
+    // e.g. `stream: OK`, `stream: Eicar-Test-Signature FOUND` or `INSTREAM size limit exceeded. ERROR`
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        ScanVerdict::Clean
+    } else if let Some(signature) = result.strip_suffix("FOUND") {
+        ScanVerdict::Infected(signature.trim().to_string())
+    } else {
+        ScanVerdict::Failed(format!("unexpected reply {reply:?}"))
+    }
+}
+
+async fn scan_with<S>(mut stream: S, path: &Path) -> std::io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut file = tokio::fs::File::open(path).await?;
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    // Each chunk is prefixed by its length, an empty chunk ends the stream
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        stream.write_all(&(read as u32).to_be_bytes()).await?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&buffer[..read]).await?;
+    }
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    Ok(String::from_utf8_lossy(&reply).into_owned())
+}
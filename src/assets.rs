@@ -0,0 +1,123 @@
+use std::{borrow::Cow, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Redirect, Response},
+};
+use rust_embed::RustEmbed;
+use sha2::{Digest, Sha256};
+
+use crate::{config::IhaCdnConfig, state::SharedState};
+
+/// Static assets embedded into the binary, served under `/static/`.
+#[derive(RustEmbed)]
+#[folder = "assets/"]
+struct StaticAssets;
+
+const STATIC_CACHE_CONTROL: &str = "public, max-age=604800, immutable";
+/// Branding overrides can be replaced in place, they can't be cached for as long.
+const OVERRIDE_CACHE_CONTROL: &str = "public, max-age=3600";
+
+/// Serve an embedded asset under `/static/{*path}`.
+pub async fn serve_static(
+    State(state): State<Arc<SharedState>>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    serve_asset(&state.config, &path, &headers).await
+}
+
+/// Serve the favicon from the root, browsers still look for it there.
+pub async fn serve_favicon(State(state): State<Arc<SharedState>>, headers: HeaderMap) -> Response {
+    serve_asset(&state.config, "img/favicon.ico", &headers).await
+}
+
+/// The file path or URL replacing an embedded branding asset, if any.
+fn branding_override<'a>(config: &'a IhaCdnConfig, path: &str) -> Option<&'a str> {
+    match path {
+        "img/favicon.ico" => config.instance.favicon.as_deref(),
+        "img/favicon.png" => config.instance.logo.as_deref(),
+        _ => None,
+    }
+}
+
+async fn serve_asset(config: &IhaCdnConfig, path: &str, headers: &HeaderMap) -> Response {
+    if let Some(replacement) = branding_override(config, path) {
+        if replacement.starts_with("http://") || replacement.starts_with("https://") {
+            return Redirect::temporary(replacement).into_response();
+        }
+        return match tokio::fs::read(replacement).await {
+            Ok(data) => {
+                let hash = Sha256::digest(&data);
+                respond(
+                    replacement,
+                    data.into(),
+                    &hash,
+                    OVERRIDE_CACHE_CONTROL,
+                    headers,
+                )
+            }
+            Err(err) => {
+                tracing::error!("Failed to read the branding asset {}: {}", replacement, err);
+                StatusCode::NOT_FOUND.into_response()
+            }
+        };
+    }
+
+    match StaticAssets::get(path) {
+        Some(asset) => respond(
+            path,
+            asset.data,
+            &asset.metadata.sha256_hash(),
+            STATIC_CACHE_CONTROL,
+            headers,
+        ),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Send an asset, `path` is only used to guess its mimetype.
+fn respond(
+    path: &str,
+    data: Cow<'static, [u8]>,
+    sha256: &[u8],
+    cache_control: &str,
+    headers: &HeaderMap,
+) -> Response {
+    // The content hash is a strong validator
+    let hash = sha256
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let etag = format!("\"{hash}\"");
+
+    let is_fresh = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+
+    let mut builder = axum::http::Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, cache_control);
+    if is_fresh {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let mimetype = mime_guess::from_path(path).first_or_octet_stream();
+    if let Ok(value) = HeaderValue::from_str(mimetype.essence_str()) {
+        builder = builder.header(header::CONTENT_TYPE, value);
+    }
+    builder
+        .status(StatusCode::OK)
+        .body(Body::from(data.into_owned()))
+        .unwrap()
+}
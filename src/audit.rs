@@ -0,0 +1,98 @@
+//! The log of admin API usage, kept in Redis.
+
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{AdminIdentity, AdminRole},
+    state::SharedState,
+    store::RedisConnection,
+};
+
+/// How many records are kept, older ones are dropped.
+const MAX_AUDIT_RECORDS: isize = 10_000;
+
+/// A use of an admin key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// UNIX timestamp.
+    pub at: i64,
+    /// The name of the admin key.
+    pub key: String,
+    pub role: AdminRole,
+    pub action: String,
+    /// The entry or file the action was done on, if any.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub target: String,
+    /// Whether the role of the key allowed the action.
+    pub allowed: bool,
+}
+
+fn audit_key(prefix: &str) -> String {
+    format!("{prefix}:audit")
+}
+
+/// Record a use of an admin key, failures are only logged.
+pub async fn record(
+    state: &SharedState,
+    identity: &AdminIdentity,
+    action: &str,
+    target: &str,
+    allowed: bool,
+) {
+    tracing::info!(
+        "Admin key {} ({}) {} {} {}",
+        identity.name,
+        identity.role.as_str(),
+        if allowed { "did" } else { "was denied" },
+        action,
+        target
+    );
+
+    let record = AuditRecord {
+        at: chrono::Utc::now().timestamp(),
+        key: identity.name.clone(),
+        role: identity.role,
+        action: action.to_string(),
+        target: target.to_string(),
+        allowed,
+    };
+    let result = match state.make_connection().await {
+        Ok(mut connection) => {
+            redis::pipe()
+                .cmd("LPUSH")
+                .arg(audit_key(&state.config.key_prefix))
+                .arg(serde_json::to_string(&record).unwrap())
+                .ignore()
+                .cmd("LTRIM")
+                .arg(audit_key(&state.config.key_prefix))
+                .arg(0)
+                .arg(MAX_AUDIT_RECORDS - 1)
+                .ignore()
+                .exec_async(&mut connection)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        tracing::error!("Failed to record the audit log: {}", e);
+    }
+}
+
+/// The most recent records, newest first.
+pub async fn recent(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    limit: usize,
+) -> RedisResult<Vec<AuditRecord>> {
+    let records = redis::cmd("LRANGE")
+        .arg(audit_key(prefix))
+        .arg(0)
+        .arg(limit.saturating_sub(1))
+        .query_async::<Vec<String>>(connection)
+        .await?;
+    Ok(records
+        .iter()
+        .filter_map(|record| serde_json::from_str(record).ok())
+        .collect())
+}
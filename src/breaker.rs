@@ -0,0 +1,122 @@
+//! Failing fast while Redis is down, and serving the entries read before it went down.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+};
+
+use crate::{config::IhaCdnRedisFallbackConfig, state::CDNData};
+
+/// Entries recently read from Redis, with when they were read.
+#[derive(Default)]
+struct EntryCache {
+    entries: HashMap<String, (u64, CDNData)>,
+    /// The IDs in the order they were added, the oldest first.
+    order: VecDeque<String>,
+}
+
+/// A circuit breaker around the connections to Redis.
+pub struct RedisBreaker {
+    config: IhaCdnRedisFallbackConfig,
+    /// The failed connections and commands in a row.
+    failures: AtomicU32,
+    /// Redis is not tried until this UNIX timestamp.
+    open_until: AtomicU64,
+    cache: Mutex<EntryCache>,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl RedisBreaker {
+    pub fn new(config: &IhaCdnRedisFallbackConfig) -> Self {
+        Self {
+            config: config.clone(),
+            failures: AtomicU32::new(0),
+            open_until: AtomicU64::new(0),
+            cache: Mutex::new(EntryCache::default()),
+        }
+    }
+
+    /// Whether Redis is considered down and should not be tried.
+    pub fn is_open(&self) -> bool {
+        self.config.enable && self.open_until.load(Ordering::Relaxed) > now()
+    }
+
+    /// Count the outcome of a connection or a command sent to Redis.
+    pub fn record(&self, reached: bool) {
+        if !self.config.enable {
+            return;
+        }
+        if reached {
+            self.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.config.failure_threshold.max(1) {
+            tracing::warn!(
+                "Redis failed {} times in a row, serving cached entries for {}s",
+                failures,
+                self.config.open_for
+            );
+            self.failures.store(0, Ordering::Relaxed);
+            self.open_until
+                .store(now() + self.config.open_for, Ordering::Relaxed);
+        }
+    }
+
+    /// Keep an entry read from Redis, entries that must be counted in Redis are skipped.
+    pub fn remember(&self, id: &str, data: &CDNData) {
+        if !self.config.enable
+            || self.config.cache_size == 0
+            || data.max_views().is_some()
+            || data.burns_after_read()
+        {
+            return;
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .entries
+            .insert(id.to_string(), (now(), data.clone()))
+            .is_none()
+        {
+            cache.order.push_back(id.to_string());
+        }
+        while cache.order.len() > self.config.cache_size {
+            if let Some(oldest) = cache.order.pop_front() {
+                cache.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// The entry read from Redis recently enough to be served without it.
+    pub fn cached(&self, id: &str) -> Option<CDNData> {
+        if !self.config.enable {
+            return None;
+        }
+
+        let cache = self.cache.lock().unwrap();
+        cache
+            .entries
+            .get(id)
+            .filter(|(read_at, _)| read_at + self.config.cache_ttl > now())
+            .map(|(_, data)| data.clone())
+    }
+
+    /// Drop the kept copy of an entry that was changed or removed, so it is not served stale.
+    pub fn forget(&self, id: &str) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.entries.remove(id).is_some() {
+            cache.order.retain(|kept| kept != id);
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! Information about this build, set by `build.rs`.
+
+/// The version of the crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The short commit hash the binary was built from, `unknown` outside of a git checkout.
+pub const GIT_SHA: &str = env!("IHACDN_GIT_SHA");
+/// When the binary was built, as an UNIX timestamp.
+pub const BUILD_TIMESTAMP: &str = env!("IHACDN_BUILD_TIMESTAMP");
@@ -1,8 +1,94 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+use crate::moderation::mimetype_matches;
+
+/// What an admin key may do, each role can do everything the previous ones can.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// Admin uploads, with the admin file size limit and no retention.
+    Uploader,
+    /// Editing, quarantining, restricting, scheduling and searching entries.
+    Moderator,
+    /// Everything, including the status and the audit log.
+    Superadmin,
+}
+
+impl AdminRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminRole::Uploader => "uploader",
+            AdminRole::Moderator => "moderator",
+            AdminRole::Superadmin => "superadmin",
+        }
+    }
+}
+
+/// An additional admin key with its own role.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnAdminKey {
+    /// Identifies the key in the audit log.
+    pub name: String,
+    pub key: String,
+    pub role: AdminRole,
+}
+
+/// The admin key a request was made with.
+#[derive(Debug, Clone)]
+pub struct AdminIdentity {
+    pub name: String,
+    pub role: AdminRole,
+}
+
+/// The identity of this instance, shown on the home page and sent with outbound requests.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnInstanceConfig {
+    /// The name of the instance.
+    #[serde(default = "default_instance_name")]
+    pub name: String,
+    /// Where the operators of the instance can be reached, sent in the `User-Agent`.
+    #[serde(default = "default_instance_contact_url")]
+    pub contact_url: String,
+    /// Where abuse can be reported, an email address or URL.
+    #[serde(default)]
+    pub abuse_contact: Option<String>,
+    /// The terms of service.
+    #[serde(default)]
+    pub terms_url: Option<String>,
+    /// The privacy policy.
+    #[serde(default)]
+    pub privacy_url: Option<String>,
+    /// A file path or URL replacing the embedded `favicon.ico`.
+    #[serde(default)]
+    pub favicon: Option<String>,
+    /// A file path or URL replacing the embedded `favicon.png`, also used as the page preview image.
+    #[serde(default)]
+    pub logo: Option<String>,
+}
+
+impl Default for IhaCdnInstanceConfig {
+    fn default() -> Self {
+        Self {
+            name: default_instance_name(),
+            contact_url: default_instance_contact_url(),
+            abuse_contact: None,
+            terms_url: None,
+            privacy_url: None,
+            favicon: None,
+            logo: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct IhaCdnNotifierConfig {
     /// Enable or disable the notifier.
     pub enable: bool,
@@ -10,7 +96,7 @@ pub struct IhaCdnNotifierConfig {
     pub discord_webhook: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct IhaCdnPlausibleConfig {
     /// Enable or disable Plausible Analytics.
     pub enable: bool,
@@ -20,16 +106,6 @@ pub struct IhaCdnPlausibleConfig {
     pub endpoint_url: Option<String>,
 }
 
-impl Default for IhaCdnPlausibleConfig {
-    fn default() -> Self {
-        Self {
-            enable: false,
-            domain: None,
-            endpoint_url: None,
-        }
-    }
-}
-
 impl IhaCdnPlausibleConfig {
     /// Check if Plausible Analytics is enabled and has a domain set.
     pub fn is_enabled(&self) -> bool {
@@ -53,7 +129,7 @@ impl IhaCdnPlausibleConfig {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct IhaCdnRetentionConfig {
     /// Enable or disable the file retention policy.
     pub enable: bool,
@@ -63,6 +139,16 @@ pub struct IhaCdnRetentionConfig {
     /// The maximum age of files to be deleted. (in days)
     #[serde(default = "default_retention_max_age")]
     pub max_age: u64,
+    /// Extensions of files that never expire, without the dot (e.g. `txt`).
+    #[serde(default)]
+    pub exempt_extensions: Vec<String>,
+    /// Mimetypes of files that never expire, `type/*` wildcards are supported.
+    #[serde(default)]
+    pub exempt_mimetypes: Vec<String>,
+    /// The cron schedule of the purge task (UTC), it also runs without retention for the
+    /// entries with an expiry.
+    #[serde(default = "default_purge_schedule")]
+    pub schedule: String,
 }
 
 impl Default for IhaCdnRetentionConfig {
@@ -71,11 +157,130 @@ impl Default for IhaCdnRetentionConfig {
             enable: false,
             min_age: default_retention_min_age(),
             max_age: default_retention_max_age(),
+            exempt_extensions: vec![],
+            exempt_mimetypes: vec![],
+            schedule: default_purge_schedule(),
+        }
+    }
+}
+
+impl IhaCdnRetentionConfig {
+    /// Whether a file with this path and mimetype is exempt from the retention policy.
+    pub fn is_exempt(&self, path: &Path, mimetype: &str) -> bool {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        let exempt_extension = !extension.is_empty()
+            && self.exempt_extensions.iter().any(|exempt| {
+                exempt
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(extension)
+            });
+
+        exempt_extension
+            || self
+                .exempt_mimetypes
+                .iter()
+                .any(|pattern| mimetype_matches(pattern, mimetype))
+    }
+}
+
+/// The retention policy of short links, separate from the file retention.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnShortRetentionConfig {
+    /// Enable or disable removing short links.
+    pub enable: bool,
+    /// Remove short links this long after they were created. (in days)
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// Remove short links that were not clicked for this long. (in days)
+    #[serde(default)]
+    pub max_idle: Option<u64>,
+}
+
+/// The targets short links may point to.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnShortLinksConfig {
+    /// The allowed URL schemes, lowercase.
+    #[serde(default = "default_short_allowed_schemes")]
+    pub allowed_schemes: Vec<String>,
+    /// Domains that can't be linked to, their subdomains included.
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+}
+
+impl Default for IhaCdnShortLinksConfig {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: default_short_allowed_schemes(),
+            blocked_domains: vec![],
+        }
+    }
+}
+
+impl IhaCdnShortLinksConfig {
+    /// Whether links to this host are refused.
+    pub fn is_domain_blocked(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        self.blocked_domains.iter().any(|domain| {
+            let domain = domain.trim_start_matches('.');
+            host.eq_ignore_ascii_case(domain)
+                || host
+                    .len()
+                    .checked_sub(domain.len() + 1)
+                    .is_some_and(|split| {
+                        host.as_bytes()[split] == b'.'
+                            && host[split + 1..].eq_ignore_ascii_case(domain)
+                    })
+        })
+    }
+}
+
+/// What is kept about the uploader of each entry, for moderation.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnUploaderConfig {
+    /// Keep the IP address and user agent of the uploader.
+    #[serde(default = "default_uploader_record")]
+    pub record: bool,
+    /// Keep a salted hash of the IP address instead of the address itself.
+    #[serde(default)]
+    pub privacy: bool,
+    /// The salt of the IP hashes, required with `privacy`.
+    #[serde(default)]
+    pub ip_salt: Option<String>,
+}
+
+impl Default for IhaCdnUploaderConfig {
+    fn default() -> Self {
+        Self {
+            record: true,
+            privacy: false,
+            ip_salt: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl IhaCdnUploaderConfig {
+    /// How an IP address is stored, the hex SHA-256 of the salted address in privacy mode.
+    pub fn identify_ip(&self, ip: &std::net::IpAddr) -> String {
+        use sha2::{Digest, Sha256};
+
+        if !self.privacy {
+            return ip.to_string();
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.ip_salt.as_deref().unwrap_or_default().as_bytes());
+        hasher.update(ip.to_string().as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct IhaCdnStorageConfig {
     /// The maximum file size limit for uploads.
     ///
@@ -90,6 +295,26 @@ pub struct IhaCdnStorageConfig {
     ///
     /// If this is set to [`None`], there is no limit.
     pub admin_filesize_limit: Option<u64>,
+    /// How much bigger than the largest file size limit a request body can be (in Kilobytes).
+    ///
+    /// Covers the multipart overhead and the other form fields, bigger bodies are cut off
+    /// before being read. There is no body limit if either file size limit is [`None`].
+    #[serde(default = "default_body_limit_slack")]
+    pub body_limit_slack: u64,
+    /// Extra upload volumes used together with `upload_path`.
+    ///
+    /// New files are placed on the volume with the most available space.
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    /// How entries are stored in Redis, run `./ihacdn reencode` after changing it.
+    #[serde(default)]
+    pub entry_encoding: EntryEncoding,
+    /// The extension files of a detected mimetype are stored with, without the dot.
+    ///
+    /// Other mimetypes use the first extension known for them, which is alphabetical and
+    /// often unusual (e.g. `jfif` for JPEG). Setting it replaces the built-in table.
+    #[serde(default = "default_preferred_extensions")]
+    pub preferred_extensions: BTreeMap<String, String>,
 }
 
 impl Default for IhaCdnStorageConfig {
@@ -97,191 +322,1832 @@ impl Default for IhaCdnStorageConfig {
         Self {
             filesize_limit: default_filesize_limit(),
             admin_filesize_limit: None,
+            body_limit_slack: default_body_limit_slack(),
+            volumes: vec![],
+            entry_encoding: EntryEncoding::default(),
+            preferred_extensions: default_preferred_extensions(),
         }
     }
 }
 
-/// Block certain file extensions and MIME types from being uploaded.
+impl IhaCdnStorageConfig {
+    /// The extension to store a file of this detected mimetype with, if one is known.
+    pub fn extension_for(&self, mimetype: &str) -> Option<String> {
+        if let Some(extension) = self.preferred_extensions.get(mimetype) {
+            return Some(extension.trim_start_matches('.').to_string());
+        }
+        mime_guess::get_mime_extensions_str(mimetype)
+            .and_then(|extensions| extensions.first())
+            .filter(|&&extension| extension != "bin")
+            .map(|extension| extension.to_string())
+    }
+}
+
+/// The encoding of the entries stored in Redis, both are always readable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryEncoding {
+    /// Plain JSON.
+    #[default]
+    Json,
+    /// JSON compressed with deflate and a preset dictionary, about a third of the size.
+    Compact,
+}
+
+/// What to do with uploads whose declared `Content-Type` doesn't match the detected one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeMismatchPolicy {
+    /// Log the mismatch and keep the extension of the file name.
+    #[default]
+    Warn,
+    /// Store the file with the extension of the detected type, checked against the blocklist.
+    PreferSniffed,
+    /// Refuse the upload.
+    Reject,
+}
+
+/// How HTML pastes are served, raw HTML on our origin could run scripts or phish visitors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HtmlPolicy {
+    /// Rendered as escaped source like every other paste.
+    #[default]
+    Paste,
+    /// Rendered in a sandboxed iframe, the raw file is served with a `sandbox` CSP.
+    Sandbox,
+    /// Served as HTML if uploaded by an admin, others are rendered as escaped source.
+    AdminRaw,
+}
+
+/// `Cache-Control` header values used when serving each content class.
 ///
-/// This will not affect existing files and will not affect admin uploads.
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct IhaCdnBlocklistConfig {
-    /// Block the following file extensions.
-    #[serde(rename = "extension", default = "default_block_extension")]
-    pub extensions: Vec<String>,
-    /// Block the following MIME types.
-    #[serde(rename = "content_type", default = "default_block_mimetypes")]
-    pub content_types: Vec<String>,
+/// Set any of them to [`None`] to not send a `Cache-Control` header for that class.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnCacheConfig {
+    /// Used for pastes, both the rendered page and the raw content.
+    #[serde(default = "default_cache_paste")]
+    pub paste: Option<String>,
+    /// Used for `image/*` files.
+    #[serde(default = "default_cache_media")]
+    pub image: Option<String>,
+    /// Used for `video/*` files.
+    #[serde(default = "default_cache_media")]
+    pub video: Option<String>,
+    /// Used for every other files.
+    #[serde(default = "default_cache_media")]
+    pub file: Option<String>,
+    /// Used for short link redirects.
+    #[serde(default = "default_cache_short")]
+    pub short: Option<String>,
 }
 
-impl Default for IhaCdnBlocklistConfig {
+impl Default for IhaCdnCacheConfig {
     fn default() -> Self {
         Self {
-            extensions: default_block_extension(),
-            content_types: default_block_mimetypes(),
+            paste: default_cache_paste(),
+            image: default_cache_media(),
+            video: default_cache_media(),
+            file: default_cache_media(),
+            short: default_cache_short(),
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct IhaCdnConfig {
-    /// The hostname of the IhaCDN server.
-    #[serde(default = "default_hostname")]
-    pub hostname: String,
-    /// The host of the IhaCDN server.
-    #[serde(default = "default_hostname")]
-    pub host: String,
-    /// The port of the IhaCDN server.
-    #[serde(default = "default_ihacdn_port")]
-    pub port: u16,
-    /// HTTPS mode, this only affects the URL generation.
-    pub https_mode: bool,
-    /// The path to upload files to.
-    #[serde(default = "default_ihacdn_upload_path")]
-    pub upload_path: String,
-    /// Admin password for uploading files.
-    #[serde(default = "default_ihacdn_admin_password")]
-    pub admin_password: String,
-    /// The length of the random filename.
-    #[serde(default = "default_filename_length")]
-    pub filename_length: usize,
-    /// Config for the Redis database.
-    pub redis: String,
-    /// Config for the notifier.
-    pub notifier: IhaCdnNotifierConfig,
-    /// Config for the retention policy.
-    #[serde(rename = "file_retention")]
-    pub retention: IhaCdnRetentionConfig,
-    /// Config for the storage.
-    pub storage: IhaCdnStorageConfig,
-    /// Config for the blocklist.
-    pub blocklist: IhaCdnBlocklistConfig,
-    /// Config for the Plausible Analytics.
-    /// This can be missing if Plausible Analytics is not used.
-    #[serde(default)]
-    pub plausible: IhaCdnPlausibleConfig,
-}
-
-impl Default for IhaCdnConfig {
-    fn default() -> Self {
-        Self {
-            hostname: default_hostname(),
-            host: default_hostname(),
-            port: default_ihacdn_port(),
-            https_mode: false,
-            upload_path: default_ihacdn_upload_path(),
-            admin_password: default_ihacdn_admin_password(),
-            filename_length: default_filename_length(),
-            redis: format!("redis://{}:{}", default_hostname(), default_redis_port()),
-            notifier: IhaCdnNotifierConfig::default(),
-            retention: IhaCdnRetentionConfig::default(),
-            storage: IhaCdnStorageConfig::default(),
-            blocklist: IhaCdnBlocklistConfig::default(),
-            plausible: IhaCdnPlausibleConfig::default(),
+impl IhaCdnCacheConfig {
+    /// Get the `Cache-Control` value for a file with the given mimetype.
+    pub fn for_file(&self, mimetype: &str) -> Option<&str> {
+        if mimetype.starts_with("image/") {
+            self.image.as_deref()
+        } else if mimetype.starts_with("video/") {
+            self.video.as_deref()
+        } else {
+            self.file.as_deref()
         }
     }
 }
 
-impl IhaCdnConfig {
-    pub fn new() -> Self {
-        Self::default()
+/// Separate upload directories per content class.
+///
+/// Every class that is not set falls back to `upload_path`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnPathsConfig {
+    /// Used for pastes.
+    pub paste: Option<String>,
+    /// Used for `image/*` files.
+    pub image: Option<String>,
+    /// Used for `video/*` files.
+    pub video: Option<String>,
+    /// Used for every other files.
+    pub file: Option<String>,
+}
+
+impl IhaCdnPathsConfig {
+    /// Every configured class directory.
+    pub fn configured(&self) -> impl Iterator<Item = &String> {
+        [&self.paste, &self.image, &self.video, &self.file]
+            .into_iter()
+            .flatten()
     }
+}
 
-    /// Load the configuration from the `config.json` file.
-    pub fn load() -> Self {
-        let config = std::fs::read_to_string("config.json");
+/// Publish internal events (upload, delete, view) to a Redis Stream.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnEventsConfig {
+    /// Enable or disable the event stream.
+    pub enable: bool,
+    /// The Redis Stream key to publish events to.
+    #[serde(default = "default_events_stream")]
+    pub stream: String,
+    /// Approximate maximum length of the stream, older events are trimmed.
+    ///
+    /// If this is set to [`None`], the stream is never trimmed.
+    #[serde(default = "default_events_max_len")]
+    pub max_len: Option<u64>,
+}
 
-        match config {
-            Ok(config) => {
-                let config: IhaCdnConfig = serde_json::from_str(&config).unwrap();
-                config
-            }
-            Err(_) => {
-                tracing::warn!("Config file not found, creating a new one.");
-                let config = Self::new();
-                config.save();
-                config
-            }
+impl Default for IhaCdnEventsConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            stream: default_events_stream(),
+            max_len: default_events_max_len(),
         }
     }
+}
 
-    /// Save the configuration to the `config.json` file.
-    pub fn save(&self) {
-        let config = serde_json::to_string_pretty(&self).unwrap();
-        std::fs::write("config.json", config).unwrap();
-    }
+/// Where the access log is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogSink {
+    /// JSON lines in rotating files.
+    #[default]
+    File,
+    /// Entries of a Redis Stream.
+    Redis,
+}
 
-    /// Verify if the config is actually valid and correctly set.
-    pub fn verify(&self) -> bool {
-        if self.hostname.is_empty() {
-            tracing::error!("Hostname is empty, please set it in the config file.");
-            return false;
-        }
+/// How often the access log file is rotated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
 
-        if self.port == 0 {
-            tracing::error!("Port is not set, please set it in the config file.");
-            return false;
-        }
+/// A record of every request, apart from the tracing logs.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnAccessLogConfig {
+    /// Enable or disable the access log.
+    pub enable: bool,
+    #[serde(default)]
+    pub sink: AccessLogSink,
+    /// The directory of the log files, for the `file` sink.
+    #[serde(default = "default_access_log_directory")]
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub rotation: AccessLogRotation,
+    /// How many rotated files are kept, if this is set to [`None`] they are never removed.
+    #[serde(default)]
+    pub max_files: Option<usize>,
+    /// The Redis Stream key, for the `redis` sink.
+    #[serde(default = "default_access_log_stream")]
+    pub stream: String,
+    /// Approximate maximum length of the stream, older records are trimmed.
+    ///
+    /// If this is set to [`None`], the stream is never trimmed.
+    #[serde(default = "default_access_log_max_len")]
+    pub max_len: Option<u64>,
+}
 
-        if self.upload_path.is_empty() {
-            tracing::error!("Upload path is empty, please set it in the config file.");
-            return false;
+impl Default for IhaCdnAccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            sink: AccessLogSink::default(),
+            directory: default_access_log_directory(),
+            rotation: AccessLogRotation::default(),
+            max_files: None,
+            stream: default_access_log_stream(),
+            max_len: default_access_log_max_len(),
         }
+    }
+}
 
-        // Verify upload_path exist
-        if !std::path::Path::new(&self.upload_path).exists() {
-            tracing::error!("Upload path does not exist, please set it in the config file.");
-            return false;
-        }
+/// Daily rollup of upload statistics.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnStatsConfig {
+    /// Enable or disable the rollup job.
+    pub enable: bool,
+    /// The cron schedule of the job, it always rolls up the previous day (UTC).
+    #[serde(default = "default_stats_schedule")]
+    pub schedule: String,
+    /// How many of the most uploaded mimetypes to keep.
+    #[serde(default = "default_stats_top_mimetypes")]
+    pub top_mimetypes: usize,
+    /// How long the rollups are kept in days.
+    ///
+    /// If this is set to [`None`], the rollups are kept forever.
+    #[serde(default = "default_stats_keep_days")]
+    pub keep_days: Option<u64>,
+    /// Post a daily digest through the notifier.
+    #[serde(default)]
+    pub digest: bool,
+    /// Serve the aggregated rollups at `/stats`.
+    #[serde(default)]
+    pub public: bool,
+}
 
-        if self.upload_path.is_empty() {
-            tracing::error!("Upload path is empty, please set it in the config file.");
-            return false;
+impl Default for IhaCdnStatsConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            schedule: default_stats_schedule(),
+            top_mimetypes: default_stats_top_mimetypes(),
+            keep_days: default_stats_keep_days(),
+            digest: false,
+            public: false,
         }
+    }
+}
 
-        // Verify upload_path exist
-        let resolved_path = std::fs::canonicalize(&self.upload_path).unwrap();
-        if !resolved_path.exists() {
-            tracing::error!("Upload path does not exist, please set it in the config file.");
-            return false;
-        }
+/// The action taken when a moderation rule matches an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Reject the upload.
+    Reject,
+    /// Store the upload but refuse to serve it until an admin releases it.
+    Quarantine,
+    /// Expire the upload after the rule `retention_days`.
+    ForceRetention,
+    /// Send a notification through the notifier.
+    Notify,
+}
 
-        if self.admin_password.is_empty() {
-            tracing::error!("Admin password is empty, please set it in the config file.");
-            return false;
-        }
+/// A moderation rule evaluated on every non-admin upload.
+///
+/// Every matcher that is set must match for the rule to apply.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct ModerationRule {
+    /// The name of the rule, used for logging and notifications.
+    pub name: String,
+    /// Match the sniffed mimetype, `type/*` wildcards are supported.
+    #[serde(default)]
+    pub mimetypes: Vec<String>,
+    /// Match the file extension.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Match files bigger or equal to this size (in Kilobytes).
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    /// Match files smaller or equal to this size (in Kilobytes).
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Match the uploader IP against these CIDR ranges.
+    #[serde(default)]
+    pub ip_ranges: Vec<String>,
+    /// Match the SHA-256 hash (hex) of the file.
+    #[serde(default)]
+    pub hashes: Vec<String>,
+    /// Match the original file name against this regex.
+    #[serde(default)]
+    pub filename_regex: Option<String>,
+    /// The actions to take when the rule matches.
+    pub actions: Vec<ModerationAction>,
+    /// The retention (in days) used by the `force_retention` action.
+    #[serde(default)]
+    pub retention_days: Option<u64>,
+}
 
-        if self.filename_length < 5 {
-            tracing::error!("Filename length must be longer or equals to 5");
-            return false;
-        }
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnModerationConfig {
+    /// The rules evaluated on upload.
+    #[serde(default)]
+    pub rules: Vec<ModerationRule>,
+}
 
-        if self.plausible.enable && self.plausible.domain.is_none() {
-            tracing::error!("Plausible Analytics is enabled but no domain is set.");
-            return false;
-        }
+/// Media processing with ffmpeg.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnMediaConfig {
+    /// The ffmpeg binary, either a name in `PATH` or a full path.
+    #[serde(default = "default_ffmpeg_path")]
+    pub ffmpeg_path: String,
+    /// Extract a poster frame for video uploads.
+    #[serde(default)]
+    pub poster: bool,
+    /// Where to take the poster frame from, in seconds.
+    #[serde(default = "default_poster_seek")]
+    pub poster_seek: f64,
+    /// The timeout of a single ffmpeg invocation in seconds.
+    #[serde(default = "default_media_timeout")]
+    pub timeout: u64,
+    /// Convert uploaded videos with the listed extensions into a browser-playable copy.
+    #[serde(default)]
+    pub transcode: bool,
+    /// The extensions of the videos to convert.
+    #[serde(default = "default_transcode_extensions")]
+    pub transcode_extensions: Vec<String>,
+    /// The output container, either `mp4` or `webm`.
+    #[serde(default = "default_transcode_format")]
+    pub transcode_format: String,
+    /// How many videos are converted at the same time.
+    #[serde(default = "default_transcode_concurrency")]
+    pub transcode_concurrency: usize,
+    /// The timeout of a single conversion in seconds.
+    #[serde(default = "default_transcode_timeout")]
+    pub transcode_timeout: u64,
+    /// Convert big GIF uploads into a video in `transcode_format`, served to clients asking for it.
+    #[serde(default)]
+    pub gif_to_video: bool,
+    /// The smallest GIF to convert, in kilobytes.
+    #[serde(default = "default_gif_min_size")]
+    pub gif_min_size: u64,
+    /// Remux MP4 uploads with their index at the end so they can play before being downloaded.
+    #[serde(default)]
+    pub faststart: bool,
+}
 
-        // Create the uploads and uploads_admin dir in upload_path if it's not exist.
-        let uploads_path = resolved_path.join("uploads");
-        if !uploads_path.exists() {
-            std::fs::create_dir_all(&uploads_path).unwrap();
-        }
-        let uploads_admin_path = resolved_path.join("uploads_admin");
-        if !uploads_admin_path.exists() {
-            std::fs::create_dir_all(&uploads_admin_path).unwrap();
-        }
+impl IhaCdnMediaConfig {
+    /// Whether anything is converted in the background.
+    pub fn is_transcoding(&self) -> bool {
+        self.transcode || self.gif_to_video || self.faststart
+    }
 
-        true
+    /// Check if an uploaded file should be converted.
+    pub fn should_transcode(&self, mimetype: &str, extension: &str, size: u64) -> bool {
+        if mimetype == "image/gif" {
+            return self.gif_to_video && size >= self.gif_min_size * 1024;
+        }
+        self.transcode
+            && mimetype.starts_with("video/")
+            && self
+                .transcode_extensions
+                .iter()
+                .any(|ext| ext.eq_ignore_ascii_case(extension))
     }
+}
 
-    pub fn get_path(&self, is_admin: bool) -> PathBuf {
-        let mut path = std::fs::canonicalize(&self.upload_path).unwrap();
+impl Default for IhaCdnMediaConfig {
+    fn default() -> Self {
+        Self {
+            ffmpeg_path: default_ffmpeg_path(),
+            poster: false,
+            poster_seek: default_poster_seek(),
+            timeout: default_media_timeout(),
+            transcode: false,
+            transcode_extensions: default_transcode_extensions(),
+            transcode_format: default_transcode_format(),
+            transcode_concurrency: default_transcode_concurrency(),
+            transcode_timeout: default_transcode_timeout(),
+            gif_to_video: false,
+            gif_min_size: default_gif_min_size(),
+            faststart: false,
+        }
+    }
+}
+
+/// Resized copies of uploaded images, served at `/{id}/thumb`.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnThumbnailConfig {
+    /// Enable or disable the thumbnails.
+    pub enable: bool,
+    /// The largest width or height that can be asked for, in pixels.
+    #[serde(default = "default_thumbnail_max_dimension")]
+    pub max_dimension: u32,
+    /// The largest image thumbnails are made of, in kilobytes.
+    #[serde(default = "default_thumbnail_max_source_size")]
+    pub max_source_size: u64,
+    /// How many sizes are kept of a single image.
+    #[serde(default = "default_thumbnail_max_per_entry")]
+    pub max_per_entry: usize,
+}
+
+impl Default for IhaCdnThumbnailConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_dimension: default_thumbnail_max_dimension(),
+            max_source_size: default_thumbnail_max_source_size(),
+            max_per_entry: default_thumbnail_max_per_entry(),
+        }
+    }
+}
+
+/// Keep a tombstone of removed entries to tell expired links apart from unknown ones.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnTombstoneConfig {
+    /// Enable or disable the tombstones.
+    pub enable: bool,
+    /// How long the tombstones are kept in days.
+    ///
+    /// If this is set to [`None`], the tombstones are kept forever.
+    #[serde(default = "default_tombstone_keep_days")]
+    pub keep_days: Option<u64>,
+}
+
+impl Default for IhaCdnTombstoneConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            keep_days: default_tombstone_keep_days(),
+        }
+    }
+}
+
+/// The runtime status at `/_/status`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnStatusConfig {
+    /// Serve the status without the admin key.
+    pub public: bool,
+}
+
+/// Automatic temporary bans of abusive IPs.
+///
+/// Every abusive request adds its weight to the score of the IP, which drains by
+/// `decay_per_minute`. Reaching `threshold` bans the IP, each ban twice as long as the previous one.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnAbuseConfig {
+    pub enable: bool,
+    #[serde(default = "default_abuse_threshold")]
+    pub threshold: f64,
+    #[serde(default = "default_abuse_decay_per_minute")]
+    pub decay_per_minute: f64,
+    /// The weight of uploads with a blocked extension or content type.
+    #[serde(default = "default_abuse_blocked_type_weight")]
+    pub blocked_type_weight: f64,
+    /// The weight of uploads over the size limit.
+    #[serde(default = "default_abuse_oversized_weight")]
+    pub oversized_weight: f64,
+    /// The weight of requests for missing entries, e.g. scanners guessing IDs.
+    #[serde(default = "default_abuse_not_found_weight")]
+    pub not_found_weight: f64,
+    /// The duration of the first ban in seconds.
+    #[serde(default = "default_abuse_ban_duration")]
+    pub ban_duration: u64,
+    /// The longest ban in seconds.
+    #[serde(default = "default_abuse_max_ban_duration")]
+    pub max_ban_duration: u64,
+    /// How long previous bans count towards the next duration, in seconds.
+    #[serde(default = "default_abuse_strike_memory")]
+    pub strike_memory: u64,
+}
+
+impl Default for IhaCdnAbuseConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            threshold: default_abuse_threshold(),
+            decay_per_minute: default_abuse_decay_per_minute(),
+            blocked_type_weight: default_abuse_blocked_type_weight(),
+            oversized_weight: default_abuse_oversized_weight(),
+            not_found_weight: default_abuse_not_found_weight(),
+            ban_duration: default_abuse_ban_duration(),
+            max_ban_duration: default_abuse_max_ban_duration(),
+            strike_memory: default_abuse_strike_memory(),
+        }
+    }
+}
+
+/// A budget of requests per IP.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
+pub struct RateBudget {
+    /// How many requests are allowed in a window.
+    pub limit: u64,
+    /// The length of the window in seconds, it starts with the first request.
+    pub window: u64,
+}
+
+/// Request budgets per IP for each kind of route, kept in Redis.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnRateLimitConfig {
+    pub enable: bool,
+    /// File and paste uploads.
+    #[serde(default = "default_rate_limit_upload")]
+    pub upload: Option<RateBudget>,
+    /// Shortened links.
+    #[serde(default = "default_rate_limit_short")]
+    pub short: Option<RateBudget>,
+    /// Paste edits and appends.
+    #[serde(default)]
+    pub edit: Option<RateBudget>,
+    /// Reads of entries, pages and raw content.
+    #[serde(default)]
+    pub read: Option<RateBudget>,
+}
+
+impl Default for IhaCdnRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            upload: default_rate_limit_upload(),
+            short: default_rate_limit_short(),
+            edit: None,
+            read: None,
+        }
+    }
+}
+
+/// Upload restrictions by the network of the uploader.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnNetworkBlocklistConfig {
+    /// Reject uploads from Tor exit nodes.
+    pub block_tor: bool,
+    /// Where the list of Tor exit nodes is downloaded from, one IP per line.
+    #[serde(default = "default_tor_exit_list_url")]
+    pub tor_exit_list_url: String,
+    /// When the list of Tor exit nodes is downloaded again, as a cron expression.
+    #[serde(default = "default_tor_refresh_schedule")]
+    pub tor_refresh_schedule: String,
+    /// A MaxMind DB with the autonomous system of IPs, e.g. `GeoLite2-ASN.mmdb`.
+    pub asn_database: Option<PathBuf>,
+    /// Reject uploads from these autonomous systems, e.g. datacenters.
+    pub blocked_asns: Vec<u32>,
+    /// The names of the admin keys that may upload from blocked networks.
+    pub allowed_keys: Vec<String>,
+}
+
+impl Default for IhaCdnNetworkBlocklistConfig {
+    fn default() -> Self {
+        Self {
+            block_tor: false,
+            tor_exit_list_url: default_tor_exit_list_url(),
+            tor_refresh_schedule: default_tor_refresh_schedule(),
+            asn_database: None,
+            blocked_asns: vec![],
+            allowed_keys: vec![],
+        }
+    }
+}
+
+/// Full-text search over the content of pastes.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnSearchConfig {
+    /// Enable or disable indexing pastes and `/api/search`.
+    pub enable: bool,
+    /// Only the first bytes of a paste are indexed.
+    #[serde(default = "default_search_max_size")]
+    pub max_size: u64,
+}
+
+impl Default for IhaCdnSearchConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_size: default_search_max_size(),
+        }
+    }
+}
+
+/// The public gallery of uploads sent with `unlisted=false`.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnGalleryConfig {
+    /// Enable or disable the gallery at `/explore`.
+    pub enable: bool,
+    /// How many uploads are shown per page.
+    #[serde(default = "default_gallery_page_size")]
+    pub page_size: usize,
+}
+
+impl Default for IhaCdnGalleryConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            page_size: default_gallery_page_size(),
+        }
+    }
+}
+
+/// Remember the result of requests sent with an `Idempotency-Key` header.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnIdempotencyConfig {
+    /// How long the result of a request is kept for retries, in hours.
+    #[serde(default = "default_idempotency_keep_hours")]
+    pub keep_hours: u64,
+    /// Identical uploads of the same uploader within this many seconds return the first one, 0 to disable.
+    #[serde(default = "default_dedup_window")]
+    pub dedup_window: u64,
+}
+
+impl Default for IhaCdnIdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            keep_hours: default_idempotency_keep_hours(),
+            dedup_window: default_dedup_window(),
+        }
+    }
+}
+
+/// Serving cached entries while Redis is down.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnRedisFallbackConfig {
+    /// Stop connecting to Redis after repeated failures and serve recently read entries from memory.
+    pub enable: bool,
+    /// How many failed connections or commands in a row open the breaker.
+    #[serde(default = "default_fallback_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long Redis is not tried again once the breaker is open, in seconds.
+    #[serde(default = "default_fallback_open_for")]
+    pub open_for: u64,
+    /// How many entries are kept in memory.
+    #[serde(default = "default_fallback_cache_size")]
+    pub cache_size: usize,
+    /// How long an entry in memory may be served for, in seconds.
+    #[serde(default = "default_fallback_cache_ttl")]
+    pub cache_ttl: u64,
+}
+
+impl Default for IhaCdnRedisFallbackConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            failure_threshold: default_fallback_failure_threshold(),
+            open_for: default_fallback_open_for(),
+            cache_size: default_fallback_cache_size(),
+            cache_ttl: default_fallback_cache_ttl(),
+        }
+    }
+}
+
+/// Preloading the most viewed recent entries on startup.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnWarmupConfig {
+    /// Preload the entries in the background once started.
+    pub enable: bool,
+    /// How many entries are preloaded.
+    #[serde(default = "default_warmup_entries")]
+    pub entries: usize,
+    /// How many of the newest entries are ranked by their views to pick them.
+    #[serde(default = "default_warmup_candidates")]
+    pub candidates: usize,
+    /// Pastes up to this size are read once so they are in the page cache, in bytes.
+    #[serde(default = "default_warmup_paste_max_size")]
+    pub paste_max_size: u64,
+}
+
+impl Default for IhaCdnWarmupConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            entries: default_warmup_entries(),
+            candidates: default_warmup_candidates(),
+            paste_max_size: default_warmup_paste_max_size(),
+        }
+    }
+}
+
+/// Checks run on startup before binding the port.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnPreflightConfig {
+    /// Run the checks, failing ones that would break every request stop the startup.
+    pub enable: bool,
+    /// How far apart the clock and the Redis server may be, in seconds.
+    #[serde(default = "default_preflight_max_clock_skew")]
+    pub max_clock_skew: u64,
+    /// Send a `HEAD` request to the configured webhook and analytics endpoints.
+    #[serde(default = "default_preflight_check_endpoints")]
+    pub check_endpoints: bool,
+}
+
+impl Default for IhaCdnPreflightConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            max_clock_skew: default_preflight_max_clock_skew(),
+            check_endpoints: default_preflight_check_endpoints(),
+        }
+    }
+}
+
+/// External commands run on uploads.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnHooksConfig {
+    /// The command run after each upload is written to disk, with its arguments.
+    ///
+    /// The path of the file is appended to the arguments and the upload is described as JSON on stdin.
+    pub post_upload: Vec<String>,
+    /// The timeout of the command in seconds.
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+    /// The exit code rejecting the upload.
+    #[serde(default = "default_hook_reject_exit_code")]
+    pub reject_exit_code: i32,
+    /// The exit code quarantining the upload.
+    #[serde(default = "default_hook_quarantine_exit_code")]
+    pub quarantine_exit_code: i32,
+    /// Reject the upload if the command fails, times out or exits with another code.
+    pub fail_closed: bool,
+}
+
+impl Default for IhaCdnHooksConfig {
+    fn default() -> Self {
+        Self {
+            post_upload: vec![],
+            timeout: default_hook_timeout(),
+            reject_exit_code: default_hook_reject_exit_code(),
+            quarantine_exit_code: default_hook_quarantine_exit_code(),
+            fail_closed: false,
+        }
+    }
+}
+
+/// The most bytes of a file an upload filter can be given.
+const MAX_FILTER_HEAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// A WASM module inspecting uploads before they are stored.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnUploadFilterConfig {
+    /// The name of the filter, used in logs and errors.
+    pub name: String,
+    /// The path to the module, `.wasm` or `.wat`.
+    pub path: PathBuf,
+    /// How many bytes from the start of the file are given to the filter.
+    #[serde(default = "default_filter_head_size")]
+    pub head_size: usize,
+    /// The fuel given to each run, roughly the number of instructions it may execute.
+    #[serde(default = "default_filter_fuel")]
+    pub fuel: u64,
+    /// The most memory the module may use, in kilobytes.
+    #[serde(default = "default_filter_memory_limit")]
+    pub memory_limit: u64,
+    /// Reject the upload if the filter fails, runs out of fuel or memory.
+    #[serde(default)]
+    pub fail_closed: bool,
+}
+
+/// WASM modules run on uploads, in order.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnFiltersConfig {
+    #[serde(default)]
+    pub modules: Vec<IhaCdnUploadFilterConfig>,
+}
+
+/// The Lua script deciding on uploads and short links.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnScriptingConfig {
+    /// The path to the script, `None` to disable it.
+    #[serde(default)]
+    pub script: Option<PathBuf>,
+    /// How long each call of the script may run, in milliseconds.
+    #[serde(default = "default_script_timeout")]
+    pub timeout: u64,
+    /// The most memory the script may use, in kilobytes.
+    #[serde(default = "default_script_memory_limit")]
+    pub memory_limit: u64,
+    /// Reject the request if the script fails or times out.
+    #[serde(default)]
+    pub fail_closed: bool,
+}
+
+impl Default for IhaCdnScriptingConfig {
+    fn default() -> Self {
+        Self {
+            script: None,
+            timeout: default_script_timeout(),
+            memory_limit: default_script_memory_limit(),
+            fail_closed: false,
+        }
+    }
+}
+
+/// Config for the signed upload URLs of `/api/sign-upload`.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnSignedUploadsConfig {
+    /// How long a signed URL is valid by default, in seconds.
+    #[serde(default = "default_signed_uploads_default_ttl")]
+    pub default_ttl: u64,
+    /// The longest a signed URL can be valid, in seconds.
+    #[serde(default = "default_signed_uploads_max_ttl")]
+    pub max_ttl: u64,
+}
+
+impl Default for IhaCdnSignedUploadsConfig {
+    fn default() -> Self {
+        Self {
+            default_ttl: default_signed_uploads_default_ttl(),
+            max_ttl: default_signed_uploads_max_ttl(),
+        }
+    }
+}
+
+/// Config for how long request bodies may take to upload.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnTimeoutsConfig {
+    /// The longest a file upload may take, in seconds, `None` for no limit.
+    #[serde(default = "default_upload_deadline")]
+    pub upload: Option<u64>,
+    /// The longest a paste edit or append may take, in seconds, `None` for no limit.
+    #[serde(default = "default_paste_deadline")]
+    pub paste: Option<u64>,
+    /// The longest an upload may send nothing, in seconds, `None` for no limit.
+    #[serde(default = "default_idle_timeout")]
+    pub idle: Option<u64>,
+}
+
+impl Default for IhaCdnTimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            upload: default_upload_deadline(),
+            paste: default_paste_deadline(),
+            idle: default_idle_timeout(),
+        }
+    }
+}
+
+/// Config for the IDs chosen by uploaders instead of a random one.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnSlugConfig {
+    /// Allow everyone to choose a slug, admins always can.
+    pub public: bool,
+    /// The shortest slug, so they don't take the short IDs random ones could use.
+    #[serde(default = "default_slug_min_length")]
+    pub min_length: usize,
+}
+
+impl Default for IhaCdnSlugConfig {
+    fn default() -> Self {
+        Self {
+            public: false,
+            min_length: default_slug_min_length(),
+        }
+    }
+}
+
+/// Config for editing pastes after upload.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnPasteConfig {
+    /// Allow replacing the content of a paste with its delete token.
+    pub editable: bool,
+    /// How many previous versions of an edited paste are kept.
+    #[serde(default = "default_paste_max_revisions")]
+    pub max_revisions: usize,
+    /// Stop following a paste with `/raw?follow=1` after this many seconds without new data.
+    #[serde(default = "default_paste_follow_timeout")]
+    pub follow_timeout: u64,
+    /// How HTML pastes are served.
+    #[serde(default)]
+    pub html_policy: HtmlPolicy,
+}
+
+impl Default for IhaCdnPasteConfig {
+    fn default() -> Self {
+        Self {
+            editable: true,
+            max_revisions: default_paste_max_revisions(),
+            follow_timeout: default_paste_follow_timeout(),
+            html_policy: HtmlPolicy::default(),
+        }
+    }
+}
+
+/// Run uploaded images through an external NSFW classification endpoint.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnNsfwConfig {
+    /// Enable or disable the classification hook.
+    pub enable: bool,
+    /// The endpoint that receives the image as the raw POST body.
+    pub endpoint: Option<String>,
+    /// JSON pointer to the score (0.0 to 1.0) in the endpoint response.
+    #[serde(default = "default_nsfw_score_pointer")]
+    pub score_pointer: String,
+    /// Quarantine non-admin uploads with a score at or above this threshold.
+    ///
+    /// If this is set to [`None`], the score is only recorded.
+    #[serde(default)]
+    pub quarantine_threshold: Option<f64>,
+    /// The request timeout in seconds.
+    #[serde(default = "default_nsfw_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for IhaCdnNsfwConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            endpoint: None,
+            score_pointer: default_nsfw_score_pointer(),
+            quarantine_threshold: None,
+            timeout: default_nsfw_timeout(),
+        }
+    }
+}
+
+/// Scanning uploads for malware with clamd.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnAntivirusConfig {
+    /// Enable or disable scanning.
+    pub enable: bool,
+    /// The clamd address, `host:port` or `unix:/path/to/clamd.sock`.
+    #[serde(default = "default_antivirus_address")]
+    pub address: String,
+    /// The scan timeout in seconds.
+    #[serde(default = "default_antivirus_timeout")]
+    pub timeout: u64,
+    /// Reject the upload if clamd can't be reached, times out or fails.
+    pub fail_closed: bool,
+}
+
+impl Default for IhaCdnAntivirusConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            address: default_antivirus_address(),
+            timeout: default_antivirus_timeout(),
+            fail_closed: false,
+        }
+    }
+}
+
+/// CORS policy applied to every route.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnCorsConfig {
+    /// Allowed origins, use `*` to allow any origin.
+    #[serde(default = "default_cors_wildcard")]
+    pub allowed_origins: Vec<String>,
+    /// Allowed HTTP methods.
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Allowed request headers, use `*` to allow any header.
+    #[serde(default = "default_cors_wildcard")]
+    pub allowed_headers: Vec<String>,
+    /// Allow credentials (cookies, authorization headers) in cross-origin requests.
+    ///
+    /// This can't be used together with a `*` origin or header.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long the preflight response can be cached, in seconds.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+impl Default for IhaCdnCorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: default_cors_wildcard(),
+            allowed_methods: default_cors_methods(),
+            allowed_headers: default_cors_wildcard(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl IhaCdnCorsConfig {
+    /// Check if any origin is allowed.
+    pub fn is_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Check if any header is allowed.
+    pub fn is_any_header(&self) -> bool {
+        self.allowed_headers.iter().any(|header| header == "*")
+    }
+}
+
+/// Block certain file extensions and MIME types from being uploaded.
+///
+/// This will not affect existing files and will not affect admin uploads.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnBlocklistConfig {
+    /// Block the following file extensions.
+    #[serde(rename = "extension", default = "default_block_extension")]
+    pub extensions: Vec<String>,
+    /// Block the following MIME types.
+    #[serde(rename = "content_type", default = "default_block_mimetypes")]
+    pub content_types: Vec<String>,
+    /// Path to a file of extra words that generated IDs must not contain.
+    ///
+    /// One word per line, this extends the built-in reserved and blocked words list.
+    #[serde(default)]
+    pub words_file: Option<String>,
+    /// What to do when the declared type of an upload doesn't match its content.
+    #[serde(default)]
+    pub type_mismatch: TypeMismatchPolicy,
+}
+
+impl Default for IhaCdnBlocklistConfig {
+    fn default() -> Self {
+        Self {
+            extensions: default_block_extension(),
+            content_types: default_block_mimetypes(),
+            words_file: None,
+            type_mismatch: TypeMismatchPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IhaCdnConfig {
+    /// The hostname of the IhaCDN server.
+    #[serde(default = "default_hostname")]
+    pub hostname: String,
+    /// A second hostname only serving the raw bytes of uploads, pages stay on `hostname`.
+    ///
+    /// Keeps user content away from the origin of the pages, it must point to the same server.
+    #[serde(default)]
+    pub content_hostname: Option<String>,
+    /// The host of the IhaCDN server.
+    #[serde(default = "default_hostname")]
+    pub host: String,
+    /// The port of the IhaCDN server.
+    #[serde(default = "default_ihacdn_port")]
+    pub port: u16,
+    /// HTTPS mode, this only affects the URL generation.
+    pub https_mode: bool,
+    /// The path to upload files to.
+    #[serde(default = "default_ihacdn_upload_path")]
+    pub upload_path: String,
+    /// Separate upload paths per content class.
+    #[serde(default)]
+    pub paths: IhaCdnPathsConfig,
+    /// Admin password for uploading files.
+    #[serde(default = "default_ihacdn_admin_password")]
+    pub admin_password: String,
+    /// Additional admin keys with their own role, `admin_password` is always a superadmin.
+    #[serde(default)]
+    pub admin_keys: Vec<IhaCdnAdminKey>,
+    /// The length of the random filename.
+    #[serde(default = "default_filename_length")]
+    pub filename_length: usize,
+    /// Config for the IDs chosen by uploaders.
+    #[serde(default)]
+    pub slugs: IhaCdnSlugConfig,
+    /// Config for the Redis database.
+    pub redis: String,
+    /// Read-only replicas of the Redis database, used to look up entries.
+    #[serde(default)]
+    pub redis_replicas: Vec<String>,
+    /// The prefix of every Redis key of this instance.
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// Config for serving entries while Redis is down.
+    #[serde(default)]
+    pub redis_fallback: IhaCdnRedisFallbackConfig,
+    /// Config for preloading entries on startup.
+    #[serde(default)]
+    pub warmup: IhaCdnWarmupConfig,
+    /// The identity of this instance.
+    #[serde(default)]
+    pub instance: IhaCdnInstanceConfig,
+    /// Config for the notifier.
+    pub notifier: IhaCdnNotifierConfig,
+    /// Config for the retention policy.
+    #[serde(rename = "file_retention")]
+    pub retention: IhaCdnRetentionConfig,
+    /// Config for the retention policy of short links.
+    #[serde(default)]
+    pub short_retention: IhaCdnShortRetentionConfig,
+    /// Config for the targets of short links.
+    #[serde(default)]
+    pub short_links: IhaCdnShortLinksConfig,
+    /// Config for the storage.
+    pub storage: IhaCdnStorageConfig,
+    /// Config for the blocklist.
+    pub blocklist: IhaCdnBlocklistConfig,
+    /// Config for the Plausible Analytics.
+    /// This can be missing if Plausible Analytics is not used.
+    #[serde(default)]
+    pub plausible: IhaCdnPlausibleConfig,
+    /// Config for the `Cache-Control` policies of served content.
+    #[serde(default)]
+    pub cache: IhaCdnCacheConfig,
+    /// Config for the CORS policy.
+    #[serde(default)]
+    pub cors: IhaCdnCorsConfig,
+    /// Config for the Redis Stream event bus.
+    #[serde(default)]
+    pub events: IhaCdnEventsConfig,
+    /// Config for the access log of every request.
+    #[serde(default)]
+    pub access_log: IhaCdnAccessLogConfig,
+    /// Config for the daily statistics rollup.
+    #[serde(default)]
+    pub stats: IhaCdnStatsConfig,
+    /// Config for the moderation rules.
+    #[serde(default)]
+    pub moderation: IhaCdnModerationConfig,
+    /// Config for the NSFW image classification hook.
+    #[serde(default)]
+    pub nsfw: IhaCdnNsfwConfig,
+    /// Config for the malware scanning of uploads.
+    #[serde(default)]
+    pub antivirus: IhaCdnAntivirusConfig,
+    /// Config for media processing with ffmpeg.
+    #[serde(default)]
+    pub media: IhaCdnMediaConfig,
+    /// Config for the resized copies of images.
+    #[serde(default)]
+    pub thumbnails: IhaCdnThumbnailConfig,
+    /// Config for editing pastes.
+    #[serde(default)]
+    pub paste: IhaCdnPasteConfig,
+    /// Config for the tombstones of removed entries.
+    #[serde(default)]
+    pub tombstone: IhaCdnTombstoneConfig,
+    /// Config for retried uploads.
+    #[serde(default)]
+    pub idempotency: IhaCdnIdempotencyConfig,
+    /// Config for the signed upload URLs.
+    #[serde(default)]
+    pub signed_uploads: IhaCdnSignedUploadsConfig,
+    /// Config for the external commands run on uploads.
+    #[serde(default)]
+    pub hooks: IhaCdnHooksConfig,
+    /// Config for the WASM modules inspecting uploads.
+    #[serde(default)]
+    pub filters: IhaCdnFiltersConfig,
+    /// Config for the Lua script deciding on uploads and short links.
+    #[serde(default)]
+    pub scripting: IhaCdnScriptingConfig,
+    /// Config for the upload timeouts.
+    #[serde(default)]
+    pub timeouts: IhaCdnTimeoutsConfig,
+    /// Config for the public gallery.
+    #[serde(default)]
+    pub gallery: IhaCdnGalleryConfig,
+    /// Config for the full-text search of pastes.
+    #[serde(default)]
+    pub search: IhaCdnSearchConfig,
+    /// Config for the runtime status endpoint.
+    #[serde(default)]
+    pub status: IhaCdnStatusConfig,
+    /// Config for the automatic bans of abusive IPs.
+    #[serde(default)]
+    pub abuse: IhaCdnAbuseConfig,
+    /// Config for the request budgets per IP.
+    #[serde(default)]
+    pub rate_limit: IhaCdnRateLimitConfig,
+    /// Config for what is kept about uploaders.
+    #[serde(default)]
+    pub uploader: IhaCdnUploaderConfig,
+    /// Config for the upload restrictions by network.
+    #[serde(default)]
+    pub network_blocklist: IhaCdnNetworkBlocklistConfig,
+    /// Config for the startup checks.
+    #[serde(default)]
+    pub preflight: IhaCdnPreflightConfig,
+    /// Refuse to start with keys the config doesn't know, instead of only warning about them.
+    ///
+    /// Every key has a default, so a misspelled key is otherwise silently ignored.
+    #[serde(default)]
+    pub strict: bool,
+    /// Other instances served by this process, picked by the `Host` of each request.
+    ///
+    /// Each one is merged over this config, see [`IhaCdnConfig::tenant_configs`].
+    #[serde(default)]
+    pub tenants: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl Default for IhaCdnConfig {
+    fn default() -> Self {
+        Self {
+            hostname: default_hostname(),
+            content_hostname: None,
+            host: default_hostname(),
+            port: default_ihacdn_port(),
+            https_mode: false,
+            upload_path: default_ihacdn_upload_path(),
+            paths: IhaCdnPathsConfig::default(),
+            admin_password: default_ihacdn_admin_password(),
+            admin_keys: vec![],
+            filename_length: default_filename_length(),
+            slugs: IhaCdnSlugConfig::default(),
+            redis: format!("redis://{}:{}", default_hostname(), default_redis_port()),
+            redis_replicas: vec![],
+            key_prefix: default_key_prefix(),
+            redis_fallback: IhaCdnRedisFallbackConfig::default(),
+            warmup: IhaCdnWarmupConfig::default(),
+            instance: IhaCdnInstanceConfig::default(),
+            notifier: IhaCdnNotifierConfig::default(),
+            retention: IhaCdnRetentionConfig::default(),
+            short_retention: IhaCdnShortRetentionConfig::default(),
+            short_links: IhaCdnShortLinksConfig::default(),
+            storage: IhaCdnStorageConfig::default(),
+            blocklist: IhaCdnBlocklistConfig::default(),
+            plausible: IhaCdnPlausibleConfig::default(),
+            cache: IhaCdnCacheConfig::default(),
+            cors: IhaCdnCorsConfig::default(),
+            events: IhaCdnEventsConfig::default(),
+            access_log: IhaCdnAccessLogConfig::default(),
+            stats: IhaCdnStatsConfig::default(),
+            moderation: IhaCdnModerationConfig::default(),
+            nsfw: IhaCdnNsfwConfig::default(),
+            antivirus: IhaCdnAntivirusConfig::default(),
+            media: IhaCdnMediaConfig::default(),
+            thumbnails: IhaCdnThumbnailConfig::default(),
+            paste: IhaCdnPasteConfig::default(),
+            tombstone: IhaCdnTombstoneConfig::default(),
+            idempotency: IhaCdnIdempotencyConfig::default(),
+            signed_uploads: IhaCdnSignedUploadsConfig::default(),
+            hooks: IhaCdnHooksConfig::default(),
+            filters: IhaCdnFiltersConfig::default(),
+            scripting: IhaCdnScriptingConfig::default(),
+            timeouts: IhaCdnTimeoutsConfig::default(),
+            gallery: IhaCdnGalleryConfig::default(),
+            search: IhaCdnSearchConfig::default(),
+            status: IhaCdnStatusConfig::default(),
+            abuse: IhaCdnAbuseConfig::default(),
+            rate_limit: IhaCdnRateLimitConfig::default(),
+            uploader: IhaCdnUploaderConfig::default(),
+            network_blocklist: IhaCdnNetworkBlocklistConfig::default(),
+            preflight: IhaCdnPreflightConfig::default(),
+            strict: false,
+            tenants: vec![],
+        }
+    }
+}
+
+impl IhaCdnConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where the config file is: the `--config` flag, the `IHACDN_CONFIG` environment variable,
+    /// or the first of `config.json`, `config.toml` and `config.yaml` found in the working
+    /// directory.
+    pub fn locate(flag: Option<String>) -> PathBuf {
+        flag.or_else(|| std::env::var(CONFIG_PATH_ENV).ok())
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                CONFIG_FILES
+                    .iter()
+                    .map(PathBuf::from)
+                    .find(|path| path.exists())
+            })
+            .unwrap_or_else(|| PathBuf::from(CONFIG_FILES[0]))
+    }
+
+    /// Load the configuration from a JSON, TOML or YAML file, then apply the `IHACDN_*`
+    /// environment variables over it.
+    ///
+    /// A missing file is created with the defaults. The paths of the keys the config doesn't
+    /// know, e.g. `storage.filesize_limt`, are returned with it to be reported once logging is
+    /// set up.
+    pub fn load(path: &Path) -> Result<(Self, Vec<String>), String> {
+        let format = ConfigFormat::of_path(path)?;
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(content) => format
+                .parse(&content)
+                .map_err(|e| format!("Failed to parse {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let config = Self::new();
+                // Containers configured from the environment may not have a writable directory
+                if let Err(e) = config.save(path) {
+                    eprintln!("Config file not found and it can't be created: {e}");
+                }
+                serde_json::to_value(config).map_err(|e| e.to_string())?
+            }
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        apply_env_overrides(&mut config, std::env::vars())?;
+        resolve_secret_files(&mut config, "")?;
+        parse_config(config).map_err(|e| format!("Invalid config {}: {e}", path.display()))
+    }
+
+    /// The JSON Schema of the config file, for editors and validators.
+    pub fn schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(IhaCdnConfig)).unwrap()
+    }
+
+    /// Save the configuration to a file, in the format of its extension.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let config = ConfigFormat::of_path(path)?.serialize(self)?;
+        std::fs::write(path, config).map_err(|e| e.to_string())
+    }
+
+    /// Verify if the config is actually valid and correctly set.
+    pub fn verify(&self) -> bool {
+        if self.hostname.is_empty() {
+            tracing::error!("Hostname is empty, please set it in the config file.");
+            return false;
+        }
+
+        if let Some(content_hostname) = &self.content_hostname
+            && (content_hostname.is_empty()
+                || content_hostname.eq_ignore_ascii_case(&self.hostname))
+        {
+            tracing::error!("Content hostname must be set and differ from the hostname.");
+            return false;
+        }
+
+        if self.port == 0 {
+            tracing::error!("Port is not set, please set it in the config file.");
+            return false;
+        }
+
+        if self.key_prefix.is_empty()
+            || !self
+                .key_prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            tracing::error!("Key prefix must only contain letters, digits, '_' and '-'.");
+            return false;
+        }
+
+        if self.upload_path.is_empty() {
+            tracing::error!("Upload path is empty, please set it in the config file.");
+            return false;
+        }
+
+        // Verify upload_path exist
+        if !std::path::Path::new(&self.upload_path).exists() {
+            tracing::error!("Upload path does not exist, please set it in the config file.");
+            return false;
+        }
+
+        if self.upload_path.is_empty() {
+            tracing::error!("Upload path is empty, please set it in the config file.");
+            return false;
+        }
+
+        // Verify upload_path exist
+        let resolved_path = std::fs::canonicalize(&self.upload_path).unwrap();
+        if !resolved_path.exists() {
+            tracing::error!("Upload path does not exist, please set it in the config file.");
+            return false;
+        }
+
+        if self.admin_password.is_empty() {
+            tracing::error!("Admin password is empty, please set it in the config file.");
+            return false;
+        }
+
+        if self.filename_length < 5 {
+            tracing::error!("Filename length must be longer or equals to 5");
+            return false;
+        }
+
+        if self.plausible.enable && self.plausible.domain.is_none() {
+            tracing::error!("Plausible Analytics is enabled but no domain is set.");
+            return false;
+        }
+
+        if self.events.enable && self.events.stream.is_empty() {
+            tracing::error!("Event stream is enabled but no stream key is set.");
+            return false;
+        }
+
+        if self.access_log.enable
+            && self.access_log.sink == AccessLogSink::Redis
+            && self.access_log.stream.is_empty()
+        {
+            tracing::error!("The access log is sent to Redis but no stream key is set.");
+            return false;
+        }
+
+        if self.nsfw.enable && self.nsfw.endpoint.is_none() {
+            tracing::error!("NSFW classification is enabled but no endpoint is set.");
+            return false;
+        }
+
+        if self.antivirus.enable && self.antivirus.address.is_empty() {
+            tracing::error!("Malware scanning is enabled but no clamd address is set.");
+            return false;
+        }
+
+        let mut admin_key_names = std::collections::HashSet::new();
+        for admin_key in &self.admin_keys {
+            if admin_key.name.is_empty() || admin_key.key.is_empty() {
+                tracing::error!("Admin keys need a name and a key.");
+                return false;
+            }
+            if admin_key.name == "admin" || !admin_key_names.insert(&admin_key.name) {
+                tracing::error!("Admin key name {} is used more than once.", admin_key.name);
+                return false;
+            }
+            if admin_key.key == self.admin_password
+                || self
+                    .admin_keys
+                    .iter()
+                    .filter(|other| other.key == admin_key.key)
+                    .count()
+                    > 1
+            {
+                tracing::error!("The key of admin key {} is not unique.", admin_key.name);
+                return false;
+            }
+        }
+
+        if self.short_retention.enable
+            && self.short_retention.max_age.is_none()
+            && self.short_retention.max_idle.is_none()
+        {
+            tracing::error!("Short link retention needs a max age or a max idle time.");
+            return false;
+        }
+
+        if self.abuse.enable {
+            let weights = [
+                self.abuse.blocked_type_weight,
+                self.abuse.oversized_weight,
+                self.abuse.not_found_weight,
+            ];
+            if self.abuse.threshold <= 0.0
+                || self.abuse.decay_per_minute < 0.0
+                || weights.iter().any(|weight| *weight < 0.0)
+            {
+                tracing::error!(
+                    "Abuse threshold must be positive, the decay and weights can't be negative."
+                );
+                return false;
+            }
+            if self.abuse.ban_duration == 0 || self.abuse.max_ban_duration < self.abuse.ban_duration
+            {
+                tracing::error!(
+                    "Abuse ban duration must be positive and at most the max ban duration."
+                );
+                return false;
+            }
+        }
+
+        if !self.network_blocklist.blocked_asns.is_empty()
+            && self.network_blocklist.asn_database.is_none()
+        {
+            tracing::error!("Blocking ASNs needs the ASN database.");
+            return false;
+        }
+        for name in &self.network_blocklist.allowed_keys {
+            if !self
+                .admin_credentials()
+                .any(|(identity, _)| &identity.name == name)
+            {
+                tracing::error!(
+                    "The allowed key {} of the network blocklist is not an admin key.",
+                    name
+                );
+                return false;
+            }
+        }
+
+        if !self.hooks.post_upload.is_empty() {
+            if self.hooks.timeout == 0 {
+                tracing::error!("Hook timeout must be positive.");
+                return false;
+            }
+            if [0, self.hooks.quarantine_exit_code].contains(&self.hooks.reject_exit_code)
+                || self.hooks.quarantine_exit_code == 0
+            {
+                tracing::error!("Hook exit codes must differ from each other and from 0.");
+                return false;
+            }
+        }
+
+        for (index, filter) in self.filters.modules.iter().enumerate() {
+            if filter.name.is_empty()
+                || self.filters.modules[..index]
+                    .iter()
+                    .any(|other| other.name == filter.name)
+            {
+                tracing::error!("Upload filters must have unique, non-empty names.");
+                return false;
+            }
+            if filter.fuel == 0 || filter.memory_limit == 0 {
+                tracing::error!(
+                    "The fuel and memory limit of the upload filter {} must be positive.",
+                    filter.name
+                );
+                return false;
+            }
+            if filter.head_size > MAX_FILTER_HEAD_SIZE {
+                tracing::error!(
+                    "The upload filter {} can get at most {} bytes of the file.",
+                    filter.name,
+                    MAX_FILTER_HEAD_SIZE
+                );
+                return false;
+            }
+        }
+
+        if self.scripting.script.is_some()
+            && (self.scripting.timeout == 0 || self.scripting.memory_limit == 0)
+        {
+            tracing::error!("The timeout and memory limit of the policy script must be positive.");
+            return false;
+        }
+
+        if [
+            self.timeouts.upload,
+            self.timeouts.paste,
+            self.timeouts.idle,
+        ]
+        .contains(&Some(0))
+        {
+            tracing::error!("Upload timeouts must be positive, use null to disable them.");
+            return false;
+        }
+
+        if self.signed_uploads.default_ttl == 0
+            || self.signed_uploads.max_ttl < self.signed_uploads.default_ttl
+        {
+            tracing::error!("Signed upload default TTL must be positive and at most the max TTL.");
+            return false;
+        }
+
+        if self.gallery.enable && !(1..=100).contains(&self.gallery.page_size) {
+            tracing::error!("Gallery page size must be between 1 and 100.");
+            return false;
+        }
+
+        if self.media.poster_seek < 0.0 {
+            tracing::error!("Poster seek must not be negative.");
+            return false;
+        }
+
+        if self.thumbnails.enable
+            && (self.thumbnails.max_dimension == 0 || self.thumbnails.max_per_entry == 0)
+        {
+            tracing::error!("Thumbnail max dimension and max per entry must be at least 1.");
+            return false;
+        }
+
+        if !matches!(self.media.transcode_format.as_str(), "mp4" | "webm") {
+            tracing::error!("Transcode format must be either mp4 or webm.");
+            return false;
+        }
+
+        if self.media.transcode_concurrency == 0 {
+            tracing::error!("Transcode concurrency must be at least 1.");
+            return false;
+        }
+
+        let rate_limit = &self.rate_limit;
+        if [
+            rate_limit.upload,
+            rate_limit.short,
+            rate_limit.edit,
+            rate_limit.read,
+        ]
+        .iter()
+        .flatten()
+        .any(|budget| budget.limit == 0 || budget.window == 0)
+        {
+            tracing::error!("Rate limit budgets need a limit and a window of at least 1.");
+            return false;
+        }
+
+        if self.uploader.privacy
+            && self
+                .uploader
+                .ip_salt
+                .as_deref()
+                .is_none_or(|salt| salt.is_empty())
+        {
+            // Without a salt, the hash of an IPv4 address is reversed in minutes
+            tracing::error!("Uploader privacy mode needs an IP salt.");
+            return false;
+        }
+
+        if self.cors.allow_credentials && (self.cors.is_any_origin() || self.cors.is_any_header()) {
+            tracing::error!("CORS credentials can't be allowed with a wildcard origin or header.");
+            return false;
+        }
+
+        for method in &self.cors.allowed_methods {
+            if axum::http::Method::from_bytes(method.as_bytes()).is_err() {
+                tracing::error!("Invalid CORS method: {}", method);
+                return false;
+            }
+        }
+
+        for origin in &self.cors.allowed_origins {
+            if origin != "*" && axum::http::HeaderValue::from_str(origin).is_err() {
+                tracing::error!("Invalid CORS origin: {}", origin);
+                return false;
+            }
+        }
+
+        for header in &self.cors.allowed_headers {
+            if header != "*" && axum::http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                tracing::error!("Invalid CORS header: {}", header);
+                return false;
+            }
+        }
+
+        let mut base_paths = vec![resolved_path];
+        for extra_path in self.paths.configured().chain(&self.storage.volumes) {
+            match std::fs::canonicalize(extra_path) {
+                Ok(path) => base_paths.push(path),
+                Err(_) => {
+                    tracing::error!("Upload path {} does not exist.", extra_path);
+                    return false;
+                }
+            }
+        }
+
+        // Create the uploads and uploads_admin dir in every upload path if it's not exist.
+        for base_path in base_paths {
+            let uploads_path = base_path.join("uploads");
+            if !uploads_path.exists() {
+                std::fs::create_dir_all(&uploads_path).unwrap();
+            }
+            let uploads_admin_path = base_path.join("uploads_admin");
+            if !uploads_admin_path.exists() {
+                std::fs::create_dir_all(&uploads_admin_path).unwrap();
+            }
+        }
+
+        true
+    }
+
+    /// The configs of the tenants, each one merged over this config.
+    ///
+    /// Objects are merged key by key, any other value replaces the one of this config.
+    /// The listener and the Redis database are shared, so tenants can't set them.
+    pub fn tenant_configs(&self) -> Result<Vec<IhaCdnConfig>, String> {
+        let mut base = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        if let Some(base) = base.as_object_mut() {
+            base.remove("tenants");
+        }
+
+        self.tenants
+            .iter()
+            .enumerate()
+            .map(|(index, overrides)| {
+                if let Some(key) = SHARED_KEYS.iter().find(|key| {
+                    overrides.contains_key(**key) || overrides.contains_key(&format!("{key}_file"))
+                }) {
+                    return Err(format!("Tenant #{index} can't set the shared `{key}`"));
+                }
+                for key in ["hostname", "key_prefix"] {
+                    if !overrides.contains_key(key) {
+                        return Err(format!("Tenant #{index} must set its own `{key}`"));
+                    }
+                }
+
+                let mut config = base.clone();
+                merge_json(&mut config, serde_json::Value::Object(overrides.clone()));
+                resolve_secret_files(&mut config, "")
+                    .map_err(|e| format!("Tenant #{index}: {e}"))?;
+                let (config, unknown) =
+                    parse_config(config).map_err(|e| format!("Tenant #{index}: {e}"))?;
+                if !unknown.is_empty() {
+                    if self.strict {
+                        return Err(format!(
+                            "Tenant #{index} has unknown keys: {}",
+                            unknown.join(", ")
+                        ));
+                    }
+                    for key in unknown {
+                        tracing::warn!("Unknown config key {} of tenant #{}", key, index);
+                    }
+                }
+                Ok(config)
+            })
+            .collect()
+    }
+
+    /// Verify that tenants don't share hostnames, Redis keys or upload paths with each other.
+    pub fn verify_tenants(&self, tenants: &[IhaCdnConfig]) -> bool {
+        let configs = std::iter::once(self).chain(tenants).collect::<Vec<_>>();
+
+        let mut hostnames = std::collections::HashSet::new();
+        for hostname in configs.iter().flat_map(|config| {
+            std::iter::once(&config.hostname).chain(config.content_hostname.as_ref())
+        }) {
+            if !hostnames.insert(hostname.to_ascii_lowercase()) {
+                tracing::error!("Hostname {} is used by more than one tenant.", hostname);
+                return false;
+            }
+        }
+
+        // The entries of a tenant are found by scanning its prefix, it must not match others
+        for (index, config) in configs.iter().enumerate() {
+            for other in &configs[index + 1..] {
+                if config.key_prefix.starts_with(&other.key_prefix)
+                    || other.key_prefix.starts_with(&config.key_prefix)
+                {
+                    tracing::error!(
+                        "Key prefixes {} and {} overlap, tenants must not share Redis keys.",
+                        config.key_prefix,
+                        other.key_prefix
+                    );
+                    return false;
+                }
+            }
+        }
+
+        let mut upload_paths = std::collections::HashSet::new();
+        for config in &configs {
+            for path in config.upload_paths() {
+                let resolved = std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+                if !upload_paths.insert(resolved) {
+                    tracing::error!("Upload path {} is used by more than one tenant.", path);
+                    return false;
+                }
+            }
+        }
+
+        let mut streams = std::collections::HashSet::new();
+        for config in configs.iter().filter(|config| config.events.enable) {
+            if !streams.insert(&config.events.stream) {
+                tracing::error!(
+                    "Event stream {} is used by more than one tenant.",
+                    config.events.stream
+                );
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Get the upload directory of a paste or a file with the given mimetype.
+    pub fn get_path(
+        &self,
+        is_admin: bool,
+        is_code: bool,
+        mimetype: &str,
+    ) -> std::io::Result<PathBuf> {
+        let class_path = if is_code {
+            self.paths.paste.as_ref()
+        } else if mimetype.starts_with("image/") {
+            self.paths.image.as_ref()
+        } else if mimetype.starts_with("video/") {
+            self.paths.video.as_ref()
+        } else {
+            self.paths.file.as_ref()
+        };
+
+        let base_path = match class_path {
+            Some(class_path) => class_path,
+            None => self.pick_volume(),
+        };
+
+        let mut path = std::fs::canonicalize(base_path)?;
+        if is_admin {
+            path.push("uploads_admin");
+        } else {
+            path.push("uploads");
+        }
+        Ok(path)
+    }
+
+    /// Get the directory where uploads are received before being moved into place.
+    pub fn get_staging_path(&self, is_admin: bool) -> std::io::Result<PathBuf> {
+        let mut path = std::fs::canonicalize(&self.upload_path)?;
         if is_admin {
             path.push("uploads_admin");
         } else {
             path.push("uploads");
         }
-        path
+        Ok(path)
+    }
+
+    /// Pick the upload volume with the most available space.
+    fn pick_volume(&self) -> &String {
+        if self.storage.volumes.is_empty() {
+            return &self.upload_path;
+        }
+
+        std::iter::once(&self.upload_path)
+            .chain(&self.storage.volumes)
+            .max_by_key(|volume| {
+                fs4::available_space(volume).unwrap_or_else(|err| {
+                    tracing::warn!("Failed to get available space of {}: {}", volume, err);
+                    0
+                })
+            })
+            .unwrap_or(&self.upload_path)
+    }
+
+    /// Resolve the path of a stored file.
+    ///
+    /// If the file is not at the stored path anymore, e.g. it was moved to another volume,
+    /// every upload path is searched for it.
+    pub async fn resolve_path(&self, path: &Path) -> PathBuf {
+        if tokio::fs::try_exists(path).await.unwrap_or(false) {
+            return path.to_path_buf();
+        }
+
+        // The stored path always ends with `uploads/<file>` or `uploads_admin/<file>`
+        let (Some(file_name), Some(dir_name)) = (
+            path.file_name(),
+            path.parent().and_then(|parent| parent.file_name()),
+        ) else {
+            return path.to_path_buf();
+        };
+
+        for base_path in self.upload_paths() {
+            let candidate = Path::new(base_path).join(dir_name).join(file_name);
+            if tokio::fs::try_exists(&candidate).await.unwrap_or(false) {
+                tracing::debug!("Resolved {} to {}", path.display(), candidate.display());
+                return candidate;
+            }
+        }
+
+        path.to_path_buf()
+    }
+
+    /// Every directory files are uploaded to.
+    pub fn upload_paths(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.upload_path)
+            .chain(self.paths.configured())
+            .chain(&self.storage.volumes)
     }
 
     pub fn get_limit(&self, is_admin: bool) -> Option<u64> {
@@ -292,6 +2158,22 @@ impl IhaCdnConfig {
         }
     }
 
+    /// The hard limit of upload request bodies in bytes, [`None`] if uploads are unlimited.
+    pub fn body_limit(&self) -> Option<usize> {
+        let limit = self.get_limit(false)?.max(self.get_limit(true)?);
+        usize::try_from(limit + self.storage.body_limit_slack * 1024).ok()
+    }
+
+    /// The `User-Agent` sent with outbound requests.
+    pub fn user_agent(&self) -> String {
+        format!(
+            "ihacdn-rs/{} ({}; +{})",
+            crate::build_info::VERSION,
+            self.instance.name,
+            self.instance.contact_url
+        )
+    }
+
     /// Verify the admin password.
     ///
     /// If the admin password is not changed, this will return `false`.
@@ -314,18 +2196,30 @@ impl IhaCdnConfig {
             return false;
         }
 
-        // To avoid timing attacks, we use a constant time comparison.
-        let password = password.as_bytes();
-        let admin_password = self.admin_password.as_bytes();
-        if password.len() != admin_password.len() {
-            return false;
-        }
+        constant_time_eq(password, &self.admin_password)
+    }
 
-        let mut result = 0;
-        for (a, b) in password.iter().zip(admin_password.iter()) {
-            result |= a ^ b;
-        }
-        result == 0
+    /// Every admin key with its configured secret.
+    ///
+    /// The `admin_password` is named `admin` and has the superadmin role, unless it was not changed.
+    pub fn admin_credentials(&self) -> impl Iterator<Item = (AdminIdentity, &str)> {
+        let admin_password = (self.admin_password != default_ihacdn_admin_password()).then(|| {
+            let identity = AdminIdentity {
+                name: "admin".to_string(),
+                role: AdminRole::Superadmin,
+            };
+            (identity, self.admin_password.as_str())
+        });
+
+        admin_password
+            .into_iter()
+            .chain(self.admin_keys.iter().map(|admin_key| {
+                let identity = AdminIdentity {
+                    name: admin_key.name.clone(),
+                    role: admin_key.role,
+                };
+                (identity, admin_key.key.as_str())
+            }))
     }
 
     pub fn is_filetype_allowed(&self, filetype: &str) -> bool {
@@ -336,11 +2230,43 @@ impl IhaCdnConfig {
         !self.blocklist.extensions.contains(&extension.to_string())
     }
 
+    /// The signed URL allowing a single upload, see [`crate::signed_upload`].
+    pub fn make_signed_upload_url(&self, signature: &str) -> String {
+        self.make_url(&format!("upload?signature={signature}"))
+    }
+
     pub fn make_url(&self, file_name: &str) -> String {
+        self.make_url_on(&self.hostname, file_name)
+    }
+
+    /// The URL of raw file bytes, on the content hostname if there is one.
+    pub fn make_content_url(&self, file_name: &str) -> String {
+        self.make_url_on(
+            self.content_hostname.as_deref().unwrap_or(&self.hostname),
+            file_name,
+        )
+    }
+
+    /// Whether an URL points back to this instance, by its hostname or content hostname.
+    pub fn is_own_url(&self, url: &url::Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        std::iter::once(&self.hostname)
+            .chain(&self.content_hostname)
+            .any(|hostname| {
+                let hostname = hostname
+                    .rsplit_once(':')
+                    .map_or(hostname.as_str(), |(hostname, _)| hostname);
+                hostname.eq_ignore_ascii_case(host.trim_end_matches('.'))
+            })
+    }
+
+    fn make_url_on(&self, hostname: &str, file_name: &str) -> String {
         if self.https_mode {
-            format!("https://{}/{}", self.hostname, file_name)
+            format!("https://{}/{}", hostname, file_name)
         } else {
-            format!("http://{}/{}", self.hostname, file_name)
+            format!("http://{}/{}", hostname, file_name)
         }
     }
 }
@@ -382,6 +2308,551 @@ fn default_filesize_limit() -> Option<u64> {
     Some(524288)
 }
 
+fn default_body_limit_slack() -> u64 {
+    1024 // 1 MiB
+}
+
+fn default_cache_paste() -> Option<String> {
+    // 5 minutes
+    Some("public, max-age=300".to_string())
+}
+
+fn default_cache_media() -> Option<String> {
+    // 1 day
+    Some("public, max-age=86400".to_string())
+}
+
+fn default_cache_short() -> Option<String> {
+    // 5 minutes
+    Some("public, max-age=300".to_string())
+}
+
+fn default_events_stream() -> String {
+    "ihacdn:events".to_string()
+}
+
+fn default_events_max_len() -> Option<u64> {
+    Some(10000)
+}
+
+fn default_access_log_directory() -> PathBuf {
+    PathBuf::from("logs")
+}
+
+fn default_access_log_stream() -> String {
+    "ihacdn:access".to_string()
+}
+
+fn default_access_log_max_len() -> Option<u64> {
+    Some(100000)
+}
+
+/// The config files looked for in the working directory, in order.
+const CONFIG_FILES: [&str; 4] = ["config.json", "config.toml", "config.yaml", "config.yml"];
+/// The environment variable with the path of the config file.
+const CONFIG_PATH_ENV: &str = "IHACDN_CONFIG";
+/// The prefix of the environment variables overriding config fields.
+const CONFIG_ENV_PREFIX: &str = "IHACDN_";
+
+/// The formats of config files, picked by their extension.
+#[derive(Debug, Clone, Copy)]
+enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn of_path(path: &Path) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("json") => Ok(ConfigFormat::Json),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("yaml" | "yml") => Ok(ConfigFormat::Yaml),
+            _ => Err(format!(
+                "Unknown config format of {}, use a .json, .toml or .yaml file",
+                path.display()
+            )),
+        }
+    }
+
+    fn parse(self, content: &str) -> Result<serde_json::Value, String> {
+        match self {
+            ConfigFormat::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_norway::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn serialize(self, config: &IhaCdnConfig) -> Result<String, String> {
+        match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(config).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::to_string_pretty(config).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_norway::to_string(config).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Apply the `IHACDN_*` environment variables to a config, `__` separates nested keys:
+/// `IHACDN_STORAGE__FILESIZE_LIMIT=51200` sets `storage.filesize_limit`.
+///
+/// Values of text fields are taken as is, the others are parsed as JSON (`true`, `42`,
+/// `["a", "b"]`). Variables naming unknown keys end up reported as unknown keys.
+fn apply_env_overrides(
+    config: &mut serde_json::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), String> {
+    let defaults = serde_json::to_value(IhaCdnConfig::default()).map_err(|e| e.to_string())?;
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(CONFIG_ENV_PREFIX) else {
+            continue;
+        };
+        if name == CONFIG_PATH_ENV || key.is_empty() {
+            continue;
+        }
+        let path = key
+            .split("__")
+            .map(str::to_ascii_lowercase)
+            .collect::<Vec<_>>();
+
+        let default = path
+            .iter()
+            .try_fold(&defaults, |value, key| value.get(key.as_str()));
+        let value = match default {
+            Some(serde_json::Value::String(_)) => serde_json::Value::String(raw),
+            // Optional fields and keys we don't know, text unless it reads as JSON
+            None | Some(serde_json::Value::Null) => {
+                serde_json::from_str(&raw).unwrap_or(serde_json::Value::String(raw))
+            }
+            Some(_) => serde_json::from_str(&raw)
+                .map_err(|e| format!("{name} must be a JSON value: {e}"))?,
+        };
+
+        let mut target = &mut *config;
+        for key in path {
+            if !target.is_object() {
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            target = target
+                .as_object_mut()
+                .unwrap()
+                .entry(key)
+                .or_insert(serde_json::Value::Null);
+        }
+        *target = value;
+    }
+    Ok(())
+}
+
+/// Secrets that can be read from a file by adding `_file` to their key, e.g.
+/// `admin_password_file`. `*` stands for every item of a list.
+const SECRET_KEYS: [&str; 5] = [
+    "admin_password",
+    "admin_keys.*.key",
+    "redis",
+    "notifier.discord_webhook",
+    "uploader.ip_salt",
+];
+
+/// Replace the `_file` variants of secret keys by the content of their file, keeping secrets
+/// out of the config, e.g. in Docker or Kubernetes secrets.
+///
+/// The file wins over a value set in the config, its trailing newline is ignored.
+fn resolve_secret_files(value: &mut serde_json::Value, path: &str) -> Result<(), String> {
+    match value {
+        serde_json::Value::Object(object) => {
+            let secrets = object
+                .keys()
+                .filter_map(|key| key.strip_suffix("_file"))
+                .filter(|key| SECRET_KEYS.contains(&format!("{path}{key}").as_str()))
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            for key in secrets {
+                let file_key = format!("{key}_file");
+                let file = match object.remove(&file_key) {
+                    Some(serde_json::Value::String(file)) => file,
+                    Some(serde_json::Value::Null) | None => continue,
+                    Some(_) => return Err(format!("{path}{file_key} must be the path of a file")),
+                };
+                let secret = std::fs::read_to_string(&file)
+                    .map_err(|e| format!("Failed to read {path}{file_key} {file}: {e}"))?;
+                object.insert(
+                    key,
+                    serde_json::Value::String(secret.trim_end_matches(['\r', '\n']).to_string()),
+                );
+            }
+
+            for (key, value) in object.iter_mut() {
+                resolve_secret_files(value, &format!("{path}{key}."))?;
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                resolve_secret_files(item, &format!("{path}*."))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Config keys shared by every tenant.
+const SHARED_KEYS: [&str; 5] = ["host", "port", "redis", "redis_replicas", "tenants"];
+
+/// Deserialize a config, collecting the path of every key it doesn't know.
+fn parse_config<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<(IhaCdnConfig, Vec<String>), D::Error> {
+    let mut unknown = vec![];
+    let config = serde_ignored::deserialize(deserializer, |path| unknown.push(path.to_string()))?;
+    Ok((config, unknown))
+}
+
+/// Merge `overrides` into `base`, objects are merged key by key.
+fn merge_json(base: &mut serde_json::Value, overrides: serde_json::Value) {
+    match (base, overrides) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn default_key_prefix() -> String {
+    "ihacdn".to_string()
+}
+
+fn default_short_allowed_schemes() -> Vec<String> {
+    vec!["http".to_string(), "https".to_string()]
+}
+
+fn default_slug_min_length() -> usize {
+    3
+}
+
+fn default_preferred_extensions() -> BTreeMap<String, String> {
+    [
+        ("application/javascript", "js"),
+        ("application/x-msdownload", "exe"),
+        ("application/xml", "xml"),
+        ("audio/mp4", "m4a"),
+        ("audio/mpeg", "mp3"),
+        ("audio/ogg", "ogg"),
+        ("image/jpeg", "jpg"),
+        ("image/tiff", "tiff"),
+        ("text/html", "html"),
+        ("text/javascript", "js"),
+        ("text/markdown", "md"),
+        ("text/plain", "txt"),
+        ("video/mpeg", "mpg"),
+        ("video/x-matroska", "mkv"),
+    ]
+    .into_iter()
+    .map(|(mimetype, extension)| (mimetype.to_string(), extension.to_string()))
+    .collect()
+}
+
+fn default_purge_schedule() -> String {
+    "0 0 0 * * *".to_string()
+}
+
+fn default_stats_schedule() -> String {
+    "0 5 0 * * *".to_string()
+}
+
+fn default_stats_top_mimetypes() -> usize {
+    5
+}
+
+fn default_stats_keep_days() -> Option<u64> {
+    Some(365)
+}
+
+fn default_ffmpeg_path() -> String {
+    "ffmpeg".to_string()
+}
+
+fn default_poster_seek() -> f64 {
+    1.0
+}
+
+fn default_media_timeout() -> u64 {
+    30
+}
+
+fn default_transcode_extensions() -> Vec<String> {
+    vec!["mkv".to_string(), "avi".to_string()]
+}
+
+fn default_transcode_format() -> String {
+    "mp4".to_string()
+}
+
+fn default_transcode_concurrency() -> usize {
+    1
+}
+
+fn default_transcode_timeout() -> u64 {
+    600
+}
+
+fn default_rate_limit_upload() -> Option<RateBudget> {
+    Some(RateBudget {
+        limit: 60,
+        window: 3600,
+    })
+}
+
+fn default_rate_limit_short() -> Option<RateBudget> {
+    Some(RateBudget {
+        limit: 30,
+        window: 60,
+    })
+}
+
+fn default_gif_min_size() -> u64 {
+    512
+}
+
+fn default_thumbnail_max_dimension() -> u32 {
+    2048
+}
+
+fn default_thumbnail_max_source_size() -> u64 {
+    20 * 1024
+}
+
+fn default_thumbnail_max_per_entry() -> usize {
+    8
+}
+
+fn default_tombstone_keep_days() -> Option<u64> {
+    Some(90)
+}
+
+fn default_paste_max_revisions() -> usize {
+    5
+}
+
+fn default_paste_follow_timeout() -> u64 {
+    300
+}
+
+fn default_idempotency_keep_hours() -> u64 {
+    24
+}
+
+fn default_dedup_window() -> u64 {
+    10
+}
+
+fn default_fallback_failure_threshold() -> u32 {
+    5
+}
+
+fn default_fallback_open_for() -> u64 {
+    30
+}
+
+fn default_fallback_cache_size() -> usize {
+    10000
+}
+
+fn default_fallback_cache_ttl() -> u64 {
+    3600
+}
+
+fn default_warmup_entries() -> usize {
+    1000
+}
+
+fn default_warmup_candidates() -> usize {
+    10000
+}
+
+fn default_warmup_paste_max_size() -> u64 {
+    64 * 1024
+}
+
+fn default_preflight_max_clock_skew() -> u64 {
+    30
+}
+
+fn default_preflight_check_endpoints() -> bool {
+    true
+}
+
+fn default_uploader_record() -> bool {
+    true
+}
+
+fn default_gallery_page_size() -> usize {
+    24
+}
+
+fn default_search_max_size() -> u64 {
+    1024 * 1024
+}
+
+/// Compare secrets in constant time, to avoid timing attacks.
+pub(crate) fn constant_time_eq(secret: &str, expected: &str) -> bool {
+    let secret = secret.as_bytes();
+    let expected = expected.as_bytes();
+    if secret.len() != expected.len() {
+        return false;
+    }
+
+    let mut result = 0;
+    for (a, b) in secret.iter().zip(expected.iter()) {
+        result |= a ^ b;
+    }
+    result == 0
+}
+
+fn default_abuse_threshold() -> f64 {
+    20.0
+}
+
+fn default_abuse_decay_per_minute() -> f64 {
+    1.0
+}
+
+fn default_abuse_blocked_type_weight() -> f64 {
+    5.0
+}
+
+fn default_abuse_oversized_weight() -> f64 {
+    3.0
+}
+
+fn default_abuse_not_found_weight() -> f64 {
+    1.0
+}
+
+fn default_abuse_ban_duration() -> u64 {
+    10 * 60 // 10 minutes
+}
+
+fn default_abuse_max_ban_duration() -> u64 {
+    7 * 24 * 60 * 60 // 7 days
+}
+
+fn default_abuse_strike_memory() -> u64 {
+    30 * 24 * 60 * 60 // 30 days
+}
+
+fn default_hook_timeout() -> u64 {
+    30
+}
+
+fn default_hook_reject_exit_code() -> i32 {
+    10
+}
+
+fn default_hook_quarantine_exit_code() -> i32 {
+    11
+}
+
+fn default_filter_head_size() -> usize {
+    64 * 1024
+}
+
+fn default_filter_fuel() -> u64 {
+    100_000_000
+}
+
+fn default_filter_memory_limit() -> u64 {
+    64 * 1024
+}
+
+fn default_script_timeout() -> u64 {
+    100
+}
+
+fn default_script_memory_limit() -> u64 {
+    16 * 1024
+}
+
+fn default_signed_uploads_default_ttl() -> u64 {
+    10 * 60 // 10 minutes
+}
+
+fn default_signed_uploads_max_ttl() -> u64 {
+    24 * 60 * 60 // 1 day
+}
+
+fn default_upload_deadline() -> Option<u64> {
+    Some(60 * 60) // 1 hour
+}
+
+fn default_paste_deadline() -> Option<u64> {
+    Some(2 * 60) // 2 minutes
+}
+
+fn default_idle_timeout() -> Option<u64> {
+    Some(30)
+}
+
+fn default_tor_exit_list_url() -> String {
+    "https://check.torproject.org/torbulkexitlist".to_string()
+}
+
+fn default_tor_refresh_schedule() -> String {
+    "0 0 * * * *".to_string() // Every hour
+}
+
+fn default_instance_name() -> String {
+    "ihaCDN".to_string()
+}
+
+fn default_instance_contact_url() -> String {
+    "https://github.com/ihateani-me/ihacdn-server-rs".to_string()
+}
+
+fn default_nsfw_score_pointer() -> String {
+    "/score".to_string()
+}
+
+fn default_nsfw_timeout() -> u64 {
+    10
+}
+
+fn default_antivirus_address() -> String {
+    "127.0.0.1:3310".to_string()
+}
+
+fn default_antivirus_timeout() -> u64 {
+    30
+}
+
+fn default_cors_wildcard() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec![
+        // GET/POST for GraphQL stuff
+        "GET".to_string(),
+        "POST".to_string(),
+        // HEAD for additional metadata
+        "HEAD".to_string(),
+        // OPTIONS for CORS preflight
+        "OPTIONS".to_string(),
+        // CONNECT for other stuff
+        "CONNECT".to_string(),
+    ]
+}
+
 fn default_block_extension() -> Vec<String> {
     vec![
         "exe".to_string(),
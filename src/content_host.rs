@@ -0,0 +1,119 @@
+//! Serving uploaded files from a separate hostname, so user content never runs on the main one.
+//!
+//! With `content_hostname` set, raw file bytes are only served there and pages only on `hostname`,
+//! requests on the wrong one are redirected.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, header},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+
+use crate::{config::IhaCdnConfig, state::SharedState};
+
+/// Single segment paths that are pages rather than entries.
+const RESERVED_PATHS: &[&str] = &["", "explore", "stats", "upload", "short", "favicon.ico"];
+/// First segments of paths that are never entries.
+const RESERVED_PREFIXES: &[&str] = &["_", "api", "static"];
+
+/// Which hostname a path belongs to.
+enum Placement {
+    /// Raw bytes, only served on the content hostname.
+    Content,
+    /// `/{id}`, depends on the entry: files go to the content hostname, the rest to the main one.
+    Entry,
+    /// Pages and APIs, only served on the main hostname.
+    Main,
+}
+
+fn placement(path: &str) -> Placement {
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    match segments.as_slice() {
+        [id] if !RESERVED_PATHS.contains(id) => Placement::Entry,
+        [first, ..] if RESERVED_PREFIXES.contains(first) => Placement::Main,
+        [_, "raw" | "download" | "poster" | "thumb"] | [_, "revisions", _] => Placement::Content,
+        _ => Placement::Main,
+    }
+}
+
+/// Whether a request was made on the content hostname.
+pub fn is_content_host(config: &IhaCdnConfig, headers: &HeaderMap) -> bool {
+    let Some(content_hostname) = &config.content_hostname else {
+        return false;
+    };
+    headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .is_some_and(|host| host.eq_ignore_ascii_case(content_hostname))
+}
+
+/// Redirect an entry to the hostname it is served on, [`None`] if it is already there.
+pub fn misplaced_entry(
+    config: &IhaCdnConfig,
+    headers: &HeaderMap,
+    path_and_query: &str,
+    is_file: bool,
+) -> Option<Response> {
+    config.content_hostname.as_ref()?;
+    let path = path_and_query.trim_start_matches('/');
+    match (is_file, is_content_host(config, headers)) {
+        (true, false) => Some(Redirect::permanent(&config.make_content_url(path)).into_response()),
+        (false, true) => Some(Redirect::permanent(&config.make_url(path)).into_response()),
+        _ => None,
+    }
+}
+
+/// Middleware redirecting requests made on the wrong hostname.
+pub async fn enforce(
+    State(state): State<Arc<SharedState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = &state.config;
+    if config.content_hostname.is_none() {
+        return next.run(request).await;
+    }
+
+    // HTTP/2 requests carry the host in the URI instead
+    if !request.headers().contains_key(header::HOST)
+        && let Some(host) = request
+            .uri()
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+    {
+        request.headers_mut().insert(header::HOST, host);
+    }
+
+    let on_content_host = is_content_host(config, request.headers());
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map_or("", |path| path.as_str())
+        .trim_start_matches('/')
+        .to_string();
+    match (placement(request.uri().path()), on_content_host) {
+        (Placement::Content, false) => {
+            return Redirect::permanent(&config.make_content_url(&path_and_query)).into_response();
+        }
+        (Placement::Main, true) => {
+            return Redirect::permanent(&config.make_url(&path_and_query)).into_response();
+        }
+        _ => (),
+    }
+
+    let mut response = next.run(request).await;
+    if on_content_host {
+        // Pages on the main hostname fetch raw pastes, e.g. to decrypt them
+        let origin = config.make_url("");
+        if let Ok(origin) = HeaderValue::from_str(origin.trim_end_matches('/')) {
+            response
+                .headers_mut()
+                .entry(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .or_insert(origin);
+        }
+    }
+    response
+}
@@ -0,0 +1,62 @@
+//! Deadlines for reading request bodies, so a stalled upload can't hold a worker forever.
+
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use futures_util::StreamExt;
+use tokio::time::Instant;
+
+use crate::error::IhaCdnError;
+
+/// Limits how long a request body is read in total, and how long it may stall between chunks.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyDeadline {
+    deadline: Option<Instant>,
+    idle: Option<Duration>,
+}
+
+impl BodyDeadline {
+    /// Start the deadline now, both limits are in seconds.
+    pub fn start(total: Option<u64>, idle: Option<u64>) -> Self {
+        Self {
+            deadline: total.map(|total| Instant::now() + Duration::from_secs(total)),
+            idle: idle.map(Duration::from_secs),
+        }
+    }
+
+    /// Wait for a read of the body, failing if it takes longer than either limit.
+    pub async fn wait<F: Future>(&self, future: F) -> Result<F::Output, IhaCdnError> {
+        let idle = self.idle.map(|idle| Instant::now() + idle);
+        let until = match (self.deadline, idle) {
+            (Some(deadline), Some(idle)) => Some(deadline.min(idle)),
+            (deadline, idle) => deadline.or(idle),
+        };
+        match until {
+            Some(until) => tokio::time::timeout_at(until, future)
+                .await
+                .map_err(|_| IhaCdnError::RequestTimeout),
+            None => Ok(future.await),
+        }
+    }
+
+    /// Read a whole body of at most `limit` bytes, like [`axum::body::to_bytes`].
+    pub async fn read_body(
+        &self,
+        body: Body,
+        limit: usize,
+    ) -> Result<Result<Bytes, axum::Error>, IhaCdnError> {
+        let mut stream = body.into_data_stream();
+        let mut content = vec![];
+        while let Some(chunk) = self.wait(stream.next()).await? {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => return Ok(Err(err)),
+            };
+            if content.len() + chunk.len() > limit {
+                return Ok(Err(axum::Error::new("length limit exceeded")));
+            }
+            content.extend_from_slice(&chunk);
+        }
+        Ok(Ok(content.into()))
+    }
+}
@@ -0,0 +1,292 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use redis::Client;
+use serde::{Serialize, de::Error as _};
+
+use crate::{
+    config::{EntryEncoding, IhaCdnConfig},
+    schema::{self, SCHEMA_VERSION},
+    state::{CDNData, entry_id},
+    store::replace_unchanged,
+};
+
+/// How many keys are fetched at once while scanning the entries.
+const SCAN_BATCH: usize = 500;
+
+// Compact entries stay one string value per entry instead of becoming Redis hashes or
+// MessagePack: hashes would lose the single GET/SET the compare-and-set updates rely on, and
+// MessagePack still spells out every field name. A preset dictionary takes those out too.
+
+/// Marks a compact entry, JSON entries always start with `{`.
+///
+/// The second byte is the version of [`COMPACT_DICTIONARY`].
+const COMPACT_MAGIC: [u8; 2] = [0xc1, 0x01];
+
+/// The preset deflate dictionary of compact entries, with the most common strings last.
+///
+/// Never change it, existing entries can't be read anymore. Add a new version instead.
+const COMPACT_DICTIONARY: &[u8] = br#"{"type":"short","target":"https://","max_views":,"revisions":[{"path":"","time_replaced":}],"parent_id":"","language":"","delete_token":"","encrypted":true,"transcode":{"status":"done","path":"","mimetype":"video/mp4"},"poster":"","nsfw_score":0.,"burn_after_read":true,"quarantined":true,"retention_days":,"headers":{},"acl":[""],"not_before":,"not_after":,"metadata":{"title":"","description":"","tags":[""],"source":"https://"},"listed":true,"expires_at":17,"type":"code","mimetype":"text/plain","type":"file","is_admin":false,"path":"","mimetype":"image/png","time_added":17"#;
+
+/// An entry as it is stored, with the version of its layout.
+#[derive(Serialize)]
+struct VersionedEntry<'a> {
+    schema_version: u64,
+    #[serde(flatten)]
+    entry: &'a CDNData,
+}
+
+impl CDNData {
+    /// Encode the entry to be stored in Redis.
+    pub fn encode(&self, encoding: EntryEncoding) -> Vec<u8> {
+        let json = serde_json::to_vec(&VersionedEntry {
+            schema_version: SCHEMA_VERSION,
+            entry: self,
+        })
+        .unwrap();
+        match encoding {
+            EntryEncoding::Json => json,
+            EntryEncoding::Compact => compress(&json),
+        }
+    }
+
+    /// Decode an entry stored in Redis, in either encoding, upgrading older layouts.
+    pub fn decode(data: &[u8]) -> Result<Self, serde_json::Error> {
+        let mut entry = decode_json(data)?;
+        schema::upgrade(&mut entry);
+        serde_json::from_value(entry)
+    }
+}
+
+/// The stored JSON of an entry, in either encoding.
+fn decode_json(data: &[u8]) -> Result<serde_json::Value, serde_json::Error> {
+    match data.strip_prefix(&COMPACT_MAGIC) {
+        Some(compressed) => serde_json::from_slice(&decompress(compressed)?),
+        None => serde_json::from_slice(data),
+    }
+}
+
+fn compress(json: &[u8]) -> Vec<u8> {
+    let mut compress = Compress::new(Compression::best(), false);
+    compress
+        .set_dictionary(COMPACT_DICTIONARY)
+        .expect("the dictionary is set before any input");
+
+    let mut output = Vec::with_capacity(COMPACT_MAGIC.len() + json.len());
+    output.extend_from_slice(&COMPACT_MAGIC);
+    loop {
+        output.reserve(json.len().max(64));
+        let consumed = compress.total_in() as usize;
+        let status = compress
+            .compress_vec(&json[consumed..], &mut output, FlushCompress::Finish)
+            .expect("compressing into memory does not fail");
+        if status == Status::StreamEnd {
+            return output;
+        }
+    }
+}
+
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>, serde_json::Error> {
+    let invalid = |e: flate2::DecompressError| {
+        serde_json::Error::custom(format!("invalid compact entry: {e}"))
+    };
+
+    let mut decompress = Decompress::new(false);
+    decompress
+        .set_dictionary(COMPACT_DICTIONARY)
+        .map_err(invalid)?;
+
+    let mut output = Vec::with_capacity(compressed.len() * 4);
+    loop {
+        output.reserve(compressed.len().max(256));
+        let consumed = decompress.total_in() as usize;
+        let produced = decompress.total_out();
+        let status = decompress
+            .decompress_vec(
+                &compressed[consumed..],
+                &mut output,
+                FlushDecompress::Finish,
+            )
+            .map_err(invalid)?;
+        match status {
+            Status::StreamEnd => return Ok(output),
+            // No progress means the input is truncated
+            _ if decompress.total_out() == produced
+                && decompress.total_in() as usize == consumed =>
+            {
+                return Err(serde_json::Error::custom("truncated compact entry"));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Rewrite every entry in the configured [`EntryEncoding`], used by the `reencode` command.
+pub async fn reencode_entries(
+    config: &IhaCdnConfig,
+    redis: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    tracing::info!(
+        "🗜️ Re-encoding entries as {:?}...",
+        config.storage.entry_encoding
+    );
+    let rewritten = rewrite_entries(config, redis, |_| true).await?;
+    tracing::info!(
+        "🗜️ Re-encoded {} entries, {} bytes to {} bytes",
+        rewritten.entries,
+        rewritten.before_size,
+        rewritten.after_size
+    );
+    Ok(())
+}
+
+/// What [`rewrite_entries`] changed.
+pub struct Rewritten {
+    pub entries: usize,
+    pub before_size: usize,
+    pub after_size: usize,
+}
+
+/// Store again every entry whose JSON matches `filter`, in the current layout and the
+/// configured [`EntryEncoding`].
+///
+/// Entries changed in the meantime are skipped, it can run while the server is running.
+pub async fn rewrite_entries(
+    config: &IhaCdnConfig,
+    redis: &Client,
+    filter: impl Fn(&serde_json::Value) -> bool,
+) -> Result<Rewritten, Box<dyn std::error::Error>> {
+    let encoding = config.storage.entry_encoding;
+    let mut connection = redis.get_multiplexed_async_connection().await?;
+
+    let mut cursor: u64 = 0;
+    let mut rewritten = Rewritten {
+        entries: 0,
+        before_size: 0,
+        after_size: 0,
+    };
+    loop {
+        let (next_cursor, keys) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{}*", config.key_prefix))
+            .arg("COUNT")
+            .arg(SCAN_BATCH)
+            .query_async::<(u64, Vec<String>)>(&mut connection)
+            .await?;
+
+        for key in keys {
+            let Some(id) = entry_id(&config.key_prefix, &key) else {
+                continue;
+            };
+
+            let Some(data) = redis::cmd("GET")
+                .arg(&key)
+                .query_async::<Option<Vec<u8>>>(&mut connection)
+                .await?
+            else {
+                continue;
+            };
+            let mut entry = match decode_json(&data) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    tracing::warn!("Skipping unparseable entry {}: {}", id, e);
+                    continue;
+                }
+            };
+            if !filter(&entry) {
+                continue;
+            }
+            schema::upgrade(&mut entry);
+            let cdn_data = match serde_json::from_value::<CDNData>(entry) {
+                Ok(cdn_data) => cdn_data,
+                Err(e) => {
+                    tracing::warn!("Skipping unparseable entry {}: {}", id, e);
+                    continue;
+                }
+            };
+
+            let encoded = cdn_data.encode(encoding);
+            if encoded == data {
+                continue;
+            }
+            // Only replace the entry if it was not changed in the meantime
+            if replace_unchanged(&mut connection, &key, &data, &encoded).await? {
+                rewritten.entries += 1;
+                rewritten.before_size += data.len();
+                rewritten.after_size += encoded.len();
+            }
+        }
+
+        cursor = next_cursor;
+        if cursor == 0 {
+            break;
+        }
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paste() -> CDNData {
+        serde_json::from_value(serde_json::json!({
+            "type": "code",
+            "is_admin": false,
+            "path": "/srv/ihacdn/uploads/qwerty.rs",
+            "mimetype": "rs",
+            "time_added": 1735689600,
+            "delete_token": "6f1e0c1b2d3a4f5e6d7c8b9a0f1e2d3c4b5a69788796a5b4c3d2e1f0a9b8c7d6",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn entries_survive_both_encodings() {
+        let entry = serde_json::to_value(paste()).unwrap();
+        for encoding in [EntryEncoding::Json, EntryEncoding::Compact] {
+            let decoded = CDNData::decode(&paste().encode(encoding)).unwrap();
+            assert_eq!(serde_json::to_value(decoded).unwrap(), entry);
+        }
+    }
+
+    #[test]
+    fn compact_entries_are_smaller() {
+        let json = paste().encode(EntryEncoding::Json);
+        let compact = paste().encode(EntryEncoding::Compact);
+        // 212 bytes to 126 at the time of writing
+        assert!(compact.len() * 10 < json.len() * 7);
+    }
+
+    #[test]
+    fn pretty_unversioned_json_is_still_read() {
+        // As written before entries were versioned or compacted
+        let stored = br#"{
+  "type": "short",
+  "target": "https://example.com",
+  "time_added": 1735689600
+}"#;
+        assert!(matches!(
+            CDNData::decode(stored).unwrap(),
+            CDNData::Short { target, .. } if target == "https://example.com"
+        ));
+    }
+
+    #[test]
+    fn compact_entries_of_the_first_dictionary_are_still_read() {
+        let stored = [
+            193, 1, 3, 198, 76, 114, 70, 106, 110, 98, 124, 25, 48, 76, 192, 30, 49, 212, 33, 152,
+            168, 82, 43, 18, 115, 11, 114, 82, 245, 146, 243, 115, 49, 12, 52, 54, 53, 179, 176,
+            52, 51, 48, 168, 5, 0,
+        ];
+        assert!(matches!(
+            CDNData::decode(&stored).unwrap(),
+            CDNData::Short { target, .. } if target == "https://example.com"
+        ));
+    }
+
+    #[test]
+    fn truncated_compact_entries_are_an_error() {
+        let compact = paste().encode(EntryEncoding::Compact);
+        assert!(CDNData::decode(&compact[..compact.len() / 2]).is_err());
+    }
+}
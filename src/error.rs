@@ -0,0 +1,793 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    config::{AdminRole, IhaCdnConfig},
+    notifier::notify_panic,
+    state::{
+        ACCESS_DENIED, BLOCKED_EXTENSION, CREATE_FILE_ERROR, CUSTOM_NAME_GENERATION_ERROR,
+        DELETED_ERROR, INFECTED_UPLOAD, INVALID_CUSTOM_HEADERS, INVALID_DELETE_TOKEN,
+        INVALID_ENCRYPTED_PAYLOAD, INVALID_URL_FORMAT, MISSING_FIELD, MODERATION_REJECTED,
+        NOT_YET_AVAILABLE, PAYLOAD_TOO_LARGE, QUARANTINED_ERROR, READ_FILE_ERROR,
+        REDIS_CONNECTION_ERROR, REDIS_GET_ERROR, REDIS_SAVE_ERROR, SAVE_FILE_ERROR, SharedState,
+        humanize_bytes,
+    },
+    templating::{HtmlTemplate, TemplateError, TemplateLegal, format_timestamp},
+    tombstone::Tombstone,
+};
+
+/// How an error is rendered.
+///
+/// Picked from the `Accept` header of the request, falling back to the default of the route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// Pages opened in a browser.
+    Html,
+    /// Raw content, the themed plain text errors.
+    Text,
+    /// API endpoints, as RFC 9457 problem details.
+    Json,
+}
+
+impl ErrorFormat {
+    /// The preferred format of an `Accept` header, wildcards express no preference.
+    pub fn from_accept(accept: &str) -> Option<ErrorFormat> {
+        let mut best: Option<(ErrorFormat, f32)> = None;
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let media_type = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media_type.as_str() {
+                "text/html" | "application/xhtml+xml" => ErrorFormat::Html,
+                "application/json" | "application/problem+json" => ErrorFormat::Json,
+                "text/plain" => ErrorFormat::Text,
+                _ => continue,
+            };
+            // The first of equally preferred types wins
+            if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+/// What errors need to know about the request they answer.
+struct ErrorContext {
+    /// The format asked for in `Accept`.
+    format: Option<ErrorFormat>,
+    /// The request path, the `instance` of problem details.
+    instance: String,
+    /// The footer links of error pages.
+    legal: TemplateLegal,
+}
+
+tokio::task_local! {
+    static ERROR_CONTEXT: ErrorContext;
+}
+
+/// Middleware making the `Accept` header of the request available to [`IhaCdnError`].
+pub async fn negotiate_errors(
+    State(state): State<Arc<SharedState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let context = ErrorContext {
+        format: request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(ErrorFormat::from_accept),
+        instance: request.uri().path().to_string(),
+        legal: TemplateLegal::new(&state.config.instance),
+    };
+    ERROR_CONTEXT.scope(context, next.run(request)).await
+}
+
+/// Answer a request whose handler panicked with an error response, and alert the notifier.
+///
+/// Used by the `CatchPanicLayer`, so one bad request can't bring down its connection.
+pub fn panic_response(
+    config: &Arc<IhaCdnConfig>,
+    panic: Box<dyn std::any::Any + Send + 'static>,
+) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| {
+            panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+        })
+        .unwrap_or_else(|| "unknown panic".to_string());
+    let path = ERROR_CONTEXT
+        .try_with(|context| context.instance.clone())
+        .unwrap_or_default();
+    notify_panic(&path, &message, config);
+    IhaCdnError::Panic(message).into_response()
+}
+
+/// The [`IhaCdnError::code`] of an error response, for middlewares.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorCode(pub &'static str);
+
+/// Everything a route can fail with.
+///
+/// Handlers return it with `?`, the response (status, body and log line) is built in one place.
+/// As an [`IntoResponse`] it defaults to [`ErrorFormat::Text`], use [`IhaCdnError::respond`]
+/// for routes with another default.
+#[derive(Debug)]
+pub enum IhaCdnError {
+    /// The ID is unknown, `404 Not Found`.
+    NotFound {
+        id_path: String,
+    },
+    /// The entry existed but was removed or its file is missing, `410 Gone`.
+    ///
+    /// The tombstone is only known if the entry was removed while tombstones were enabled.
+    Gone {
+        id_path: String,
+        tombstone: Option<Tombstone>,
+    },
+    /// The entry is quarantined by moderation, `451 Unavailable For Legal Reasons`.
+    Quarantined {
+        id_path: String,
+    },
+    /// The entry is restricted by an ACL and no allowed access token was given.
+    AccessDenied {
+        id_path: String,
+    },
+    /// The entry is embargoed until `not_before`, an UNIX timestamp.
+    NotYetAvailable {
+        id_path: String,
+        not_before: i64,
+    },
+    RedisConnection(redis::RedisError),
+    RedisGet {
+        id_path: String,
+        source: redis::RedisError,
+    },
+    RedisSave(redis::RedisError),
+    ParseData(serde_json::Error),
+    ReadFile {
+        id_path: String,
+        source: std::io::Error,
+    },
+    CreateFile {
+        file_name: String,
+        source: std::io::Error,
+    },
+    SaveFile {
+        file_name: String,
+        reason: String,
+        source: std::io::Error,
+    },
+    PayloadTooLarge {
+        file_name: String,
+        limit: Option<u64>,
+    },
+    /// The mimetype or extension is not allowed.
+    BlockedType(String),
+    /// The declared `Content-Type` of the upload doesn't match its content.
+    TypeMismatch {
+        declared: String,
+        detected: String,
+    },
+    /// The upload has no `file` field.
+    MissingField,
+    InvalidUrl(String),
+    /// The target of a short link isn't allowed, with the reason.
+    BlockedUrl(String),
+    InvalidEncryptedPayload,
+    /// The offending header.
+    InvalidCustomHeaders(String),
+    /// The name of the moderation rule.
+    ModerationRejected(String),
+    NameGeneration(String),
+    /// The task scheduler refused a job.
+    Scheduler(String),
+    InvalidDeleteToken {
+        id_path: String,
+    },
+    EditingDisabled,
+    /// A request with the same `Idempotency-Key` is still being processed.
+    IdempotencyInProgress,
+    /// The `Idempotency-Key` was used for a different request.
+    IdempotencyMismatch,
+    /// The admin key is missing or wrong.
+    Unauthorized,
+    /// The admin key does not have the required role.
+    InsufficientRole(AdminRole),
+    /// The post-upload hook rejected the upload.
+    HookRejected,
+    /// The name of the WASM upload filter that rejected the upload.
+    FilterRejected(String),
+    /// The policy script rejected the request, with the reason.
+    PolicyRejected(String),
+    /// The antivirus found malware in the upload, with the name of the signature.
+    Infected(String),
+    /// The signed upload URL is unknown, expired or was already used.
+    InvalidSignature,
+    /// The uploader is on a blocked network, e.g. `Tor` or `AS64496`.
+    NetworkBlocked(String),
+    /// The IP of the request is temporarily banned for abuse, until an UNIX timestamp.
+    Banned {
+        until: i64,
+    },
+    /// The uploaded file doesn't match the checksum sent by the client.
+    ChecksumMismatch,
+    /// The request body took too long, or stalled, while uploading.
+    RequestTimeout,
+    /// The `Expect` header asks for something else than `100-continue`, with its value.
+    ExpectationFailed(String),
+    BadRequest(String),
+    /// A response header could not be built from a stored value, e.g. a mimetype.
+    InvalidHeader {
+        name: header::HeaderName,
+        source: header::InvalidHeaderValue,
+    },
+    /// The handler panicked, with the panic message.
+    Panic(String),
+    /// The client used up the request budget of the route.
+    RateLimited {
+        /// Seconds until the budget is refilled.
+        retry_after: u64,
+    },
+    /// The requested slug is already used, or was used by a removed entry.
+    SlugTaken(String),
+    /// The entry is under legal hold and can't be deleted, `423 Locked`.
+    LegalHold {
+        id_path: String,
+    },
+    /// The entry kept being changed by other requests while this one updated it.
+    EntryChanged {
+        id_path: String,
+    },
+    /// A background task run on demand failed.
+    TaskFailed {
+        task: &'static str,
+        reason: String,
+    },
+}
+
+impl IhaCdnError {
+    pub fn not_found(id_path: &str) -> Self {
+        IhaCdnError::NotFound {
+            id_path: id_path.to_string(),
+        }
+    }
+
+    pub fn gone(id_path: &str) -> Self {
+        IhaCdnError::Gone {
+            id_path: id_path.to_string(),
+            tombstone: None,
+        }
+    }
+
+    pub fn quarantined(id_path: &str) -> Self {
+        IhaCdnError::Quarantined {
+            id_path: id_path.to_string(),
+        }
+    }
+
+    /// A stored file that can't be read, a missing file means the entry is gone.
+    pub fn file(source: std::io::Error, id_path: &str) -> Self {
+        if source.kind() == std::io::ErrorKind::NotFound {
+            IhaCdnError::gone(id_path)
+        } else {
+            IhaCdnError::ReadFile {
+                id_path: id_path.to_string(),
+                source,
+            }
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            IhaCdnError::NotFound { .. } => StatusCode::NOT_FOUND,
+            IhaCdnError::Gone { .. } => StatusCode::GONE,
+            IhaCdnError::Quarantined { .. } => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            IhaCdnError::RedisConnection(_)
+            | IhaCdnError::RedisGet { .. }
+            | IhaCdnError::RedisSave(_)
+            | IhaCdnError::ParseData(_)
+            | IhaCdnError::ReadFile { .. }
+            | IhaCdnError::CreateFile { .. }
+            | IhaCdnError::SaveFile { .. }
+            | IhaCdnError::NameGeneration(_)
+            | IhaCdnError::Scheduler(_)
+            | IhaCdnError::InvalidHeader { .. }
+            | IhaCdnError::Panic(_)
+            | IhaCdnError::TaskFailed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            IhaCdnError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            IhaCdnError::BlockedType(_) | IhaCdnError::TypeMismatch { .. } => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            IhaCdnError::MissingField
+            | IhaCdnError::InvalidUrl(_)
+            | IhaCdnError::BlockedUrl(_)
+            | IhaCdnError::InvalidEncryptedPayload
+            | IhaCdnError::InvalidCustomHeaders(_)
+            | IhaCdnError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            IhaCdnError::AccessDenied { .. }
+            | IhaCdnError::NotYetAvailable { .. }
+            | IhaCdnError::ModerationRejected(_)
+            | IhaCdnError::InvalidDeleteToken { .. }
+            | IhaCdnError::HookRejected
+            | IhaCdnError::FilterRejected(_)
+            | IhaCdnError::PolicyRejected(_)
+            | IhaCdnError::Infected(_)
+            | IhaCdnError::InvalidSignature
+            | IhaCdnError::NetworkBlocked(_)
+            | IhaCdnError::Banned { .. } => StatusCode::FORBIDDEN,
+            IhaCdnError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            IhaCdnError::EditingDisabled => StatusCode::METHOD_NOT_ALLOWED,
+            IhaCdnError::IdempotencyInProgress
+            | IhaCdnError::SlugTaken(_)
+            | IhaCdnError::EntryChanged { .. } => StatusCode::CONFLICT,
+            IhaCdnError::IdempotencyMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            IhaCdnError::Unauthorized => StatusCode::UNAUTHORIZED,
+            IhaCdnError::InsufficientRole(_) => StatusCode::FORBIDDEN,
+            IhaCdnError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            IhaCdnError::ExpectationFailed(_) => StatusCode::EXPECTATION_FAILED,
+            IhaCdnError::ChecksumMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            IhaCdnError::LegalHold { .. } => StatusCode::LOCKED,
+        }
+    }
+
+    /// A short machine-readable code for the JSON variant.
+    pub fn code(&self) -> &'static str {
+        match self {
+            IhaCdnError::NotFound { .. } => "not_found",
+            IhaCdnError::Gone { .. } => "gone",
+            IhaCdnError::Quarantined { .. } => "quarantined",
+            IhaCdnError::AccessDenied { .. } => "access_denied",
+            IhaCdnError::NotYetAvailable { .. } => "not_yet_available",
+            IhaCdnError::RedisConnection(_)
+            | IhaCdnError::RedisGet { .. }
+            | IhaCdnError::RedisSave(_)
+            | IhaCdnError::ParseData(_) => "storage_error",
+            IhaCdnError::ReadFile { .. }
+            | IhaCdnError::CreateFile { .. }
+            | IhaCdnError::SaveFile { .. } => "file_error",
+            IhaCdnError::NameGeneration(_) => "name_generation_failed",
+            IhaCdnError::Scheduler(_) => "scheduler_error",
+            IhaCdnError::PayloadTooLarge { .. } => "payload_too_large",
+            IhaCdnError::BlockedType(_) => "blocked_type",
+            IhaCdnError::TypeMismatch { .. } => "type_mismatch",
+            IhaCdnError::MissingField => "missing_field",
+            IhaCdnError::InvalidUrl(_) => "invalid_url",
+            IhaCdnError::BlockedUrl(_) => "blocked_url",
+            IhaCdnError::InvalidEncryptedPayload => "invalid_encrypted_payload",
+            IhaCdnError::InvalidCustomHeaders(_) => "invalid_headers",
+            IhaCdnError::ModerationRejected(_) => "moderation_rejected",
+            IhaCdnError::InvalidDeleteToken { .. } => "invalid_delete_token",
+            IhaCdnError::EditingDisabled => "editing_disabled",
+            IhaCdnError::IdempotencyInProgress => "idempotency_in_progress",
+            IhaCdnError::SlugTaken(_) => "slug_taken",
+            IhaCdnError::LegalHold { .. } => "legal_hold",
+            IhaCdnError::EntryChanged { .. } => "entry_changed",
+            IhaCdnError::IdempotencyMismatch => "idempotency_mismatch",
+            IhaCdnError::Unauthorized => "unauthorized",
+            IhaCdnError::InsufficientRole(_) => "insufficient_role",
+            IhaCdnError::HookRejected => "hook_rejected",
+            IhaCdnError::FilterRejected(_) => "filter_rejected",
+            IhaCdnError::PolicyRejected(_) => "policy_rejected",
+            IhaCdnError::Infected(_) => "infected",
+            IhaCdnError::InvalidSignature => "invalid_signature",
+            IhaCdnError::NetworkBlocked(_) => "network_blocked",
+            IhaCdnError::Banned { .. } => "banned",
+            IhaCdnError::RateLimited { .. } => "rate_limited",
+            IhaCdnError::RequestTimeout => "request_timeout",
+            IhaCdnError::ExpectationFailed(_) => "expectation_failed",
+            IhaCdnError::ChecksumMismatch => "checksum_mismatch",
+            IhaCdnError::BadRequest(_) => "bad_request",
+            IhaCdnError::InvalidHeader { .. } => "invalid_header",
+            IhaCdnError::Panic(_) => "internal_error",
+            IhaCdnError::TaskFailed { .. } => "task_failed",
+        }
+    }
+
+    /// A short human-readable description.
+    pub fn message(&self) -> String {
+        match self {
+            IhaCdnError::NotFound { id_path } => format!("There is nothing at {id_path}."),
+            IhaCdnError::Gone {
+                tombstone: Some(tombstone),
+                ..
+            } => tombstone.message(),
+            IhaCdnError::Gone { id_path, .. } => format!("{id_path} is no longer available."),
+            IhaCdnError::Quarantined { id_path } => {
+                format!("{id_path} is quarantined pending moderator review.")
+            }
+            IhaCdnError::AccessDenied { id_path } => {
+                format!("{id_path} is restricted, a valid access token is required.")
+            }
+            IhaCdnError::NotYetAvailable {
+                id_path,
+                not_before,
+            } => format!(
+                "{id_path} is available from {}.",
+                format_timestamp(*not_before)
+            ),
+            IhaCdnError::RedisConnection(_) => "Failed to connect to Redis".to_string(),
+            IhaCdnError::RedisGet { id_path, .. } => format!("Failed to get data of {id_path}"),
+            IhaCdnError::RedisSave(_) => "Failed to save data".to_string(),
+            IhaCdnError::ParseData(_) => "Failed to parse data".to_string(),
+            IhaCdnError::ReadFile { id_path, .. } => format!("Failed to read {id_path}"),
+            IhaCdnError::CreateFile { file_name, .. } => format!("Failed to create {file_name}"),
+            IhaCdnError::SaveFile {
+                file_name, reason, ..
+            } => format!("Failed to save {file_name}: {reason}"),
+            IhaCdnError::PayloadTooLarge { limit, .. } => format!(
+                "File too big, maximum allowed is {}",
+                limit.map_or("unknown".to_string(), humanize_bytes)
+            ),
+            IhaCdnError::BlockedType(file_type) => format!("{file_type} is not allowed"),
+            IhaCdnError::TypeMismatch { declared, detected } => {
+                format!("The file was sent as {declared} but its content is {detected}")
+            }
+            IhaCdnError::MissingField => "The file field is missing".to_string(),
+            IhaCdnError::InvalidUrl(url) => format!("Invalid URL: {url}"),
+            IhaCdnError::BlockedUrl(reason) => reason.clone(),
+            IhaCdnError::InvalidEncryptedPayload => {
+                "Encrypted paste payload must be base64 encoded".to_string()
+            }
+            IhaCdnError::InvalidCustomHeaders(header) => format!("Header is not allowed: {header}"),
+            IhaCdnError::ModerationRejected(rule) => format!("Rejected by moderation rule {rule}"),
+            IhaCdnError::NameGeneration(reason) => format!("Failed to generate a name: {reason}"),
+            IhaCdnError::Scheduler(reason) => format!("Failed to schedule the task: {reason}"),
+            IhaCdnError::InvalidDeleteToken { id_path } => {
+                format!("Invalid delete token for {id_path}")
+            }
+            IhaCdnError::EditingDisabled => "Editing pastes is disabled".to_string(),
+            IhaCdnError::IdempotencyInProgress => {
+                "A request with this Idempotency-Key is still in progress".to_string()
+            }
+            IhaCdnError::IdempotencyMismatch => {
+                "This Idempotency-Key was already used for another request".to_string()
+            }
+            IhaCdnError::Unauthorized => "Invalid admin key".to_string(),
+            IhaCdnError::InsufficientRole(role) => {
+                format!("This admin key needs the {} role", role.as_str())
+            }
+            IhaCdnError::HookRejected => "Rejected by the upload hook".to_string(),
+            IhaCdnError::FilterRejected(filter) => {
+                format!("Rejected by the upload filter {filter}")
+            }
+            IhaCdnError::PolicyRejected(reason) => format!("Rejected by policy: {reason}"),
+            IhaCdnError::Infected(signature) => format!("Malware found in the upload: {signature}"),
+            IhaCdnError::InvalidSignature => {
+                "The upload URL is invalid, expired or was already used".to_string()
+            }
+            IhaCdnError::RequestTimeout => "The upload took too long".to_string(),
+            IhaCdnError::ExpectationFailed(expect) => {
+                format!("Only Expect: 100-continue is supported, not {expect}")
+            }
+            IhaCdnError::ChecksumMismatch => {
+                "The file doesn't match its checksum, it was corrupted during the upload"
+                    .to_string()
+            }
+            IhaCdnError::NetworkBlocked(network) => {
+                format!("Uploads from {network} are not allowed")
+            }
+            IhaCdnError::RateLimited { retry_after } => {
+                format!("Too many requests, try again in {retry_after} seconds")
+            }
+            IhaCdnError::Banned { until } => {
+                format!(
+                    "You are banned for abuse until {}",
+                    format_timestamp(*until)
+                )
+            }
+            IhaCdnError::BadRequest(message) => message.clone(),
+            IhaCdnError::InvalidHeader { name, .. } => format!("Failed to build the {name} header"),
+            IhaCdnError::Panic(_) => "Something went wrong while handling the request".to_string(),
+            IhaCdnError::SlugTaken(slug) => format!("The slug {slug} is already taken"),
+            IhaCdnError::LegalHold { id_path } => {
+                format!("{id_path} is under legal hold and can't be changed or deleted")
+            }
+            IhaCdnError::EntryChanged { id_path } => {
+                format!("{id_path} was changed by another request, try again")
+            }
+            IhaCdnError::TaskFailed { task, reason } => format!("The {task} task failed: {reason}"),
+        }
+    }
+
+    /// The themed plain text body.
+    fn text(&self) -> String {
+        match self {
+            IhaCdnError::NotFound { id_path }
+            | IhaCdnError::Gone {
+                id_path,
+                tombstone: None,
+            } => DELETED_ERROR.replace("{{ FN }}", id_path),
+            IhaCdnError::Gone {
+                tombstone: Some(tombstone),
+                ..
+            } => format!("{}\n", tombstone.message()),
+            IhaCdnError::Quarantined { id_path } => QUARANTINED_ERROR.replace("{{ FN }}", id_path),
+            IhaCdnError::AccessDenied { id_path } => ACCESS_DENIED.replace("{{ FN }}", id_path),
+            IhaCdnError::NotYetAvailable {
+                id_path,
+                not_before,
+            } => NOT_YET_AVAILABLE
+                .replace("{{ FN }}", id_path)
+                .replace("{{ TIME }}", &format_timestamp(*not_before)),
+            IhaCdnError::RedisConnection(_) => REDIS_CONNECTION_ERROR.to_string(),
+            IhaCdnError::RedisGet { id_path, .. } => REDIS_GET_ERROR.replace("{{ FN }}", id_path),
+            IhaCdnError::RedisSave(_) => REDIS_SAVE_ERROR.to_string(),
+            IhaCdnError::ReadFile { id_path, .. } => READ_FILE_ERROR.replace("{{ FN }}", id_path),
+            IhaCdnError::CreateFile { file_name, .. } => {
+                CREATE_FILE_ERROR.replace("{{ FN }}", file_name)
+            }
+            IhaCdnError::SaveFile {
+                file_name, reason, ..
+            } => SAVE_FILE_ERROR
+                .replace("{{ FN }}", file_name)
+                .replace("{{ REASON }}", reason),
+            IhaCdnError::PayloadTooLarge { file_name, limit } => PAYLOAD_TOO_LARGE
+                .replace(
+                    "{{ FS }}",
+                    &limit.map_or("unknown".to_string(), humanize_bytes),
+                )
+                .replace("{{ FN }}", file_name),
+            IhaCdnError::BlockedType(file_type) => {
+                BLOCKED_EXTENSION.replace("{{ FILE_TYPE }}", file_type)
+            }
+            IhaCdnError::MissingField => MISSING_FIELD.to_string(),
+            IhaCdnError::InvalidUrl(url) => INVALID_URL_FORMAT.replace("{{ URL }}", url),
+            IhaCdnError::InvalidEncryptedPayload => INVALID_ENCRYPTED_PAYLOAD.to_string(),
+            IhaCdnError::InvalidCustomHeaders(header) => {
+                INVALID_CUSTOM_HEADERS.replace("{{ HEADER }}", header)
+            }
+            IhaCdnError::ModerationRejected(rule) => {
+                MODERATION_REJECTED.replace("{{ RULE }}", rule)
+            }
+            IhaCdnError::Infected(signature) => {
+                INFECTED_UPLOAD.replace("{{ SIGNATURE }}", signature)
+            }
+            IhaCdnError::NameGeneration(reason) => {
+                CUSTOM_NAME_GENERATION_ERROR.replace("{{ REASON }}", reason)
+            }
+            IhaCdnError::InvalidDeleteToken { id_path } => {
+                INVALID_DELETE_TOKEN.replace("{{ FN }}", id_path)
+            }
+            IhaCdnError::ParseData(_)
+            | IhaCdnError::Scheduler(_)
+            | IhaCdnError::EditingDisabled
+            | IhaCdnError::IdempotencyInProgress
+            | IhaCdnError::SlugTaken(_)
+            | IhaCdnError::LegalHold { .. }
+            | IhaCdnError::EntryChanged { .. }
+            | IhaCdnError::IdempotencyMismatch
+            | IhaCdnError::Unauthorized
+            | IhaCdnError::InsufficientRole(_)
+            | IhaCdnError::HookRejected
+            | IhaCdnError::FilterRejected(_)
+            | IhaCdnError::PolicyRejected(_)
+            | IhaCdnError::InvalidSignature
+            | IhaCdnError::NetworkBlocked(_)
+            | IhaCdnError::Banned { .. }
+            | IhaCdnError::RateLimited { .. }
+            | IhaCdnError::RequestTimeout
+            | IhaCdnError::ExpectationFailed(_)
+            | IhaCdnError::ChecksumMismatch
+            | IhaCdnError::BadRequest(_)
+            | IhaCdnError::BlockedUrl(_)
+            | IhaCdnError::TypeMismatch { .. }
+            | IhaCdnError::InvalidHeader { .. }
+            | IhaCdnError::Panic(_)
+            | IhaCdnError::TaskFailed { .. } => self.message(),
+        }
+    }
+
+    /// The underlying failure, for the logs.
+    fn source(&self) -> Option<&dyn std::fmt::Display> {
+        match self {
+            IhaCdnError::RedisConnection(source)
+            | IhaCdnError::RedisGet { source, .. }
+            | IhaCdnError::RedisSave(source) => Some(source),
+            IhaCdnError::ParseData(source) => Some(source),
+            IhaCdnError::ReadFile { source, .. }
+            | IhaCdnError::CreateFile { source, .. }
+            | IhaCdnError::SaveFile { source, .. } => Some(source),
+            IhaCdnError::InvalidHeader { source, .. } => Some(source),
+            IhaCdnError::Panic(message) => Some(message),
+            _ => None,
+        }
+    }
+
+    fn log(&self, status: StatusCode) {
+        match (status.is_server_error(), self.source()) {
+            (true, Some(source)) => tracing::error!("{} ({}): {}", self.message(), status, source),
+            (true, None) => tracing::error!("{} ({})", self.message(), status),
+            (false, _) => tracing::warn!("{} ({})", self.message(), status),
+        }
+    }
+
+    /// Render the error in the negotiated format, or `default` if the client has no preference.
+    ///
+    /// The error is logged on the way out.
+    pub fn respond(self, default: ErrorFormat) -> Response {
+        let status = self.status();
+        let code = self.code();
+        let retry_after = match &self {
+            IhaCdnError::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        };
+        self.log(status);
+        let (format, instance, legal) = ERROR_CONTEXT
+            .try_with(|context| {
+                (
+                    context.format,
+                    Some(context.instance.clone()),
+                    context.legal.clone(),
+                )
+            })
+            .unwrap_or_default();
+        let mut response = match format.unwrap_or(default) {
+            ErrorFormat::Html => {
+                let message = self.message();
+                let (file_id, title, message) = match self {
+                    IhaCdnError::NotFound { id_path } => (Some(id_path), "Not Found", message),
+                    IhaCdnError::Gone { id_path, .. } => (Some(id_path), "Gone", message),
+                    IhaCdnError::Quarantined { id_path } => (Some(id_path), "Unavailable", message),
+                    // Everything else keeps the themed text error on a plain page
+                    other => (
+                        None,
+                        status.canonical_reason().unwrap_or("Error"),
+                        other.text(),
+                    ),
+                };
+                let page = HtmlTemplate::new(TemplateError {
+                    file_id,
+                    status: status.as_u16(),
+                    title: title.to_string(),
+                    message,
+                    legal,
+                })
+                .into_response();
+                (status, page).into_response()
+            }
+            ErrorFormat::Text => (status, self.text()).into_response(),
+            ErrorFormat::Json => {
+                let mut body = serde_json::json!({
+                    "type": "about:blank",
+                    "title": status.canonical_reason().unwrap_or("Error"),
+                    "status": status.as_u16(),
+                    "detail": self.message(),
+                    "error": self.code(),
+                });
+                if let Some(instance) = instance {
+                    body["instance"] = instance.into();
+                }
+                match &self {
+                    IhaCdnError::Gone {
+                        tombstone: Some(tombstone),
+                        ..
+                    } => {
+                        body["reason"] = serde_json::json!(tombstone.reason);
+                        body["deleted_at"] = tombstone.deleted_at.into();
+                    }
+                    IhaCdnError::NotYetAvailable { not_before, .. } => {
+                        body["not_before"] = (*not_before).into();
+                    }
+                    IhaCdnError::Banned { until } => {
+                        body["banned_until"] = (*until).into();
+                    }
+                    IhaCdnError::RateLimited { retry_after } => {
+                        body["retry_after"] = (*retry_after).into();
+                    }
+                    _ => {}
+                }
+                (
+                    status,
+                    [(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/problem+json"),
+                    )],
+                    body.to_string(),
+                )
+                    .into_response()
+            }
+        };
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept"));
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            // The rest of the body is never read, browsers only show the response if the connection is closed
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+        response.extensions_mut().insert(ErrorCode(code));
+        response
+    }
+}
+
+impl IntoResponse for IhaCdnError {
+    fn into_response(self) -> Response {
+        self.respond(ErrorFormat::Text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_picks_the_highest_quality() {
+        assert_eq!(
+            ErrorFormat::from_accept("text/html;q=0.5, application/json;q=0.9"),
+            Some(ErrorFormat::Json)
+        );
+        assert_eq!(
+            ErrorFormat::from_accept("text/plain; q=0.8, text/html"),
+            Some(ErrorFormat::Html)
+        );
+        // The first of equally preferred types wins
+        assert_eq!(
+            ErrorFormat::from_accept("text/plain, application/json"),
+            Some(ErrorFormat::Text)
+        );
+        // q=0 means not acceptable
+        assert_eq!(
+            ErrorFormat::from_accept("application/json;q=0, text/plain;q=0.1"),
+            Some(ErrorFormat::Text)
+        );
+    }
+
+    #[test]
+    fn accept_understands_problem_details() {
+        assert_eq!(
+            ErrorFormat::from_accept("application/problem+json"),
+            Some(ErrorFormat::Json)
+        );
+        assert_eq!(
+            ErrorFormat::from_accept("Application/Problem+JSON;q=0.9, text/html;q=0.1"),
+            Some(ErrorFormat::Json)
+        );
+    }
+
+    #[test]
+    fn wildcards_express_no_preference() {
+        assert_eq!(ErrorFormat::from_accept("*/*"), None);
+        assert_eq!(ErrorFormat::from_accept("text/*, image/*;q=0.8"), None);
+        assert_eq!(
+            ErrorFormat::from_accept("*/*;q=0.8, text/html;q=0.1"),
+            Some(ErrorFormat::Html)
+        );
+    }
+
+    #[test]
+    fn missing_accept_keeps_the_route_default() {
+        assert_eq!(ErrorFormat::from_accept(""), None);
+
+        // Outside of the middleware there is no `Accept` header to follow
+        let response = IhaCdnError::not_found("abcdef").respond(ErrorFormat::Json);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::{path::PathBuf, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::mpsc::{Receiver, Sender, channel, error::TrySendError};
+
+use crate::{
+    state::{CDNData, SharedState},
+    store::RedisConnection,
+};
+
+/// How many events may wait to be published before new ones are dropped.
+const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// Internal events published to the Redis Stream.
+#[derive(Debug, Clone, Copy)]
+pub enum CdnEvent {
+    Upload,
+    Delete,
+    View,
+}
+
+impl CdnEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CdnEvent::Upload => "upload",
+            CdnEvent::Delete => "delete",
+            CdnEvent::View => "view",
+        }
+    }
+}
+
+/// The entry fields sent with an event.
+///
+/// Only what consumers need is published, never the uploader, the delete token or the ACL.
+#[derive(Serialize, Debug)]
+struct EventData {
+    id: String,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mimetype: Option<String>,
+    /// Missing once the file is gone, e.g. for most `delete` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<i64>,
+}
+
+/// An event waiting for the publisher.
+pub struct PendingEvent {
+    event: CdnEvent,
+    is_admin: bool,
+    timestamp: u64,
+    /// The stored file, to fill in [`EventData::size`].
+    path: Option<PathBuf>,
+    data: EventData,
+}
+
+impl PendingEvent {
+    fn new(event: CdnEvent, id: String, cdn_data: &CDNData) -> Self {
+        let (path, mimetype) = match cdn_data {
+            CDNData::Short { .. } => (None, None),
+            CDNData::File { path, mimetype, .. } | CDNData::Code { path, mimetype, .. } => {
+                (Some(path.clone()), Some(mimetype.clone()))
+            }
+        };
+        Self {
+            event,
+            is_admin: cdn_data.is_admin(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            path,
+            data: EventData {
+                id,
+                kind: cdn_data.kind(),
+                mimetype,
+                size: None,
+                time: cdn_data.time_added(),
+            },
+        }
+    }
+}
+
+/// The queue of events waiting to be published to the Redis Stream.
+pub struct EventQueue {
+    sender: Sender<PendingEvent>,
+}
+
+impl EventQueue {
+    pub fn new() -> (Self, Receiver<PendingEvent>) {
+        let (sender, receiver) = channel(EVENT_QUEUE_SIZE);
+        (Self { sender }, receiver)
+    }
+}
+
+/// Publish an event to the configured Redis Stream.
+///
+/// This is fire-and-forget, the event is dropped if the queue is full and failures are only
+/// logged.
+pub fn publish_event(
+    state: &Arc<SharedState>,
+    event: CdnEvent,
+    id: impl Into<String>,
+    cdn_data: &CDNData,
+) {
+    if !state.config.events.enable {
+        return;
+    }
+
+    let pending = PendingEvent::new(event, id.into(), cdn_data);
+    match state.events.sender.try_send(pending) {
+        Ok(()) => {}
+        Err(TrySendError::Full(pending)) => {
+            tracing::warn!(
+                "Event queue is full, dropping {} event of {}",
+                pending.event.as_str(),
+                pending.data.id
+            );
+        }
+        Err(TrySendError::Closed(_)) => {}
+    }
+}
+
+/// Publish the queued events one by one over a single connection, opened again after a failure.
+pub async fn run_publisher(state: Arc<SharedState>, mut receiver: Receiver<PendingEvent>) {
+    let events_config = &state.config.events;
+    let mut connection: Option<RedisConnection> = None;
+    while let Some(mut pending) = receiver.recv().await {
+        if let Some(path) = &pending.path {
+            pending.data.size = tokio::fs::metadata(state.config.resolve_path(path).await)
+                .await
+                .ok()
+                .map(|metadata| metadata.len());
+        }
+        let data = match serde_json::to_string(&pending.data) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("Failed to serialize event data: {}", e);
+                continue;
+            }
+        };
+
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(&events_config.stream);
+        if let Some(max_len) = events_config.max_len {
+            // Approximate trimming is a lot cheaper for Redis
+            cmd.arg("MAXLEN").arg("~").arg(max_len);
+        }
+        cmd.arg("*")
+            .arg("event")
+            .arg(pending.event.as_str())
+            .arg("id")
+            .arg(&pending.data.id)
+            .arg("kind")
+            .arg(pending.data.kind)
+            .arg("is_admin")
+            .arg(pending.is_admin)
+            .arg("timestamp")
+            .arg(pending.timestamp)
+            .arg("data")
+            .arg(data);
+
+        if connection.is_none() {
+            match state.make_connection().await {
+                Ok(opened) => connection = Some(opened),
+                Err(e) => {
+                    tracing::error!("Failed to connect to Redis for event publishing: {}", e);
+                    continue;
+                }
+            }
+        }
+        let Some(publisher) = connection.as_mut() else {
+            continue;
+        };
+        if let Err(e) = cmd.query_async::<String>(publisher).await {
+            tracing::error!("Failed to publish {} event: {}", pending.event.as_str(), e);
+            connection = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_data_leaves_out_private_fields() {
+        let cdn_data: CDNData = serde_json::from_value(serde_json::json!({
+            "type": "file",
+            "is_admin": false,
+            "path": "uploads/abcdef.png",
+            "mimetype": "image/png",
+            "time_added": 1735689600,
+            "delete_token": "0123456789abcdef",
+            "acl": ["fedcba9876543210"],
+            "uploader": { "ip": "203.0.113.7", "user_agent": "curl/8.0" },
+        }))
+        .unwrap();
+        let pending = PendingEvent::new(CdnEvent::Upload, "abcdef".to_string(), &cdn_data);
+
+        assert_eq!(
+            serde_json::to_value(&pending.data).unwrap(),
+            serde_json::json!({
+                "id": "abcdef",
+                "kind": "file",
+                "mimetype": "image/png",
+                "time": 1735689600,
+            })
+        );
+    }
+}
@@ -0,0 +1,516 @@
+//! WASM modules inspecting uploads before they are stored.
+//!
+//! A filter exports its `memory`, `ihacdn_alloc(len) -> ptr` to receive its input and
+//! `ihacdn_filter(subject_ptr, subject_len, head_ptr, head_len) -> i64`, which gets the upload
+//! as JSON and the start of the file. It returns the pointer and length of its JSON verdict,
+//! packed as `ptr << 32 | len`.
+
+use std::{net::IpAddr, path::Path, sync::Arc};
+
+#[cfg(feature = "wasm-filters")]
+use serde::Deserialize;
+use serde::Serialize;
+#[cfg(feature = "wasm-filters")]
+use tokio::io::AsyncReadExt;
+#[cfg(feature = "wasm-filters")]
+use wasmtime::{
+    Config, Engine, InstancePre, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+#[cfg(feature = "wasm-filters")]
+use wasmtime_wasi::{
+    WasiCtxBuilder,
+    preview1::{self, WasiP1Ctx},
+};
+
+use crate::config::IhaCdnFiltersConfig;
+#[cfg(feature = "wasm-filters")]
+use crate::config::IhaCdnUploadFilterConfig;
+
+#[cfg(feature = "wasm-filters")]
+/// The WASI `notsup` errno, returned to modules trying to sleep.
+const ERRNO_NOTSUP: i32 = 58;
+
+/// The upload given to the filters as JSON.
+#[derive(Debug, Serialize)]
+pub struct FilterSubject<'a> {
+    /// `file` or `code`.
+    #[serde(rename = "type")]
+    pub kind: &'a str,
+    pub mimetype: &'a str,
+    pub extension: &'a str,
+    /// The file size in bytes.
+    pub size: u64,
+    pub original_name: &'a str,
+    /// The SHA-256 of the file as lowercase hex.
+    pub sha256: &'a str,
+    pub is_admin: bool,
+    pub ip_address: &'a [IpAddr],
+}
+
+#[cfg(feature = "wasm-filters")]
+/// What a filter decided, returned by the module as JSON.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum FilterAction {
+    Allow,
+    Deny {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Change how the upload is stored.
+    Transform {
+        #[serde(default)]
+        quarantine: bool,
+        #[serde(default)]
+        retention_days: Option<u64>,
+        #[serde(default)]
+        unlisted: bool,
+    },
+}
+
+/// The combined outcome of every filter.
+#[derive(Debug, Default)]
+pub struct FilterVerdict {
+    /// The name of the filter that rejected the upload.
+    pub rejected_by: Option<String>,
+    pub quarantine: bool,
+    /// The shortest retention asked by the filters, in days.
+    pub retention_days: Option<u64>,
+    /// Keep the upload out of the gallery.
+    pub unlisted: bool,
+}
+
+#[cfg(feature = "wasm-filters")]
+/// The data of the store of each run.
+struct FilterState {
+    /// WASI without any preopened directory, environment or network.
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+}
+
+#[cfg(feature = "wasm-filters")]
+struct LoadedFilter {
+    config: IhaCdnUploadFilterConfig,
+    instance: InstancePre<FilterState>,
+}
+
+#[cfg(feature = "wasm-filters")]
+pub struct UploadFilters {
+    engine: Engine,
+    filters: Vec<LoadedFilter>,
+}
+
+#[cfg(feature = "wasm-filters")]
+impl UploadFilters {
+    /// Compile the filter modules from the config.
+    pub fn load(config: &IhaCdnFiltersConfig) -> Result<Self, String> {
+        let engine = filter_engine().map_err(|e| e.to_string())?;
+        let modules = config
+            .modules
+            .iter()
+            .map(|filter| {
+                Module::from_file(&engine, &filter.path)
+                    .map(|module| (filter.clone(), module))
+                    .map_err(|e| format!("Failed to compile the filter `{}`: {:#}", filter.name, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::new(engine, modules)
+    }
+
+    fn new(
+        engine: Engine,
+        modules: Vec<(IhaCdnUploadFilterConfig, Module)>,
+    ) -> Result<Self, String> {
+        let linker = filter_linker(&engine).map_err(|e| format!("{e:#}"))?;
+        let filters = modules
+            .into_iter()
+            .map(|(config, module)| {
+                if let Some(export) = ["memory", "ihacdn_alloc", "ihacdn_filter"]
+                    .into_iter()
+                    .find(|export| module.get_export(export).is_none())
+                {
+                    return Err(format!(
+                        "The filter `{}` doesn't export `{}`",
+                        config.name, export
+                    ));
+                }
+                // Fails on imports other than WASI
+                let instance = linker
+                    .instantiate_pre(&module)
+                    .map_err(|e| format!("Failed to link the filter `{}`: {:#}", config.name, e))?;
+                Ok(LoadedFilter { config, instance })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { engine, filters })
+    }
+
+    /// Run every filter on the upload, stopping at the first one denying it.
+    pub async fn evaluate(
+        self: &Arc<Self>,
+        subject: &FilterSubject<'_>,
+        path: &Path,
+    ) -> FilterVerdict {
+        if self.filters.is_empty() {
+            return FilterVerdict::default();
+        }
+
+        let head_size = self
+            .filters
+            .iter()
+            .map(|filter| filter.config.head_size)
+            .max()
+            .unwrap_or_default();
+        let head = match read_head(path, head_size).await {
+            Ok(head) => head,
+            Err(e) => {
+                tracing::error!("Failed to read the upload for the filters: {}", e);
+                return self.failed();
+            }
+        };
+        let subject = serde_json::to_vec(subject).unwrap();
+
+        let filters = Arc::clone(self);
+        match tokio::task::spawn_blocking(move || filters.run_all(&subject, &head)).await {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                tracing::error!("The upload filters panicked: {}", e);
+                self.failed()
+            }
+        }
+    }
+
+    /// The verdict when the filters couldn't be run at all.
+    fn failed(&self) -> FilterVerdict {
+        FilterVerdict {
+            rejected_by: self
+                .filters
+                .iter()
+                .find(|filter| filter.config.fail_closed)
+                .map(|filter| filter.config.name.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn run_all(&self, subject: &[u8], head: &[u8]) -> FilterVerdict {
+        let mut verdict = FilterVerdict::default();
+        for filter in &self.filters {
+            let name = &filter.config.name;
+            let head = &head[..head.len().min(filter.config.head_size)];
+            match self.run(filter, subject, head) {
+                Ok(FilterAction::Allow) => (),
+                Ok(FilterAction::Deny { reason }) => {
+                    tracing::warn!(
+                        "The upload filter {} denied the upload: {}",
+                        name,
+                        reason.as_deref().unwrap_or("no reason given")
+                    );
+                    verdict.rejected_by = Some(name.clone());
+                    break;
+                }
+                Ok(FilterAction::Transform {
+                    quarantine,
+                    retention_days,
+                    unlisted,
+                }) => {
+                    verdict.quarantine |= quarantine;
+                    verdict.unlisted |= unlisted;
+                    if let Some(days) = retention_days {
+                        verdict.retention_days = Some(
+                            verdict
+                                .retention_days
+                                .map_or(days, |current| current.min(days)),
+                        );
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("The upload filter {} failed: {:#}", name, e);
+                    if filter.config.fail_closed {
+                        verdict.rejected_by = Some(name.clone());
+                        break;
+                    }
+                }
+            }
+        }
+        verdict
+    }
+
+    /// Run a filter in a new store, within its fuel and memory limits.
+    fn run(
+        &self,
+        filter: &LoadedFilter,
+        subject: &[u8],
+        head: &[u8],
+    ) -> wasmtime::Result<FilterAction> {
+        let memory_limit = usize::try_from(filter.config.memory_limit)
+            .unwrap_or(usize::MAX)
+            .saturating_mul(1024);
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(memory_limit)
+            .instances(1)
+            .memories(1)
+            .tables(1)
+            .build();
+        let mut store = Store::new(
+            &self.engine,
+            FilterState {
+                wasi: WasiCtxBuilder::new().build_p1(),
+                limits,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(filter.config.fuel)?;
+
+        let instance = filter.instance.instantiate(&mut store)?;
+        // WASI reactors set themselves up in `_initialize`
+        if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            initialize.call(&mut store, ())?;
+        }
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("`memory` is not a memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "ihacdn_alloc")?;
+        let run =
+            instance.get_typed_func::<(i32, i32, i32, i32), i64>(&mut store, "ihacdn_filter")?;
+
+        let (subject_ptr, subject_len) = write_input(&mut store, memory, &alloc, subject)?;
+        let (head_ptr, head_len) = write_input(&mut store, memory, &alloc, head)?;
+        let packed = run.call(&mut store, (subject_ptr, subject_len, head_ptr, head_len))? as u64;
+
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        if ptr.saturating_add(len) > memory.data_size(&store) {
+            return Err(wasmtime::Error::msg("the verdict is out of bounds"));
+        }
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output)?;
+        Ok(serde_json::from_slice(&output)?)
+    }
+}
+
+/// Without the `wasm-filters` feature no filter can be configured, every upload is allowed.
+#[cfg(not(feature = "wasm-filters"))]
+pub struct UploadFilters;
+
+#[cfg(not(feature = "wasm-filters"))]
+impl UploadFilters {
+    pub fn load(config: &IhaCdnFiltersConfig) -> Result<Self, String> {
+        if config.modules.is_empty() {
+            Ok(Self)
+        } else {
+            Err("Upload filters need ihacdn built with the `wasm-filters` feature".to_string())
+        }
+    }
+
+    pub async fn evaluate(
+        self: &Arc<Self>,
+        _subject: &FilterSubject<'_>,
+        _path: &Path,
+    ) -> FilterVerdict {
+        FilterVerdict::default()
+    }
+}
+
+#[cfg(feature = "wasm-filters")]
+/// Copy the input into memory allocated by the module, returning its pointer and length.
+fn write_input(
+    store: &mut Store<FilterState>,
+    memory: Memory,
+    alloc: &TypedFunc<i32, i32>,
+    input: &[u8],
+) -> wasmtime::Result<(i32, i32)> {
+    let len = i32::try_from(input.len())?;
+    let ptr = alloc.call(&mut *store, len)?;
+    memory.write(&mut *store, ptr as u32 as usize, input)?;
+    Ok((ptr, len))
+}
+
+#[cfg(feature = "wasm-filters")]
+fn filter_engine() -> wasmtime::Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config)
+}
+
+#[cfg(feature = "wasm-filters")]
+/// WASI for the filters, without sleeping since it doesn't use any fuel.
+fn filter_linker(engine: &Engine) -> wasmtime::Result<Linker<FilterState>> {
+    let mut linker = Linker::new(engine);
+    preview1::add_to_linker_sync(&mut linker, |state: &mut FilterState| &mut state.wasi)?;
+    linker.allow_shadowing(true).func_wrap(
+        "wasi_snapshot_preview1",
+        "poll_oneoff",
+        |_: i32, _: i32, _: i32, _: i32| ERRNO_NOTSUP,
+    )?;
+    Ok(linker)
+}
+
+#[cfg(feature = "wasm-filters")]
+/// Read up to `size` bytes from the start of the file.
+async fn read_head(path: &Path, size: usize) -> std::io::Result<Vec<u8>> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut head = Vec::with_capacity(size);
+    file.take(size as u64).read_to_end(&mut head).await?;
+    Ok(head)
+}
+
+#[cfg(all(test, feature = "wasm-filters"))]
+mod tests {
+    use wasmtime::Trap;
+
+    use super::*;
+
+    /// A filter giving `verdict`, or `head_verdict` if the file starts with `M`.
+    fn verdict_module(verdict: &str, head_verdict: &str, body: &str) -> String {
+        format!(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (data (i32.const 512) "{}")
+                (global $next (mut i32) (i32.const 1024))
+                (func (export "ihacdn_alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $next))
+                    (global.set $next (i32.add (global.get $next) (local.get $len)))
+                    (local.get $ptr))
+                (func (export "ihacdn_filter")
+                    (param $subject i32) (param $subject_len i32)
+                    (param $head i32) (param $head_len i32)
+                    (result i64)
+                    {}
+                    (if (i32.and
+                            (i32.gt_u (local.get $head_len) (i32.const 0))
+                            (i32.eq (i32.load8_u (local.get $head)) (i32.const 77)))
+                        (then (return (i64.or (i64.shl (i64.const 512) (i64.const 32))
+                                              (i64.const {})))))
+                    (i64.const {})))"#,
+            verdict.replace('"', "\\\""),
+            head_verdict.replace('"', "\\\""),
+            body,
+            head_verdict.len(),
+            verdict.len(),
+        )
+    }
+
+    fn filter_config(name: &str) -> IhaCdnUploadFilterConfig {
+        IhaCdnUploadFilterConfig {
+            name: name.to_string(),
+            path: format!("{name}.wasm").into(),
+            head_size: 16,
+            fuel: 1_000_000,
+            memory_limit: 1024,
+            fail_closed: true,
+        }
+    }
+
+    fn load_filters(
+        modules: &[(IhaCdnUploadFilterConfig, String)],
+    ) -> Result<UploadFilters, String> {
+        let engine = filter_engine().unwrap();
+        let modules = modules
+            .iter()
+            .map(|(config, source)| (config.clone(), Module::new(&engine, source).unwrap()))
+            .collect();
+        UploadFilters::new(engine, modules)
+    }
+
+    fn run(filters: &UploadFilters, head: &[u8]) -> wasmtime::Result<FilterAction> {
+        filters.run(&filters.filters[0], b"{}", head)
+    }
+
+    const ALLOW: &str = r#"{"action":"allow"}"#;
+    const DENY: &str = r#"{"action":"deny","reason":"executable"}"#;
+
+    #[test]
+    fn filters_see_the_head_of_the_file() {
+        let filters =
+            load_filters(&[(filter_config("mz"), verdict_module(ALLOW, DENY, ""))]).unwrap();
+
+        assert_eq!(run(&filters, b"PK\x03\x04").unwrap(), FilterAction::Allow);
+        assert_eq!(
+            run(&filters, b"MZ\x90\x00").unwrap(),
+            FilterAction::Deny {
+                reason: Some("executable".to_string())
+            }
+        );
+        assert_eq!(
+            filters.run_all(b"{}", b"MZ").rejected_by.as_deref(),
+            Some("mz")
+        );
+    }
+
+    #[test]
+    fn transforms_are_combined() {
+        let first = r#"{"action":"transform","quarantine":true,"retention_days":30}"#;
+        let second = r#"{"action":"transform","retention_days":7,"unlisted":true}"#;
+        let filters = load_filters(&[
+            (filter_config("first"), verdict_module(first, first, "")),
+            (filter_config("second"), verdict_module(second, second, "")),
+        ])
+        .unwrap();
+
+        let verdict = filters.run_all(b"{}", b"");
+        assert!(verdict.rejected_by.is_none());
+        assert!(verdict.quarantine);
+        assert!(verdict.unlisted);
+        assert_eq!(verdict.retention_days, Some(7));
+    }
+
+    #[test]
+    fn filters_run_out_of_fuel() {
+        let looping = verdict_module(ALLOW, ALLOW, "(loop $spin (br $spin))");
+        let mut lenient = filter_config("lenient");
+        lenient.fail_closed = false;
+        let filters = load_filters(&[
+            (lenient, looping.clone()),
+            (filter_config("strict"), looping),
+        ])
+        .unwrap();
+
+        let error = run(&filters, b"").unwrap_err();
+        assert_eq!(error.downcast_ref::<Trap>(), Some(&Trap::OutOfFuel));
+        // Only the filter failing closed rejects the upload
+        assert_eq!(
+            filters.run_all(b"{}", b"").rejected_by.as_deref(),
+            Some("strict")
+        );
+    }
+
+    #[test]
+    fn filters_cannot_grow_past_the_memory_limit() {
+        let growing = verdict_module(
+            ALLOW,
+            ALLOW,
+            "(if (i32.eq (memory.grow (i32.const 32)) (i32.const -1)) (then unreachable))",
+        );
+        let filters = load_filters(&[(filter_config("growing"), growing.clone())]).unwrap();
+
+        let error = run(&filters, b"").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::UnreachableCodeReached)
+        );
+
+        let mut roomy = filter_config("roomy");
+        roomy.memory_limit = 4096;
+        let filters = load_filters(&[(roomy, growing)]).unwrap();
+        assert_eq!(run(&filters, b"").unwrap(), FilterAction::Allow);
+    }
+
+    #[test]
+    fn filters_only_get_wasi() {
+        let module = verdict_module(ALLOW, ALLOW, "").replacen(
+            r#"(import "wasi_snapshot_preview1" "fd_write""#,
+            r#"(import "env" "system""#,
+            1,
+        );
+
+        let error = load_filters(&[(filter_config("escape"), module)])
+            .err()
+            .unwrap();
+        assert!(error.contains("Failed to link the filter `escape`"));
+    }
+}
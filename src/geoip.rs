@@ -0,0 +1,294 @@
+//! A minimal reader of MaxMind DB files, enough to resolve the ASN of an IP with GeoLite2-ASN.
+//!
+//! See <https://maxmind.github.io/MaxMind-DB/> for the format.
+
+use std::{net::IpAddr, path::Path};
+
+/// Marks the start of the metadata, at the end of the file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// The null bytes between the search tree and the data section.
+const DATA_SECTION_SEPARATOR: usize = 16;
+
+/// A decoded value of the data section.
+#[derive(Debug, Clone)]
+enum Value {
+    String(String),
+    Unsigned(u64),
+    Signed(i64),
+    Map(Vec<(String, Value)>),
+    /// Doubles, floats, booleans, bytes and arrays, which are never needed.
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Unsigned(value) => Some(*value),
+            Value::Signed(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// The autonomous system an IP belongs to.
+#[derive(Debug, Clone)]
+pub struct Asn {
+    pub number: u32,
+    pub organization: Option<String>,
+}
+
+/// A loaded GeoLite2-ASN (or compatible) database.
+#[derive(Debug)]
+pub struct AsnDatabase {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// The node IPv4 addresses start at in an IPv6 tree.
+    ipv4_start: usize,
+}
+
+impl AsnDatabase {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_bytes(data)
+    }
+
+    fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        let metadata_start = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("not a MaxMind DB file")?
+            + METADATA_MARKER.len();
+        let (metadata, _) = Decoder {
+            data: &data,
+            base: metadata_start,
+        }
+        .decode(metadata_start)?;
+
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_u64)
+                .ok_or(format!("the metadata has no {name}"))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("unsupported record size {record_size}"));
+        }
+        if node_count * record_size / 4 + DATA_SECTION_SEPARATOR > metadata_start {
+            return Err("the search tree is truncated".to_string());
+        }
+
+        let mut database = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.record(node, 0);
+            }
+            database.ipv4_start = node;
+        }
+        Ok(database)
+    }
+
+    /// The record of a node, following the left (`0`) or right (`1`) branch.
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let node_bytes = self.record_size / 4;
+        let bytes = &self.data[node * node_bytes..(node + 1) * node_bytes];
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize);
+        match (self.record_size, bit) {
+            (24, 0) => be(&bytes[0..3]),
+            (24, _) => be(&bytes[3..6]),
+            (28, 0) => ((bytes[3] as usize & 0xf0) << 20) | be(&bytes[0..3]),
+            (28, _) => ((bytes[3] as usize & 0x0f) << 24) | be(&bytes[4..7]),
+            (_, 0) => be(&bytes[0..4]),
+            (_, _) => be(&bytes[4..8]),
+        }
+    }
+
+    /// The data of the network an IP is in, if any.
+    fn find(&self, ip: IpAddr) -> Result<Option<Value>, String> {
+        let (bits, mut node) = match ip {
+            IpAddr::V4(ipv4) => (ipv4.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(ipv6) => (ipv6.octets().to_vec(), 0),
+        };
+
+        for index in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits[index / 8] >> (7 - index % 8)) & 1;
+            node = self.record(node, bit);
+        }
+        if node <= self.node_count {
+            return Ok(None);
+        }
+
+        let data_section = self.node_count * self.record_size / 4 + DATA_SECTION_SEPARATOR;
+        let offset = data_section + (node - self.node_count - DATA_SECTION_SEPARATOR);
+        let (value, _) = Decoder {
+            data: &self.data,
+            base: data_section,
+        }
+        .decode(offset)?;
+        Ok(Some(value))
+    }
+
+    /// The autonomous system of an IP, if it is known.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Asn> {
+        let value = match self.find(ip) {
+            Ok(value) => value?,
+            Err(e) => {
+                tracing::warn!("Failed to look up the ASN of {}: {}", ip, e);
+                return None;
+            }
+        };
+        Some(Asn {
+            number: value
+                .get("autonomous_system_number")
+                .and_then(Value::as_u64)
+                .and_then(|number| u32::try_from(number).ok())?,
+            organization: value
+                .get("autonomous_system_organization")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        })
+    }
+}
+
+/// Decodes values of a data section starting at `base`, pointers are relative to it.
+struct Decoder<'a> {
+    data: &'a [u8],
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], String> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or_else(|| "the data section is truncated".to_string())
+    }
+
+    fn unsigned(&self, offset: usize, len: usize) -> Result<u64, String> {
+        Ok(self
+            .bytes(offset, len)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    /// Decode the value at `offset`, returns it with the offset after it.
+    fn decode(&self, offset: usize) -> Result<(Value, usize), String> {
+        let control = self.bytes(offset, 1)?[0];
+        let mut offset = offset + 1;
+
+        let mut kind = control >> 5;
+        if kind == 1 {
+            return self.pointer(control, offset);
+        }
+        if kind == 0 {
+            kind = 7u8.saturating_add(self.bytes(offset, 1)?[0]);
+            offset += 1;
+        }
+
+        let size = match control & 0x1f {
+            29 => 29 + self.unsigned(offset, 1)? as usize,
+            30 => 285 + self.unsigned(offset, 2)? as usize,
+            31 => 65821 + self.unsigned(offset, 3)? as usize,
+            size => size as usize,
+        };
+        offset += match control & 0x1f {
+            29 => 1,
+            30 => 2,
+            31 => 3,
+            _ => 0,
+        };
+
+        match kind {
+            2 => {
+                let text = String::from_utf8_lossy(self.bytes(offset, size)?).into_owned();
+                Ok((Value::String(text), offset + size))
+            }
+            3 => {
+                self.bytes(offset, 8)?;
+                Ok((Value::Other, offset + 8))
+            }
+            4 | 10 => {
+                self.bytes(offset, size)?;
+                Ok((Value::Other, offset + size))
+            }
+            5 | 6 | 9 => Ok((Value::Unsigned(self.unsigned(offset, size)?), offset + size)),
+            7 => {
+                let mut entries = Vec::with_capacity(size);
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset)?;
+                    let (value, next) = self.decode(next)?;
+                    let Value::String(key) = key else {
+                        return Err("a map key is not a string".to_string());
+                    };
+                    entries.push((key, value));
+                    offset = next;
+                }
+                Ok((Value::Map(entries), offset))
+            }
+            8 => {
+                let value = self.unsigned(offset, size)? as u32 as i32;
+                Ok((Value::Signed(value.into()), offset + size))
+            }
+            11 => {
+                for _ in 0..size {
+                    (_, offset) = self.decode(offset)?;
+                }
+                Ok((Value::Other, offset))
+            }
+            14 => Ok((Value::Other, offset)),
+            15 => {
+                self.bytes(offset, 4)?;
+                Ok((Value::Other, offset + 4))
+            }
+            kind => Err(format!("unsupported data type {kind}")),
+        }
+    }
+
+    /// Follow a pointer, the offset after it is the one after the pointer itself.
+    fn pointer(&self, control: u8, offset: usize) -> Result<(Value, usize), String> {
+        let size = ((control >> 3) & 0x3) as usize;
+        let high = (control & 0x7) as usize;
+        let target = match size {
+            0 => (high << 8) | self.unsigned(offset, 1)? as usize,
+            1 => ((high << 16) | self.unsigned(offset, 2)? as usize) + 2048,
+            2 => ((high << 24) | self.unsigned(offset, 3)? as usize) + 526336,
+            _ => self.unsigned(offset, 4)? as usize,
+        };
+        let (value, _) = self.decode(self.base + target)?;
+        Ok((value, offset + size + 1))
+    }
+}
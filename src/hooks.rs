@@ -0,0 +1,97 @@
+//! External commands run on uploads, e.g. custom scanners or converters.
+
+use std::{net::IpAddr, path::Path, process::Stdio, time::Duration};
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::IhaCdnHooksConfig;
+
+/// What the hook decided about an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookVerdict {
+    Accept,
+    Reject,
+    Quarantine,
+}
+
+/// The upload given to the hook as JSON on stdin.
+#[derive(Debug, Serialize)]
+pub struct HookSubject<'a> {
+    pub id: &'a str,
+    pub path: &'a Path,
+    /// `file` or `code`.
+    #[serde(rename = "type")]
+    pub kind: &'a str,
+    pub mimetype: &'a str,
+    pub extension: &'a str,
+    /// The file size in bytes.
+    pub size: u64,
+    pub original_name: &'a str,
+    pub is_admin: bool,
+    pub ip_address: &'a [IpAddr],
+}
+
+/// Run the post-upload hook, if there is one.
+pub async fn run_post_upload(config: &IhaCdnHooksConfig, subject: &HookSubject<'_>) -> HookVerdict {
+    let Some((program, args)) = config.post_upload.split_first() else {
+        return HookVerdict::Accept;
+    };
+    let failed = if config.fail_closed {
+        HookVerdict::Reject
+    } else {
+        HookVerdict::Accept
+    };
+
+    let child = tokio::process::Command::new(program)
+        .args(args)
+        .arg(subject.path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::error!("Failed to spawn the post-upload hook: {}", e);
+            return failed;
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = serde_json::to_vec(subject).unwrap();
+        // The hook may exit without reading its input
+        if let Err(e) = stdin.write_all(&input).await {
+            tracing::warn!("Failed to write to the post-upload hook: {}", e);
+        }
+    }
+
+    let timeout = Duration::from_secs(config.timeout);
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(result)) => match result.status.code() {
+            Some(0) => HookVerdict::Accept,
+            Some(code) if code == config.reject_exit_code => HookVerdict::Reject,
+            Some(code) if code == config.quarantine_exit_code => HookVerdict::Quarantine,
+            _ => {
+                tracing::error!(
+                    "The post-upload hook exited with {}: {}",
+                    result.status,
+                    String::from_utf8_lossy(&result.stderr).trim()
+                );
+                failed
+            }
+        },
+        Ok(Err(e)) => {
+            tracing::error!("Failed to wait for the post-upload hook: {}", e);
+            failed
+        }
+        Err(_) => {
+            tracing::error!(
+                "The post-upload hook timed out after {} seconds",
+                config.timeout
+            );
+            failed
+        }
+    }
+}
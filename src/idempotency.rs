@@ -0,0 +1,340 @@
+//! Replaying the result of retried uploads sent with an `Idempotency-Key` header.
+
+use std::sync::Arc;
+
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::AdminIdentity,
+    error::IhaCdnError,
+    notifier::extract_ip_address,
+    state::{SharedState, hash_token},
+    store::RedisConnection,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on responses replayed from an earlier request.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+/// How long a request may hold its key before another request can retry, in seconds.
+const PENDING_TTL: u64 = 300;
+const MAX_KEY_LENGTH: usize = 255;
+
+/// What a successful request returned.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredResult {
+    pub url: String,
+    /// The ID of the entry, empty for results stored before it was kept.
+    #[serde(default)]
+    pub id: String,
+    /// The raw content of a paste, the file itself or the target of a short link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Only the hash of the token is ever stored, replayed results are answered without it.
+    #[serde(skip)]
+    pub delete_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct IdempotencyRecord {
+    /// Tells retries apart from other requests reusing the key.
+    fingerprint: String,
+    /// [`None`] while the request is still being processed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<StoredResult>,
+}
+
+pub enum Idempotency {
+    /// The request was already done, answer with its result.
+    Replay(StoredResult),
+    /// The request is new, its result must be saved with [`IdempotencyClaim::complete`].
+    Claimed(IdempotencyClaim),
+}
+
+/// A key held by the request being processed, released if the request fails.
+pub struct IdempotencyClaim {
+    state: Arc<SharedState>,
+    key: String,
+    fingerprint: String,
+    completed: bool,
+}
+
+/// A recent upload, returned again for identical uploads of the same uploader.
+#[derive(Serialize, Deserialize, Debug)]
+struct RecentUpload {
+    id: String,
+    result: StoredResult,
+}
+
+fn recent_upload_key(prefix: &str, fingerprint: &str) -> String {
+    format!("{prefix}:recent:{}", hash_token(fingerprint))
+}
+
+fn idempotency_key(prefix: &str, scope: &str, key: &str) -> String {
+    // Keys are chosen by clients, only keep their hash
+    format!(
+        "{prefix}:idempotency:{}",
+        hash_token(&format!("{scope}:{key}"))
+    )
+}
+
+/// Who sent a request, the admin key or the client IP.
+///
+/// Idempotency keys and recent uploads are kept apart per uploader, so nobody can replay the
+/// result of someone else's request by guessing their key.
+pub fn uploader(admin: Option<&AdminIdentity>, headers: &HeaderMap) -> Option<String> {
+    match admin {
+        Some(admin) => Some(format!("key:{}", admin.name)),
+        None => extract_ip_address(headers)
+            .first()
+            .map(|ip| format!("ip:{ip}")),
+    }
+}
+
+/// Read the `Idempotency-Key` header of a request.
+pub fn header_key(headers: &HeaderMap) -> Result<Option<String>, IhaCdnError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(Some(key.to_string())),
+        _ => Err(IhaCdnError::BadRequest(format!(
+            "Idempotency-Key must be between 1 and {MAX_KEY_LENGTH} visible characters"
+        ))),
+    }
+}
+
+/// Hold the key for this request, or get the result of the earlier request with the same key.
+///
+/// Keys are only shared by the requests of the same `uploader`, see [`uploader`].
+/// `fingerprint` identifies the request content, reusing a key for another content is an error.
+pub async fn claim(
+    state: &Arc<SharedState>,
+    connection: &mut RedisConnection,
+    uploader: Option<&str>,
+    key: &str,
+    fingerprint: String,
+) -> Result<Idempotency, IhaCdnError> {
+    let redis_key = idempotency_key(
+        &state.config.key_prefix,
+        uploader.unwrap_or("anonymous"),
+        key,
+    );
+    let pending = IdempotencyRecord {
+        fingerprint,
+        result: None,
+    };
+    let claimed = redis::cmd("SET")
+        .arg(&redis_key)
+        .arg(serde_json::to_string(&pending).unwrap())
+        .arg("NX")
+        .arg("EX")
+        .arg(PENDING_TTL)
+        .query_async::<Option<String>>(connection)
+        .await
+        .map_err(IhaCdnError::RedisSave)?
+        .is_some();
+    if claimed {
+        return Ok(Idempotency::Claimed(IdempotencyClaim {
+            state: Arc::clone(state),
+            key: redis_key,
+            fingerprint: pending.fingerprint,
+            completed: false,
+        }));
+    }
+
+    let data = redis::cmd("GET")
+        .arg(&redis_key)
+        .query_async::<Option<String>>(connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: key.to_string(),
+            source,
+        })?
+        // Released in between, the client can simply retry
+        .ok_or(IhaCdnError::IdempotencyInProgress)?;
+    let record =
+        serde_json::from_str::<IdempotencyRecord>(&data).map_err(IhaCdnError::ParseData)?;
+    if record.fingerprint != pending.fingerprint {
+        return Err(IhaCdnError::IdempotencyMismatch);
+    }
+    record
+        .result
+        .map(Idempotency::Replay)
+        .ok_or(IhaCdnError::IdempotencyInProgress)
+}
+
+/// The result of an upload with the same fingerprint made recently, if its entry still exists.
+///
+/// `fingerprint` identifies the uploader and the upload content.
+pub async fn recent_upload(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    fingerprint: &str,
+) -> Result<Option<StoredResult>, IhaCdnError> {
+    let data = redis::cmd("GET")
+        .arg(recent_upload_key(prefix, fingerprint))
+        .query_async::<Option<String>>(connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: "recent upload".to_string(),
+            source,
+        })?;
+    let Some(recent) = data.and_then(|data| serde_json::from_str::<RecentUpload>(&data).ok())
+    else {
+        return Ok(None);
+    };
+    // Burnt or deleted in between
+    let exists = redis::cmd("EXISTS")
+        .arg(format!("{prefix}{}", recent.id))
+        .query_async::<bool>(connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: recent.id.clone(),
+            source,
+        })?;
+    Ok(exists.then_some(recent.result))
+}
+
+/// Remember an upload for `window` seconds, for [`recent_upload`].
+pub async fn remember_upload(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    fingerprint: &str,
+    id: &str,
+    result: &StoredResult,
+    window: u64,
+) {
+    let saved = redis::cmd("SET")
+        .arg(recent_upload_key(prefix, fingerprint))
+        .arg(recent_record(id, result))
+        .arg("EX")
+        .arg(window)
+        .exec_async(connection)
+        .await;
+    if let Err(err) = saved {
+        tracing::error!("Failed to remember recent upload: {}", err);
+    }
+}
+
+/// The JSON of a recent upload as stored in Redis.
+fn recent_record(id: &str, result: &StoredResult) -> String {
+    let recent = RecentUpload {
+        id: id.to_string(),
+        result: result.clone(),
+    };
+    serde_json::to_string(&recent).unwrap()
+}
+
+/// The JSON of a completed request as stored in Redis.
+fn completed_record(fingerprint: String, result: StoredResult) -> String {
+    let record = IdempotencyRecord {
+        fingerprint,
+        result: Some(result),
+    };
+    serde_json::to_string(&record).unwrap()
+}
+
+impl IdempotencyClaim {
+    /// Save the result of the request for its retries.
+    pub async fn complete(mut self, connection: &mut RedisConnection, result: StoredResult) {
+        let saved = redis::cmd("SET")
+            .arg(&self.key)
+            .arg(completed_record(
+                std::mem::take(&mut self.fingerprint),
+                result,
+            ))
+            .arg("EX")
+            .arg(self.state.config.idempotency.keep_hours.max(1) * 3600)
+            .exec_async(connection)
+            .await;
+        match saved {
+            Ok(()) => self.completed = true,
+            Err(err) => tracing::error!("Failed to save idempotent result: {}", err),
+        }
+    }
+}
+
+impl Drop for IdempotencyClaim {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let state = Arc::clone(&self.state);
+        let key = std::mem::take(&mut self.key);
+        tokio::spawn(async move {
+            let released = match state.make_connection().await {
+                Ok(mut connection) => {
+                    redis::cmd("DEL")
+                        .arg(&key)
+                        .exec_async(&mut connection)
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = released {
+                tracing::error!("Failed to release idempotency key: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "4f3c2b1a0e9d8c7b6a5f4e3d2c1b0a99";
+
+    fn result() -> StoredResult {
+        StoredResult {
+            url: "https://p.ihateani.me/abcdef.png".to_string(),
+            id: "abcdef".to_string(),
+            raw_url: None,
+            mimetype: Some("image/png".to_string()),
+            size: Some(1234),
+            delete_token: Some(TOKEN.to_string()),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn stored_records_never_contain_the_delete_token() {
+        let recent = recent_record("abcdef", &result());
+        let completed = completed_record("upload:abcdef".to_string(), result());
+
+        assert!(!recent.contains(TOKEN));
+        assert!(!completed.contains(TOKEN));
+    }
+
+    #[test]
+    fn keys_are_scoped_by_uploader() {
+        assert_eq!(
+            idempotency_key("cdn", "ip:203.0.113.7", "retry-1"),
+            idempotency_key("cdn", "ip:203.0.113.7", "retry-1")
+        );
+        assert_ne!(
+            idempotency_key("cdn", "ip:203.0.113.7", "retry-1"),
+            idempotency_key("cdn", "ip:198.51.100.2", "retry-1")
+        );
+        assert_ne!(
+            idempotency_key("cdn", "key:ci", "retry-1"),
+            idempotency_key("cdn", "ip:203.0.113.7", "retry-1")
+        );
+    }
+
+    #[test]
+    fn replayed_results_have_no_delete_token() {
+        let completed = completed_record("upload:abcdef".to_string(), result());
+        let record = serde_json::from_str::<IdempotencyRecord>(&completed).unwrap();
+        let replayed = record.result.unwrap();
+
+        assert_eq!(replayed.url, "https://p.ihateani.me/abcdef.png");
+        assert!(replayed.delete_token.is_none());
+    }
+}
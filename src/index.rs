@@ -0,0 +1,546 @@
+//! Secondary indexes of the entries for the admin search.
+//!
+//! Every entry is kept in a sorted set by upload time and in plain sets per type, mimetype and
+//! tag. They are maintained when entries are added or removed, so searching, the purge task and
+//! the stats rollup never need to scan the whole keyspace.
+//!
+//! If enabled, the words of pastes are also kept in an inverted index, one set of IDs per word.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    config::IhaCdnConfig,
+    state::{CDNData, entry_id},
+    store::RedisConnection,
+};
+
+/// How many keys are fetched at once while rebuilding the indexes.
+const SCAN_BATCH: usize = 500;
+/// Shorter words are not indexed.
+const MIN_WORD_LENGTH: usize = 2;
+/// Longer words are not indexed, they are usually hashes or encoded data.
+const MAX_WORD_LENGTH: usize = 40;
+/// The maximum amount of different words indexed per paste.
+const MAX_WORDS: usize = 10_000;
+
+/// All entries, scored by their upload time.
+fn added_key(prefix: &str) -> String {
+    format!("{prefix}:index:added")
+}
+
+/// Set once the indexes were built from the existing entries.
+fn built_key(prefix: &str) -> String {
+    format!("{prefix}:index:built")
+}
+
+fn type_key(prefix: &str, kind: &str) -> String {
+    format!("{prefix}:index:type:{kind}")
+}
+
+fn mimetype_key(prefix: &str, mimetype: &str) -> String {
+    format!("{prefix}:index:mimetype:{}", mimetype.to_ascii_lowercase())
+}
+
+fn tag_key(prefix: &str, tag: &str) -> String {
+    format!("{prefix}:index:tag:{tag}")
+}
+
+/// Entries shown in the public gallery, scored by their upload time.
+fn listed_key(prefix: &str) -> String {
+    format!("{prefix}:index:listed")
+}
+
+/// The IDs of the pastes containing a word.
+fn word_key(prefix: &str, word: &str) -> String {
+    format!("{prefix}:index:word:{word}")
+}
+
+/// The words indexed for a paste, to remove them again.
+fn paste_words_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}:index:words:{id}")
+}
+
+/// Split text into the lowercase words used by the full-text index.
+pub fn tokenize(text: &str) -> BTreeSet<String> {
+    let mut words = BTreeSet::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| (MIN_WORD_LENGTH..=MAX_WORD_LENGTH).contains(&word.chars().count()))
+    {
+        if words.len() >= MAX_WORDS {
+            break;
+        }
+        words.insert(word.to_lowercase());
+    }
+    words
+}
+
+/// Add the words of `content` to the full-text index of a paste.
+pub async fn add_paste_words(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+    content: &str,
+) {
+    let words = tokenize(content);
+    if words.is_empty() {
+        return;
+    }
+
+    let mut pipe = redis::pipe();
+    for word in &words {
+        pipe.cmd("SADD")
+            .arg(word_key(prefix, word))
+            .arg(id)
+            .ignore();
+    }
+    pipe.cmd("SADD")
+        .arg(paste_words_key(prefix, id))
+        .arg(words.into_iter().collect::<Vec<_>>())
+        .ignore();
+    if let Err(e) = pipe.exec_async(connection).await {
+        tracing::error!("Failed to index the content of {}: {}", id, e);
+    }
+}
+
+/// Replace the full-text index of a paste with the words of `content`.
+pub async fn index_paste_content(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+    content: &str,
+) {
+    remove_paste_words(connection, prefix, id).await;
+    add_paste_words(connection, prefix, id, content).await;
+}
+
+/// Remove a paste from the full-text index.
+async fn remove_paste_words(connection: &mut RedisConnection, prefix: &str, id: &str) {
+    let words = match redis::cmd("SMEMBERS")
+        .arg(paste_words_key(prefix, id))
+        .query_async::<Vec<String>>(connection)
+        .await
+    {
+        Ok(words) => words,
+        Err(e) => {
+            tracing::error!("Failed to get the indexed words of {}: {}", id, e);
+            return;
+        }
+    };
+    if words.is_empty() {
+        return;
+    }
+
+    let mut pipe = redis::pipe();
+    for word in &words {
+        pipe.cmd("SREM")
+            .arg(word_key(prefix, word))
+            .arg(id)
+            .ignore();
+    }
+    pipe.cmd("DEL").arg(paste_words_key(prefix, id)).ignore();
+    if let Err(e) = pipe.exec_async(connection).await {
+        tracing::error!(
+            "Failed to remove the content of {} from the index: {}",
+            id,
+            e
+        );
+    }
+}
+
+/// The IDs of the pastes containing every word of `query`.
+pub async fn search_paste_words(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    query: &str,
+) -> redis::RedisResult<Vec<String>> {
+    let words = tokenize(query);
+    if words.is_empty() {
+        return Ok(vec![]);
+    }
+
+    redis::cmd("SINTER")
+        .arg(
+            words
+                .iter()
+                .map(|word| word_key(prefix, word))
+                .collect::<Vec<_>>(),
+        )
+        .query_async::<Vec<String>>(connection)
+        .await
+}
+
+/// The mimetype an entry is indexed under, pastes only store their extension.
+fn indexed_mimetype(cdn_data: &CDNData) -> Option<String> {
+    match cdn_data {
+        CDNData::Short { .. } => None,
+        CDNData::File { mimetype, .. } => Some(mimetype.clone()),
+        CDNData::Code {
+            encrypted,
+            mimetype,
+            ..
+        } => Some(if *encrypted {
+            "text/plain".to_string()
+        } else {
+            mime_guess::from_ext(mimetype)
+                .first()
+                .map_or("text/plain".to_string(), |mime| {
+                    mime.essence_str().to_string()
+                })
+        }),
+    }
+}
+
+/// The sets an entry belongs to.
+fn set_keys(prefix: &str, cdn_data: &CDNData) -> Vec<String> {
+    let mut keys = vec![type_key(prefix, cdn_data.kind())];
+    keys.extend(
+        indexed_mimetype(cdn_data)
+            .as_deref()
+            .map(|mimetype| mimetype_key(prefix, mimetype)),
+    );
+    if let Some(metadata) = cdn_data.metadata() {
+        keys.extend(metadata.tags.iter().map(|tag| tag_key(prefix, tag)));
+    }
+    keys
+}
+
+/// Add an entry to the indexes.
+pub async fn index_entry(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+    cdn_data: &CDNData,
+) {
+    let mut pipe = redis::pipe();
+    pipe.cmd("ZADD")
+        .arg(added_key(prefix))
+        .arg(cdn_data.time_added().unwrap_or_default())
+        .arg(id)
+        .ignore();
+    for key in set_keys(prefix, cdn_data) {
+        pipe.cmd("SADD").arg(key).arg(id).ignore();
+    }
+    if cdn_data.is_listed() {
+        pipe.cmd("ZADD")
+            .arg(listed_key(prefix))
+            .arg(cdn_data.time_added().unwrap_or_default())
+            .arg(id)
+            .ignore();
+    }
+    if let Err(e) = pipe.exec_async(connection).await {
+        tracing::error!("Failed to index {}: {}", id, e);
+    }
+}
+
+/// Remove a deleted entry from the indexes.
+pub async fn unindex_entry(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+    cdn_data: &CDNData,
+) {
+    let mut pipe = redis::pipe();
+    pipe.cmd("ZREM").arg(added_key(prefix)).arg(id).ignore();
+    pipe.cmd("ZREM").arg(listed_key(prefix)).arg(id).ignore();
+    for key in set_keys(prefix, cdn_data) {
+        pipe.cmd("SREM").arg(key).arg(id).ignore();
+    }
+    if let Err(e) = pipe.exec_async(connection).await {
+        tracing::error!("Failed to remove {} from the indexes: {}", id, e);
+    }
+    if matches!(cdn_data, CDNData::Code { .. }) {
+        remove_paste_words(connection, prefix, id).await;
+    }
+}
+
+/// What to search for, all filters must match.
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    pub tag: Option<String>,
+    pub mimetype: Option<String>,
+    pub kind: Option<String>,
+    /// Only entries added after this UNIX timestamp.
+    pub after: Option<i64>,
+    /// Only entries added before this UNIX timestamp.
+    pub before: Option<i64>,
+}
+
+/// The IDs of the entries matching `filter` with their upload time, newest first.
+pub async fn search(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    filter: &SearchFilter,
+) -> redis::RedisResult<Vec<(String, i64)>> {
+    let min = filter
+        .after
+        .map_or("-inf".to_string(), |after| format!("({after}"));
+    let max = filter
+        .before
+        .map_or("+inf".to_string(), |before| format!("({before}"));
+
+    let mut sets = vec![];
+    sets.extend(filter.kind.as_deref().map(|kind| type_key(prefix, kind)));
+    sets.extend(
+        filter
+            .mimetype
+            .as_deref()
+            .map(|mimetype| mimetype_key(prefix, mimetype)),
+    );
+    sets.extend(filter.tag.as_deref().map(|tag| tag_key(prefix, tag)));
+
+    if sets.is_empty() {
+        return redis::cmd("ZREVRANGEBYSCORE")
+            .arg(added_key(prefix))
+            .arg(max)
+            .arg(min)
+            .arg("WITHSCORES")
+            .query_async::<Vec<(String, i64)>>(connection)
+            .await;
+    }
+
+    let ids = redis::cmd("SINTER")
+        .arg(&sets)
+        .query_async::<Vec<String>>(connection)
+        .await?;
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for id in &ids {
+        pipe.cmd("ZSCORE").arg(added_key(prefix)).arg(id);
+    }
+    let scores = pipe.query_async::<Vec<Option<i64>>>(connection).await?;
+
+    let mut results = ids
+        .into_iter()
+        .zip(scores)
+        .filter_map(|(id, score)| Some((id, score?)))
+        .filter(|(_, added)| {
+            filter.after.is_none_or(|after| *added > after)
+                && filter.before.is_none_or(|before| *added < before)
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|(_, a), (_, b)| b.cmp(a));
+    Ok(results)
+}
+
+/// The amount of entries of each type.
+pub async fn count_by_type(
+    connection: &mut RedisConnection,
+    prefix: &str,
+) -> redis::RedisResult<Vec<(&'static str, u64)>> {
+    const KINDS: [&str; 3] = ["file", "code", "short"];
+
+    let mut pipe = redis::pipe();
+    for kind in KINDS {
+        pipe.cmd("SCARD").arg(type_key(prefix, kind));
+    }
+    let counts = pipe.query_async::<Vec<u64>>(connection).await?;
+    Ok(KINDS.into_iter().zip(counts).collect())
+}
+
+/// A page of all entries, oldest first.
+pub async fn added_page(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    offset: usize,
+    count: usize,
+) -> redis::RedisResult<Vec<String>> {
+    redis::cmd("ZRANGE")
+        .arg(added_key(prefix))
+        .arg(offset)
+        .arg(offset + count.max(1) - 1)
+        .query_async::<Vec<String>>(connection)
+        .await
+}
+
+/// The IDs of the newest entries, newest first.
+pub async fn newest(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    count: usize,
+) -> redis::RedisResult<Vec<String>> {
+    redis::cmd("ZREVRANGE")
+        .arg(added_key(prefix))
+        .arg(0)
+        .arg(count.max(1) - 1)
+        .query_async::<Vec<String>>(connection)
+        .await
+}
+
+/// The IDs of the entries added in `[start, end)`, as UNIX timestamps.
+pub async fn added_between(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    start: i64,
+    end: i64,
+) -> redis::RedisResult<Vec<String>> {
+    redis::cmd("ZRANGEBYSCORE")
+        .arg(added_key(prefix))
+        .arg(start)
+        .arg(format!("({end}"))
+        .query_async::<Vec<String>>(connection)
+        .await
+}
+
+/// Remove IDs whose entry is gone from the upload time indexes.
+pub async fn remove_stale(connection: &mut RedisConnection, prefix: &str, ids: &[String]) {
+    if ids.is_empty() {
+        return;
+    }
+
+    let mut pipe = redis::pipe();
+    pipe.cmd("ZREM").arg(added_key(prefix)).arg(ids).ignore();
+    pipe.cmd("ZREM").arg(listed_key(prefix)).arg(ids).ignore();
+    if let Err(e) = pipe.exec_async(connection).await {
+        tracing::error!("Failed to remove stale entries from the indexes: {}", e);
+    }
+}
+
+/// A page of the entries listed in the public gallery, newest first, and the amount of them.
+pub async fn listed_page(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    offset: usize,
+    count: usize,
+) -> redis::RedisResult<(Vec<String>, usize)> {
+    let (ids, total) = redis::pipe()
+        .cmd("ZREVRANGE")
+        .arg(listed_key(prefix))
+        .arg(offset)
+        .arg(offset + count.max(1) - 1)
+        .cmd("ZCARD")
+        .arg(listed_key(prefix))
+        .query_async::<(Vec<String>, usize)>(connection)
+        .await?;
+    Ok((ids, total))
+}
+
+/// Drop the indexes and build them again from every stored entry.
+pub async fn rebuild_index(
+    config: &IhaCdnConfig,
+    redis: &redis::Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let prefix = config.key_prefix.as_str();
+    let mut connection = RedisConnection::from(redis.get_multiplexed_async_connection().await?);
+
+    tracing::info!("🗂️ Removing the old indexes...");
+    let mut cursor = 0u64;
+    loop {
+        let (next_cursor, keys) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{prefix}:index:*"))
+            .arg("COUNT")
+            .arg(SCAN_BATCH)
+            .query_async::<(u64, Vec<String>)>(&mut connection)
+            .await?;
+        if !keys.is_empty() {
+            redis::cmd("DEL")
+                .arg(keys)
+                .exec_async(&mut connection)
+                .await?;
+        }
+
+        cursor = next_cursor;
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    tracing::info!("🗂️ Indexing entries...");
+    let mut indexed = 0u64;
+    let mut cursor = 0u64;
+    loop {
+        let (next_cursor, keys) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{prefix}*"))
+            .arg("COUNT")
+            .arg(SCAN_BATCH)
+            .query_async::<(u64, Vec<String>)>(&mut connection)
+            .await?;
+
+        for key in keys {
+            let Some(id) = entry_id(prefix, &key) else {
+                continue;
+            };
+
+            let data = redis::cmd("GET")
+                .arg(&key)
+                .query_async::<Option<Vec<u8>>>(&mut connection)
+                .await?;
+            let Some(cdn_data) = data.and_then(|data| {
+                CDNData::decode(&data)
+                    .inspect_err(|e| tracing::warn!("Skipping unparseable entry {}: {}", id, e))
+                    .ok()
+            }) else {
+                continue;
+            };
+
+            index_entry(&mut connection, prefix, id, &cdn_data).await;
+            if config.search.enable
+                && let CDNData::Code {
+                    path,
+                    encrypted: false,
+                    ..
+                } = &cdn_data
+            {
+                match read_indexed_content(config, path).await {
+                    Ok(content) => add_paste_words(&mut connection, prefix, id, &content).await,
+                    Err(e) => tracing::warn!("Skipping the content of {}: {}", id, e),
+                }
+            }
+            indexed += 1;
+        }
+
+        cursor = next_cursor;
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    redis::cmd("SET")
+        .arg(built_key(prefix))
+        .arg(chrono::Utc::now().timestamp())
+        .exec_async(&mut connection)
+        .await?;
+    tracing::info!("🗂️ Indexed {} entries", indexed);
+    Ok(())
+}
+
+/// Build the indexes if they were never built, e.g. after upgrading from a version without them.
+pub async fn ensure_index(
+    config: &IhaCdnConfig,
+    redis: &redis::Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connection = RedisConnection::from(redis.get_multiplexed_async_connection().await?);
+    let built = redis::cmd("EXISTS")
+        .arg(built_key(&config.key_prefix))
+        .query_async::<bool>(&mut connection)
+        .await?;
+    if built {
+        return Ok(());
+    }
+
+    tracing::info!("🗂️ The indexes were never built, building them now...");
+    rebuild_index(config, redis).await
+}
+
+/// Read the part of a paste that is indexed, as configured by `search.max_size`.
+pub async fn read_indexed_content(
+    config: &IhaCdnConfig,
+    path: &std::path::Path,
+) -> std::io::Result<String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut content = vec![];
+    tokio::fs::File::open(config.resolve_path(path).await)
+        .await?
+        .take(config.search.max_size)
+        .read_to_end(&mut content)
+        .await?;
+    Ok(String::from_utf8_lossy(&content).into_owned())
+}
@@ -4,30 +4,82 @@ use axum::{
     Router,
     extract::{DefaultBodyLimit, State},
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
 };
-use state::{SharedState, humanize_bytes};
+use rate_limit::RateLimitRoute;
+use state::{SharedState, humanize_bytes, retention_table};
 use tokio::net::TcpListener;
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tower_http::{
+    catch_panic::CatchPanicLayer, compression::CompressionLayer, cors::CorsLayer,
+    limit::RequestBodyLimitLayer, trace::TraceLayer,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod abuse;
+mod access_log;
+mod admin_keys;
+mod antivirus;
+mod assets;
+mod audit;
+mod breaker;
+mod build_info;
 mod config;
+mod content_host;
+mod deadline;
+mod encoding;
+mod error;
+mod events;
+mod filters;
+mod geoip;
+mod hooks;
+mod idempotency;
+mod index;
+mod media;
+mod migrate;
+mod moderation;
+mod network;
 // mod middleware;
 mod notifier;
+mod nsfw;
+mod policy;
+mod preflight;
 mod purge;
+mod rate_limit;
 mod routes;
+mod sanitize;
+mod schedule;
+mod schema;
+mod signed_upload;
 mod state;
+mod stats;
+mod store;
+mod tasks;
 mod templating;
+mod tenant;
+mod thumbnail;
+mod tombstone;
 mod track;
-
-const ASSET_FAVICON_ICO: &[u8] = include_bytes!("../assets/favicon.ico");
-const ASSET_FAVICON_PNG: &[u8] = include_bytes!("../assets/favicon.png");
+#[cfg(unix)]
+mod upgrade;
+mod warmup;
+mod words;
 
 #[tokio::main]
 async fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+    let config_flag = take_config_flag(&mut args);
+    if args.iter().map(String::as_str).eq(["config", "schema"]) {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config::IhaCdnConfig::schema()).unwrap()
+        );
+        return;
+    }
+
     // load the configuration file
-    let config = config::IhaCdnConfig::load();
+    let config_path = config::IhaCdnConfig::locate(config_flag);
+    let loaded = config::IhaCdnConfig::load(&config_path);
 
     let merged_env_trace = "ihacdn=debug,tower_http=debug,axum::rejection=trace";
 
@@ -37,23 +89,51 @@ async fn main() {
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .map(|filter| {
                     let split_filter = merged_env_trace.split(',').collect::<Vec<&str>>();
-                    let directives = split_filter
+                    split_filter
                         .iter()
-                        .fold(filter, |acc, &x| acc.add_directive(x.parse().unwrap()));
-                    directives
+                        .fold(filter, |acc, &x| acc.add_directive(x.parse().unwrap()))
                 })
                 .unwrap_or_else(|_| merged_env_trace.parse().unwrap()),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let version = env!("CARGO_PKG_VERSION");
-    tracing::info!("💭 Starting ihaCDN v{}", version);
+    tracing::info!(
+        "💭 Starting ihaCDN v{} ({})",
+        build_info::VERSION,
+        build_info::GIT_SHA
+    );
 
+    let (config, unknown_keys) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            tracing::error!("🔌💥 {}", e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("🔌 Loaded the configuration from {}", config_path.display());
+    for key in &unknown_keys {
+        tracing::warn!("Unknown config key {}, is it misspelled?", key);
+    }
+    if config.strict && !unknown_keys.is_empty() {
+        tracing::error!("🔌💥 Configuration file has unknown keys, fix them or disable `strict`");
+        std::process::exit(1);
+    }
     if !config.verify() {
         tracing::error!("🔌💥 Configuration file is invalid");
         std::process::exit(1);
     }
+    let tenants = match config.tenant_configs() {
+        Ok(tenants) => tenants,
+        Err(e) => {
+            tracing::error!("🔌💥 {}", e);
+            std::process::exit(1);
+        }
+    };
+    if !tenants.iter().all(|tenant| tenant.verify()) || !config.verify_tenants(&tenants) {
+        tracing::error!("🔌💥 Tenant configuration is invalid");
+        std::process::exit(1);
+    }
 
     tracing::info!("🔌 Loading services...");
     tracing::info!("🔌📒 Loading Redis database...");
@@ -67,72 +147,201 @@ async fn main() {
             std::process::exit(1);
         }
     };
-
-    let state = state::SharedState {
-        config: Arc::new(config.clone()),
-        redis: redis_handle,
+    let replicas = match state::RedisReplicas::open(&config.redis_replicas) {
+        Ok(replicas) => Arc::new(replicas),
+        Err(e) => {
+            tracing::error!("🔌💥 Invalid Redis replica: {}", e);
+            std::process::exit(1);
+        }
     };
-    let shared_state = Arc::new(state);
 
-    tracing::info!("🚀 Starting server...");
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/{id_path}", get(routes::reader::file_reader))
-        .route("/{id_path}/raw", get(routes::reader::file_reader_raw))
-        .route("/_/health", get(|| async { "OK" }))
-        .route(
-            "/upload",
-            // Disable limiting the body size
-            post(routes::uploads::uploads_file).layer(DefaultBodyLimit::disable()),
-        )
-        .route("/short", post(routes::uploads::shorten_url))
-        .route("/favicon.ico", get(index_favicons_ico))
-        .route("/static/img/favicon.ico", get(index_favicons_ico))
-        .route("/static/img/favicon.png", get(index_favicons_png))
-        .layer(TraceLayer::new_for_http())
-        .layer(
-            CorsLayer::new()
-                .allow_origin(tower_http::cors::Any)
-                .allow_methods(vec![
-                    // GET/POST for GraphQL stuff
-                    axum::http::Method::GET,
-                    axum::http::Method::POST,
-                    // HEAD for additional metadata
-                    axum::http::Method::HEAD,
-                    // OPTIONS for CORS preflight
-                    axum::http::Method::OPTIONS,
-                    // CONNECT for other stuff
-                    axum::http::Method::CONNECT,
-                ])
-                .allow_headers(tower_http::cors::Any),
-        )
-        .with_state(Arc::clone(&shared_state));
+    // Maintenance commands run for the main config and every tenant
+    let configs = std::iter::once(&config).chain(&tenants);
+    if args.first().map(String::as_str) == Some("migrate-storage") {
+        let options = match migrate::MigrateOptions::parse(&args[1..]) {
+            Ok(options) => options,
+            Err(e) => {
+                tracing::error!("📦💥 {}", e);
+                tracing::error!(
+                    "📦 Usage: ihacdn migrate-storage --from <volume|local> --to <volume|local> [--delete-source]"
+                );
+                std::process::exit(1);
+            }
+        };
 
-    tracing::info!("🌐 Creating HTTP listener...");
-    let listener = TcpListener::bind(format!("{}:{}", config.host.clone(), config.port))
-        .await
-        .unwrap();
+        for config in configs {
+            if let Err(e) = migrate::migrate_storage(config, &redis_handle, &options).await {
+                tracing::error!(
+                    "📦💥 Storage migration of {} failed: {}",
+                    config.hostname,
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("reindex") {
+        for config in configs {
+            if let Err(e) = index::rebuild_index(config, &redis_handle).await {
+                tracing::error!(
+                    "🗂️💥 Rebuilding the indexes of {} failed: {}",
+                    config.hostname,
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("migrate-entries") {
+        for config in configs {
+            if let Err(e) = schema::migrate_entries(config, &redis_handle).await {
+                tracing::error!(
+                    "🧬💥 Upgrading the entries of {} failed: {}",
+                    config.hostname,
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("reencode") {
+        for config in configs {
+            if let Err(e) = encoding::reencode_entries(config, &redis_handle).await {
+                tracing::error!(
+                    "🗜️💥 Re-encoding the entries of {} failed: {}",
+                    config.hostname,
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut preflight_passed = true;
+    for config in std::iter::once(&config).chain(&tenants) {
+        preflight_passed &= preflight::run(config, &redis_handle).await;
+    }
+    if !preflight_passed {
+        tracing::error!("🩺💥 Preflight checks failed, fix the errors above and try again");
+        std::process::exit(1);
+    }
 
-    // Start tasks
     tracing::info!("⚡ Preparing task scheduler...");
     let mut scheduler = JobScheduler::new().await.unwrap();
-    let cloned_state = Arc::clone(&shared_state);
-    let job_purge = Job::new_cron_job_async("0 0 0 * * *", move |_uuid, _lock| {
-        Box::pin({
-            let state_val = cloned_state.clone();
-            async move {
-                match purge::purge_task(state_val).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        tracing::error!("Purge task failed: {}", e);
+
+    let mut states = vec![start_tenant(&config, &redis_handle, &replicas, &scheduler).await];
+    for tenant in &tenants {
+        tracing::info!("🏘️ Loading tenant {}...", tenant.hostname);
+        states.push(start_tenant(tenant, &redis_handle, &replicas, &scheduler).await);
+    }
+    let app = if tenants.is_empty() {
+        make_router(&states[0])
+    } else {
+        let mut routers = tenant::Tenants::new(make_router(&states[0]));
+        for state in &states[1..] {
+            routers.add(&state.config, make_router(state));
+        }
+        Router::new()
+            .fallback(tenant::dispatch)
+            .with_state(Arc::new(routers))
+    };
+
+    tracing::info!("🌐 Creating HTTP listener...");
+    #[cfg(unix)]
+    let inherited = upgrade::inherited_listener();
+    #[cfg(not(unix))]
+    let inherited: Option<std::net::TcpListener> = None;
+    let listener = match inherited {
+        Some(listener) => {
+            tracing::info!("🔁 Taking over the listener of the previous process");
+            TcpListener::from_std(listener).unwrap()
+        }
+        None => TcpListener::bind(format!("{}:{}", config.host.clone(), config.port))
+            .await
+            .unwrap(),
+    };
+
+    // Start tasks
+    let mut job_uuids = vec![];
+    for shared_state in &states {
+        let config = &shared_state.config;
+        let cloned_state = Arc::clone(shared_state);
+        let job_purge = match Job::new_cron_job_async(
+            config.retention.schedule.as_str(),
+            move |_uuid, _lock| {
+                Box::pin({
+                    let state_val = cloned_state.clone();
+                    async move {
+                        let _ = tasks::run_recorded(
+                            &state_val,
+                            "purge",
+                            purge::purge_task(state_val.clone()),
+                        )
+                        .await;
                     }
-                }
+                })
+            },
+        ) {
+            Ok(job) => job,
+            Err(e) => {
+                tracing::error!("⚡💥 Invalid purge schedule: {}", e);
+                std::process::exit(1);
             }
-        })
-    })
-    .unwrap();
+        };
+
+        job_uuids.push(scheduler.add(job_purge).await.unwrap());
 
-    let job_purge_uuid = scheduler.add(job_purge).await.unwrap();
+        if config.stats.enable {
+            let cloned_state = Arc::clone(shared_state);
+            let job_stats = match Job::new_cron_job_async(
+                config.stats.schedule.as_str(),
+                move |_uuid, _lock| {
+                    Box::pin({
+                        let state_val = cloned_state.clone();
+                        async move {
+                            let _ = tasks::run_recorded(
+                                &state_val,
+                                "stats",
+                                stats::stats_task(state_val.clone()),
+                            )
+                            .await;
+                        }
+                    })
+                },
+            ) {
+                Ok(job) => job,
+                Err(e) => {
+                    tracing::error!("⚡💥 Invalid stats rollup schedule: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            job_uuids.push(scheduler.add(job_stats).await.unwrap());
+        }
+        if config.network_blocklist.block_tor {
+            let cloned_state = Arc::clone(shared_state);
+            let job_tor = match Job::new_cron_job_async(
+                config.network_blocklist.tor_refresh_schedule.as_str(),
+                move |_uuid, _lock| Box::pin(refresh_tor_exits(cloned_state.clone())),
+            ) {
+                Ok(job) => job,
+                Err(e) => {
+                    tracing::error!("⚡💥 Invalid Tor exit list refresh schedule: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            job_uuids.push(scheduler.add(job_tor).await.unwrap());
+            tokio::spawn(refresh_tor_exits(Arc::clone(shared_state)));
+        }
+        match schedule::restore_scheduled_deletions(shared_state).await {
+            Ok(0) => (),
+            Ok(count) => tracing::info!("⚡ Restored {} scheduled deletions", count),
+            Err(e) => tracing::error!("⚡💥 Failed to restore scheduled deletions: {}", e),
+        }
+    }
     tracing::info!("⚡ Starting task scheduler...");
     scheduler.start().await.unwrap();
 
@@ -140,6 +349,14 @@ async fn main() {
     let local_addr = listener.local_addr().unwrap();
     tracing::info!("🌍 Fast serving at http://{}", local_addr);
 
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsRawFd;
+
+        tokio::spawn(upgrade_signal(listener.as_raw_fd()));
+        upgrade::notify_parent();
+    }
+
     axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_signal())
         .await
@@ -147,24 +364,376 @@ async fn main() {
 
     // Stop tasks
     tracing::info!("🔕 Shutting down task scheduler...");
-    scheduler.remove(&job_purge_uuid).await.unwrap();
+    for job_uuid in job_uuids {
+        scheduler.remove(&job_uuid).await.unwrap();
+    }
     scheduler.shutdown().await.unwrap();
     tracing::info!("🔕 Shutting down server...");
 }
 
+/// Load the services of an instance and start its background work.
+async fn start_tenant(
+    config: &config::IhaCdnConfig,
+    redis_handle: &Arc<redis::Client>,
+    replicas: &Arc<state::RedisReplicas>,
+    scheduler: &JobScheduler,
+) -> Arc<SharedState> {
+    tracing::info!("🔌📖 Loading reserved words list...");
+    let words = match words::WordFilter::load(&config.blocklist) {
+        Ok(words) => Arc::new(words),
+        Err(e) => {
+            tracing::error!("🔌💥 Failed to load words file: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("🔌🛡️ Loading moderation rules...");
+    let moderation = match moderation::ModerationEngine::load(&config.moderation) {
+        Ok(moderation) => Arc::new(moderation),
+        Err(e) => {
+            tracing::error!("🔌💥 Failed to load moderation rules: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("🔌🧩 Loading upload filters...");
+    let filters = match filters::UploadFilters::load(&config.filters) {
+        Ok(filters) => Arc::new(filters),
+        Err(e) => {
+            tracing::error!("🔌💥 Failed to load upload filters: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("🔌📜 Loading policy script...");
+    let policy = match policy::PolicyScript::load(&config.scripting) {
+        Ok(policy) => Arc::new(policy),
+        Err(e) => {
+            tracing::error!("🔌💥 Failed to load the policy script: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("🔌🧱 Loading network blocklist...");
+    let network = match network::NetworkFilter::load(&config.network_blocklist) {
+        Ok(network) => Arc::new(network),
+        Err(e) => {
+            tracing::error!("🔌💥 Failed to load the ASN database: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let access_log = match access_log::AccessLog::open(&config.access_log) {
+        Ok(access_log) => Arc::new(access_log),
+        Err(e) => {
+            tracing::error!("🔌💥 Failed to open the access log: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let transcoder = media::TranscodeQueue::new();
+    let (events, event_receiver) = events::EventQueue::new();
+    let state = state::SharedState {
+        config: Arc::new(config.clone()),
+        redis: Arc::clone(redis_handle),
+        replicas: Arc::clone(replicas),
+        breaker: Arc::new(breaker::RedisBreaker::new(&config.redis_fallback)),
+        words,
+        moderation,
+        filters,
+        policy,
+        network,
+        transcoder: Arc::new(transcoder),
+        events: Arc::new(events),
+        scheduler: scheduler.clone(),
+        access_log,
+        started_at: std::time::Instant::now(),
+    };
+    let shared_state = Arc::new(state);
+
+    {
+        let config = config.clone();
+        let redis = Arc::clone(&shared_state.redis);
+        tokio::spawn(async move {
+            if let Err(e) = index::ensure_index(&config, &redis).await {
+                tracing::error!("🗂️💥 Building the indexes failed: {}", e);
+            }
+        });
+    }
+
+    if config.warmup.enable {
+        tokio::spawn(warmup::preload(Arc::clone(&shared_state)));
+    }
+
+    if config.events.enable {
+        tokio::spawn(events::run_publisher(
+            Arc::clone(&shared_state),
+            event_receiver,
+        ));
+    }
+
+    if config.media.is_transcoding() {
+        tracing::info!("🎞️ Starting transcode queue...");
+        tokio::spawn(media::run_transcoder(Arc::clone(&shared_state)));
+    }
+
+    shared_state
+}
+
+fn make_router(shared_state: &Arc<SharedState>) -> Router {
+    let config = &shared_state.config;
+    // The per-uploader limits are checked while reading, this only cuts off grossly oversized bodies
+    let body_limit = RequestBodyLimitLayer::new(config.body_limit().unwrap_or(usize::MAX));
+    let abuse_contact = axum::middleware::from_fn_with_state(
+        Arc::clone(shared_state),
+        routes::reader::abuse_contact,
+    );
+    let rate_limit = |route| {
+        axum::middleware::from_fn_with_state((Arc::clone(shared_state), route), rate_limit::enforce)
+    };
+    Router::new()
+        .route("/", get(index))
+        .route(
+            "/{id_path}",
+            get(routes::reader::file_reader)
+                .route_layer(abuse_contact.clone())
+                .route_layer(rate_limit(RateLimitRoute::Read))
+                .merge(
+                    patch(routes::uploads::edit_paste)
+                        .route_layer(rate_limit(RateLimitRoute::Edit)),
+                )
+                .delete(routes::uploads::delete_entry)
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(body_limit),
+        )
+        .route(
+            "/{id_path}/raw",
+            get(routes::reader::file_reader_raw)
+                .route_layer(abuse_contact.clone())
+                .route_layer(rate_limit(RateLimitRoute::Read)),
+        )
+        .route(
+            "/{id_path}/download",
+            get(routes::reader::file_reader_download)
+                .route_layer(abuse_contact.clone())
+                .route_layer(rate_limit(RateLimitRoute::Read)),
+        )
+        .route(
+            "/{id_path}/poster",
+            get(routes::reader::file_reader_poster)
+                .route_layer(abuse_contact.clone())
+                .route_layer(rate_limit(RateLimitRoute::Read)),
+        )
+        .route(
+            "/{id_path}/thumb",
+            get(routes::reader::file_reader_thumb)
+                .route_layer(abuse_contact.clone())
+                .route_layer(rate_limit(RateLimitRoute::Read)),
+        )
+        .route("/{id_path}/info", get(routes::reader::file_info))
+        .route(
+            "/{id_path}/append",
+            post(routes::uploads::append_paste)
+                .route_layer(rate_limit(RateLimitRoute::Edit))
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(body_limit),
+        )
+        .route(
+            "/{id_path}/delete/{token}",
+            get(routes::uploads::delete_entry_link),
+        )
+        .route(
+            "/{id_path}/revisions/{index}",
+            get(routes::reader::file_reader_revision)
+                .route_layer(abuse_contact.clone())
+                .route_layer(rate_limit(RateLimitRoute::Read)),
+        )
+        .route("/explore", get(routes::gallery::explore))
+        .route("/stats", get(routes::status::stats_page))
+        .route("/_/health", get(|| async { "OK" }))
+        .route("/_/version", get(routes::status::version))
+        .route("/_/status", get(routes::status::status))
+        .route("/_/metrics", get(routes::status::metrics))
+        .route(
+            "/upload",
+            post(routes::uploads::uploads_file)
+                .route_layer(rate_limit(RateLimitRoute::Upload))
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(body_limit),
+        )
+        .route(
+            "/short",
+            post(routes::uploads::shorten_url).route_layer(rate_limit(RateLimitRoute::Short)),
+        )
+        .route("/api/sign-upload", post(routes::admin::sign_upload))
+        .route(
+            "/api/admin/entries/{id}/headers",
+            put(routes::admin::set_entry_headers),
+        )
+        .route(
+            "/api/admin/entries/{id}/quarantine",
+            put(routes::admin::set_entry_quarantine),
+        )
+        .route(
+            "/api/admin/entries/{id}/legal-hold",
+            put(routes::admin::set_entry_legal_hold),
+        )
+        .route(
+            "/api/admin/entries/{id}/acl",
+            put(routes::admin::set_entry_acl),
+        )
+        .route(
+            "/api/admin/entries/{id}/availability",
+            put(routes::admin::set_entry_availability),
+        )
+        .route(
+            "/api/admin/schedule-delete/{id}",
+            post(routes::admin::schedule_entry_deletion)
+                .delete(routes::admin::cancel_entry_deletion),
+        )
+        .route("/api/admin/search", get(routes::admin::search_entries))
+        .route("/api/admin/audit", get(routes::admin::audit_log))
+        .route("/api/admin/export", get(routes::admin::export_entries))
+        .route("/api/admin/purge", post(routes::admin::run_purge))
+        .route("/api/admin/erase", post(routes::admin::erase_uploader))
+        .route("/api/admin/keys", get(routes::admin::list_admin_keys))
+        .route("/api/admin/bans", get(routes::admin::list_bans))
+        .route("/api/admin/bans/{ip}", delete(routes::admin::lift_ban))
+        .route(
+            "/api/admin/keys/{name}/rotate",
+            post(routes::admin::rotate_admin_key).delete(routes::admin::revoke_admin_key),
+        )
+        .route(
+            "/api/admin/keys/{name}/promote",
+            post(routes::admin::promote_admin_key),
+        )
+        .route("/api/search", get(routes::search::search_pastes))
+        .route("/favicon.ico", get(assets::serve_favicon))
+        .route(
+            "/static/{*path}",
+            get(assets::serve_static).layer(CompressionLayer::new()),
+        )
+        .fallback(|uri: axum::http::Uri| async move {
+            error::IhaCdnError::not_found(uri.path().trim_start_matches('/'))
+        })
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::clone(shared_state),
+            content_host::enforce,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::clone(shared_state),
+            abuse::enforce,
+        ))
+        .layer(CatchPanicLayer::custom({
+            let config = Arc::clone(config);
+            move |panic| error::panic_response(&config, panic)
+        }))
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::clone(shared_state),
+            error::negotiate_errors,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::clone(shared_state),
+            access_log::record,
+        ))
+        .layer(TraceLayer::new_for_http())
+        .layer(make_cors_layer(&config.cors))
+        .with_state(Arc::clone(shared_state))
+}
+
+/// Remove `--config <path>` or `--config=<path>` from the arguments, returning the path.
+fn take_config_flag(args: &mut Vec<String>) -> Option<String> {
+    let index = args
+        .iter()
+        .position(|arg| arg == "--config" || arg.starts_with("--config="))?;
+    let flag = args.remove(index);
+    match flag.strip_prefix("--config=") {
+        Some(path) => Some(path.to_string()),
+        None if index < args.len() => Some(args.remove(index)),
+        None => None,
+    }
+}
+
+async fn refresh_tor_exits(state: Arc<SharedState>) {
+    let config = &state.config.network_blocklist;
+    match state
+        .network
+        .refresh_tor_exits(&config.tor_exit_list_url, &state.config.user_agent())
+        .await
+    {
+        Ok(count) => tracing::info!("🧅 Loaded {} Tor exit nodes", count),
+        Err(e) => tracing::error!("🧅💥 Failed to refresh the Tor exit list: {}", e),
+    }
+}
+
+fn make_cors_layer(cors: &config::IhaCdnCorsConfig) -> CorsLayer {
+    // The values are already checked in `IhaCdnConfig::verify`
+    let methods: Vec<axum::http::Method> = cors
+        .allowed_methods
+        .iter()
+        .filter_map(|method| axum::http::Method::from_bytes(method.as_bytes()).ok())
+        .collect();
+
+    let mut layer = CorsLayer::new()
+        .allow_methods(methods)
+        .allow_credentials(cors.allow_credentials);
+
+    layer = if cors.is_any_origin() {
+        layer.allow_origin(tower_http::cors::Any)
+    } else {
+        let origins: Vec<axum::http::HeaderValue> = cors
+            .allowed_origins
+            .iter()
+            .filter_map(|origin| origin.parse().ok())
+            .collect();
+        layer.allow_origin(origins)
+    };
+
+    layer = if cors.is_any_header() {
+        layer.allow_headers(tower_http::cors::Any)
+    } else {
+        let headers: Vec<axum::http::HeaderName> = cors
+            .allowed_headers
+            .iter()
+            .filter_map(|header| header.parse().ok())
+            .collect();
+        layer.allow_headers(headers)
+    };
+
+    if let Some(max_age) = cors.max_age {
+        layer = layer.max_age(std::time::Duration::from_secs(max_age));
+    }
+
+    layer
+}
+
 async fn index(State(state): State<Arc<SharedState>>) -> impl IntoResponse {
     let retention = if state.config.retention.enable {
         Some(templating::TemplateIndexRetention {
             min_age: state.config.retention.min_age.to_string(),
             max_age: state.config.retention.max_age.to_string(),
+            table: retention_table(&state.config),
         })
     } else {
         None
     };
 
+    let base_url = state.config.make_url("").trim_end_matches('/').to_string();
+    let sharex_config = serde_json::json!({
+        "Version": "15.0.0",
+        "Name": state.config.instance.name,
+        "DestinationType": "ImageUploader, TextUploader, FileUploader",
+        "RequestMethod": "POST",
+        "RequestURL": format!("{base_url}/upload"),
+        "Body": "MultipartFormData",
+        "FileFormName": "file",
+        "URL": "{response}",
+        "DeletionURL": "{response}/delete/{header:x-delete-token}",
+    });
+
     let template = templating::TemplateIndex {
-        https_mode: state.config.https_mode,
-        hostname: state.config.hostname.clone(),
+        sharex_config: serde_json::to_string_pretty(&sharex_config).unwrap(),
+        base_url,
         filesize_limit: state
             .config
             .storage
@@ -173,39 +742,34 @@ async fn index(State(state): State<Arc<SharedState>>) -> impl IntoResponse {
         blacklist_extensions: state.config.blocklist.extensions.clone(),
         blacklist_ctypes: state.config.blocklist.content_types.clone(),
         file_retention: retention,
+        instance_name: state.config.instance.name.clone(),
+        legal: templating::TemplateLegal::new(&state.config.instance),
+        version: build_info::VERSION,
+        git_sha: build_info::GIT_SHA,
     };
 
     templating::HtmlTemplate::new(template)
 }
 
-async fn index_favicons_ico() -> impl IntoResponse {
-    let etag = format!("ihacdn-favicons-ico-{}", env!("CARGO_PKG_VERSION"));
-
-    axum::http::Response::builder()
-        .status(axum::http::StatusCode::OK)
-        .header(axum::http::header::CONTENT_TYPE, "image/x-icon")
-        .header(
-            axum::http::header::CACHE_CONTROL,
-            "public, max-age=604800, immutable",
-        )
-        .header(axum::http::header::ETAG, etag)
-        .body(axum::body::Body::from(ASSET_FAVICON_ICO))
-        .unwrap()
-}
-
-async fn index_favicons_png() -> impl IntoResponse {
-    let etag = format!("ihacdn-favicons-png-{}", env!("CARGO_PKG_VERSION"));
-
-    axum::http::Response::builder()
-        .status(axum::http::StatusCode::OK)
-        .header(axum::http::header::CONTENT_TYPE, "image/png")
-        .header(
-            axum::http::header::CACHE_CONTROL,
-            "public, max-age=604800, immutable",
-        )
-        .header(axum::http::header::ETAG, etag)
-        .body(axum::body::Body::from(ASSET_FAVICON_PNG))
-        .unwrap()
+/// Start a new process on the same listener for every `SIGUSR2`, it stops us once it's ready.
+#[cfg(unix)]
+async fn upgrade_signal(fd: std::os::fd::RawFd) {
+    let mut signal =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                tracing::error!("🔁💥 Failed to install SIGUSR2 handler: {}", e);
+                return;
+            }
+        };
+    while signal.recv().await.is_some() {
+        tracing::info!("🔁 Received SIGUSR2, upgrading...");
+        // The listener is served until the process exits
+        let listener = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+        if let Err(e) = upgrade::spawn_upgrade(&listener) {
+            tracing::error!("🔁💥 Failed to start the new process: {}", e);
+        }
+    }
 }
 
 async fn shutdown_signal() {
@@ -0,0 +1,520 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::{Notify, Semaphore},
+};
+
+use crate::{
+    config::IhaCdnMediaConfig,
+    state::{CDNData, SharedState, TranscodeState, TranscodeStatus, entry_key},
+    store::{RedisConnection, replace_unchanged},
+};
+
+/// How often the queue is read again without being woken up, to retry after Redis errors and
+/// pick up entries queued by another process, in seconds.
+const TRANSCODE_RESCAN_INTERVAL: u64 = 60;
+
+/// The entries waiting for or being given a browser-playable copy, or a video copy of their GIF.
+///
+/// The queue is kept in Redis so entries queued before a restart are transcoded after it.
+pub fn transcode_queue_key(prefix: &str) -> String {
+    format!("{prefix}:transcode-queue")
+}
+
+/// Wakes the transcoder up when an entry is queued.
+pub struct TranscodeQueue {
+    queued: Notify,
+}
+
+impl TranscodeQueue {
+    pub fn new() -> Self {
+        Self {
+            queued: Notify::new(),
+        }
+    }
+
+    /// Queue an entry, the entry must already be saved with a queued [`TranscodeState`].
+    pub async fn enqueue(&self, connection: &mut RedisConnection, prefix: &str, id: &str) {
+        let queued = redis::cmd("SADD")
+            .arg(transcode_queue_key(prefix))
+            .arg(id)
+            .exec_async(connection)
+            .await;
+        match queued {
+            Ok(()) => self.queued.notify_one(),
+            Err(e) => tracing::error!("Failed to queue transcode job of {}: {}", id, e),
+        }
+    }
+}
+
+/// How many top-level boxes of an MP4 are read looking for its index.
+const MAX_MP4_BOXES: usize = 64;
+
+/// Whether an MP4 has its index (the `moov` box) after the media data.
+///
+/// Players need the index to start, so those files only play once fully downloaded.
+pub async fn needs_faststart(path: &Path) -> bool {
+    let Ok(mut file) = tokio::fs::File::open(path).await else {
+        return false;
+    };
+    let Ok(size) = file.metadata().await.map(|metadata| metadata.len()) else {
+        return false;
+    };
+
+    let mut offset = 0u64;
+    for _ in 0..MAX_MP4_BOXES {
+        let mut header = [0u8; 16];
+        if offset + 8 > size
+            || file.seek(std::io::SeekFrom::Start(offset)).await.is_err()
+            || file.read_exact(&mut header[..8]).await.is_err()
+        {
+            return false;
+        }
+        let box_type = &header[4..8];
+        match box_type {
+            b"moov" => return false,
+            b"mdat" => return true,
+            _ => (),
+        }
+        let box_size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The box goes to the end of the file
+            0 => return false,
+            1 => {
+                if file.read_exact(&mut header[8..16]).await.is_err() {
+                    return false;
+                }
+                u64::from_be_bytes(header[8..16].try_into().unwrap())
+            }
+            box_size => box_size as u64,
+        };
+        if box_size < 8 {
+            return false;
+        }
+        offset += box_size;
+    }
+    false
+}
+
+/// The path of the poster frame stored alongside a video file.
+pub fn poster_path(path: &Path) -> PathBuf {
+    path.with_extension("poster.jpg")
+}
+
+/// Extract a poster frame of an uploaded video with ffmpeg.
+///
+/// The frame is taken at `poster_seek` seconds, falling back to the first frame for videos
+/// shorter than that.
+///
+/// Returns [`None`] if the extraction is disabled, the file is not a video or ffmpeg failed.
+pub async fn extract_poster(
+    config: &IhaCdnMediaConfig,
+    mimetype: &str,
+    path: &Path,
+) -> Option<PathBuf> {
+    if !config.poster || !mimetype.starts_with("video/") {
+        return None;
+    }
+
+    let output = poster_path(path);
+    let mut seeks = vec![config.poster_seek];
+    if config.poster_seek > 0.0 {
+        seeks.push(0.0);
+    }
+
+    for seek in seeks {
+        if run_ffmpeg(config, poster_args(path, &output, seek), config.timeout).await
+            && tokio::fs::try_exists(&output).await.unwrap_or(false)
+        {
+            return Some(output);
+        }
+    }
+
+    tracing::warn!("Failed to extract poster frame for {}", path.display());
+    None
+}
+
+/// Process the transcode queue, starting with the entries left over by the previous run.
+pub async fn run_transcoder(state: Arc<SharedState>) {
+    let semaphore = Arc::new(Semaphore::new(state.config.media.transcode_concurrency));
+    let running = Arc::new(Mutex::new(HashSet::new()));
+    loop {
+        for id in queued_entries(&state).await {
+            if !running.lock().unwrap().insert(id.clone()) {
+                continue;
+            }
+            let permit = match Arc::clone(&semaphore).acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+            let state = Arc::clone(&state);
+            let running = Arc::clone(&running);
+            tokio::spawn(async move {
+                transcode_entry(&state, &id).await;
+                dequeue(&state, &id).await;
+                running.lock().unwrap().remove(&id);
+                drop(permit);
+            });
+        }
+
+        let _ = tokio::time::timeout(
+            Duration::from_secs(TRANSCODE_RESCAN_INTERVAL),
+            state.transcoder.queued.notified(),
+        )
+        .await;
+    }
+}
+
+async fn queued_entries(state: &SharedState) -> Vec<String> {
+    let mut connection = match state.make_connection().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::error!("Failed to connect to Redis: {}", e);
+            return vec![];
+        }
+    };
+    redis::cmd("SMEMBERS")
+        .arg(transcode_queue_key(&state.config.key_prefix))
+        .query_async::<Vec<String>>(&mut connection)
+        .await
+        .inspect_err(|e| tracing::error!("Failed to read the transcode queue: {}", e))
+        .unwrap_or_default()
+}
+
+async fn dequeue(state: &SharedState, id: &str) {
+    let removed = match state.make_connection().await {
+        Ok(mut connection) => {
+            redis::cmd("SREM")
+                .arg(transcode_queue_key(&state.config.key_prefix))
+                .arg(id)
+                .exec_async(&mut connection)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = removed {
+        tracing::error!("Failed to remove {} from the transcode queue: {}", id, e);
+    }
+}
+
+async fn transcode_entry(state: &SharedState, id: &str) {
+    let (path, mimetype) = match load_entry(state, id).await {
+        Some((_, CDNData::File { path, mimetype, .. })) => (path, mimetype),
+        _ => {
+            tracing::warn!("Skipping transcode of {}, entry is gone", id);
+            return;
+        }
+    };
+
+    // Entries still processing were interrupted by a restart
+    let started = update_transcode(
+        state,
+        id,
+        &[TranscodeStatus::Queued, TranscodeStatus::Processing],
+        TranscodeState {
+            status: TranscodeStatus::Processing,
+            path: None,
+            mimetype: None,
+        },
+    )
+    .await;
+    if !started {
+        tracing::warn!("Skipping transcode of {}, entry is gone or not queued", id);
+        return;
+    }
+
+    tracing::info!("Transcoding {}", path.display());
+    let config = &state.config.media;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    // MP4s that aren't converted were only queued to move their index to the front
+    let faststart = mimetype == "video/mp4" && !config.should_transcode(&mimetype, extension, 0);
+    // Never named like the original, which may already have the output format
+    let (output, output_mimetype) = if faststart {
+        (
+            path.with_extension("faststart.mp4"),
+            "video/mp4".to_string(),
+        )
+    } else {
+        (
+            path.with_extension(format!("transcoded.{}", config.transcode_format)),
+            format!("video/{}", config.transcode_format),
+        )
+    };
+    // ffmpeg writes next to it, the copy is only served once complete
+    let partial = output.with_extension(format!(
+        "partial.{}",
+        output
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+    ));
+    let encoded = if faststart {
+        run_ffmpeg(
+            config,
+            faststart_args(&path, &partial),
+            config.transcode_timeout,
+        )
+        .await
+    } else if mimetype == "image/gif" {
+        // There is nothing to remux in a GIF
+        run_ffmpeg(
+            config,
+            gif_args(config, &path, &partial),
+            config.transcode_timeout,
+        )
+        .await
+    } else if run_ffmpeg(
+        config,
+        remux_args(config, &path, &partial),
+        config.transcode_timeout,
+    )
+    .await
+    {
+        true
+    } else {
+        tracing::warn!("Remuxing {} failed, re-encoding instead", path.display());
+        run_ffmpeg(
+            config,
+            encode_args(config, &path, &partial),
+            config.transcode_timeout,
+        )
+        .await
+    };
+    let success = encoded && tokio::fs::rename(&partial, &output).await.is_ok();
+    if !success {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+
+    let new_state = if success {
+        TranscodeState {
+            status: TranscodeStatus::Done,
+            path: Some(output.clone()),
+            mimetype: Some(output_mimetype),
+        }
+    } else {
+        tracing::error!("Failed to transcode {}", path.display());
+        TranscodeState {
+            status: TranscodeStatus::Failed,
+            path: None,
+            mimetype: None,
+        }
+    };
+
+    // The entry might have been deleted while we were busy
+    if !update_transcode(state, id, &[TranscodeStatus::Processing], new_state).await || !success {
+        let _ = tokio::fs::remove_file(&output).await;
+    }
+}
+
+/// Load an entry with the value it is stored as.
+async fn load_entry(state: &SharedState, id: &str) -> Option<(Vec<u8>, CDNData)> {
+    let mut connection = match state.make_connection().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::error!("Failed to connect to Redis: {}", e);
+            return None;
+        }
+    };
+
+    match redis::cmd("GET")
+        .arg(entry_key(&state.config.key_prefix, id))
+        .query_async::<Option<Vec<u8>>>(&mut connection)
+        .await
+    {
+        Ok(Some(data)) => CDNData::decode(&data)
+            .inspect_err(|e| tracing::error!("Failed to parse data: {}", e))
+            .ok()
+            .map(|cdn_data| (data, cdn_data)),
+        Ok(None) => None,
+        Err(e) => {
+            tracing::error!("Failed to get data from Redis: {}", e);
+            None
+        }
+    }
+}
+
+/// How many times a transcode state is saved again when the entry changed in the meantime.
+const UPDATE_ATTEMPTS: usize = 5;
+
+/// Save the new transcode state of an entry if it is still in one of the `expected` states,
+/// keeping its expiry.
+///
+/// Returns `false` if the entry is gone or its transcode state changed.
+async fn update_transcode(
+    state: &SharedState,
+    id: &str,
+    expected: &[TranscodeStatus],
+    new_state: TranscodeState,
+) -> bool {
+    let mut connection = match state.make_connection().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::error!("Failed to connect to Redis: {}", e);
+            return false;
+        }
+    };
+
+    let key = entry_key(&state.config.key_prefix, id);
+    for _ in 0..UPDATE_ATTEMPTS {
+        let Some((data, mut cdn_data)) = load_entry(state, id).await else {
+            return false;
+        };
+        match &mut cdn_data {
+            CDNData::File {
+                transcode: transcode @ Some(_),
+                ..
+            } if transcode
+                .as_ref()
+                .is_some_and(|current| expected.contains(&current.status)) =>
+            {
+                *transcode = Some(new_state.clone());
+            }
+            _ => return false,
+        }
+
+        let encoded = cdn_data.encode(state.config.storage.entry_encoding);
+        match replace_unchanged(&mut connection, &key, &data, &encoded).await {
+            Ok(true) => {
+                state.breaker.forget(id);
+                return true;
+            }
+            Ok(false) => continue,
+            Err(e) => {
+                tracing::error!("Failed to set key in Redis: {}", e);
+                return false;
+            }
+        }
+    }
+    tracing::warn!(
+        "Gave up saving the transcode state of {}, it kept changing",
+        id
+    );
+    false
+}
+
+fn poster_args(input: &Path, output: &Path, seek: f64) -> Vec<OsString> {
+    let mut args = base_args();
+    args.extend(["-ss".into(), seek.to_string().into()]);
+    args.extend(["-i".into(), input.into()]);
+    args.extend(["-frames:v".into(), "1".into()]);
+    args.extend(["-vf".into(), "scale='min(1280,iw)':-2".into()]);
+    args.push(output.into());
+    args
+}
+
+fn remux_args(config: &IhaCdnMediaConfig, input: &Path, output: &Path) -> Vec<OsString> {
+    let mut args = base_args();
+    args.extend(["-i".into(), input.into()]);
+    // Only keep the first video and audio stream, subtitles usually can't be copied
+    args.extend([
+        "-map".into(),
+        "0:v:0".into(),
+        "-map".into(),
+        "0:a:0?".into(),
+    ]);
+    args.extend(["-c".into(), "copy".into()]);
+    if config.transcode_format == "mp4" {
+        args.extend(["-movflags".into(), "+faststart".into()]);
+    }
+    args.push(output.into());
+    args
+}
+
+fn encode_args(config: &IhaCdnMediaConfig, input: &Path, output: &Path) -> Vec<OsString> {
+    let mut args = base_args();
+    args.extend(["-i".into(), input.into()]);
+    args.extend([
+        "-map".into(),
+        "0:v:0".into(),
+        "-map".into(),
+        "0:a:0?".into(),
+    ]);
+    if config.transcode_format == "webm" {
+        args.extend(["-c:v", "libvpx-vp9", "-crf", "32", "-b:v", "0"].map(OsString::from));
+        args.extend(["-c:a", "libopus"].map(OsString::from));
+    } else {
+        args.extend(["-c:v", "libx264", "-preset", "veryfast", "-crf", "23"].map(OsString::from));
+        args.extend(["-pix_fmt", "yuv420p", "-c:a", "aac"].map(OsString::from));
+        args.extend(["-movflags".into(), "+faststart".into()]);
+    }
+    args.push(output.into());
+    args
+}
+
+fn faststart_args(input: &Path, output: &Path) -> Vec<OsString> {
+    let mut args = base_args();
+    args.extend(["-i".into(), input.into()]);
+    args.extend(["-map", "0", "-c", "copy", "-movflags", "+faststart"].map(OsString::from));
+    args.push(output.into());
+    args
+}
+
+fn gif_args(config: &IhaCdnMediaConfig, input: &Path, output: &Path) -> Vec<OsString> {
+    let mut args = base_args();
+    args.extend(["-i".into(), input.into()]);
+    args.extend(["-map".into(), "0:v:0".into(), "-an".into()]);
+    // Both encoders need even dimensions
+    args.extend(["-vf".into(), "scale=trunc(iw/2)*2:trunc(ih/2)*2".into()]);
+    if config.transcode_format == "webm" {
+        args.extend(["-c:v", "libvpx-vp9", "-crf", "36", "-b:v", "0"].map(OsString::from));
+    } else {
+        args.extend(["-c:v", "libx264", "-preset", "veryfast", "-crf", "25"].map(OsString::from));
+        args.extend(["-pix_fmt", "yuv420p", "-movflags", "+faststart"].map(OsString::from));
+    }
+    args.push(output.into());
+    args
+}
+
+fn base_args() -> Vec<OsString> {
+    ["-hide_banner", "-loglevel", "error", "-y"]
+        .map(OsString::from)
+        .to_vec()
+}
+
+async fn run_ffmpeg(config: &IhaCdnMediaConfig, args: Vec<OsString>, timeout: u64) -> bool {
+    let child = tokio::process::Command::new(&config.ffmpeg_path)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::error!("Failed to spawn ffmpeg: {}", e);
+            return false;
+        }
+    };
+
+    match tokio::time::timeout(Duration::from_secs(timeout), child.wait_with_output()).await {
+        Ok(Ok(result)) if result.status.success() => true,
+        Ok(Ok(result)) => {
+            tracing::error!(
+                "ffmpeg exited with {}: {}",
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim()
+            );
+            false
+        }
+        Ok(Err(e)) => {
+            tracing::error!("Failed to wait for ffmpeg: {}", e);
+            false
+        }
+        Err(_) => {
+            tracing::error!("ffmpeg timed out after {} seconds", timeout);
+            false
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    config::IhaCdnConfig,
+    state::{CDNData, TranscodeState, entry_id},
+};
+
+/// How many keys are fetched at once while scanning the entries.
+const SCAN_BATCH: usize = 500;
+
+/// Options of the `migrate-storage` command.
+#[derive(Debug)]
+pub struct MigrateOptions {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Delete the source file once the copy is verified.
+    pub delete_source: bool,
+}
+
+impl MigrateOptions {
+    /// Parse the arguments after `migrate-storage`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut from = None;
+        let mut to = None;
+        let mut delete_source = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--from" => from = args.next().cloned(),
+                "--to" => to = args.next().cloned(),
+                "--delete-source" => delete_source = true,
+                other => return Err(format!("Unknown argument: {other}")),
+            }
+        }
+
+        let from = from.ok_or("Missing --from <volume>")?;
+        let to = to.ok_or("Missing --to <volume>")?;
+        Ok(Self {
+            from: parse_backend(&from)?,
+            to: parse_backend(&to)?,
+            delete_source,
+        })
+    }
+}
+
+/// Only local volumes exist, `local` is an alias of `upload_path`.
+fn parse_backend(value: &str) -> Result<PathBuf, String> {
+    match value {
+        "local" => Ok(PathBuf::from(".")),
+        value if value.contains("://") || value == "s3" => Err(format!(
+            "Unsupported storage backend `{value}`, only local volumes are supported"
+        )),
+        value => Ok(PathBuf::from(value)),
+    }
+}
+
+/// Copy every stored file under `from` to `to` and update the entries.
+///
+/// Files are verified with SHA-256 before the entry is updated, entries that were already
+/// migrated no longer point to `from`, so an interrupted migration can simply be run again.
+pub async fn migrate_storage(
+    config: &IhaCdnConfig,
+    redis: &redis::Client,
+    options: &MigrateOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let from = if options.from == Path::new(".") {
+        std::fs::canonicalize(&config.upload_path)?
+    } else {
+        std::fs::canonicalize(&options.from)?
+    };
+    let to = if options.to == Path::new(".") {
+        std::fs::canonicalize(&config.upload_path)?
+    } else {
+        std::fs::canonicalize(&options.to)?
+    };
+    if from == to {
+        return Err("Source and destination are the same volume".into());
+    }
+
+    tracing::info!("📦 Migrating {} to {}", from.display(), to.display());
+    let mut connection = redis.get_multiplexed_async_connection().await?;
+
+    let mut migrated = 0u64;
+    let mut failed = 0u64;
+    let mut cursor = 0u64;
+    loop {
+        let (next_cursor, keys) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{}*", config.key_prefix))
+            .arg("COUNT")
+            .arg(SCAN_BATCH)
+            .query_async::<(u64, Vec<String>)>(&mut connection)
+            .await?;
+
+        for key in keys {
+            let Some(id) = entry_id(&config.key_prefix, &key) else {
+                continue;
+            };
+
+            let data = redis::cmd("GET")
+                .arg(&key)
+                .query_async::<Option<Vec<u8>>>(&mut connection)
+                .await?;
+            let Some(mut cdn_data) = data.and_then(|data| {
+                CDNData::decode(&data)
+                    .inspect_err(|e| tracing::warn!("Skipping unparseable entry {}: {}", id, e))
+                    .ok()
+            }) else {
+                continue;
+            };
+
+            let mut moved = vec![];
+            let mut success = true;
+            for path in stored_paths(&mut cdn_data) {
+                let Ok(relative) = path.strip_prefix(&from).map(Path::to_path_buf) else {
+                    continue;
+                };
+
+                let target = to.join(&relative);
+                match copy_verified(path, &target).await {
+                    Ok(()) => {
+                        moved.push(path.clone());
+                        *path = target;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to migrate {}: {}", path.display(), e);
+                        success = false;
+                        break;
+                    }
+                }
+            }
+
+            if !success {
+                failed += 1;
+                continue;
+            }
+            if moved.is_empty() {
+                continue;
+            }
+
+            redis::cmd("SET")
+                .arg(&key)
+                .arg(cdn_data.encode(config.storage.entry_encoding))
+                .arg("KEEPTTL")
+                .exec_async(&mut connection)
+                .await?;
+            migrated += 1;
+            tracing::info!("📦 Migrated {}", id);
+
+            if options.delete_source {
+                for path in moved {
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        tracing::warn!("Failed to delete {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+
+        cursor = next_cursor;
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    tracing::info!("📦 Migrated {} entries, {} failed", migrated, failed);
+    if failed > 0 {
+        return Err(format!("{failed} entries failed to migrate, run the command again").into());
+    }
+    Ok(())
+}
+
+/// Every file path stored in an entry.
+fn stored_paths(cdn_data: &mut CDNData) -> Vec<&mut PathBuf> {
+    match cdn_data {
+        CDNData::Short { .. } => vec![],
+        CDNData::Code {
+            path, revisions, ..
+        } => {
+            let mut paths = vec![path];
+            paths.extend(revisions.iter_mut().map(|revision| &mut revision.path));
+            paths
+        }
+        CDNData::File {
+            path,
+            poster,
+            transcode,
+            thumbnails,
+            ..
+        } => {
+            let mut paths = vec![path];
+            paths.extend(poster.as_mut());
+            paths.extend(thumbnails.iter_mut());
+            if let Some(TranscodeState {
+                path: Some(transcoded),
+                ..
+            }) = transcode
+            {
+                paths.push(transcoded);
+            }
+            paths
+        }
+    }
+}
+
+/// Copy a file, skipping it if an identical copy already exists at the target.
+async fn copy_verified(source: &Path, target: &Path) -> std::io::Result<()> {
+    let source_hash = hash_file(source).await?;
+    if tokio::fs::try_exists(target).await? && hash_file(target).await? == source_hash {
+        return Ok(());
+    }
+
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // Copy to a temporary file first so a partial copy is never mistaken for a finished one
+    let partial = target.with_extension("migrating");
+    tokio::fs::copy(source, &partial).await?;
+    if hash_file(&partial).await? != source_hash {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(std::io::Error::other("hash mismatch after copy"));
+    }
+    tokio::fs::rename(&partial, target).await
+}
+
+async fn hash_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
@@ -0,0 +1,205 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use regex::Regex;
+
+use crate::config::{IhaCdnModerationConfig, ModerationAction, ModerationRule};
+
+/// A moderation rule with its matchers compiled.
+#[derive(Debug)]
+struct CompiledRule {
+    name: String,
+    mimetypes: Vec<String>,
+    extensions: Vec<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    ip_ranges: Vec<IpNet>,
+    hashes: Vec<String>,
+    filename_regex: Option<Regex>,
+    actions: Vec<ModerationAction>,
+    retention_days: Option<u64>,
+}
+
+/// The upload being evaluated by the moderation rules.
+#[derive(Debug)]
+pub struct ModerationSubject<'a> {
+    pub mimetype: &'a str,
+    pub extension: &'a str,
+    /// The file size in bytes.
+    pub size: u64,
+    pub ip_address: &'a [IpAddr],
+    /// The SHA-256 of the file as lowercase hex, only computed if any rule needs it.
+    pub sha256: Option<&'a str>,
+    pub file_name: &'a str,
+}
+
+/// The combined outcome of every matching rule.
+#[derive(Debug, Default)]
+pub struct ModerationVerdict {
+    /// The name of the rule that rejected the upload.
+    pub rejected_by: Option<String>,
+    pub quarantine: bool,
+    /// The shortest forced retention of all matching rules, in days.
+    pub retention_days: Option<u64>,
+    /// The name of the rules that asked for a notification.
+    pub notify: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ModerationEngine {
+    rules: Vec<CompiledRule>,
+}
+
+impl ModerationEngine {
+    /// Compile the moderation rules from the config.
+    pub fn load(config: &IhaCdnModerationConfig) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .map(compile_rule)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Check if any of the rules needs the file hash.
+    pub fn needs_hash(&self) -> bool {
+        self.rules.iter().any(|rule| !rule.hashes.is_empty())
+    }
+
+    /// Evaluate every rule against the upload.
+    pub fn evaluate(&self, subject: &ModerationSubject) -> ModerationVerdict {
+        let mut verdict = ModerationVerdict::default();
+        for rule in self.rules.iter().filter(|rule| rule.matches(subject)) {
+            tracing::info!("Moderation rule matched: {}", rule.name);
+            for action in &rule.actions {
+                match action {
+                    ModerationAction::Reject => {
+                        if verdict.rejected_by.is_none() {
+                            verdict.rejected_by = Some(rule.name.clone());
+                        }
+                    }
+                    ModerationAction::Quarantine => verdict.quarantine = true,
+                    ModerationAction::ForceRetention => {
+                        if let Some(days) = rule.retention_days {
+                            verdict.retention_days = Some(
+                                verdict
+                                    .retention_days
+                                    .map_or(days, |current| current.min(days)),
+                            );
+                        }
+                    }
+                    ModerationAction::Notify => verdict.notify.push(rule.name.clone()),
+                }
+            }
+        }
+        verdict
+    }
+}
+
+impl CompiledRule {
+    fn matches(&self, subject: &ModerationSubject) -> bool {
+        if !self.mimetypes.is_empty()
+            && !self
+                .mimetypes
+                .iter()
+                .any(|mimetype| mimetype_matches(mimetype, subject.mimetype))
+        {
+            return false;
+        }
+
+        if !self.extensions.is_empty()
+            && !self
+                .extensions
+                .iter()
+                .any(|ext| ext.eq_ignore_ascii_case(subject.extension))
+        {
+            return false;
+        }
+
+        if self
+            .min_size
+            .is_some_and(|min_size| subject.size < min_size)
+            || self
+                .max_size
+                .is_some_and(|max_size| subject.size > max_size)
+        {
+            return false;
+        }
+
+        if !self.ip_ranges.is_empty()
+            && !subject
+                .ip_address
+                .iter()
+                .any(|ip| self.ip_ranges.iter().any(|net| net.contains(ip)))
+        {
+            return false;
+        }
+
+        if !self.hashes.is_empty() {
+            match subject.sha256 {
+                Some(sha256) if self.hashes.iter().any(|hash| hash == sha256) => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(filename_regex) = &self.filename_regex
+            && !filename_regex.is_match(subject.file_name)
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Match a mimetype against a pattern, supporting `type/*` wildcards.
+pub(crate) fn mimetype_matches(pattern: &str, mimetype: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mimetype
+            .split_once('/')
+            .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case(prefix)),
+        None => pattern.eq_ignore_ascii_case(mimetype),
+    }
+}
+
+fn compile_rule(rule: &ModerationRule) -> Result<CompiledRule, String> {
+    let ip_ranges = rule
+        .ip_ranges
+        .iter()
+        .map(|range| {
+            range
+                .parse::<IpNet>()
+                .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid IP range in rule `{}`: {}", rule.name, range))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let filename_regex = match &rule.filename_regex {
+        Some(pattern) => Some(
+            Regex::new(pattern)
+                .map_err(|e| format!("Invalid filename regex in rule `{}`: {}", rule.name, e))?,
+        ),
+        None => None,
+    };
+
+    if rule.actions.contains(&ModerationAction::ForceRetention) && rule.retention_days.is_none() {
+        return Err(format!(
+            "Rule `{}` forces retention but has no retention_days",
+            rule.name
+        ));
+    }
+
+    Ok(CompiledRule {
+        name: rule.name.clone(),
+        mimetypes: rule.mimetypes.clone(),
+        extensions: rule.extensions.clone(),
+        min_size: rule.min_size.map(|size| size * 1024),
+        max_size: rule.max_size.map(|size| size * 1024),
+        ip_ranges,
+        hashes: rule.hashes.iter().map(|hash| hash.to_lowercase()).collect(),
+        filename_regex,
+        actions: rule.actions.clone(),
+        retention_days: rule.retention_days,
+    })
+}
@@ -0,0 +1,111 @@
+//! Upload restrictions by network, Tor exit nodes and blocked autonomous systems.
+
+use std::{collections::HashSet, net::IpAddr, sync::RwLock};
+
+use axum::http::HeaderMap;
+
+use crate::{
+    admin_keys, config::IhaCdnNetworkBlocklistConfig, error::IhaCdnError, geoip::AsnDatabase,
+    notifier::extract_ip_address, state::SharedState,
+};
+
+#[derive(Debug, Default)]
+pub struct NetworkFilter {
+    block_tor: bool,
+    blocked_asns: HashSet<u32>,
+    /// Empty until the list is downloaded.
+    tor_exits: RwLock<HashSet<IpAddr>>,
+    asn_database: Option<AsnDatabase>,
+}
+
+impl NetworkFilter {
+    /// Open the ASN database from the config, the Tor exit list is loaded by [`NetworkFilter::refresh_tor_exits`].
+    pub fn load(config: &IhaCdnNetworkBlocklistConfig) -> Result<Self, String> {
+        let asn_database = config
+            .asn_database
+            .as_deref()
+            .map(AsnDatabase::open)
+            .transpose()?;
+        Ok(Self {
+            block_tor: config.block_tor,
+            blocked_asns: config.blocked_asns.iter().copied().collect(),
+            tor_exits: RwLock::new(HashSet::new()),
+            asn_database,
+        })
+    }
+
+    /// The blocked network any of the IPs is on, if any.
+    pub fn blocked_network(&self, ip_address: &[IpAddr]) -> Option<String> {
+        if self.block_tor {
+            let tor_exits = self.tor_exits.read().unwrap();
+            if ip_address.iter().any(|ip| tor_exits.contains(ip)) {
+                return Some("Tor".to_string());
+            }
+        }
+
+        if let Some(asn_database) = &self.asn_database
+            && !self.blocked_asns.is_empty()
+        {
+            return ip_address
+                .iter()
+                .filter_map(|ip| asn_database.lookup(*ip))
+                .find(|asn| self.blocked_asns.contains(&asn.number))
+                .map(|asn| match asn.organization {
+                    Some(organization) => format!("AS{} ({})", asn.number, organization),
+                    None => format!("AS{}", asn.number),
+                });
+        }
+        None
+    }
+
+    /// Download the list of Tor exit nodes again, returns how many there are.
+    pub async fn refresh_tor_exits(
+        &self,
+        url: &str,
+        user_agent: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let body = reqwest::Client::new()
+            .get(url)
+            .header("User-Agent", user_agent)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let tor_exits = body
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.parse::<IpAddr>().ok())
+            .collect::<HashSet<_>>();
+        if tor_exits.is_empty() {
+            return Err("the Tor exit list is empty".into());
+        }
+
+        let count = tor_exits.len();
+        *self.tor_exits.write().unwrap() = tor_exits;
+        Ok(count)
+    }
+}
+
+/// Reject uploads from blocked networks, unless made with an allowed admin key.
+pub async fn check_upload(state: &SharedState, headers: &HeaderMap) -> Result<(), IhaCdnError> {
+    let Some(network) = state.network.blocked_network(&extract_ip_address(headers)) else {
+        return Ok(());
+    };
+
+    let secret = headers
+        .get("x-admin-key")
+        .and_then(|key| key.to_str().ok())
+        .unwrap_or_default();
+    if let Some(admin) = admin_keys::identify(state, secret).await?
+        && state
+            .config
+            .network_blocklist
+            .allowed_keys
+            .contains(&admin.name)
+    {
+        return Ok(());
+    }
+    Err(IhaCdnError::NetworkBlocked(network))
+}
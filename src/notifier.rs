@@ -1,6 +1,9 @@
 use std::{
     net::IpAddr,
-    sync::{Arc, LazyLock},
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicI64, Ordering},
+    },
 };
 
 use axum::http::{
@@ -9,10 +12,21 @@ use axum::http::{
 };
 use ipnet::IpNet;
 
-use crate::{config::IhaCdnConfig, state::CDNData};
+use crate::{
+    abuse::Ban,
+    config::IhaCdnConfig,
+    state::{CDNData, humanize_bytes},
+    stats::DailyStats,
+    templating::format_timestamp,
+};
+
+/// The minimum seconds between two panic alerts, a panic loop shouldn't flood the webhook.
+const PANIC_ALERT_INTERVAL: i64 = 60;
+/// When the last panic alert was sent, as a UNIX timestamp.
+static LAST_PANIC_ALERT: AtomicI64 = AtomicI64::new(0);
 
 static CF_IPV4_BLOCKS: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
-    let blocked_ranges = vec![
+    let blocked_ranges = [
         "173.245.48.0/20",
         "103.21.244.0/22",
         "103.22.200.0/22",
@@ -39,7 +53,7 @@ static CF_IPV4_BLOCKS: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
 });
 
 static CF_IPV6_BLOCKS: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
-    let blocked_ranges = vec![
+    let blocked_ranges = [
         "2400:cb00::/32",
         "2606:4700::/32",
         "2803:f800::/32",
@@ -131,37 +145,18 @@ pub fn notify_discord(
     config: &Arc<IhaCdnConfig>,
     ip_address: Vec<IpAddr>,
 ) {
-    if !config.notifier.enable {
-        return;
-    }
-
-    let webhook_url = match &config.notifier.discord_webhook {
-        Some(url) => {
-            if url.is_empty() {
-                tracing::warn!("Discord webhook URL is empty. Skipping notification.");
-                return;
-            }
-            url.to_string()
-        }
-        None => {
-            tracing::warn!("Discord webhook URL is not set. Skipping notification.");
-            return;
-        }
+    let webhook_url = match get_webhook_url(config) {
+        Some(url) => url,
+        None => return,
     };
 
     let final_url = final_url.into();
+    let user_agent = config.user_agent();
     tokio::spawn(async move {
-        let ip_address = ip_address
-            .iter()
-            .map(|ip| ip.to_string())
-            .collect::<Vec<String>>()
-            .join(", ");
-        let ip_address = if ip_address.is_empty() {
-            "Unknown IP".to_string()
-        } else {
-            ip_address
-        };
-        let mut msg_contents = vec![format!("Uploader IPs: **{}**", ip_address)];
+        let mut msg_contents = vec![format!(
+            "Uploader IPs: **{}**",
+            format_ip_address(&ip_address)
+        )];
         match cdn_data {
             CDNData::Short { .. } => {
                 msg_contents.push(format!("Short URL: **<{}>**", final_url));
@@ -173,33 +168,203 @@ pub fn notify_discord(
         let is_admin = if cdn_data.is_admin() { "Yes" } else { "No" };
         msg_contents.push(format!("Is Admin? **{}**", is_admin));
 
-        let serde_data = serde_json::json!({
-            "content": msg_contents.join("\n"),
-            "avatar_url": "https://p.ihateani.me/static/img/favicon.png",
-            "username": "ihaCDN Notificator",
-            "tts": false,
-        });
-
-        let body_data = serde_json::to_string(&serde_data).unwrap();
-
-        // post to discord webhook
-        match reqwest::Client::new()
-            .post(webhook_url)
-            .body(body_data)
-            .header("Content-Type", "application/json")
-            .header(
-                "User-Agent",
-                "ihacdn-rs/0.1.0 (+https://github.com/ihateani-me/ihacdn-server-rs)",
-            )
-            .send()
-            .await
-        {
-            Ok(_) => {
-                tracing::info!("Discord notification sent successfully.");
-            }
-            Err(e) => {
-                tracing::error!("Failed to send Discord notification: {}", e);
+        post_discord_webhook(webhook_url, user_agent, msg_contents.join("\n")).await;
+    });
+}
+
+/// Notify that an upload matched moderation rules with the `notify` action.
+pub fn notify_moderation(
+    final_url: impl Into<String>,
+    rules: Vec<String>,
+    config: &Arc<IhaCdnConfig>,
+    ip_address: Vec<IpAddr>,
+) {
+    let webhook_url = match get_webhook_url(config) {
+        Some(url) => url,
+        None => return,
+    };
+
+    let final_url = final_url.into();
+    let user_agent = config.user_agent();
+    tokio::spawn(async move {
+        let msg_contents = [
+            "🛡️ **Moderation rule matched**".to_string(),
+            format!("Rules: **{}**", rules.join(", ")),
+            format!("File: **<{}>**", final_url),
+            format!("Uploader IPs: **{}**", format_ip_address(&ip_address)),
+        ];
+
+        post_discord_webhook(webhook_url, user_agent, msg_contents.join("\n")).await;
+    });
+}
+
+/// Notify that an upload was refused because malware was found in it.
+pub fn notify_infected(
+    file_name: impl Into<String>,
+    signature: impl Into<String>,
+    config: &Arc<IhaCdnConfig>,
+    ip_address: Vec<IpAddr>,
+) {
+    let webhook_url = match get_webhook_url(config) {
+        Some(url) => url,
+        None => return,
+    };
+
+    let file_name = file_name.into();
+    let signature = signature.into();
+    let user_agent = config.user_agent();
+    tokio::spawn(async move {
+        let msg_contents = [
+            "☣️ **Malware upload blocked**".to_string(),
+            format!("Signature: **{}**", signature),
+            format!("File name: **{}**", file_name),
+            format!("Uploader IPs: **{}**", format_ip_address(&ip_address)),
+        ];
+
+        post_discord_webhook(webhook_url, user_agent, msg_contents.join("\n")).await;
+    });
+}
+
+/// Notify that an IP was banned for abuse.
+pub fn notify_ban(ban: &Ban, config: &Arc<IhaCdnConfig>) {
+    let webhook_url = match get_webhook_url(config) {
+        Some(url) => url,
+        None => return,
+    };
+
+    let msg_contents = [
+        "🔨 **IP banned for abuse**".to_string(),
+        format!("IP: **{}**", ban.ip),
+        format!("Reason: **{}**", ban.reason),
+        format!(
+            "Until: **{}** (strike {})",
+            format_timestamp(ban.until),
+            ban.strikes
+        ),
+    ];
+
+    let user_agent = config.user_agent();
+    tokio::spawn(async move {
+        post_discord_webhook(webhook_url, user_agent, msg_contents.join("\n")).await;
+    });
+}
+
+/// Post the daily statistics digest.
+pub fn notify_stats_digest(stats: &DailyStats, config: &Arc<IhaCdnConfig>) {
+    let webhook_url = match get_webhook_url(config) {
+        Some(url) => url,
+        None => return,
+    };
+
+    let mut msg_contents = vec![
+        format!("📊 **Daily digest for {}**", stats.date),
+        format!(
+            "Uploads: **{}** ({} files, {} pastes)",
+            stats.uploads, stats.files, stats.pastes
+        ),
+        format!("Total size: **{}**", humanize_bytes(stats.bytes)),
+    ];
+    if !stats.top_mimetypes.is_empty() {
+        let top_mimetypes = stats
+            .top_mimetypes
+            .iter()
+            .map(|entry| format!("`{}` ({})", entry.mimetype, entry.count))
+            .collect::<Vec<String>>()
+            .join(", ");
+        msg_contents.push(format!("Top mimetypes: {}", top_mimetypes));
+    }
+
+    let user_agent = config.user_agent();
+    tokio::spawn(async move {
+        post_discord_webhook(webhook_url, user_agent, msg_contents.join("\n")).await;
+    });
+}
+
+/// Alert that a handler panicked, at most once every [`PANIC_ALERT_INTERVAL`] seconds.
+pub fn notify_panic(path: &str, message: &str, config: &Arc<IhaCdnConfig>) {
+    let now = chrono::Utc::now().timestamp();
+    let last_alert = LAST_PANIC_ALERT.load(Ordering::Relaxed);
+    if now - last_alert < PANIC_ALERT_INTERVAL
+        || LAST_PANIC_ALERT
+            .compare_exchange(last_alert, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    let webhook_url = match get_webhook_url(config) {
+        Some(url) => url,
+        None => return,
+    };
+
+    let msg_contents = [
+        "💥 **A request handler panicked**".to_string(),
+        format!("Path: `{}`", path),
+        format!("Panic: `{}`", message),
+    ];
+
+    let user_agent = config.user_agent();
+    tokio::spawn(async move {
+        post_discord_webhook(webhook_url, user_agent, msg_contents.join("\n")).await;
+    });
+}
+
+fn get_webhook_url(config: &IhaCdnConfig) -> Option<String> {
+    if !config.notifier.enable {
+        return None;
+    }
+
+    match &config.notifier.discord_webhook {
+        Some(url) => {
+            if url.is_empty() {
+                tracing::warn!("Discord webhook URL is empty. Skipping notification.");
+                return None;
             }
+            Some(url.to_string())
+        }
+        None => {
+            tracing::warn!("Discord webhook URL is not set. Skipping notification.");
+            None
         }
+    }
+}
+
+fn format_ip_address(ip_address: &[IpAddr]) -> String {
+    let ip_address = ip_address
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    if ip_address.is_empty() {
+        "Unknown IP".to_string()
+    } else {
+        ip_address
+    }
+}
+
+async fn post_discord_webhook(webhook_url: String, user_agent: String, content: String) {
+    let serde_data = serde_json::json!({
+        "content": content,
+        "avatar_url": "https://p.ihateani.me/static/img/favicon.png",
+        "username": "ihaCDN Notificator",
+        "tts": false,
     });
+
+    let body_data = serde_json::to_string(&serde_data).unwrap();
+
+    // post to discord webhook
+    match reqwest::Client::new()
+        .post(webhook_url)
+        .body(body_data)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", user_agent)
+        .send()
+        .await
+    {
+        Ok(_) => {
+            tracing::info!("Discord notification sent successfully.");
+        }
+        Err(e) => {
+            tracing::error!("Failed to send Discord notification: {}", e);
+        }
+    }
 }
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use crate::config::IhaCdnNsfwConfig;
+
+/// Run an image through the configured classification endpoint.
+///
+/// The image is sent as the raw request body, and the endpoint is expected to respond with JSON
+/// containing the NSFW confidence (0.0 to 1.0) at `score_pointer`.
+///
+/// Returns [`None`] if the hook is disabled, the file is not an image or the classification failed.
+pub async fn classify_image(
+    config: &IhaCdnNsfwConfig,
+    user_agent: &str,
+    mimetype: &str,
+    path: &Path,
+) -> Option<f64> {
+    if !config.enable || !mimetype.starts_with("image/") {
+        return None;
+    }
+
+    let endpoint = config.endpoint.as_deref()?;
+    let content = match tokio::fs::read(path).await {
+        Ok(content) => content,
+        Err(e) => {
+            tracing::error!("Failed to read image for NSFW classification: {}", e);
+            return None;
+        }
+    };
+    let response = reqwest::Client::new()
+        .post(endpoint)
+        .timeout(std::time::Duration::from_secs(config.timeout))
+        .header("Content-Type", mimetype)
+        .header("User-Agent", user_agent)
+        .body(content)
+        .send()
+        .await;
+
+    let response = match response {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            tracing::error!(
+                "NSFW classification failed with status {}",
+                response.status()
+            );
+            return None;
+        }
+        Err(e) => {
+            tracing::error!("Failed to send image for NSFW classification: {}", e);
+            return None;
+        }
+    };
+
+    let body = match response
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| {
+            serde_json::from_slice::<serde_json::Value>(&bytes).map_err(|e| e.to_string())
+        }) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to parse NSFW classification response: {}", e);
+            return None;
+        }
+    };
+
+    let score = body.pointer(&config.score_pointer).and_then(|v| v.as_f64());
+    if score.is_none() {
+        tracing::error!(
+            "NSFW classification response has no score at {}",
+            config.score_pointer
+        );
+    }
+    score
+}
@@ -0,0 +1,546 @@
+//! The Lua script deciding on uploads and short links.
+//!
+//! The script defines an `upload(request)` and/or a `shorten(request)` function, returning
+//! `"allow"` (or nothing), `"reject"` with an optional reason, or `"quarantine"` for uploads.
+
+use std::{net::IpAddr, sync::Arc};
+#[cfg(feature = "lua-policy")]
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "lua-policy")]
+use chrono::Timelike;
+#[cfg(feature = "lua-policy")]
+use mlua::{ChunkMode, Function, HookTriggers, Lua, LuaOptions, LuaSerdeExt, StdLib, Variadic};
+use serde::Serialize;
+
+use crate::config::IhaCdnScriptingConfig;
+
+/// How many instructions run between the checks of the timeout.
+#[cfg(feature = "lua-policy")]
+const TIMEOUT_CHECK_INTERVAL: u32 = 1000;
+
+/// The globals of the base library that reach outside of the script.
+#[cfg(feature = "lua-policy")]
+const REMOVED_GLOBALS: [&str; 5] = ["dofile", "loadfile", "load", "require", "collectgarbage"];
+
+/// The longest pattern the string library matches with.
+#[cfg(feature = "lua-policy")]
+const MAX_PATTERN_LENGTH: usize = 256;
+
+/// The longest string the string library matches patterns in or builds with `string.rep`.
+///
+/// The instruction hook can't interrupt the C functions of the string library, this keeps
+/// each call short.
+#[cfg(feature = "lua-policy")]
+const MAX_STRING_LENGTH: usize = 64 * 1024;
+
+/// Wraps the string functions that can run for long in a single call with size checks, the
+/// string metatable points to the same table so `s:rep()` is covered too.
+#[cfg(feature = "lua-policy")]
+const STRING_LIMITS: &str = r#"
+local max_pattern, max_string = ...
+local rep, find, match, gmatch, gsub = string.rep, string.find, string.match, string.gmatch, string.gsub
+
+local function checked(matcher)
+    return function(s, pattern, ...)
+        if type(s) == "string" and #s > max_string then
+            error("the string is too long to match patterns in", 2)
+        end
+        if type(pattern) == "string" and #pattern > max_pattern then
+            error("the pattern is too long", 2)
+        end
+        return matcher(s, pattern, ...)
+    end
+end
+
+string.find = checked(find)
+string.match = checked(match)
+string.gmatch = checked(gmatch)
+string.gsub = checked(gsub)
+string.rep = function(s, n, sep)
+    if type(n) == "number" and n > 0
+        and (#tostring(s) + #tostring(sep or "")) * n > max_string then
+        error("the repeated string is too long", 2)
+    end
+    return rep(s, n, sep)
+end
+"#;
+
+/// What the script decided about the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "lua-policy"), allow(dead_code))]
+pub enum PolicyVerdict {
+    Allow,
+    /// Rejected, with the reason.
+    Reject(String),
+    Quarantine,
+}
+
+/// The upload given to the `upload` function.
+#[derive(Debug, Serialize)]
+pub struct UploadPolicySubject<'a> {
+    /// `file` or `code`.
+    #[serde(rename = "type")]
+    pub kind: &'a str,
+    pub mimetype: &'a str,
+    pub extension: &'a str,
+    /// The file size in bytes.
+    pub size: u64,
+    pub original_name: &'a str,
+    pub is_admin: bool,
+    pub ip_address: &'a [IpAddr],
+}
+
+/// The short link given to the `shorten` function.
+#[derive(Debug, Serialize)]
+pub struct ShortenPolicySubject<'a> {
+    pub url: &'a str,
+    pub scheme: &'a str,
+    pub domain: &'a str,
+    pub is_admin: bool,
+    pub ip_address: &'a [IpAddr],
+}
+
+/// The subject with the time of the request, for time-of-day rules.
+#[cfg(feature = "lua-policy")]
+#[derive(Serialize)]
+struct PolicyRequest<'a, S> {
+    #[serde(flatten)]
+    subject: &'a S,
+    /// The UNIX timestamp of the request.
+    time: i64,
+    /// The UTC hour of the request.
+    hour: u32,
+}
+
+#[cfg(feature = "lua-policy")]
+pub struct PolicyScript {
+    config: IhaCdnScriptingConfig,
+    /// The compiled script, loaded in a new sandbox for every call.
+    bytecode: Option<Vec<u8>>,
+}
+
+/// Without the `lua-policy` feature no script can be configured, everything is allowed.
+#[cfg(not(feature = "lua-policy"))]
+pub struct PolicyScript;
+
+#[cfg(not(feature = "lua-policy"))]
+impl PolicyScript {
+    pub fn load(config: &IhaCdnScriptingConfig) -> Result<Self, String> {
+        match &config.script {
+            Some(_) => Err("Policy scripts need ihacdn built with the `lua-policy` feature".into()),
+            None => Ok(Self),
+        }
+    }
+
+    pub async fn check_upload(
+        self: &Arc<Self>,
+        _subject: &UploadPolicySubject<'_>,
+    ) -> PolicyVerdict {
+        PolicyVerdict::Allow
+    }
+
+    pub async fn check_short_link(
+        self: &Arc<Self>,
+        _subject: &ShortenPolicySubject<'_>,
+    ) -> PolicyVerdict {
+        PolicyVerdict::Allow
+    }
+}
+
+#[cfg(feature = "lua-policy")]
+impl PolicyScript {
+    /// Read the script from the config, compile it and check that it runs.
+    pub fn load(config: &IhaCdnScriptingConfig) -> Result<Self, String> {
+        let source = match &config.script {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?,
+            ),
+            None => None,
+        };
+
+        Self::new(config.clone(), source)
+    }
+
+    fn new(config: IhaCdnScriptingConfig, source: Option<String>) -> Result<Self, String> {
+        let mut script = Self {
+            config,
+            bytecode: None,
+        };
+        if let Some(source) = &source {
+            script.bytecode = Some(script.compile(source).map_err(|e| e.to_string())?);
+            script.instantiate().map_err(|e| e.to_string())?;
+        }
+        Ok(script)
+    }
+
+    /// Ask the `upload` function about an upload.
+    pub async fn check_upload(
+        self: &Arc<Self>,
+        subject: &UploadPolicySubject<'_>,
+    ) -> PolicyVerdict {
+        self.evaluate("upload", subject).await
+    }
+
+    /// Ask the `shorten` function about a short link, which can't be quarantined.
+    pub async fn check_short_link(
+        self: &Arc<Self>,
+        subject: &ShortenPolicySubject<'_>,
+    ) -> PolicyVerdict {
+        match self.evaluate("shorten", subject).await {
+            PolicyVerdict::Quarantine => {
+                tracing::warn!("Short links can't be quarantined, allowing {}", subject.url);
+                PolicyVerdict::Allow
+            }
+            verdict => verdict,
+        }
+    }
+
+    async fn evaluate<S: Serialize>(
+        self: &Arc<Self>,
+        function: &'static str,
+        subject: &S,
+    ) -> PolicyVerdict {
+        if self.bytecode.is_none() {
+            return PolicyVerdict::Allow;
+        }
+
+        let now = chrono::Utc::now();
+        let request = serde_json::to_value(PolicyRequest {
+            subject,
+            time: now.timestamp(),
+            hour: now.hour(),
+        })
+        .unwrap();
+
+        let script = Arc::clone(self);
+        let result = tokio::task::spawn_blocking(move || {
+            script.call(function, &request).map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        match result {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                tracing::error!("The policy script failed in `{}`: {}", function, e);
+                if self.config.fail_closed {
+                    PolicyVerdict::Reject("The policy script failed".to_string())
+                } else {
+                    PolicyVerdict::Allow
+                }
+            }
+        }
+    }
+
+    /// Run the function of the script in a new sandbox, nothing is kept between calls.
+    fn call(&self, function: &str, request: &serde_json::Value) -> mlua::Result<PolicyVerdict> {
+        let started = Instant::now();
+        let Some(lua) = self.instantiate()? else {
+            return Ok(PolicyVerdict::Allow);
+        };
+        let Some(callback) = lua.globals().get::<_, Option<Function>>(function)? else {
+            return Ok(PolicyVerdict::Allow);
+        };
+
+        let (action, reason) =
+            callback.call::<_, (Option<String>, Option<String>)>(lua.to_value(request)?)?;
+        // The hook can't stop the C functions, a call may finish after the timeout
+        if started.elapsed() > self.timeout() {
+            return Err(timed_out());
+        }
+        match action.as_deref() {
+            None | Some("allow") => Ok(PolicyVerdict::Allow),
+            Some("reject") => {
+                Ok(PolicyVerdict::Reject(reason.unwrap_or_else(|| {
+                    "Rejected by the policy script".to_string()
+                })))
+            }
+            Some("quarantine") => Ok(PolicyVerdict::Quarantine),
+            Some(action) => Err(mlua::Error::runtime(format!(
+                "`{function}` returned the unknown action `{action}`"
+            ))),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.config.timeout)
+    }
+
+    /// Compile the script once, it is loaded from the bytecode in every sandbox.
+    fn compile(&self, source: &str) -> mlua::Result<Vec<u8>> {
+        let lua = self.sandbox()?;
+        let name = self
+            .config
+            .script
+            .as_deref()
+            .and_then(Path::to_str)
+            .unwrap_or("policy");
+        let function = lua
+            .load(source)
+            .set_name(name)
+            .set_mode(ChunkMode::Text)
+            .into_function()?;
+        Ok(function.dump(false))
+    }
+
+    /// A new sandbox with the script loaded, [`None`] if there is no script.
+    fn instantiate(&self) -> mlua::Result<Option<Lua>> {
+        let Some(bytecode) = &self.bytecode else {
+            return Ok(None);
+        };
+        let lua = self.sandbox()?;
+        lua.load(bytecode.as_slice())
+            .set_mode(ChunkMode::Binary)
+            .exec()?;
+        Ok(Some(lua))
+    }
+
+    /// A Lua state with only the pure standard libraries, within the memory limit and timeout.
+    fn sandbox(&self) -> mlua::Result<Lua> {
+        let lua = Lua::new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::default(),
+        )?;
+        let memory_limit = usize::try_from(self.config.memory_limit)
+            .unwrap_or(usize::MAX)
+            .saturating_mul(1024);
+        lua.set_memory_limit(memory_limit)?;
+
+        let started = Instant::now();
+        let timeout = self.timeout();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(TIMEOUT_CHECK_INTERVAL),
+            move |_, _| {
+                if started.elapsed() > timeout {
+                    Err(timed_out())
+                } else {
+                    Ok(())
+                }
+            },
+        );
+
+        lua.load(STRING_LIMITS)
+            .set_name("string limits")
+            .set_mode(ChunkMode::Text)
+            .call::<_, ()>((MAX_PATTERN_LENGTH, MAX_STRING_LENGTH))?;
+        let globals = lua.globals();
+        for name in REMOVED_GLOBALS {
+            globals.raw_remove(name)?;
+        }
+        let print = lua.create_function(|_, message: Variadic<String>| {
+            tracing::info!("Policy script: {}", message.join("\t"));
+            Ok(())
+        })?;
+        globals.raw_set("print", print)?;
+        drop(globals);
+        Ok(lua)
+    }
+}
+
+#[cfg(feature = "lua-policy")]
+fn timed_out() -> mlua::Error {
+    mlua::Error::runtime("the policy script timed out")
+}
+
+#[cfg(all(test, feature = "lua-policy"))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP_ADDRESS: [IpAddr; 1] = [IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))];
+
+    fn script(source: &str) -> PolicyScript {
+        let config = IhaCdnScriptingConfig {
+            script: None,
+            timeout: 100,
+            memory_limit: 1024,
+            fail_closed: true,
+        };
+        PolicyScript::new(config, Some(source.to_string())).unwrap()
+    }
+
+    fn upload(size: u64) -> serde_json::Value {
+        serde_json::to_value(PolicyRequest {
+            subject: &UploadPolicySubject {
+                kind: "file",
+                mimetype: "application/zip",
+                extension: "zip",
+                size,
+                original_name: "archive.zip",
+                is_admin: false,
+                ip_address: &IP_ADDRESS,
+            },
+            time: 1_700_000_000,
+            hour: 22,
+        })
+        .unwrap()
+    }
+
+    fn short_link(domain: &str) -> serde_json::Value {
+        let url = format!("https://{domain}/");
+        serde_json::to_value(PolicyRequest {
+            subject: &ShortenPolicySubject {
+                url: &url,
+                scheme: "https",
+                domain,
+                is_admin: false,
+                ip_address: &IP_ADDRESS,
+            },
+            time: 1_700_000_000,
+            hour: 12,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn scripts_decide_on_uploads_and_short_links() {
+        let script = script(
+            r#"
+            function upload(request)
+                if request.size > 1024 and (request.hour >= 22 or request.hour < 6) then
+                    return "reject", "No large uploads at night"
+                elseif request.ip_address[1] == "203.0.113.7" then
+                    return "quarantine"
+                end
+            end
+
+            function shorten(request)
+                if request.domain == "example.net" then
+                    return "reject"
+                end
+                return "allow"
+            end
+            "#,
+        );
+
+        assert_eq!(
+            script.call("upload", &upload(4096)).unwrap(),
+            PolicyVerdict::Reject("No large uploads at night".to_string())
+        );
+        assert_eq!(
+            script.call("upload", &upload(512)).unwrap(),
+            PolicyVerdict::Quarantine
+        );
+        assert!(matches!(
+            script.call("shorten", &short_link("example.net")).unwrap(),
+            PolicyVerdict::Reject(_)
+        ));
+        assert_eq!(
+            script.call("shorten", &short_link("example.com")).unwrap(),
+            PolicyVerdict::Allow
+        );
+    }
+
+    #[test]
+    fn missing_functions_allow_everything() {
+        let script = script("function shorten(request) return 'reject' end");
+
+        assert_eq!(
+            script.call("upload", &upload(4096)).unwrap(),
+            PolicyVerdict::Allow
+        );
+    }
+
+    #[test]
+    fn scripts_are_sandboxed() {
+        let script = script(
+            r#"
+            function upload(request)
+                assert(io == nil and os == nil and package == nil and debug == nil)
+                assert(dofile == nil and loadfile == nil and load == nil and require == nil)
+            end
+
+            function shorten(request)
+                return io.open("/etc/passwd")
+            end
+            "#,
+        );
+
+        assert_eq!(
+            script.call("upload", &upload(0)).unwrap(),
+            PolicyVerdict::Allow
+        );
+        assert!(script.call("shorten", &short_link("example.com")).is_err());
+    }
+
+    #[test]
+    fn scripts_time_out() {
+        let script = script("function upload(request) while true do end end");
+
+        let started = Instant::now();
+        let error = script.call("upload", &upload(0)).unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn scripts_cannot_go_past_the_memory_limit() {
+        let script = script(
+            r#"
+            function upload(request)
+                local chunks = {}
+                for i = 1, 4096 do
+                    chunks[i] = string.rep("x", 1024) .. i
+                end
+            end
+            "#,
+        );
+
+        let error = script.call("upload", &upload(0)).unwrap_err();
+        assert!(matches!(error, mlua::Error::MemoryError(_)));
+    }
+
+    #[test]
+    fn string_functions_are_capped() {
+        let script = script(
+            r#"
+            function upload(request)
+                return "reject", string.rep("x", 1024 * 1024)
+            end
+
+            function shorten(request)
+                local pattern = string.rep("a?", 200) .. string.rep("a", 200)
+                return "reject", request.url:match(pattern)
+            end
+            "#,
+        );
+
+        let error = script.call("upload", &upload(0)).unwrap_err();
+        assert!(error.to_string().contains("too long"));
+        let error = script
+            .call("shorten", &short_link("example.com"))
+            .unwrap_err();
+        assert!(error.to_string().contains("pattern is too long"));
+    }
+
+    #[test]
+    fn scripts_are_compiled_once() {
+        let script =
+            script("calls = (calls or 0) + 1 function upload() return 'reject', calls end");
+
+        // Every call gets a new sandbox, the compiled script runs again in each of them
+        for _ in 0..2 {
+            assert_eq!(
+                script.call("upload", &upload(0)).unwrap(),
+                PolicyVerdict::Reject("1".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn broken_scripts_are_refused_on_load() {
+        let config = IhaCdnScriptingConfig {
+            script: None,
+            timeout: 100,
+            memory_limit: 1024,
+            fail_closed: false,
+        };
+
+        assert!(PolicyScript::new(config, Some("function upload(".to_string())).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Checks run on startup before binding the port, each failure says what to fix.
+
+use std::path::Path;
+
+use crate::{build_info, config::IhaCdnConfig};
+
+/// How long an endpoint may take to answer the check, in seconds.
+const ENDPOINT_TIMEOUT: u64 = 5;
+
+/// The outcome of a single check.
+struct Check {
+    name: String,
+    /// What went wrong and how to fix it.
+    failure: Option<String>,
+    /// A failure stops the startup, otherwise it is only a warning.
+    fatal: bool,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, fatal: bool, result: Result<(), String>) -> Self {
+        Self {
+            name: name.into(),
+            failure: result.err(),
+            fatal,
+        }
+    }
+}
+
+/// Run every check of an instance and print the checklist, returns whether it can start.
+pub async fn run(config: &IhaCdnConfig, redis: &redis::Client) -> bool {
+    if !config.preflight.enable {
+        return true;
+    }
+
+    let mut checks = vec![];
+    let redis_time = match redis_round_trip(config, redis).await {
+        Ok(redis_time) => {
+            checks.push(Check::new("Redis round-trip", true, Ok(())));
+            Some(redis_time)
+        }
+        Err(e) => {
+            checks.push(Check::new("Redis round-trip", true, Err(e)));
+            None
+        }
+    };
+
+    for base_path in config.upload_paths() {
+        for directory in ["uploads", "uploads_admin"] {
+            let path = Path::new(base_path).join(directory);
+            let result = probe_directory(&path).await;
+            checks.push(Check::new(
+                format!("Writing to {}", path.display()),
+                true,
+                result,
+            ));
+        }
+    }
+
+    checks.push(Check::new(
+        "Clock",
+        false,
+        check_clock(config.preflight.max_clock_skew, redis_time),
+    ));
+
+    if config.preflight.check_endpoints {
+        let client = reqwest::Client::new();
+        let user_agent = config.user_agent();
+        if config.notifier.enable
+            && let Some(webhook) = &config.notifier.discord_webhook
+        {
+            let result = check_endpoint(&client, &user_agent, webhook).await;
+            checks.push(Check::new("Discord webhook", false, result));
+        }
+        if config.plausible.is_enabled() {
+            let endpoint = config.plausible.endpoint_url();
+            let result = check_endpoint(&client, &user_agent, endpoint.as_str()).await;
+            checks.push(Check::new("Plausible Analytics", false, result));
+        }
+        if config.nsfw.enable
+            && let Some(endpoint) = &config.nsfw.endpoint
+        {
+            let result = check_endpoint(&client, &user_agent, endpoint).await;
+            checks.push(Check::new("NSFW classification", false, result));
+        }
+    }
+
+    tracing::info!("🩺 Preflight checks of {}:", config.hostname);
+    let mut passed = true;
+    for check in &checks {
+        match &check.failure {
+            None => tracing::info!("🩺✅ {}", check.name),
+            Some(failure) if check.fatal => {
+                tracing::error!("🩺❌ {}: {}", check.name, failure);
+                passed = false;
+            }
+            Some(failure) => tracing::warn!("🩺⚠️ {}: {}", check.name, failure),
+        }
+    }
+    passed
+}
+
+/// Write, read and remove a key, returns the time of the Redis server.
+async fn redis_round_trip(config: &IhaCdnConfig, redis: &redis::Client) -> Result<i64, String> {
+    let mut connection = redis
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| {
+            format!(
+                "{e}. Check that Redis is running and that `redis` points to it, with its password"
+            )
+        })?;
+
+    let key = format!("{}:preflight:{}", config.key_prefix, std::process::id());
+    let value = build_info::GIT_SHA;
+    let read = redis::pipe()
+        .cmd("SET")
+        .arg(&key)
+        .arg(value)
+        .arg("EX")
+        .arg(60)
+        .ignore()
+        .cmd("GET")
+        .arg(&key)
+        .cmd("DEL")
+        .arg(&key)
+        .ignore()
+        .cmd("TIME")
+        .query_async::<(Option<String>, (i64, i64))>(&mut connection)
+        .await
+        .map_err(|e| {
+            format!("{e}. Check that the Redis user may write keys and isn't a read-only replica")
+        })?;
+
+    match read {
+        (Some(read), (redis_time, _)) if read == value => Ok(redis_time),
+        _ => Err(
+            "The key written to Redis could not be read back. Check that `redis` isn't a proxy or a replica"
+                .to_string(),
+        ),
+    }
+}
+
+/// Write, read and remove a probe file in an upload directory.
+async fn probe_directory(path: &Path) -> Result<(), String> {
+    let probe = path.join(format!(".preflight-{}", std::process::id()));
+    let content = build_info::GIT_SHA.as_bytes();
+    let result = async {
+        tokio::fs::write(&probe, content).await?;
+        let read = tokio::fs::read(&probe).await?;
+        if read != content {
+            return Err(std::io::Error::other(
+                "the probe file was read back changed",
+            ));
+        }
+        Ok(())
+    }
+    .await;
+    let _ = tokio::fs::remove_file(&probe).await;
+
+    result.map_err(|e| {
+        format!(
+            "{e}. Check that the directory exists, has free space and is writable by the user running ihaCDN"
+        )
+    })
+}
+
+/// Check that the clock is after the build and agrees with Redis, expiries depend on it.
+fn check_clock(max_skew: u64, redis_time: Option<i64>) -> Result<(), String> {
+    let now = chrono::Utc::now().timestamp();
+    if let Ok(built_at) = build_info::BUILD_TIMESTAMP.parse::<i64>()
+        && now < built_at
+    {
+        return Err(format!(
+            "The clock is before the build time of this binary ({built_at}). Sync it with NTP"
+        ));
+    }
+
+    if let Some(redis_time) = redis_time
+        && now.abs_diff(redis_time) > max_skew
+    {
+        return Err(format!(
+            "The clock is {}s apart from the Redis server. Sync both with NTP",
+            now.abs_diff(redis_time)
+        ));
+    }
+    Ok(())
+}
+
+/// Send a `HEAD` request to an endpoint, it must exist and accept us.
+async fn check_endpoint(
+    client: &reqwest::Client,
+    user_agent: &str,
+    url: &str,
+) -> Result<(), String> {
+    let response = client
+        .head(url)
+        .header("User-Agent", user_agent)
+        .timeout(std::time::Duration::from_secs(ENDPOINT_TIMEOUT))
+        .send()
+        .await
+        .map_err(|e| format!("{e}. Check the URL and that outbound requests are allowed"))?;
+
+    let status = response.status();
+    if matches!(status.as_u16(), 401 | 403 | 404 | 410) {
+        return Err(format!(
+            "Responded with {status}. Check that the URL is still valid, e.g. the webhook wasn't deleted"
+        ));
+    }
+    Ok(())
+}
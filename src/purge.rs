@@ -1,57 +1,328 @@
 use std::sync::Arc;
 
-use crate::state::{CDNData, PREFIX, SharedState};
+use serde::Serialize;
 
-pub async fn purge_task(state: Arc<SharedState>) -> Result<(), Box<dyn std::error::Error>> {
+use crate::{
+    events::{CdnEvent, publish_event},
+    index::{self, unindex_entry},
+    schedule::scheduled_deletions_key,
+    state::{
+        CDNData, SharedState, entry_key, humanize_bytes, last_clicked_key, remaining_views_key,
+        views_key,
+    },
+    store::RedisConnection,
+    tombstone::{TombstoneReason, record_tombstone},
+};
+
+/// How many entries are checked, and deleted, at once.
+const PURGE_BATCH: usize = 500;
+
+/// What a purge run went through and removed.
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeSummary {
+    /// The entries checked against their expiry.
+    pub scanned: usize,
+    pub deleted: usize,
+    /// The size of the removed files, revisions, posters and thumbnails.
+    pub bytes_reclaimed: u64,
+}
+
+pub async fn purge_task(
+    state: Arc<SharedState>,
+) -> Result<PurgeSummary, Box<dyn std::error::Error>> {
     // Perform the purge task
     tracing::info!("Running purge task...");
+    let mut summary = PurgeSummary::default();
 
-    if !state.config.retention.enable {
-        tracing::info!("Retention is disabled, skipping purge task.");
-        return Ok(());
-    }
-
+    // Entries with an expiry chosen by their uploader are removed even without retention
+    let file_retention = state.config.retention.enable;
+    let short_retention = state.config.short_retention.enable;
     let mut connection = state.make_connection().await?;
 
-    let available_keys = redis::cmd("KEYS")
-        .arg(format!("{PREFIX}*"))
-        .query_async::<Vec<String>>(&mut connection)
+    let now_time = chrono::Utc::now().timestamp();
+    let mut keys_to_be_deleted = vec![];
+    let mut stale_ids = vec![];
+    let mut offset = 0;
+    loop {
+        let ids = index::added_page(
+            &mut connection,
+            &state.config.key_prefix,
+            offset,
+            PURGE_BATCH,
+        )
         .await?;
+        if ids.is_empty() {
+            break;
+        }
+        offset += ids.len();
+        summary.scanned += ids.len();
+
+        let keys = ids
+            .iter()
+            .map(|id| entry_key(&state.config.key_prefix, id))
+            .collect::<Vec<_>>();
+        let keys_metadata = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async::<Vec<Option<Vec<u8>>>>(&mut connection)
+            .await?;
+        let last_clicks = if short_retention {
+            redis::cmd("MGET")
+                .arg(
+                    ids.iter()
+                        .map(|id| last_clicked_key(&state.config.key_prefix, id))
+                        .collect::<Vec<_>>(),
+                )
+                .query_async::<Vec<Option<i64>>>(&mut connection)
+                .await?
+        } else {
+            vec![None; ids.len()]
+        };
+
+        for ((keys_meta, id), last_clicked) in keys_metadata.into_iter().zip(ids).zip(last_clicks) {
+            let Some(value) = keys_meta else {
+                stale_ids.push(id);
+                continue;
+            };
+            let serde_data = CDNData::decode(&value)?;
+            if serde_data.is_held() {
+                continue;
+            }
+            let is_short = matches!(serde_data, CDNData::Short { .. });
+            if serde_data.has_expired(now_time) {
+                keys_to_be_deleted.push((id, value, serde_data));
+                continue;
+            }
+            if (is_short && !short_retention) || (!is_short && !file_retention) {
+                continue;
+            }
+            // Old short links have no creation time, they are idle from the first purge on
+            if is_short && serde_data.time_added().is_none() && last_clicked.is_none() {
+                redis::cmd("SET")
+                    .arg(last_clicked_key(&state.config.key_prefix, &id))
+                    .arg(now_time)
+                    .arg("NX")
+                    .exec_async(&mut connection)
+                    .await?;
+                continue;
+            }
+            // check file size
+            if serde_data.is_expired(&state.config, last_clicked).await {
+                keys_to_be_deleted.push((id, value, serde_data));
+            }
+        }
+    }
+    index::remove_stale(&mut connection, &state.config.key_prefix, &stale_ids).await;
 
-    if !available_keys.is_empty() {
+    if keys_to_be_deleted.is_empty() {
         tracing::info!("No keys to purge.");
-        return Ok(());
+        return Ok(summary);
     }
+    tracing::info!("Purging {} keys", keys_to_be_deleted.len());
 
-    tracing::info!("Purging {} keys", available_keys.len());
-    let keys_metadata = redis::cmd("MGET")
-        .arg(available_keys.clone())
-        .query_async::<Vec<Option<String>>>(&mut connection)
-        .await?;
+    // A single DEL of every key would block Redis on large purges
+    for batch in keys_to_be_deleted.chunks(PURGE_BATCH) {
+        // Entries changed since they were read (e.g. put under legal hold) are kept
+        let mut pipe = redis::pipe();
+        for (id, value, _) in batch {
+            pipe.cmd("EVAL")
+                .arg(DELETE_UNCHANGED_SCRIPT)
+                .arg(1)
+                .arg(entry_key(&state.config.key_prefix, id))
+                .arg(value);
+        }
+        let removed = pipe.query_async::<Vec<i64>>(&mut connection).await?;
+        let removed = batch
+            .iter()
+            .zip(removed)
+            .filter(|(_, removed)| *removed == 1)
+            .map(|((id, _, data), _)| (id, data))
+            .collect::<Vec<_>>();
+        if removed.len() < batch.len() {
+            tracing::warn!(
+                "Keeping {} entries, they were changed while being purged",
+                batch.len() - removed.len()
+            );
+        }
+        if removed.is_empty() {
+            continue;
+        }
 
-    let mut keys_to_be_deleted = vec![];
-    for (keys_meta, key) in keys_metadata.iter().zip(available_keys.iter()) {
-        if let Some(value) = keys_meta {
-            let serde_data = serde_json::from_str::<CDNData>(value)?;
-            // check file size
-            if serde_data.is_expired(&state.config).await {
-                keys_to_be_deleted.push((key.clone(), serde_data));
-            }
+        for (id, data) in &removed {
+            summary.deleted += 1;
+            summary.bytes_reclaimed += stored_size(&state, data).await;
+            data.delete_file(&state.config).await;
+            state.breaker.forget(id);
+            record_tombstone(
+                &state.config.tombstone,
+                &mut connection,
+                &state.config.key_prefix,
+                id,
+                data,
+                TombstoneReason::Expired,
+            )
+            .await;
+            unindex_entry(&mut connection, &state.config.key_prefix, id, data).await;
+            publish_event(&state, CdnEvent::Delete, id.as_str(), data);
         }
+        let ids = removed
+            .iter()
+            .map(|(id, _)| id.as_str())
+            .collect::<Vec<_>>();
+        let counters = ids
+            .iter()
+            .flat_map(|id| {
+                [
+                    views_key(&state.config.key_prefix, id),
+                    remaining_views_key(&state.config.key_prefix, id),
+                    last_clicked_key(&state.config.key_prefix, id),
+                ]
+            })
+            .collect::<Vec<_>>();
+        redis::pipe()
+            .cmd("ZREM")
+            .arg(scheduled_deletions_key(&state.config.key_prefix))
+            .arg(&ids)
+            .ignore()
+            .cmd("DEL")
+            .arg(counters)
+            .ignore()
+            .exec_async(&mut connection)
+            .await?;
     }
+    tracing::info!(
+        "Purged {} entries, reclaiming {}",
+        summary.deleted,
+        humanize_bytes(summary.bytes_reclaimed)
+    );
 
-    let bulk_delete: Vec<String> = keys_to_be_deleted
-        .iter()
-        .map(|(key, _)| key.clone())
-        .collect();
-    // delete files from disk first
-    for (_, data) in keys_to_be_deleted {
-        data.delete_file().await;
+    Ok(summary)
+}
+
+/// The size on disk of the files of an entry, the files that are already gone count as empty.
+async fn stored_size(state: &SharedState, data: &CDNData) -> u64 {
+    let mut paths = vec![];
+    match data {
+        CDNData::Short { .. } => (),
+        CDNData::File {
+            path,
+            poster,
+            thumbnails,
+            ..
+        } => {
+            paths.push(path);
+            paths.extend(poster);
+            paths.extend(thumbnails);
+        }
+        CDNData::Code {
+            path, revisions, ..
+        } => {
+            paths.push(path);
+            paths.extend(revisions.iter().map(|revision| &revision.path));
+        }
     }
-    redis::cmd("DEL")
-        .arg(bulk_delete)
-        .query_async::<Vec<String>>(&mut connection)
-        .await?;
 
-    Ok(())
+    let mut size = 0;
+    for path in paths {
+        if let Ok(metadata) = tokio::fs::metadata(state.config.resolve_path(path).await).await {
+            size += metadata.len();
+        }
+    }
+    size
+}
+
+/// Delete a key only if its value is still the one read before.
+const DELETE_UNCHANGED_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Remove an entry with its files and counters, leaving a tombstone.
+///
+/// Entries under legal hold are kept, returns whether the entry was removed.
+pub async fn destroy_entry(
+    state: &Arc<SharedState>,
+    connection: &mut RedisConnection,
+    raw_id: &str,
+    reason: TombstoneReason,
+) -> bool {
+    let key = entry_key(&state.config.key_prefix, raw_id);
+    let data = redis::cmd("GET")
+        .arg(&key)
+        .query_async::<Option<Vec<u8>>>(connection)
+        .await;
+    let (data, cdn_data) = match data {
+        Ok(Some(data)) => match CDNData::decode(&data) {
+            Ok(cdn_data) => (data, cdn_data),
+            Err(err) => {
+                tracing::error!("Failed to parse data: {}", err);
+                return false;
+            }
+        },
+        Ok(None) => return false,
+        Err(err) => {
+            tracing::error!("Failed to delete entry {}: {}", raw_id, err);
+            return false;
+        }
+    };
+    if cdn_data.is_held() {
+        tracing::warn!("Keeping {} ({:?}), it is under legal hold", raw_id, reason);
+        return false;
+    }
+    // The hold may have been set since the entry was read
+    match redis::cmd("EVAL")
+        .arg(DELETE_UNCHANGED_SCRIPT)
+        .arg(1)
+        .arg(&key)
+        .arg(&data)
+        .query_async::<i64>(connection)
+        .await
+    {
+        Ok(1) => state.breaker.forget(raw_id),
+        Ok(_) => {
+            tracing::warn!("Keeping {}, it was changed while being removed", raw_id);
+            return false;
+        }
+        Err(err) => {
+            tracing::error!("Failed to delete entry {}: {}", raw_id, err);
+            return false;
+        }
+    }
+
+    cdn_data.delete_file(&state.config).await;
+    record_tombstone(
+        &state.config.tombstone,
+        connection,
+        &state.config.key_prefix,
+        raw_id,
+        &cdn_data,
+        reason,
+    )
+    .await;
+    if let Err(err) = redis::cmd("DEL")
+        .arg(views_key(&state.config.key_prefix, raw_id))
+        .arg(remaining_views_key(&state.config.key_prefix, raw_id))
+        .arg(last_clicked_key(&state.config.key_prefix, raw_id))
+        .exec_async(connection)
+        .await
+    {
+        tracing::error!("Failed to delete counters of {}: {}", raw_id, err);
+    }
+    if let Err(err) = redis::cmd("ZREM")
+        .arg(scheduled_deletions_key(&state.config.key_prefix))
+        .arg(raw_id)
+        .exec_async(connection)
+        .await
+    {
+        tracing::error!(
+            "Failed to drop the scheduled deletion of {}: {}",
+            raw_id,
+            err
+        );
+    }
+    unindex_entry(connection, &state.config.key_prefix, raw_id, &cdn_data).await;
+    publish_event(state, CdnEvent::Delete, raw_id, &cdn_data);
+    tracing::info!("Removed {} ({:?})", raw_id, reason);
+    true
 }
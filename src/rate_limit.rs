@@ -0,0 +1,152 @@
+//! Request budgets per IP and kind of route, counted in Redis so they are shared by replicas.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use redis::RedisResult;
+
+use crate::{
+    admin_keys, config::RateBudget, error::IhaCdnError, notifier::extract_ip_address,
+    state::SharedState, store::RedisConnection,
+};
+
+/// The kind of routes sharing a budget.
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitRoute {
+    Upload,
+    Short,
+    Edit,
+    Read,
+}
+
+impl RateLimitRoute {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitRoute::Upload => "upload",
+            RateLimitRoute::Short => "short",
+            RateLimitRoute::Edit => "edit",
+            RateLimitRoute::Read => "read",
+        }
+    }
+
+    fn budget(&self, state: &SharedState) -> Option<RateBudget> {
+        let config = &state.config.rate_limit;
+        if !config.enable {
+            return None;
+        }
+        match self {
+            RateLimitRoute::Upload => config.upload,
+            RateLimitRoute::Short => config.short,
+            RateLimitRoute::Edit => config.edit,
+            RateLimitRoute::Read => config.read,
+        }
+    }
+}
+
+fn counter_key(prefix: &str, route: RateLimitRoute, ip: &str) -> String {
+    format!("{prefix}:ratelimit:{}:{ip}", route.as_str())
+}
+
+/// Count a request, returns the requests in the current window and the seconds left in it.
+async fn count_request(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    route: RateLimitRoute,
+    ip: &str,
+    budget: RateBudget,
+) -> RedisResult<(u64, u64)> {
+    redis::cmd("EVAL")
+        .arg(COUNT_SCRIPT)
+        .arg(1)
+        .arg(counter_key(prefix, route, ip))
+        .arg(budget.window)
+        .query_async::<(u64, u64)>(connection)
+        .await
+}
+
+/// Middleware rejecting the requests of an IP over the budget of the route.
+///
+/// Requests with a valid admin key are never counted, and a Redis failure lets requests through.
+pub async fn enforce(
+    State((state, route)): State<(Arc<SharedState>, RateLimitRoute)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(budget) = route.budget(&state) else {
+        return next.run(request).await;
+    };
+    let Some(ip) = extract_ip_address(request.headers()).first().copied() else {
+        return next.run(request).await;
+    };
+    if let Some(secret) = request
+        .headers()
+        .get("x-admin-key")
+        .and_then(|key| key.to_str().ok())
+        && matches!(admin_keys::identify(&state, secret).await, Ok(Some(_)))
+    {
+        return next.run(request).await;
+    }
+
+    let ip = ip.to_string();
+    let counted = match state.make_connection().await {
+        Ok(mut connection) => {
+            count_request(
+                &mut connection,
+                &state.config.key_prefix,
+                route,
+                &ip,
+                budget,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    let (count, reset) = match counted {
+        Ok(counted) => counted,
+        Err(e) => {
+            tracing::error!("Failed to count the request of {}: {}", ip, e);
+            return next.run(request).await;
+        }
+    };
+    if count > budget.limit {
+        tracing::warn!("Rate limited {} on {} routes", ip, route.as_str());
+        return IhaCdnError::RateLimited { retry_after: reset }.into_response();
+    }
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-limit"),
+        HeaderValue::from(budget.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-remaining"),
+        HeaderValue::from(budget.limit - count),
+    );
+    headers.insert(
+        HeaderName::from_static("x-ratelimit-reset"),
+        HeaderValue::from(reset),
+    );
+    response
+}
+
+/// Count a request in `KEYS[1]`, the window of `ARGV[1]` seconds starts with its first request.
+///
+/// Returns the count and the seconds left in the window.
+const COUNT_SCRIPT: &str = r#"
+local count = redis.call("INCR", KEYS[1])
+if count == 1 then
+    redis.call("EXPIRE", KEYS[1], ARGV[1])
+end
+local ttl = redis.call("TTL", KEYS[1])
+if ttl < 0 then
+    redis.call("EXPIRE", KEYS[1], ARGV[1])
+    ttl = tonumber(ARGV[1])
+end
+return {count, ttl}
+"#;
@@ -0,0 +1,1390 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use axum_extra::body::AsyncReadBody;
+use serde::Deserialize;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+
+use crate::{
+    abuse, admin_keys, audit,
+    config::{AdminIdentity, AdminRole},
+    error::{ErrorFormat, IhaCdnError},
+    index::{self, SearchFilter},
+    purge::{self, destroy_entry},
+    routes::uploads::validate_availability,
+    sanitize::parse_timestamp_field,
+    sanitize::validate_custom_headers,
+    schedule,
+    signed_upload::{self, UploadGrant},
+    state::{CDNData, SharedState, UploaderInfo, entry_key, generate_token, views_key},
+    store::{RedisConnection, replace_unchanged},
+    tasks,
+    tombstone::TombstoneReason,
+};
+
+#[derive(Deserialize)]
+pub struct QuarantineBody {
+    quarantined: bool,
+}
+
+#[derive(Deserialize)]
+pub struct LegalHoldBody {
+    held: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AvailabilityBody {
+    /// UNIX timestamp, `null` to remove.
+    not_before: Option<i64>,
+    /// UNIX timestamp, `null` to remove.
+    not_after: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AclBody {
+    /// The access tokens allowed to view the entry, empty to make it public again.
+    tokens: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleDeleteParams {
+    /// When to delete the entry, UNIX timestamp or RFC 3339.
+    at: Option<String>,
+}
+
+/// The default amount of search results.
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+/// The maximum amount of search results.
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    /// Text searched in the title, description, tags and source.
+    q: Option<String>,
+    /// Only entries with this tag.
+    tag: Option<String>,
+    /// Only entries with this mimetype, e.g. `image/png`.
+    mimetype: Option<String>,
+    /// Only entries of this type, `file`, `code` or `short`.
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    /// Only entries added after this time, UNIX timestamp or RFC 3339.
+    after: Option<String>,
+    /// Only entries added before this time, UNIX timestamp or RFC 3339.
+    before: Option<String>,
+    /// Only entries uploaded from this IP address, hashed like stored ones in privacy mode.
+    ip: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Parse an optional timestamp query parameter.
+fn timestamp_param(value: Option<&str>, name: &str) -> Result<Option<i64>, IhaCdnError> {
+    value
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            parse_timestamp_field(value).ok_or_else(|| {
+                IhaCdnError::BadRequest(format!("Invalid timestamp for {name}: {value}"))
+            })
+        })
+        .transpose()
+}
+
+/// Trim an optional query parameter, empty values are ignored.
+fn text_param(value: Option<&str>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_lowercase())
+        .filter(|value| !value.is_empty())
+}
+
+/// The admin key of the `x-admin-key` header of a request, if valid.
+pub(crate) async fn request_identity(
+    state: &SharedState,
+    headers: &HeaderMap,
+) -> Result<Option<AdminIdentity>, IhaCdnError> {
+    let secret = headers
+        .get("x-admin-key")
+        .and_then(|key| key.to_str().ok())
+        .unwrap_or_default();
+
+    admin_keys::identify(state, secret).await
+}
+
+/// Whether the request has an admin key with at least `role`, failures count as no.
+pub(crate) async fn has_role(state: &SharedState, headers: &HeaderMap, role: AdminRole) -> bool {
+    match request_identity(state, headers).await {
+        Ok(identity) => identity.is_some_and(|identity| identity.role >= role),
+        Err(err) => {
+            tracing::error!("Unable to verify the admin key: {}", err.message());
+            false
+        }
+    }
+}
+
+/// Verify the admin key of an admin API request has at least `role`.
+///
+/// The attempt is recorded in the audit log, allowed or not.
+pub(crate) async fn authorize(
+    state: &SharedState,
+    headers: &HeaderMap,
+    role: AdminRole,
+    action: &str,
+    target: &str,
+) -> Result<AdminIdentity, IhaCdnError> {
+    let identity = request_identity(state, headers)
+        .await?
+        .ok_or(IhaCdnError::Unauthorized)?;
+    let allowed = identity.role >= role;
+    audit::record(state, &identity, action, target, allowed).await;
+    if allowed {
+        Ok(identity)
+    } else {
+        Err(IhaCdnError::InsufficientRole(role))
+    }
+}
+
+/// Load an entry with the value it is stored as, to replace it with [`replace_unchanged`].
+async fn load_entry(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+) -> Result<(Vec<u8>, CDNData), IhaCdnError> {
+    let data = redis::cmd("GET")
+        .arg(entry_key(prefix, id))
+        .query_async::<Option<Vec<u8>>>(connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: id.to_string(),
+            source,
+        })?
+        .ok_or_else(|| IhaCdnError::not_found(id))?;
+    let cdn_data = CDNData::decode(&data).map_err(IhaCdnError::ParseData)?;
+    Ok((data, cdn_data))
+}
+
+/// How many times an update is applied again when the entry was changed in the meantime.
+const UPDATE_ATTEMPTS: usize = 5;
+
+/// Load an entry, apply `update` to it and save it back.
+///
+/// The entry is only saved if it was not changed since it was loaded, otherwise `update` is
+/// applied again to the new version. The expiry of the entry is kept.
+async fn update_entry(
+    state: &SharedState,
+    headers: &HeaderMap,
+    id: &str,
+    action: &str,
+    update: impl FnMut(&mut CDNData) -> Result<(), IhaCdnError>,
+) -> Result<CDNData, IhaCdnError> {
+    update_entry_as(state, headers, id, action, AdminRole::Moderator, update).await
+}
+
+/// Like [`update_entry`], for changes that need more than the moderator role.
+async fn update_entry_as(
+    state: &SharedState,
+    headers: &HeaderMap,
+    id: &str,
+    action: &str,
+    role: AdminRole,
+    mut update: impl FnMut(&mut CDNData) -> Result<(), IhaCdnError>,
+) -> Result<CDNData, IhaCdnError> {
+    authorize(state, headers, role, action, id).await?;
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let key = entry_key(&state.config.key_prefix, id);
+    for _ in 0..UPDATE_ATTEMPTS {
+        let (data, mut cdn_data) =
+            load_entry(&mut connection, &state.config.key_prefix, id).await?;
+        update(&mut cdn_data)?;
+        let encoded = cdn_data.encode(state.config.storage.entry_encoding);
+        if replace_unchanged(&mut connection, &key, &data, &encoded)
+            .await
+            .map_err(IhaCdnError::RedisSave)?
+        {
+            state.breaker.forget(id);
+            return Ok(cdn_data);
+        }
+    }
+    Err(IhaCdnError::EntryChanged {
+        id_path: id.to_string(),
+    })
+}
+
+/// Replace the custom response headers of an existing file or paste.
+pub async fn set_entry_headers(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(new_headers): Json<BTreeMap<String, String>>,
+) -> Response {
+    let result = update_entry(&state, &headers, &id, "set_headers", |cdn_data| {
+        let new_headers = validate_custom_headers(new_headers.clone())
+            .map_err(IhaCdnError::InvalidCustomHeaders)?;
+        if cdn_data.set_custom_headers(new_headers) {
+            Ok(())
+        } else {
+            Err(IhaCdnError::BadRequest(
+                "Short links do not support custom headers".to_string(),
+            ))
+        }
+    })
+    .await;
+
+    match result {
+        Ok(cdn_data) => (StatusCode::OK, Json(cdn_data.custom_headers().cloned())).into_response(),
+        Err(err) => err.respond(ErrorFormat::Json),
+    }
+}
+
+/// Quarantine or release an existing file or paste.
+pub async fn set_entry_quarantine(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<QuarantineBody>,
+) -> Response {
+    let result = update_entry(&state, &headers, &id, "set_quarantine", |cdn_data| {
+        if cdn_data.set_quarantined(body.quarantined) {
+            Ok(())
+        } else {
+            Err(IhaCdnError::BadRequest(
+                "Short links can't be quarantined".to_string(),
+            ))
+        }
+    })
+    .await;
+
+    match result {
+        Ok(cdn_data) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "quarantined": cdn_data.is_quarantined(),
+            })),
+        )
+            .into_response(),
+        Err(err) => err.respond(ErrorFormat::Json),
+    }
+}
+
+/// Put an entry under legal hold, or release it, nothing deletes a held entry.
+pub async fn set_entry_legal_hold(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<LegalHoldBody>,
+) -> Response {
+    let result = update_entry_as(
+        &state,
+        &headers,
+        &id,
+        "set_legal_hold",
+        AdminRole::Superadmin,
+        |cdn_data| {
+            cdn_data.set_legal_hold(body.held);
+            Ok(())
+        },
+    )
+    .await;
+
+    match result {
+        Ok(cdn_data) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "legal_hold": cdn_data.is_held(),
+            })),
+        )
+            .into_response(),
+        Err(err) => err.respond(ErrorFormat::Json),
+    }
+}
+
+/// Restrict viewing an existing file or paste to a list of access tokens.
+pub async fn set_entry_acl(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<AclBody>,
+) -> Response {
+    let result = update_entry(&state, &headers, &id, "set_acl", |cdn_data| {
+        if body.tokens.iter().any(|token| token.trim().is_empty()) {
+            return Err(IhaCdnError::BadRequest(
+                "Access tokens can't be empty".to_string(),
+            ));
+        }
+        if cdn_data.set_acl(&body.tokens) {
+            Ok(())
+        } else {
+            Err(IhaCdnError::BadRequest(
+                "Short links can't be restricted".to_string(),
+            ))
+        }
+    })
+    .await;
+
+    match result {
+        Ok(cdn_data) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "restricted": cdn_data.is_restricted(),
+            })),
+        )
+            .into_response(),
+        Err(err) => err.respond(ErrorFormat::Json),
+    }
+}
+
+/// Set or clear the time window an existing file or paste is served in.
+pub async fn set_entry_availability(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<AvailabilityBody>,
+) -> Response {
+    let result = update_entry(&state, &headers, &id, "set_availability", |cdn_data| {
+        validate_availability(body.not_before, body.not_after)?;
+        if cdn_data.set_availability(body.not_before, body.not_after) {
+            Ok(())
+        } else {
+            Err(IhaCdnError::BadRequest(
+                "Short links do not support a time window".to_string(),
+            ))
+        }
+    })
+    .await;
+
+    match result {
+        Ok(cdn_data) => {
+            let (not_before, not_after) = cdn_data.availability();
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({
+                    "not_before": not_before,
+                    "not_after": not_after,
+                })),
+            )
+                .into_response()
+        }
+        Err(err) => err.respond(ErrorFormat::Json),
+    }
+}
+
+/// Delete an existing entry at a later time, replacing any earlier schedule.
+pub async fn schedule_entry_deletion(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(params): Query<ScheduleDeleteParams>,
+) -> Response {
+    schedule_deletion(&state, &headers, &id, params)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn schedule_deletion(
+    state: &Arc<SharedState>,
+    headers: &HeaderMap,
+    id: &str,
+    params: ScheduleDeleteParams,
+) -> Result<Response, IhaCdnError> {
+    authorize(
+        state,
+        headers,
+        AdminRole::Moderator,
+        "schedule_deletion",
+        id,
+    )
+    .await?;
+
+    let at = timestamp_param(params.at.as_deref(), "at")?
+        .ok_or_else(|| IhaCdnError::BadRequest("Missing the deletion time `at`".to_string()))?;
+    if at <= chrono::Utc::now().timestamp() {
+        return Err(IhaCdnError::BadRequest(
+            "The deletion time must be in the future".to_string(),
+        ));
+    }
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    // Only existing entries can be scheduled
+    let (_, cdn_data) = load_entry(&mut connection, &state.config.key_prefix, id).await?;
+    if cdn_data.is_held() {
+        return Err(IhaCdnError::LegalHold {
+            id_path: id.to_string(),
+        });
+    }
+    schedule::schedule_deletion(state, &mut connection, id, at).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "id": id,
+            "delete_at": at,
+        })),
+    )
+        .into_response())
+}
+
+/// Cancel the scheduled deletion of an entry.
+pub async fn cancel_entry_deletion(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    cancel_deletion(&state, &headers, &id)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn cancel_deletion(
+    state: &SharedState,
+    headers: &HeaderMap,
+    id: &str,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Moderator, "cancel_deletion", id).await?;
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let cancelled = schedule::cancel_deletion(&mut connection, &state.config.key_prefix, id)
+        .await
+        .map_err(IhaCdnError::RedisSave)?;
+    if !cancelled {
+        return Err(IhaCdnError::not_found(id));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Search entries by type, mimetype, tag, upload time and metadata, newest first.
+pub async fn search_entries(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Query(params): Query<SearchParams>,
+) -> Response {
+    search(&state, &headers, params)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn search(
+    state: &SharedState,
+    headers: &HeaderMap,
+    params: SearchParams,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Moderator, "search", "").await?;
+
+    let filter = SearchFilter {
+        tag: text_param(params.tag.as_deref()),
+        mimetype: text_param(params.mimetype.as_deref()),
+        kind: text_param(params.kind.as_deref()),
+        after: timestamp_param(params.after.as_deref(), "after")?,
+        before: timestamp_param(params.before.as_deref(), "before")?,
+    };
+    let query = params.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let uploader_ip = text_param(params.ip.as_deref())
+        .map(|ip| {
+            ip.parse::<std::net::IpAddr>()
+                .map(|ip| state.config.uploader.identify_ip(&ip))
+                .map_err(|_| IhaCdnError::BadRequest(format!("Invalid IP address: {ip}")))
+        })
+        .transpose()?;
+    // These filters need the entries, otherwise only the returned page is loaded
+    let filters_entries = query.is_some() || uploader_ip.is_some();
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let mut matches = index::search(&mut connection, &state.config.key_prefix, &filter)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: "search".to_string(),
+            source,
+        })?;
+    let total = matches.len();
+    if !filters_entries {
+        matches.truncate(limit);
+    }
+
+    let values = if matches.is_empty() {
+        vec![]
+    } else {
+        redis::cmd("MGET")
+            .arg(
+                matches
+                    .iter()
+                    .map(|(id, _)| entry_key(&state.config.key_prefix, id))
+                    .collect::<Vec<_>>(),
+            )
+            .query_async::<Vec<Option<Vec<u8>>>>(&mut connection)
+            .await
+            .map_err(|source| IhaCdnError::RedisGet {
+                id_path: "search".to_string(),
+                source,
+            })?
+    };
+
+    let results = matches
+        .iter()
+        .zip(values)
+        .filter_map(|((id, _), value)| {
+            let cdn_data = CDNData::decode(&value?).ok()?;
+            if let Some(query) = query
+                && !cdn_data
+                    .metadata()
+                    .is_some_and(|metadata| metadata.matches(query))
+            {
+                return None;
+            }
+            if let Some(uploader_ip) = &uploader_ip
+                && cdn_data
+                    .uploader()
+                    .and_then(|uploader| uploader.ip.as_ref())
+                    != Some(uploader_ip)
+            {
+                return None;
+            }
+
+            let mut result = serde_json::Map::new();
+            result.insert("id".to_string(), id.clone().into());
+            result.insert("type".to_string(), cdn_data.kind().into());
+            result.insert("url".to_string(), state.config.make_url(id).into());
+            match &cdn_data {
+                CDNData::Short { target, .. } => {
+                    result.insert("target".to_string(), target.clone().into());
+                }
+                CDNData::File { mimetype, .. } | CDNData::Code { mimetype, .. } => {
+                    result.insert("mimetype".to_string(), mimetype.clone().into());
+                }
+            }
+            if let Some(time_added) = cdn_data.time_added() {
+                result.insert("time_added".to_string(), time_added.into());
+            }
+            if let Some(metadata) = cdn_data.metadata() {
+                result.insert(
+                    "metadata".to_string(),
+                    serde_json::to_value(metadata).unwrap(),
+                );
+            }
+            if let Some(uploader) = cdn_data.uploader() {
+                result.insert(
+                    "uploader".to_string(),
+                    serde_json::to_value(uploader).unwrap(),
+                );
+            }
+            if cdn_data.is_held() {
+                result.insert("legal_hold".to_string(), true.into());
+            }
+            Some(serde_json::Value::Object(result))
+        })
+        .collect::<Vec<_>>();
+    let total = if filters_entries {
+        results.len()
+    } else {
+        total
+    };
+    let results = results.into_iter().take(limit).collect::<Vec<_>>();
+
+    Ok((
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "total": total,
+            "results": results,
+        })),
+    )
+        .into_response())
+}
+
+/// How many entries are read from Redis at once while exporting.
+const EXPORT_BATCH: usize = 500;
+/// The fields of an exported entry, in the order of the CSV columns.
+const EXPORT_COLUMNS: [&str; 16] = [
+    "id",
+    "type",
+    "url",
+    "mimetype",
+    "declared_mimetype",
+    "target",
+    "size",
+    "views",
+    "time_added",
+    "expires",
+    "quarantined",
+    "listed",
+    "title",
+    "description",
+    "tags",
+    "source",
+];
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    /// `jsonl` (the default) or `csv`.
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum ExportFormat {
+    Jsonl,
+    Csv,
+}
+
+/// Stream the metadata of every entry, oldest first, without the file contents.
+pub async fn export_entries(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    export(&state, &headers, params)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn export(
+    state: &Arc<SharedState>,
+    headers: &HeaderMap,
+    params: ExportParams,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Superadmin, "export", "").await?;
+
+    let (format, content_type, file_name) = match params.format.as_deref().unwrap_or("jsonl") {
+        "jsonl" => (
+            ExportFormat::Jsonl,
+            "application/jsonl",
+            "ihacdn-export.jsonl",
+        ),
+        "csv" => (
+            ExportFormat::Csv,
+            "text/csv; charset=utf-8",
+            "ihacdn-export.csv",
+        ),
+        other => {
+            return Err(IhaCdnError::BadRequest(format!(
+                "Unknown export format {other}, use jsonl or csv"
+            )));
+        }
+    };
+    // Fail before the response starts if Redis is down
+    let connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+
+    let (mut tx, rx) = tokio::io::duplex(64 * 1024);
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        // The response is cut short on errors, there is no way to report them anymore
+        if let Err(e) = write_export(&state, connection, format, &mut tx).await {
+            tracing::error!("Failed to export the entries: {}", e);
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+        ],
+        AsyncReadBody::new(rx),
+    )
+        .into_response())
+}
+
+async fn write_export(
+    state: &SharedState,
+    mut connection: RedisConnection,
+    format: ExportFormat,
+    output: &mut DuplexStream,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let prefix = &state.config.key_prefix;
+    if let ExportFormat::Csv = format {
+        output
+            .write_all(format!("{}\n", EXPORT_COLUMNS.join(",")).as_bytes())
+            .await?;
+    }
+
+    let mut offset = 0;
+    loop {
+        let ids = index::added_page(&mut connection, prefix, offset, EXPORT_BATCH).await?;
+        if ids.is_empty() {
+            break;
+        }
+        offset += ids.len();
+
+        let (values, views) = redis::pipe()
+            .cmd("MGET")
+            .arg(
+                ids.iter()
+                    .map(|id| entry_key(prefix, id))
+                    .collect::<Vec<_>>(),
+            )
+            .cmd("MGET")
+            .arg(
+                ids.iter()
+                    .map(|id| views_key(prefix, id))
+                    .collect::<Vec<_>>(),
+            )
+            .query_async::<(Vec<Option<Vec<u8>>>, Vec<Option<u64>>)>(&mut connection)
+            .await?;
+
+        let mut batch = String::new();
+        for ((id, value), views) in ids.iter().zip(values).zip(views) {
+            // Removed since they were indexed
+            let Some(cdn_data) = value.and_then(|value| CDNData::decode(&value).ok()) else {
+                continue;
+            };
+            let row = export_row(state, id, &cdn_data, views).await;
+            match format {
+                ExportFormat::Jsonl => {
+                    batch.push_str(&serde_json::Value::Object(row).to_string());
+                    batch.push('\n');
+                }
+                ExportFormat::Csv => {
+                    let fields = EXPORT_COLUMNS
+                        .iter()
+                        .map(|column| csv_field(row.get(*column)))
+                        .collect::<Vec<_>>();
+                    batch.push_str(&fields.join(","));
+                    batch.push('\n');
+                }
+            }
+        }
+        output.write_all(batch.as_bytes()).await?;
+    }
+    output.flush().await?;
+    Ok(())
+}
+
+/// The exported fields of an entry, see [`EXPORT_COLUMNS`].
+async fn export_row(
+    state: &SharedState,
+    id: &str,
+    cdn_data: &CDNData,
+    views: Option<u64>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut row = serde_json::Map::new();
+    row.insert("id".to_string(), id.into());
+    row.insert("type".to_string(), cdn_data.kind().into());
+    row.insert("url".to_string(), state.config.make_url(id).into());
+    match cdn_data {
+        CDNData::Short { target, .. } => {
+            row.insert("target".to_string(), target.clone().into());
+        }
+        CDNData::File {
+            path,
+            mimetype,
+            declared_mimetype,
+            ..
+        }
+        | CDNData::Code {
+            path,
+            mimetype,
+            declared_mimetype,
+            ..
+        } => {
+            row.insert("mimetype".to_string(), mimetype.clone().into());
+            row.insert(
+                "declared_mimetype".to_string(),
+                declared_mimetype.clone().into(),
+            );
+            let size = tokio::fs::metadata(state.config.resolve_path(path).await)
+                .await
+                .ok()
+                .map(|metadata| metadata.len());
+            row.insert("size".to_string(), size.into());
+        }
+    }
+    row.insert("views".to_string(), views.unwrap_or_default().into());
+    row.insert("time_added".to_string(), cdn_data.time_added().into());
+    row.insert("expires".to_string(), cdn_data.expires().into());
+    row.insert("quarantined".to_string(), cdn_data.is_quarantined().into());
+    row.insert("listed".to_string(), cdn_data.is_listed().into());
+    if let Some(metadata) = cdn_data.metadata() {
+        row.insert("title".to_string(), metadata.title.clone().into());
+        row.insert(
+            "description".to_string(),
+            metadata.description.clone().into(),
+        );
+        row.insert("tags".to_string(), metadata.tags.clone().into());
+        row.insert("source".to_string(), metadata.source.clone().into());
+    }
+    row
+}
+
+/// A value as a CSV field, quoted when needed and with lists joined by spaces.
+fn csv_field(value: Option<&serde_json::Value>) -> String {
+    let text = match value {
+        None | Some(serde_json::Value::Null) => return String::new(),
+        Some(serde_json::Value::String(text)) => text.clone(),
+        Some(serde_json::Value::Array(values)) => values
+            .iter()
+            .filter_map(|value| value.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        Some(other) => other.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// Run the purge task now, answering with what it removed once it is done.
+pub async fn run_purge(State(state): State<Arc<SharedState>>, headers: HeaderMap) -> Response {
+    purge_now(&state, &headers)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn purge_now(state: &Arc<SharedState>, headers: &HeaderMap) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Superadmin, "purge", "").await?;
+    let summary = tasks::run_recorded(state, "purge", purge::purge_task(Arc::clone(state)))
+        .await
+        .map_err(|reason| IhaCdnError::TaskFailed {
+            task: "purge",
+            reason,
+        })?;
+    Ok(Json(summary).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct EraseBody {
+    /// The uploader IP address, hashed like stored ones in privacy mode.
+    ip: Option<String>,
+    /// The name of the admin key the entries were uploaded with.
+    key: Option<String>,
+    /// Only forget the uploader of the entries instead of deleting them.
+    #[serde(default)]
+    anonymize: bool,
+}
+
+/// Whose entries are erased.
+enum ErasureSubject {
+    /// The IP as stored, see [`crate::config::IhaCdnUploaderConfig::identify_ip`].
+    Ip(String),
+    Key(String),
+}
+
+impl ErasureSubject {
+    fn matches(&self, uploader: &UploaderInfo) -> bool {
+        match self {
+            ErasureSubject::Ip(ip) => uploader.ip.as_ref() == Some(ip),
+            ErasureSubject::Key(key) => uploader.key.as_ref() == Some(key),
+        }
+    }
+
+    /// How the subject is named in the audit log, without the IP being erased.
+    fn describe(&self) -> String {
+        match self {
+            ErasureSubject::Ip(_) => "an uploader IP".to_string(),
+            ErasureSubject::Key(key) => format!("the admin key {key}"),
+        }
+    }
+}
+
+/// Delete or anonymize every entry of an uploader, to answer erasure requests.
+pub async fn erase_uploader(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Json(body): Json<EraseBody>,
+) -> Response {
+    erase(&state, &headers, body)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn erase(
+    state: &Arc<SharedState>,
+    headers: &HeaderMap,
+    body: EraseBody,
+) -> Result<Response, IhaCdnError> {
+    let identity = authorize(state, headers, AdminRole::Superadmin, "erase", "").await?;
+
+    let ip = body
+        .ip
+        .as_deref()
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty());
+    let key = body
+        .key
+        .as_deref()
+        .map(str::trim)
+        .filter(|key| !key.is_empty());
+    let subject = match (ip, key) {
+        (Some(ip), None) => ErasureSubject::Ip(
+            ip.parse::<std::net::IpAddr>()
+                .map(|ip| state.config.uploader.identify_ip(&ip))
+                .map_err(|_| IhaCdnError::BadRequest(format!("Invalid IP address: {ip}")))?,
+        ),
+        (None, Some(key)) => ErasureSubject::Key(key.to_string()),
+        _ => {
+            return Err(IhaCdnError::BadRequest(
+                "Give either the uploader `ip` or `key`".to_string(),
+            ));
+        }
+    };
+
+    let prefix = &state.config.key_prefix;
+    let read_error = |source| IhaCdnError::RedisGet {
+        id_path: "erase".to_string(),
+        source,
+    };
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    // Collected first, deleting entries while paging would skip some
+    let mut matched = vec![];
+    let mut offset = 0;
+    loop {
+        let ids = index::added_page(&mut connection, prefix, offset, EXPORT_BATCH)
+            .await
+            .map_err(read_error)?;
+        if ids.is_empty() {
+            break;
+        }
+        offset += ids.len();
+
+        let values = redis::cmd("MGET")
+            .arg(
+                ids.iter()
+                    .map(|id| entry_key(prefix, id))
+                    .collect::<Vec<_>>(),
+            )
+            .query_async::<Vec<Option<Vec<u8>>>>(&mut connection)
+            .await
+            .map_err(read_error)?;
+        for (id, value) in ids.into_iter().zip(values) {
+            let Some(cdn_data) = value.and_then(|value| CDNData::decode(&value).ok()) else {
+                continue;
+            };
+            if cdn_data
+                .uploader()
+                .is_some_and(|uploader| subject.matches(uploader))
+            {
+                matched.push((id, cdn_data));
+            }
+        }
+    }
+
+    let mut erased = 0;
+    let mut held = 0;
+    for (id, mut cdn_data) in matched {
+        // Kept as they are, anonymizing would destroy what the hold preserves
+        if cdn_data.is_held() {
+            held += 1;
+            continue;
+        }
+        if body.anonymize {
+            cdn_data.forget_uploader();
+            // `XX` so entries removed since the scan are not brought back
+            redis::cmd("SET")
+                .arg(entry_key(prefix, &id))
+                .arg(cdn_data.encode(state.config.storage.entry_encoding))
+                .arg("XX")
+                .arg("KEEPTTL")
+                .exec_async(&mut connection)
+                .await
+                .map_err(IhaCdnError::RedisSave)?;
+        } else if !destroy_entry(state, &mut connection, &id, TombstoneReason::Deleted).await {
+            continue;
+        }
+        erased += 1;
+    }
+
+    let outcome = format!(
+        "{} entries of {} {}, {} under legal hold",
+        erased,
+        subject.describe(),
+        if body.anonymize {
+            "anonymized"
+        } else {
+            "deleted"
+        },
+        held
+    );
+    audit::record(state, &identity, "erase_done", &outcome, true).await;
+
+    Ok(Json(serde_json::json!({
+        "erased": erased,
+        "held": held,
+        "anonymized": body.anonymize,
+    }))
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct AuditParams {
+    limit: Option<usize>,
+}
+
+/// The most recent uses of admin keys, newest first.
+pub async fn audit_log(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Query(params): Query<AuditParams>,
+) -> Response {
+    recent_audit_records(&state, &headers, params)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn recent_audit_records(
+    state: &SharedState,
+    headers: &HeaderMap,
+    params: AuditParams,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Superadmin, "audit_log", "").await?;
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let records = audit::recent(&mut connection, &state.config.key_prefix, limit)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: "audit".to_string(),
+            source,
+        })?;
+    Ok(Json(records).into_response())
+}
+
+/// The minimum length of a new admin key given in a rotation.
+const MIN_ADMIN_KEY_LENGTH: usize = 16;
+
+#[derive(Deserialize)]
+pub struct RotateKeyBody {
+    /// The new key, generated if not given.
+    key: Option<String>,
+}
+
+/// The configured secret of the admin key `name`.
+fn configured_key<'a>(state: &'a SharedState, name: &str) -> Result<&'a str, IhaCdnError> {
+    state
+        .config
+        .admin_credentials()
+        .find(|(identity, _)| identity.name == name)
+        .map(|(_, configured)| configured)
+        .ok_or_else(|| IhaCdnError::not_found(name))
+}
+
+fn rotation_error(source: redis::RedisError) -> IhaCdnError {
+    IhaCdnError::RedisGet {
+        id_path: "admin keys".to_string(),
+        source,
+    }
+}
+
+/// The admin keys with their role and rotation state.
+pub async fn list_admin_keys(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+) -> Response {
+    admin_key_states(&state, &headers)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn admin_key_states(
+    state: &SharedState,
+    headers: &HeaderMap,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Superadmin, "list_keys", "").await?;
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let rotations = admin_keys::load_rotations(&mut connection, &state.config.key_prefix)
+        .await
+        .map_err(rotation_error)?;
+    let keys = state
+        .config
+        .admin_credentials()
+        .map(|(identity, configured)| {
+            let rotation = rotations.get(&identity.name);
+            serde_json::json!({
+                "name": identity.name,
+                "role": identity.role,
+                "promoted": rotation.is_some_and(|rotation| rotation.is_promoted(configured)),
+                "pending": rotation.is_some_and(|rotation| rotation.is_pending()),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(keys).into_response())
+}
+
+/// Add a new key to an admin key, both work until the new one is promoted or revoked.
+pub async fn rotate_admin_key(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    body: Option<Json<RotateKeyBody>>,
+) -> Response {
+    let key = body.and_then(|Json(body)| body.key);
+    rotate_key(&state, &headers, &name, key)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn rotate_key(
+    state: &SharedState,
+    headers: &HeaderMap,
+    name: &str,
+    key: Option<String>,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Superadmin, "rotate_key", name).await?;
+    let configured = configured_key(state, name)?;
+
+    let key = key.unwrap_or_else(generate_token);
+    if key.len() < MIN_ADMIN_KEY_LENGTH {
+        return Err(IhaCdnError::BadRequest(format!(
+            "Admin keys need at least {MIN_ADMIN_KEY_LENGTH} characters"
+        )));
+    }
+    if key == configured {
+        return Err(IhaCdnError::BadRequest(
+            "The new key is the current key".to_string(),
+        ));
+    }
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    admin_keys::rotate(&mut connection, &state.config.key_prefix, name, &key)
+        .await
+        .map_err(IhaCdnError::RedisSave)?;
+    Ok(Json(serde_json::json!({
+        "name": name,
+        "key": key,
+    }))
+    .into_response())
+}
+
+/// Make the new key of an admin key the only one, the configured key stops working.
+pub async fn promote_admin_key(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Response {
+    promote_key(&state, &headers, &name)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn promote_key(
+    state: &SharedState,
+    headers: &HeaderMap,
+    name: &str,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Superadmin, "promote_key", name).await?;
+    let configured = configured_key(state, name)?;
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    if !admin_keys::promote(&mut connection, &state.config.key_prefix, name, configured)
+        .await
+        .map_err(IhaCdnError::RedisSave)?
+    {
+        return Err(IhaCdnError::BadRequest(format!(
+            "The admin key {name} is not being rotated"
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Drop the new key of an admin key, only the current key keeps working.
+pub async fn revoke_admin_key(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Response {
+    revoke_key(&state, &headers, &name)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn revoke_key(
+    state: &SharedState,
+    headers: &HeaderMap,
+    name: &str,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Superadmin, "revoke_key", name).await?;
+    configured_key(state, name)?;
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    if !admin_keys::revoke(&mut connection, &state.config.key_prefix, name)
+        .await
+        .map_err(IhaCdnError::RedisSave)?
+    {
+        return Err(IhaCdnError::not_found(name));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// The IPs that are currently banned for abuse.
+pub async fn list_bans(State(state): State<Arc<SharedState>>, headers: HeaderMap) -> Response {
+    active_bans(&state, &headers)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn active_bans(state: &SharedState, headers: &HeaderMap) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Moderator, "list_bans", "").await?;
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let bans = abuse::list_bans(&mut connection, &state.config.key_prefix)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: "bans".to_string(),
+            source,
+        })?;
+    Ok(Json(bans).into_response())
+}
+
+/// Lift the ban of an IP, its abuse score and previous bans are forgotten.
+pub async fn lift_ban(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(ip): Path<String>,
+) -> Response {
+    unban(&state, &headers, &ip)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn unban(
+    state: &SharedState,
+    headers: &HeaderMap,
+    ip: &str,
+) -> Result<Response, IhaCdnError> {
+    authorize(state, headers, AdminRole::Moderator, "lift_ban", ip).await?;
+    let ip = ip
+        .parse::<std::net::IpAddr>()
+        .map_err(|_| IhaCdnError::BadRequest(format!("Invalid IP address: {ip}")))?;
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    if !abuse::lift_ban(&mut connection, &state.config.key_prefix, &ip.to_string())
+        .await
+        .map_err(IhaCdnError::RedisSave)?
+    {
+        return Err(IhaCdnError::not_found(&ip.to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+pub struct SignUploadBody {
+    /// The maximum file size in bytes.
+    max_size: Option<u64>,
+    /// The allowed mimetypes, e.g. `image/*`.
+    #[serde(default)]
+    allowed_types: Vec<String>,
+    /// Delete the upload after this many days.
+    retention_days: Option<u64>,
+    /// How long the URL is valid, in seconds.
+    expires_in: Option<u64>,
+}
+
+/// Sign an URL allowing a single upload with preset constraints, for delegating uploads.
+pub async fn sign_upload(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Json(body): Json<SignUploadBody>,
+) -> Response {
+    sign(&state, &headers, body)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn sign(
+    state: &SharedState,
+    headers: &HeaderMap,
+    body: SignUploadBody,
+) -> Result<Response, IhaCdnError> {
+    let identity = authorize(state, headers, AdminRole::Uploader, "sign_upload", "").await?;
+
+    let config = &state.config.signed_uploads;
+    let ttl = body.expires_in.unwrap_or(config.default_ttl);
+    if !(1..=config.max_ttl).contains(&ttl) {
+        return Err(IhaCdnError::BadRequest(format!(
+            "expires_in must be between 1 and {} seconds",
+            config.max_ttl
+        )));
+    }
+    if let (Some(max_size), Some(limit)) = (body.max_size, state.config.get_limit(true))
+        && max_size > limit
+    {
+        return Err(IhaCdnError::BadRequest(format!(
+            "max_size can't be over the admin limit of {limit} bytes"
+        )));
+    }
+    if body.retention_days == Some(0) {
+        return Err(IhaCdnError::BadRequest(
+            "retention_days must be positive".to_string(),
+        ));
+    }
+    let allowed_types = body
+        .allowed_types
+        .iter()
+        .map(|mimetype| mimetype.trim().to_lowercase())
+        .collect::<Vec<_>>();
+    if allowed_types.iter().any(String::is_empty) {
+        return Err(IhaCdnError::BadRequest(
+            "Allowed types can't be empty".to_string(),
+        ));
+    }
+
+    let grant = UploadGrant {
+        max_size: body.max_size,
+        allowed_types,
+        retention_days: body.retention_days,
+        signed_by: identity.name,
+    };
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let signature = signed_upload::sign(&mut connection, &state.config.key_prefix, &grant, ttl)
+        .await
+        .map_err(IhaCdnError::RedisSave)?;
+    Ok(Json(serde_json::json!({
+        "url": state.config.make_signed_upload_url(&signature),
+        "signature": signature,
+        "expires_at": chrono::Utc::now().timestamp() + ttl as i64,
+    }))
+    .into_response())
+}
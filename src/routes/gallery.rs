@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    error::{ErrorFormat, IhaCdnError},
+    index::listed_page,
+    state::{CDNData, SharedState, entry_key},
+    templating::{HtmlTemplate, TemplateExplore, TemplateExploreItem, format_timestamp},
+};
+
+/// How much of a paste is read for its preview, in bytes.
+const SNIPPET_BYTES: u64 = 1024;
+/// How many lines of a paste are shown in its preview.
+const SNIPPET_LINES: usize = 12;
+
+#[derive(Deserialize)]
+pub struct ExploreParams {
+    /// The page number, starting at 1.
+    page: Option<usize>,
+}
+
+/// The public gallery of recent listed images and pastes.
+pub async fn explore(
+    State(state): State<Arc<SharedState>>,
+    Query(params): Query<ExploreParams>,
+) -> Response {
+    explore_page(&state, params.page.unwrap_or(1).max(1))
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Html))
+}
+
+async fn explore_page(state: &SharedState, page: usize) -> Result<Response, IhaCdnError> {
+    if !state.config.gallery.enable {
+        return Err(IhaCdnError::not_found("explore"));
+    }
+
+    let page_size = state.config.gallery.page_size;
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let (ids, total) = listed_page(
+        &mut connection,
+        &state.config.key_prefix,
+        (page - 1).saturating_mul(page_size),
+        page_size,
+    )
+    .await
+    .map_err(|source| IhaCdnError::RedisGet {
+        id_path: "explore".to_string(),
+        source,
+    })?;
+
+    let values = if ids.is_empty() {
+        vec![]
+    } else {
+        redis::cmd("MGET")
+            .arg(
+                ids.iter()
+                    .map(|id| entry_key(&state.config.key_prefix, id))
+                    .collect::<Vec<_>>(),
+            )
+            .query_async::<Vec<Option<Vec<u8>>>>(&mut connection)
+            .await
+            .map_err(|source| IhaCdnError::RedisGet {
+                id_path: "explore".to_string(),
+                source,
+            })?
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let mut items = vec![];
+    for (id, value) in ids.iter().zip(values) {
+        let Some(cdn_data) = value.and_then(|value| CDNData::decode(&value).ok()) else {
+            continue;
+        };
+        // Moderation or an admin may have hidden it since the upload
+        if !cdn_data.is_public(now) {
+            continue;
+        }
+        if let Some(item) = explore_item(state, id, &cdn_data).await {
+            items.push(item);
+        }
+    }
+
+    let template = TemplateExplore {
+        items,
+        page,
+        previous_page: (page > 1).then(|| page - 1),
+        next_page: (page.saturating_mul(page_size) < total).then_some(page + 1),
+    };
+    Ok(HtmlTemplate::new(template).into_response())
+}
+
+/// The card of an entry, only images and plain pastes are shown.
+async fn explore_item(
+    state: &SharedState,
+    id: &str,
+    cdn_data: &CDNData,
+) -> Option<TemplateExploreItem> {
+    let metadata = cdn_data.metadata();
+    let title = metadata
+        .and_then(|metadata| metadata.title.clone())
+        .unwrap_or_else(|| id.to_string());
+    let tags = metadata
+        .map(|metadata| metadata.tags.clone())
+        .unwrap_or_default();
+
+    match cdn_data {
+        CDNData::File {
+            path,
+            mimetype,
+            time_added,
+            ..
+        } if mimetype.starts_with("image/") => {
+            let file_name = path.file_name()?.to_string_lossy();
+            Some(TemplateExploreItem {
+                url: format!("/{file_name}"),
+                title,
+                tags,
+                image: Some(format!("/{file_name}")),
+                snippet: None,
+                added: format_timestamp(*time_added),
+            })
+        }
+        CDNData::Code {
+            path,
+            time_added,
+            encrypted: false,
+            ..
+        } => {
+            let path = state.config.resolve_path(path).await;
+            let mut content = vec![];
+            tokio::fs::File::open(&path)
+                .await
+                .ok()?
+                .take(SNIPPET_BYTES)
+                .read_to_end(&mut content)
+                .await
+                .ok()?;
+            let snippet = String::from_utf8_lossy(&content)
+                .lines()
+                .take(SNIPPET_LINES)
+                .collect::<Vec<_>>()
+                .join("\n");
+            Some(TemplateExploreItem {
+                url: format!("/{id}"),
+                title,
+                tags,
+                image: None,
+                snippet: Some(snippet),
+                added: format_timestamp(*time_added),
+            })
+        }
+        _ => None,
+    }
+}
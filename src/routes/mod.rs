@@ -1,2 +1,6 @@
+pub mod admin;
+pub mod gallery;
 pub mod reader;
+pub mod search;
+pub mod status;
 pub mod uploads;
@@ -1,44 +1,244 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use axum::{
+    Json,
     body::Body,
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    extract::{FromRequestParts, Path, Query, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, request::Parts},
+    middleware::Next,
     response::{IntoResponse, Response},
 };
 use axum_extra::body::AsyncReadBody;
-use tokio::io::AsyncWriteExt;
+use futures_util::StreamExt;
+use redis::RedisResult;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 use crate::{
+    config::{AdminRole, HtmlPolicy, IhaCdnConfig},
+    content_host,
+    error::{ErrorFormat, IhaCdnError},
+    events::{CdnEvent, publish_event},
     notifier::extract_ip_address,
-    state::{
-        CDNData, DELETED_ERROR, PREFIX, READ_FILE_ERROR, REDIS_CONNECTION_ERROR, REDIS_GET_ERROR,
-        SharedState,
+    purge::destroy_entry,
+    routes::admin::has_role,
+    sanitize::{content_disposition, parse_bool_field, sanitize_language, split_id_path},
+    state::{CDNData, SharedState, burn_claim_key, entry_key, humanize_bytes, remaining_views_key},
+    store::RedisConnection,
+    templating::{
+        HtmlTemplate, TemplateEncryptedPaste, TemplatePaste, TemplatePreview,
+        TemplateSandboxedPaste, format_timestamp,
     },
-    templating::{HtmlTemplate, TemplatePaste},
-    track::report_to_plausible,
+    thumbnail::{ThumbnailFormat, make_thumbnail, record_thumbnail, thumbnail_path},
+    tombstone::{TombstoneReason, load_tombstone},
+    track::{count_view, get_views, record_click, report_to_plausible},
 };
 
+/// Robots directive for content that must never be indexed or archived.
+const NO_INDEX_ROBOTS: &str = "noindex, nofollow, noarchive";
+/// Pastes change or expire, search engines should not keep copies of them.
+const NO_ARCHIVE_ROBOTS: &str = "noarchive";
+
+/// How many ancestors of a forked paste are shown.
+const MAX_FORK_CHAIN: usize = 10;
+
+/// How often a followed paste is checked for new data.
+const FOLLOW_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The header carrying an access token for entries restricted by an ACL.
+const ACCESS_TOKEN_HEADER: &str = "x-access-token";
+
+/// Restricted entries must never be kept by shared caches.
+const PRIVATE_CACHE: &str = "private, no-store";
+
+/// How long the only download of a burn-after-read file may take, in seconds.
+const BURN_CLAIM_TTL: u64 = 6 * 60 * 60;
+
+/// Raw HTML pastes in sandbox mode run on an opaque origin, without forms, popups or navigation.
+const SANDBOX_CSP: &str = "sandbox allow-scripts";
+
+/// Where abuse of served content can be reported, see `instance.abuse_contact`.
+const ABUSE_CONTACT_HEADER: HeaderName = HeaderName::from_static("x-abuse-contact");
+
+/// Entries with a time window must be revalidated so they stop being served once it ends.
+const WINDOWED_CACHE: &str = "no-cache";
+
+/// User agents of the crawlers making embeds of links, they get the preview page of files.
+const LINK_UNFURLERS: &[&str] = &[
+    "discordbot",
+    "slackbot",
+    "twitterbot",
+    "facebookexternalhit",
+    "telegrambot",
+    "whatsapp",
+    "linkedinbot",
+    "mastodon",
+];
+
+#[derive(Deserialize)]
+pub struct RawParams {
+    /// Keep the response open and stream data appended to the paste.
+    follow: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasteParams {
+    /// Override the syntax highlighting language.
+    lang: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AccessParams {
+    /// The access token, for links opened in a browser.
+    token: Option<String>,
+}
+
+/// Who is reading an entry, checked against the ACL of restricted entries.
+///
+/// The access token is read from the `x-access-token` header or the `token` query parameter.
+pub struct Viewer {
+    token: Option<String>,
+    is_admin: bool,
+}
+
+impl FromRequestParts<Arc<SharedState>> for Viewer {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<SharedState>,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(ACCESS_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+            .or_else(|| {
+                Query::<AccessParams>::try_from_uri(&parts.uri)
+                    .ok()
+                    .and_then(|Query(params)| params.token)
+            });
+
+        Ok(Viewer {
+            token,
+            is_admin: has_role(state, &parts.headers, AdminRole::Moderator).await,
+        })
+    }
+}
+
+impl Viewer {
+    /// Admins or viewers with an access token.
+    pub(crate) fn is_authenticated(&self) -> bool {
+        self.is_admin || self.token.is_some()
+    }
+
+    /// Check if a search may return the entry, admins find everything and other viewers only
+    /// the restricted entries their access token opens.
+    pub(crate) fn may_find(&self, cdn_data: &CDNData) -> bool {
+        self.is_admin
+            || (cdn_data.is_restricted()
+                && !cdn_data.is_quarantined()
+                && self.check(cdn_data, "").is_ok())
+    }
+
+    /// Refuse expired entries, entries outside of their time window or the viewer is not in
+    /// the ACL of, admins can view everything that didn't expire.
+    fn check(&self, cdn_data: &CDNData, id_path: &str) -> Result<(), IhaCdnError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        // Expired entries are only waiting for the purge task
+        if cdn_data.has_expired(now) {
+            return Err(IhaCdnError::gone(id_path));
+        }
+        if self.is_admin {
+            return Ok(());
+        }
+
+        match cdn_data.availability() {
+            (Some(not_before), _) if now < not_before => {
+                return Err(IhaCdnError::NotYetAvailable {
+                    id_path: id_path.to_string(),
+                    not_before,
+                });
+            }
+            (_, Some(not_after)) if now >= not_after => {
+                return Err(IhaCdnError::gone(id_path));
+            }
+            _ => {}
+        }
+
+        if cdn_data.is_visible_to(self.token.as_deref()) {
+            Ok(())
+        } else {
+            Err(IhaCdnError::AccessDenied {
+                id_path: id_path.to_string(),
+            })
+        }
+    }
+}
+
+/// Middleware adding the abuse contact of the instance to served content.
+pub async fn abuse_contact(
+    State(state): State<Arc<SharedState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if let Some(contact) = state
+        .config
+        .instance
+        .abuse_contact
+        .as_deref()
+        .and_then(|contact| HeaderValue::from_str(contact).ok())
+    {
+        response.headers_mut().insert(ABUSE_CONTACT_HEADER, contact);
+    }
+    response
+}
+
 pub async fn file_reader(
     method: axum::http::Method,
     State(state): State<Arc<SharedState>>,
     headers: HeaderMap,
+    uri: axum::http::Uri,
+    viewer: Viewer,
     Path(id_path): Path<String>,
+    Query(params): Query<PasteParams>,
 ) -> Response {
-    // Placeholder for file reading logic
-    let mut connection = match state.make_connection().await {
-        Ok(connection) => connection,
-        Err(err) => {
-            tracing::error!("Failed to connect to Redis: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, REDIS_CONNECTION_ERROR).into_response();
-        }
-    };
+    let path_and_query = uri.path_and_query().map_or("", |path| path.as_str());
+    let mut last_view = LastView(None);
+    let response = read_entry(
+        method,
+        state,
+        headers,
+        path_and_query,
+        &viewer,
+        &id_path,
+        params,
+        &mut last_view,
+    )
+    .await
+    .unwrap_or_else(|err| err.respond(ErrorFormat::Html));
+    last_view.attach(response)
+}
 
+#[allow(clippy::too_many_arguments)]
+async fn read_entry(
+    method: axum::http::Method,
+    state: Arc<SharedState>,
+    headers: HeaderMap,
+    path_and_query: &str,
+    viewer: &Viewer,
+    id_path: &str,
+    params: PasteParams,
+    last_view: &mut LastView,
+) -> Result<Response, IhaCdnError> {
     // Split id_path into ID and extension
-    let (raw_id, ext) = match id_path.rsplit_once('.') {
-        Some((id, ext)) => (id.to_string(), ext.to_string()),
-        None => (id_path.clone(), String::new()),
-    };
+    let (raw_id, ext) = split_id_path(id_path).ok_or_else(|| IhaCdnError::not_found(id_path))?;
+
+    let (mut connection, parsed_data) = connect_and_load(&state, &raw_id, id_path).await?;
 
     let ip_address = extract_ip_address(&headers);
     let user_agent = headers
@@ -50,381 +250,1937 @@ pub async fn file_reader(
         .and_then(|v| v.to_str().ok())
         .map(|s| s.to_string());
 
-    match redis::cmd("GET")
-        .arg(format!("{PREFIX}{}", &raw_id))
-        .query_async::<Option<String>>(&mut connection)
-        .await
+    if parsed_data.is_quarantined() {
+        return Err(IhaCdnError::quarantined(id_path));
+    }
+    viewer.check(&parsed_data, id_path)?;
+    let is_file = matches!(parsed_data, CDNData::File { .. });
+    if let Some(redirect) =
+        content_host::misplaced_entry(&state.config, &headers, path_and_query, is_file)
     {
-        Ok(Some(data)) => {
-            let parsed_data = match serde_json::from_str::<CDNData>(&data) {
-                Ok(parsed_data) => parsed_data,
-                Err(err) => {
-                    tracing::error!("Failed to parse data: {}", err);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse data")
-                        .into_response();
+        return Ok(redirect);
+    }
+    let override_policy = cache_override(&parsed_data);
+    // Showing the preview is not a view, the unfurler fetches the file itself afterwards
+    let has_preview = serves_original(&parsed_data);
+    if has_preview && method == axum::http::Method::GET && is_link_unfurler(&headers) {
+        return file_preview(&state, &raw_id, id_path, &parsed_data, override_policy).await;
+    }
+    if matches!(
+        parsed_data,
+        CDNData::Code {
+            encrypted: true,
+            ..
+        }
+    ) {
+        // The page only decrypts what it fetches from `/raw`, which counts as the view
+        check_views_left(&state, &raw_id, id_path, &parsed_data).await?;
+    } else {
+        *last_view = consume_view(&state, &method, &raw_id, id_path, &parsed_data).await?;
+    }
+
+    match &parsed_data {
+        CDNData::Code {
+            path,
+            mimetype,
+            time_added,
+            encrypted,
+            headers: custom_headers,
+            parent_id,
+            revisions,
+            language,
+            ..
+        } => {
+            let path = &state.config.resolve_path(path).await;
+            if method == axum::http::Method::HEAD {
+                let mut builder = axum::http::Response::builder();
+                let headers = builder.headers_mut().unwrap();
+                headers.insert(
+                    axum::http::header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=UTF-8"),
+                );
+                headers.insert(
+                    axum::http::header::HeaderName::from_static("x-robots-tag"),
+                    HeaderValue::from_static(if *encrypted {
+                        NO_INDEX_ROBOTS
+                    } else {
+                        NO_ARCHIVE_ROBOTS
+                    }),
+                );
+                apply_cache_control(
+                    headers,
+                    cache_policy(override_policy, state.config.cache.paste.as_deref()),
+                );
+                apply_custom_headers(headers, custom_headers);
+                return Ok(peek_file(builder, path).await);
+            }
+            if *encrypted {
+                // We never read or render the content, the browser decrypts it with the
+                // key from the URL fragment.
+                if !tokio::fs::try_exists(&path)
+                    .await
+                    .map_err(|err| IhaCdnError::file(err, id_path))?
+                {
+                    return Err(IhaCdnError::gone(id_path));
                 }
+
+                let final_url = state.config.make_url(&raw_id);
+                report_to_plausible(
+                    final_url,
+                    &parsed_data,
+                    &state.config,
+                    ip_address,
+                    referer,
+                    user_agent,
+                );
+                publish_event(&state, CdnEvent::View, &raw_id, &parsed_data);
+                count_served_view(&state, connection.get(&state).await, &raw_id).await;
+                let tpl = TemplateEncryptedPaste {
+                    file_id: raw_id.clone(),
+                };
+                let mut response = HtmlTemplate::new(tpl).into_response();
+                response.headers_mut().insert(
+                    axum::http::header::HeaderName::from_static("x-robots-tag"),
+                    HeaderValue::from_static(NO_INDEX_ROBOTS),
+                );
+                apply_cache_control(
+                    response.headers_mut(),
+                    cache_policy(override_policy, state.config.cache.paste.as_deref()),
+                );
+                apply_custom_headers(response.headers_mut(), custom_headers);
+                return Ok(response);
+            }
+
+            let content = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|err| IhaCdnError::file(err, id_path))?;
+
+            // An explicit language shows the source of HTML pastes
+            let html_policy = html_policy(&state.config, mimetype, parsed_data.is_admin());
+            if html_policy != HtmlPolicy::Paste && params.lang.is_none() {
+                count_served_view(&state, connection.get(&state).await, &raw_id).await;
+                report_to_plausible(
+                    state.config.make_url(&raw_id),
+                    &parsed_data,
+                    &state.config,
+                    ip_address,
+                    referer,
+                    user_agent,
+                );
+                publish_event(&state, CdnEvent::View, &raw_id, &parsed_data);
+                let mut response = match html_policy {
+                    HtmlPolicy::Sandbox => HtmlTemplate::new(TemplateSandboxedPaste {
+                        file_id: raw_id.clone(),
+                        content,
+                    })
+                    .into_response(),
+                    _ => (
+                        [(axum::http::header::CONTENT_TYPE, "text/html; charset=UTF-8")],
+                        content,
+                    )
+                        .into_response(),
+                };
+                response.headers_mut().insert(
+                    axum::http::header::HeaderName::from_static("x-robots-tag"),
+                    HeaderValue::from_static(NO_ARCHIVE_ROBOTS),
+                );
+                apply_cache_control(
+                    response.headers_mut(),
+                    cache_policy(override_policy, state.config.cache.paste.as_deref()),
+                );
+                apply_custom_headers(response.headers_mut(), custom_headers);
+                return Ok(response);
+            }
+
+            // Render the HTML content
+            // Explicit choices win over the guessed language
+            let prefer_type = params
+                .lang
+                .as_deref()
+                .and_then(sanitize_language)
+                .or_else(|| (!ext.is_empty()).then(|| ext.clone()))
+                .or_else(|| language.clone())
+                .unwrap_or_else(|| mimetype.clone());
+
+            let file_size = content.len() as u64;
+            let views = count_served_view(&state, connection.get(&state).await, &raw_id).await;
+            let expires_at = parsed_data.expires_at(&state.config, file_size);
+            let parents = match connection.get(&state).await {
+                Some(connection) => {
+                    load_fork_chain(connection, &state.config.key_prefix, parent_id.clone()).await
+                }
+                None => vec![],
+            };
+            let metadata = parsed_data.metadata().cloned().unwrap_or_default();
+            let tpl = TemplatePaste {
+                code_type: prefer_type.clone(),
+                code_data: content,
+                file_id: raw_id.clone(),
+                file_size: humanize_bytes(file_size),
+                created_at: format_timestamp(*time_added),
+                expires_at,
+                expires_at_display: expires_at.map(format_timestamp),
+                views,
+                edited_at: revisions
+                    .first()
+                    .map(|revision| format_timestamp(revision.time_replaced)),
+                parents,
+                title: metadata.title,
+                description: metadata.description,
+                tags: metadata.tags,
             };
+            let final_url = state.config.make_url(&format!("{raw_id}.{prefer_type}"));
+            report_to_plausible(
+                final_url,
+                &parsed_data,
+                &state.config,
+                ip_address,
+                referer,
+                user_agent,
+            );
+            publish_event(&state, CdnEvent::View, &raw_id, &parsed_data);
+            let mut response = HtmlTemplate::new(tpl).into_response();
+            response.headers_mut().insert(
+                axum::http::header::HeaderName::from_static("x-robots-tag"),
+                HeaderValue::from_static(NO_ARCHIVE_ROBOTS),
+            );
+            apply_cache_control(
+                response.headers_mut(),
+                cache_policy(override_policy, state.config.cache.paste.as_deref()),
+            );
+            apply_custom_headers(response.headers_mut(), custom_headers);
+            Ok(response)
+        }
+        CDNData::File {
+            path,
+            mimetype,
+            headers: custom_headers,
+            transcode,
+            burn_after_read,
+            ..
+        } => {
+            // Only one download of a burn-after-read file may be in progress
+            // Burned entries are never served from memory, they always have a connection
+            let completed = if let Some(connection) = connection.get(&state).await
+                && *burn_after_read
+                && method != axum::http::Method::HEAD
+            {
+                let claimed = redis::cmd("SET")
+                    .arg(burn_claim_key(&state.config.key_prefix, &raw_id))
+                    .arg(1)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(BURN_CLAIM_TTL)
+                    .query_async::<Option<String>>(connection)
+                    .await
+                    .map_err(IhaCdnError::RedisSave)?;
+                if claimed.is_none() {
+                    return Err(IhaCdnError::gone(id_path));
+                }
 
-            match &parsed_data {
-                CDNData::Code {
-                    is_admin: _,
-                    path,
-                    mimetype,
-                    time_added: _,
-                } => {
-                    if method == axum::http::Method::HEAD {
-                        // Peek file if exists
-                        let mut builder = axum::http::Response::builder();
-                        let headers = builder.headers_mut().unwrap();
-                        headers.insert(
-                            axum::http::header::CONTENT_TYPE,
-                            "text/html; charset=UTF-8".parse().unwrap(),
-                        );
-
-                        match tokio::fs::try_exists(path).await {
-                            Ok(true) => {
-                                return builder
-                                    .status(axum::http::StatusCode::OK)
-                                    .body(Body::empty())
-                                    .unwrap()
-                                    .into_response();
-                            }
-                            Ok(false) => {
-                                return builder
-                                    .status(axum::http::StatusCode::GONE)
-                                    .body(Body::empty())
-                                    .unwrap()
-                                    .into_response();
-                            }
-                            Err(err) => {
-                                if err.kind() == std::io::ErrorKind::NotFound {
-                                    return builder
-                                        .status(axum::http::StatusCode::GONE)
-                                        .body(Body::empty())
-                                        .unwrap()
-                                        .into_response();
-                                } else {
-                                    return builder
-                                        .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                                        .body(Body::empty())
-                                        .unwrap()
-                                        .into_response();
-                                };
-                            }
-                        }
-                    }
-                    // Check if file exists in the filesystem
-                    match tokio::fs::read_to_string(&path).await {
-                        Ok(content) => {
-                            // Render the HTML content
-                            let prefer_type = if ext.is_empty() { mimetype } else { &ext };
-
-                            let tpl = TemplatePaste {
-                                code_type: prefer_type.clone(),
-                                code_data: content,
-                                file_id: raw_id.clone(),
-                            };
-                            let final_url =
-                                state.config.make_url(&format!("{raw_id}.{prefer_type}"));
-                            report_to_plausible(
-                                final_url,
-                                &parsed_data,
-                                &state.config,
-                                ip_address,
-                                referer,
-                                user_agent,
-                            );
-                            HtmlTemplate::new(tpl).into_response()
-                        }
-                        Err(err) => {
-                            if err.kind() == std::io::ErrorKind::NotFound {
-                                tracing::warn!("File not found: {}", path.display());
-                                let missing_key =
-                                    DELETED_ERROR.to_string().replace("{{ FN }}", &id_path);
-                                (StatusCode::GONE, missing_key).into_response()
-                            } else {
-                                tracing::error!("Failed to read file: {}", err);
-                                let read_error =
-                                    READ_FILE_ERROR.to_string().replace("{{ FN }}", &id_path);
-                                (StatusCode::INTERNAL_SERVER_ERROR, read_error).into_response()
-                            }
-                        }
+                let (sender, receiver) = tokio::sync::oneshot::channel();
+                tokio::spawn(burn_after_download(
+                    Arc::clone(&state),
+                    raw_id.clone(),
+                    receiver,
+                ));
+                Some(sender)
+            } else {
+                None
+            };
+
+            // Prefer the browser-playable copy if we have one, `<img>` can't show the video of
+            // a GIF so it only goes to clients asking for it
+            let is_gif = mimetype == "image/gif";
+            let has_video = transcode.as_ref().and_then(|t| t.output());
+            let output = has_video.filter(|(_, output_mimetype)| {
+                !is_gif || accepts_mimetype(&headers, output_mimetype)
+            });
+            let (path, mimetype) = match output {
+                Some((path, mimetype)) => (path, mimetype),
+                None => (path, mimetype.as_str()),
+            };
+            let path = &state.config.resolve_path(path).await;
+            // The transcoded copy isn't what was hashed, it is only validated by its date
+            let content_hash = match output {
+                Some(_) => None,
+                None => parsed_data.content_hash(),
+            };
+            let conditional = is_revalidable(&parsed_data).then_some(Conditional {
+                request: &headers,
+                content_hash,
+            });
+
+            // We want to stream the file for images and videos, everything else we want to download
+            let should_stream = mimetype.starts_with("image/") || mimetype.starts_with("video/");
+            let disposition = if should_stream {
+                "inline"
+            } else {
+                "attachment"
+            };
+            let mut response = stream_file(
+                &method,
+                id_path,
+                path,
+                mimetype,
+                disposition,
+                cache_policy(override_policy, state.config.cache.for_file(mimetype)),
+                custom_headers,
+                conditional,
+                completed,
+            )
+            .await?;
+            if is_gif && has_video.is_some() {
+                response
+                    .headers_mut()
+                    .insert(axum::http::header::VARY, HeaderValue::from_static("Accept"));
+            }
+            if has_preview {
+                response.headers_mut().append(
+                    axum::http::header::VARY,
+                    HeaderValue::from_static("User-Agent"),
+                );
+            }
+
+            if method != axum::http::Method::HEAD && is_new_view(&response) {
+                let final_url = state.config.make_url(&format!("{raw_id}.{ext}"));
+                report_to_plausible(
+                    final_url,
+                    &parsed_data,
+                    &state.config,
+                    ip_address,
+                    referer,
+                    user_agent,
+                );
+                publish_event(&state, CdnEvent::View, &raw_id, &parsed_data);
+                count_served_view(&state, connection.get(&state).await, &raw_id).await;
+            }
+
+            Ok(response)
+        }
+        CDNData::Short { target, .. } => {
+            let mut builder = axum::http::Response::builder();
+            let headers = builder.headers_mut().unwrap();
+            let final_url = state.config.make_url(&raw_id);
+            report_to_plausible(
+                final_url,
+                &parsed_data,
+                &state.config,
+                ip_address,
+                referer,
+                user_agent,
+            );
+            publish_event(&state, CdnEvent::View, &raw_id, &parsed_data);
+            count_served_view(&state, connection.get(&state).await, &raw_id).await;
+            if let Some(connection) = connection.get(&state).await
+                && state.config.short_retention.enable
+            {
+                record_click(
+                    connection,
+                    &state.config.key_prefix,
+                    &raw_id,
+                    chrono::Utc::now().timestamp(),
+                )
+                .await;
+            }
+            headers.insert(
+                axum::http::header::LOCATION,
+                header_value(axum::http::header::LOCATION, target)?,
+            );
+            apply_cache_control(headers, state.config.cache.short.as_deref());
+            Ok(builder
+                .status(StatusCode::TEMPORARY_REDIRECT)
+                .body(Body::empty())
+                .unwrap())
+        }
+    }
+}
+
+/// Serve the raw content of a paste inline.
+pub async fn file_reader_raw(
+    method: axum::http::Method,
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    viewer: Viewer,
+    Path(id_path): Path<String>,
+    Query(params): Query<RawParams>,
+) -> Result<Response, IhaCdnError> {
+    let follow = params.follow.as_deref().is_some_and(parse_bool_field);
+    if follow && method != axum::http::Method::HEAD {
+        return follow_raw(state, &viewer, id_path).await;
+    }
+    read_raw(method, state, &headers, &viewer, id_path, false).await
+}
+
+/// Stream the content of a paste, then keep sending anything appended to it.
+///
+/// The stream ends once nothing was appended for `paste.follow_timeout` seconds, the entry is
+/// gone or the client disconnected.
+async fn follow_raw(
+    state: Arc<SharedState>,
+    viewer: &Viewer,
+    id_path: String,
+) -> Result<Response, IhaCdnError> {
+    let (raw_id, _) = split_id_path(&id_path).ok_or_else(|| IhaCdnError::not_found(&id_path))?;
+    let parsed_data = fetch_entry(&state, viewer, &raw_id, &id_path).await?;
+
+    let path = match &parsed_data {
+        CDNData::Code {
+            encrypted: false,
+            path,
+            ..
+        } => state.config.resolve_path(path).await,
+        // Everything else is served as usual
+        _ => {
+            return read_raw(
+                axum::http::Method::GET,
+                state,
+                &HeaderMap::new(),
+                viewer,
+                id_path,
+                false,
+            )
+            .await;
+        }
+    };
+
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|err| IhaCdnError::file(err, &id_path))?;
+    let last_view = consume_view(
+        &state,
+        &axum::http::Method::GET,
+        &raw_id,
+        &id_path,
+        &parsed_data,
+    )
+    .await?;
+
+    let (mut tx, rx) = tokio::io::duplex(64 * 1024);
+    let follow_timeout = std::time::Duration::from_secs(state.config.paste.follow_timeout);
+    tokio::spawn(async move {
+        // Only count the view as done once the follow ends
+        let _last_view = last_view;
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut idle = std::time::Duration::ZERO;
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => {
+                    if idle >= follow_timeout
+                        || !tokio::fs::try_exists(&path).await.unwrap_or(false)
+                    {
+                        break;
                     }
+                    tokio::time::sleep(FOLLOW_POLL_INTERVAL).await;
+                    idle += FOLLOW_POLL_INTERVAL;
                 }
-                CDNData::File {
-                    is_admin: _,
-                    path,
-                    mimetype,
-                    time_added: _,
-                } => {
-                    // We want to stream the file for images and videos, everything else we want to download
-                    let mut stream = match tokio::fs::File::open(&path).await {
-                        Ok(file) => file,
-                        Err(err) => {
-                            if err.kind() == std::io::ErrorKind::NotFound {
-                                tracing::warn!("File not found: {}", path.display());
-                                let missing_key =
-                                    DELETED_ERROR.to_string().replace("{{ FN }}", &id_path);
-                                return (StatusCode::GONE, missing_key).into_response();
-                            } else {
-                                tracing::error!("Failed to read file: {}", err);
-                                let read_error =
-                                    READ_FILE_ERROR.to_string().replace("{{ FN }}", &id_path);
-                                return (StatusCode::INTERNAL_SERVER_ERROR, read_error)
-                                    .into_response();
-                            }
-                        }
-                    };
-                    let data = match stream.metadata().await {
-                        Ok(metadata) => metadata,
-                        Err(err) => {
-                            tracing::error!("Failed to get metadata: {}", err);
-                            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get metadata")
-                                .into_response();
-                        }
-                    };
-
-                    let file_name_part = path.file_name().unwrap_or_default().to_string_lossy();
-                    let mut raw_headers = vec![
-                        (axum::http::header::CONTENT_TYPE, mimetype.clone()),
-                        (axum::http::header::CONTENT_LENGTH, data.len().to_string()),
-                    ];
-
-                    let should_stream =
-                        mimetype.starts_with("image/") || mimetype.starts_with("video/");
-                    if should_stream {
-                        raw_headers.push((
-                            axum::http::header::CONTENT_DISPOSITION,
-                            format!("inline; filename=\"{}\"", file_name_part),
-                        ));
-                    } else {
-                        raw_headers.push((
-                            axum::http::header::CONTENT_DISPOSITION,
-                            format!("attachment; filename=\"{}\"", file_name_part),
-                        ));
+                Ok(read) => {
+                    idle = std::time::Duration::ZERO;
+                    // The client went away
+                    if tx.write_all(&buffer[..read]).await.is_err() || tx.flush().await.is_err() {
+                        break;
                     }
+                }
+                Err(err) => {
+                    tracing::error!("Failed to follow paste: {}", err);
+                    break;
+                }
+            }
+        }
+    });
 
-                    let (mut tx, rx) = tokio::io::duplex(64 * 1024);
-                    let body = AsyncReadBody::new(rx);
-
-                    if method == axum::http::Method::HEAD {
-                        let mut builder = axum::http::Response::builder();
-                        let headers = builder.headers_mut().unwrap();
-                        for (key, value) in raw_headers {
-                            headers.insert(key, value.parse().unwrap());
-                        }
-
-                        return builder
-                            .status(axum::http::StatusCode::OK)
-                            .body(body)
-                            .unwrap()
-                            .into_response();
-                    }
+    let mut response = AsyncReadBody::new(rx).into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert(
+        axum::http::header::CACHE_CONTROL,
+        HeaderValue::from_static("no-cache"),
+    );
+    // Ask reverse proxies to pass chunks through as they come
+    headers.insert(
+        HeaderName::from_static("x-accel-buffering"),
+        HeaderValue::from_static("no"),
+    );
+    if let Some(custom_headers) = parsed_data.custom_headers() {
+        apply_custom_headers(headers, custom_headers);
+    }
+    Ok(response)
+}
 
-                    let final_url = state.config.make_url(&format!("{raw_id}.{ext}"));
-                    report_to_plausible(
-                        final_url,
-                        &parsed_data,
-                        &state.config,
-                        ip_address,
-                        referer,
-                        user_agent,
-                    );
+/// Serve the raw content of a paste as a download.
+pub async fn file_reader_download(
+    method: axum::http::Method,
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    viewer: Viewer,
+    Path(id_path): Path<String>,
+) -> Result<Response, IhaCdnError> {
+    read_raw(method, state, &headers, &viewer, id_path, true).await
+}
 
-                    tokio::spawn(async move {
-                        let _ = tokio::io::copy(&mut stream, &mut tx).await;
-                        let _ = tx.flush().await;
-                    });
+async fn read_raw(
+    method: axum::http::Method,
+    state: Arc<SharedState>,
+    request_headers: &HeaderMap,
+    viewer: &Viewer,
+    id_path: String,
+    download: bool,
+) -> Result<Response, IhaCdnError> {
+    let mut last_view = LastView(None);
+    let response = serve_raw(
+        method,
+        state,
+        request_headers,
+        viewer,
+        id_path,
+        download,
+        &mut last_view,
+    )
+    .await?;
+    Ok(last_view.attach(response))
+}
 
-                    let mut builder = axum::http::Response::builder();
-                    let headers = builder.headers_mut().unwrap();
-                    for (key, value) in raw_headers {
-                        headers.insert(key, value.parse().unwrap());
-                    }
+async fn serve_raw(
+    method: axum::http::Method,
+    state: Arc<SharedState>,
+    request_headers: &HeaderMap,
+    viewer: &Viewer,
+    id_path: String,
+    download: bool,
+    last_view: &mut LastView,
+) -> Result<Response, IhaCdnError> {
+    // Split id_path into ID and extension
+    let (raw_id, _) = split_id_path(&id_path).ok_or_else(|| IhaCdnError::not_found(&id_path))?;
+    let parsed_data = fetch_entry(&state, viewer, &raw_id, &id_path).await?;
+    let override_policy = cache_override(&parsed_data);
+    let serve_original = serves_original(&parsed_data);
+    *last_view = consume_view(&state, &method, &raw_id, &id_path, &parsed_data).await?;
+    let is_admin = parsed_data.is_admin();
+    let revalidable = is_revalidable(&parsed_data);
+    let content_hash = parsed_data.content_hash().map(str::to_string);
 
-                    builder
-                        .status(StatusCode::OK)
-                        .body(body)
-                        .unwrap()
-                        .into_response()
+    match parsed_data {
+        CDNData::Code {
+            path,
+            mimetype,
+            encrypted,
+            headers: custom_headers,
+            ..
+        } => {
+            let path = state.config.resolve_path(&path).await;
+            let html_policy = html_policy(&state.config, &mimetype, is_admin);
+            let actual_mimetype = if encrypted {
+                "text/plain".to_string()
+            } else {
+                match mime_guess::from_ext(&mimetype)
+                    .first()
+                    .map(|m| m.essence_str().to_string())
+                {
+                    // Never render active content inline on our origin, unless the HTML policy allows it
+                    Some(mime)
+                        if !download
+                            && is_active_content(&mime)
+                            && html_policy == HtmlPolicy::Paste =>
+                    {
+                        "text/plain".to_string()
+                    }
+                    Some(mime) => mime,
+                    None => "text/plain".to_string(),
                 }
-                CDNData::Short { target } => {
-                    let mut builder = axum::http::Response::builder();
-                    let headers = builder.headers_mut().unwrap();
-                    let final_url = state.config.make_url(&raw_id);
-                    report_to_plausible(
-                        final_url,
-                        &parsed_data,
-                        &state.config,
-                        ip_address,
-                        referer,
-                        user_agent,
+            };
+            let disposition = if download { "attachment" } else { "inline" };
+
+            if method == axum::http::Method::HEAD {
+                let mut builder = axum::http::Response::builder();
+                let headers = builder.headers_mut().unwrap();
+
+                headers.insert(
+                    axum::http::header::CONTENT_TYPE,
+                    header_value(axum::http::header::CONTENT_TYPE, &actual_mimetype)?,
+                );
+                if html_policy == HtmlPolicy::Sandbox {
+                    headers.insert(
+                        axum::http::header::CONTENT_SECURITY_POLICY,
+                        HeaderValue::from_static(SANDBOX_CSP),
                     );
-                    headers.insert(axum::http::header::LOCATION, target.parse().unwrap());
-                    builder
-                        .status(StatusCode::TEMPORARY_REDIRECT)
-                        .body(Body::empty())
-                        .unwrap()
                 }
+                apply_cache_control(
+                    headers,
+                    cache_policy(override_policy, state.config.cache.paste.as_deref()),
+                );
+                apply_custom_headers(headers, &custom_headers);
+                return Ok(peek_file(builder, &path).await);
+            };
+
+            // Streamed from disk, raw logs can be hundreds of megabytes
+            let mut response = stream_file(
+                &method,
+                &id_path,
+                &path,
+                &actual_mimetype,
+                disposition,
+                cache_policy(override_policy, state.config.cache.paste.as_deref()),
+                &custom_headers,
+                revalidable.then_some(Conditional {
+                    request: request_headers,
+                    content_hash: content_hash.as_deref(),
+                }),
+                None,
+            )
+            .await?;
+            // The custom headers of the entry win
+            response
+                .headers_mut()
+                .entry(axum::http::header::HeaderName::from_static("x-robots-tag"))
+                .or_insert(HeaderValue::from_static(if encrypted {
+                    NO_INDEX_ROBOTS
+                } else {
+                    NO_ARCHIVE_ROBOTS
+                }));
+            if html_policy == HtmlPolicy::Sandbox {
+                response.headers_mut().insert(
+                    axum::http::header::CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_static(SANDBOX_CSP),
+                );
             }
+            Ok(response)
         }
-        Ok(None) => {
-            tracing::warn!("No data found for ID: {}", raw_id);
-            let missing_key = DELETED_ERROR.to_string().replace("{{ FN }}", &id_path);
-            (StatusCode::NOT_FOUND, missing_key).into_response()
+        CDNData::File {
+            path,
+            mimetype,
+            headers: custom_headers,
+            transcode,
+            ..
+        } if serve_original => {
+            // The original of a transcoded video or GIF, or the file linked by its preview
+            let disposition = if mimetype.starts_with("image/")
+                || (transcode.is_none() && mimetype.starts_with("video/"))
+            {
+                "inline"
+            } else {
+                "attachment"
+            };
+            stream_file(
+                &method,
+                &id_path,
+                &state.config.resolve_path(&path).await,
+                &mimetype,
+                disposition,
+                cache_policy(override_policy, state.config.cache.for_file(&mimetype)),
+                &custom_headers,
+                revalidable.then_some(Conditional {
+                    request: request_headers,
+                    content_hash: content_hash.as_deref(),
+                }),
+                None,
+            )
+            .await
         }
-        Err(err) => {
-            tracing::error!("Failed to get data from Redis: {}", err);
-            let fetch_error = REDIS_GET_ERROR.to_string().replace("{{ FN }}", &id_path);
-            (StatusCode::INTERNAL_SERVER_ERROR, fetch_error).into_response()
+        CDNData::File { .. } | CDNData::Short { .. } => Err(IhaCdnError::not_found(&id_path)),
+    }
+}
+
+/// Whether `/raw` serves the original of a file, which is also what its preview page for link
+/// unfurlers links to. The only download of burn-after-read files must stay claimed by
+/// `file_reader`.
+fn serves_original(cdn_data: &CDNData) -> bool {
+    matches!(
+        cdn_data,
+        CDNData::File {
+            burn_after_read: false,
+            ..
+        }
+    )
+}
+
+/// Whether the request comes from a chat or social network crawler making an embed of a link.
+fn is_link_unfurler(headers: &HeaderMap) -> bool {
+    headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_ascii_lowercase)
+        .is_some_and(|user_agent| {
+            LINK_UNFURLERS
+                .iter()
+                .any(|unfurler| user_agent.contains(unfurler))
+        })
+}
+
+/// Render the preview page of a file, linking to its bytes on `/{id}/raw`.
+async fn file_preview(
+    state: &SharedState,
+    raw_id: &str,
+    id_path: &str,
+    cdn_data: &CDNData,
+    override_policy: Option<&'static str>,
+) -> Result<Response, IhaCdnError> {
+    let CDNData::File {
+        path,
+        mimetype,
+        time_added,
+        poster,
+        transcode,
+        ..
+    } = cdn_data
+    else {
+        return Err(IhaCdnError::not_found(id_path));
+    };
+    let file_size = tokio::fs::metadata(state.config.resolve_path(path).await)
+        .await
+        .map_err(|err| IhaCdnError::file(err, id_path))?
+        .len();
+
+    let raw_url = state.config.make_content_url(&format!("{id_path}/raw"));
+    let poster_url = poster
+        .as_ref()
+        .map(|_| state.config.make_content_url(&format!("{raw_id}/poster")));
+    let is_image = mimetype.starts_with("image/");
+    // `/raw` serves the original of a transcoded video, which browsers may not play
+    let video = (mimetype.starts_with("video/") && transcode.is_none()).then(|| raw_url.clone());
+    let image = if is_image {
+        Some(raw_url.clone())
+    } else {
+        poster_url
+    };
+    let (og_type, card) = match (&video, &image) {
+        (Some(_), _) => ("video.other", "summary_large_image"),
+        (None, Some(_)) => ("website", "summary_large_image"),
+        (None, None) => ("website", "summary"),
+    };
+
+    let created_at = format_timestamp(*time_added);
+    let metadata = cdn_data.metadata().cloned().unwrap_or_default();
+    let tpl = TemplatePreview {
+        file_id: id_path.to_string(),
+        title: metadata.title.unwrap_or_else(|| id_path.to_string()),
+        description: metadata.description.unwrap_or_else(|| {
+            format!(
+                "{mimetype}, {}, uploaded {created_at}",
+                humanize_bytes(file_size)
+            )
+        }),
+        instance_name: state.config.instance.name.clone(),
+        page_url: state.config.make_content_url(id_path),
+        raw_url,
+        mimetype: mimetype.clone(),
+        file_size: humanize_bytes(file_size),
+        created_at,
+        og_type,
+        card,
+        image,
+        video,
+        is_image,
+    };
+    let mut response = HtmlTemplate::new(tpl).into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+        axum::http::header::HeaderName::from_static("x-robots-tag"),
+        HeaderValue::from_static(NO_ARCHIVE_ROBOTS),
+    );
+    headers.insert(
+        axum::http::header::VARY,
+        HeaderValue::from_static("User-Agent"),
+    );
+    apply_cache_control(
+        headers,
+        cache_policy(override_policy, state.config.cache.for_file(mimetype)),
+    );
+    Ok(response)
+}
+
+/// Follow the fork parents of a paste, nearest first.
+///
+/// Deleted parents end the chain.
+async fn load_fork_chain(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    mut parent_id: Option<String>,
+) -> Vec<String> {
+    let mut chain: Vec<String> = vec![];
+    while let Some(id) = parent_id.take() {
+        if chain.len() >= MAX_FORK_CHAIN || chain.contains(&id) {
+            break;
+        }
+
+        let data = redis::cmd("GET")
+            .arg(entry_key(prefix, &id))
+            .query_async::<Option<Vec<u8>>>(connection)
+            .await;
+        let Ok(Some(data)) = data else {
+            break;
+        };
+        if let Ok(CDNData::Code {
+            parent_id: next, ..
+        }) = CDNData::decode(&data)
+        {
+            parent_id = next;
         }
+        chain.push(id);
     }
+    chain
 }
 
-pub async fn file_reader_raw(
-    method: axum::http::Method,
-    State(state): State<Arc<SharedState>>,
-    Path(id_path): Path<String>,
-) -> Response {
-    // Placeholder for file reading logic
+/// How a paste is served, [`HtmlPolicy::Paste`] for anything but HTML.
+fn html_policy(config: &IhaCdnConfig, extension: &str, is_admin: bool) -> HtmlPolicy {
+    let is_html = mime_guess::from_ext(extension)
+        .first()
+        .is_some_and(|mime| matches!(mime.essence_str(), "text/html" | "application/xhtml+xml"));
+    match config.paste.html_policy {
+        _ if !is_html => HtmlPolicy::Paste,
+        HtmlPolicy::AdminRaw if !is_admin => HtmlPolicy::Paste,
+        policy => policy,
+    }
+}
+
+/// Mimetypes that browsers execute or render as documents.
+fn is_active_content(mimetype: &str) -> bool {
+    matches!(
+        mimetype,
+        "text/html" | "application/xhtml+xml" | "image/svg+xml" | "text/xml" | "application/xml"
+    )
+}
+
+/// Stream a file from disk, only the headers are sent for `HEAD` requests.
+///
+/// `completed` is notified once the whole file was sent, failing to open the file is reported
+/// with [`IhaCdnError::file`]. With `conditional`, a client holding the current copy is
+/// answered with `304 Not Modified`.
+#[allow(clippy::too_many_arguments)]
+async fn stream_file(
+    method: &axum::http::Method,
+    id_path: &str,
+    path: &std::path::Path,
+    mimetype: &str,
+    disposition: &str,
+    cache_policy: Option<&str>,
+    custom_headers: &BTreeMap<String, String>,
+    conditional: Option<Conditional<'_>>,
+    completed: Option<tokio::sync::oneshot::Sender<()>>,
+) -> Result<Response, IhaCdnError> {
+    let mut stream = tokio::fs::File::open(path)
+        .await
+        .map_err(|err| IhaCdnError::file(err, id_path))?;
+    let data = stream
+        .metadata()
+        .await
+        .map_err(|err| IhaCdnError::file(err, id_path))?;
+
+    let validators = conditional
+        .as_ref()
+        .map(|conditional| Validators::new(conditional.content_hash, &data));
+    if let (Some(conditional), Some(validators)) = (&conditional, &validators)
+        && validators.is_fresh(conditional.request)
+    {
+        return Ok(not_modified(validators, cache_policy, custom_headers));
+    }
+
+    // Only entries that may be revalidated are served in parts, every serve of the others counts
+    let range = match (&conditional, &validators) {
+        (Some(conditional), Some(validators)) => {
+            requested_range(conditional.request, validators, data.len())
+        }
+        _ => ByteRange::Full,
+    };
+    let (status, start, length) = match range {
+        ByteRange::Full => (StatusCode::OK, 0, data.len()),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        ByteRange::Unsatisfiable => {
+            let mut builder = axum::http::Response::builder();
+            let headers = builder.headers_mut().unwrap();
+            headers.insert(
+                axum::http::header::CONTENT_RANGE,
+                header_value(
+                    axum::http::header::CONTENT_RANGE,
+                    &format!("bytes */{}", data.len()),
+                )?,
+            );
+            apply_custom_headers(headers, custom_headers);
+            return Ok(builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
+
+    let file_name_part = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut raw_headers = vec![
+        (axum::http::header::CONTENT_TYPE, mimetype.to_string()),
+        (axum::http::header::CONTENT_LENGTH, length.to_string()),
+        (
+            axum::http::header::CONTENT_DISPOSITION,
+            content_disposition(disposition, &file_name_part),
+        ),
+    ];
+    if status == StatusCode::PARTIAL_CONTENT {
+        raw_headers.push((
+            axum::http::header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, start + length - 1, data.len()),
+        ));
+    }
+
+    let mut builder = axum::http::Response::builder();
+    let headers = builder.headers_mut().unwrap();
+    for (key, value) in raw_headers {
+        let value = header_value(key.clone(), &value)?;
+        headers.insert(key, value);
+    }
+    apply_cache_control(headers, cache_policy);
+    apply_custom_headers(headers, custom_headers);
+    if let Some(validators) = &validators {
+        validators.apply(headers);
+        headers.insert(
+            axum::http::header::ACCEPT_RANGES,
+            HeaderValue::from_static("bytes"),
+        );
+    }
+
+    let (mut tx, rx) = tokio::io::duplex(64 * 1024);
+    let body = AsyncReadBody::new(rx);
+
+    if method != axum::http::Method::HEAD {
+        if start > 0 {
+            stream
+                .seek(std::io::SeekFrom::Start(start))
+                .await
+                .map_err(|err| IhaCdnError::file(err, id_path))?;
+        }
+        tokio::spawn(async move {
+            let copied = tokio::io::copy(&mut stream.take(length), &mut tx)
+                .await
+                .is_ok();
+            if tx.flush().await.is_ok()
+                && copied
+                && let Some(completed) = completed
+            {
+                let _ = completed.send(());
+            }
+        });
+    }
+
+    Ok(builder.status(status).body(body).unwrap().into_response())
+}
+
+/// Whether a file response is a new view, players fetch a video in many parts after its start.
+fn is_new_view(response: &Response) -> bool {
+    match response.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT => response
+            .headers()
+            .get(axum::http::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|range| range.starts_with("bytes 0-")),
+        _ => false,
+    }
+}
+
+/// The part of a file a request asks for.
+enum ByteRange {
+    Full,
+    /// The first and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// The single byte range of the `Range` header of a request, anything else gets the whole file.
+///
+/// An `If-Range` that doesn't match the current validators also gets the whole file.
+fn requested_range(request: &HeaderMap, validators: &Validators, size: u64) -> ByteRange {
+    let Some(range) = request
+        .get(axum::http::header::RANGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return ByteRange::Full;
+    };
+    if let Some(if_range) = request
+        .get(axum::http::header::IF_RANGE)
+        .and_then(|value| value.to_str().ok())
+        && !validators.matches_if_range(if_range)
+    {
+        return ByteRange::Full;
+    }
+
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    // Multiple ranges would need a multipart body, which players never ask for
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        // The last bytes of the file
+        match last.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        let Ok(start) = first.parse::<u64>() else {
+            return ByteRange::Full;
+        };
+        let end = match last {
+            "" => size.saturating_sub(1),
+            last => match last.parse::<u64>() {
+                Ok(end) if end >= start => end.min(size.saturating_sub(1)),
+                _ => return ByteRange::Full,
+            },
+        };
+        (start, end)
+    };
+    if size == 0 || start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+/// Delete a burn-after-read file once its download completed, or release the claim on it if
+/// the download failed so it can be retried.
+async fn burn_after_download(
+    state: Arc<SharedState>,
+    raw_id: String,
+    completed: tokio::sync::oneshot::Receiver<()>,
+) {
+    let downloaded = completed.await.is_ok();
     let mut connection = match state.make_connection().await {
         Ok(connection) => connection,
         Err(err) => {
             tracing::error!("Failed to connect to Redis: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, REDIS_CONNECTION_ERROR).into_response();
+            return;
         }
     };
 
-    // Split id_path into ID and extension
-    let (raw_id, _) = match id_path.rsplit_once('.') {
-        Some((id, ext)) => (id.to_string(), ext.to_string()),
-        None => (id_path.clone(), String::new()),
-    };
+    if downloaded {
+        destroy_entry(&state, &mut connection, &raw_id, TombstoneReason::Burned).await;
+    }
+    if let Err(err) = redis::cmd("DEL")
+        .arg(burn_claim_key(&state.config.key_prefix, &raw_id))
+        .exec_async(&mut connection)
+        .await
+    {
+        tracing::error!("Failed to release download claim of {}: {}", raw_id, err);
+    }
+}
+
+/// Decrement the remaining views, without creating the counter if it is missing.
+const CONSUME_VIEW_SCRIPT: &str = r#"
+if redis.call("EXISTS", KEYS[1]) == 1 then
+    return redis.call("DECR", KEYS[1])
+end
+return -1
+"#;
+
+/// Removes an entry that used up its last view once dropped, i.e. after it was served.
+struct LastView(Option<Box<dyn FnOnce() + Send>>);
+
+impl LastView {
+    fn destroy(state: &Arc<SharedState>, raw_id: &str) -> Self {
+        let state = Arc::clone(state);
+        let raw_id = raw_id.to_string();
+        Self(Some(Box::new(move || {
+            tokio::spawn(async move {
+                match state.make_connection().await {
+                    Ok(mut connection) => {
+                        destroy_entry(&state, &mut connection, &raw_id, TombstoneReason::ViewLimit)
+                            .await;
+                    }
+                    Err(err) => tracing::error!("Failed to connect to Redis: {}", err),
+                }
+            });
+        })))
+    }
+
+    /// Keep the entry until the body of the response was sent, or the client went away.
+    fn attach(self, response: Response) -> Response {
+        if self.0.is_none() {
+            return response;
+        }
+        response.map(|body| {
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                let _ = &self;
+                chunk
+            }))
+        })
+    }
+}
+
+impl Drop for LastView {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.0.take() {
+            destroy();
+        }
+    }
+}
+
+/// Count a serve against the view limit of the entry, `HEAD` requests are free.
+///
+/// Entries without views left are gone, the guard removes the entry after its last view.
+async fn consume_view(
+    state: &Arc<SharedState>,
+    method: &axum::http::Method,
+    raw_id: &str,
+    id_path: &str,
+    cdn_data: &CDNData,
+) -> Result<LastView, IhaCdnError> {
+    if cdn_data.max_views().is_none() || method == axum::http::Method::HEAD {
+        return Ok(LastView(None));
+    }
 
-    match redis::cmd("GET")
-        .arg(format!("{PREFIX}{}", &raw_id))
-        .query_async::<Option<String>>(&mut connection)
+    let mut connection = state
+        .make_connection()
         .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let remaining = take_view(
+        &mut connection,
+        &remaining_views_key(&state.config.key_prefix, raw_id),
+    )
+    .await
+    .map_err(IhaCdnError::RedisSave)?;
+    match remaining {
+        ..0 => Err(IhaCdnError::gone(id_path)),
+        0 => Ok(LastView::destroy(state, raw_id)),
+        _ => Ok(LastView(None)),
+    }
+}
+
+/// Take a view from the remaining views at `key`, returns the views left after it or `-1` if
+/// there were none left.
+async fn take_view(connection: &mut RedisConnection, key: &str) -> RedisResult<i64> {
+    redis::cmd("EVAL")
+        .arg(CONSUME_VIEW_SCRIPT)
+        .arg(1)
+        .arg(key)
+        .query_async::<i64>(connection)
+        .await
+}
+
+/// Check that the entry has views left without counting one.
+async fn check_views_left(
+    state: &SharedState,
+    raw_id: &str,
+    id_path: &str,
+    cdn_data: &CDNData,
+) -> Result<(), IhaCdnError> {
+    if cdn_data.max_views().is_none() {
+        return Ok(());
+    }
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let remaining = redis::cmd("GET")
+        .arg(remaining_views_key(&state.config.key_prefix, raw_id))
+        .query_async::<Option<i64>>(&mut connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: id_path.to_string(),
+            source,
+        })?;
+    match remaining {
+        Some(remaining) if remaining > 0 => Ok(()),
+        _ => Err(IhaCdnError::gone(id_path)),
+    }
+}
+
+/// Answer a `HEAD` request by checking that the stored file still exists.
+async fn peek_file(builder: axum::http::response::Builder, path: &std::path::Path) -> Response {
+    let status = match tokio::fs::try_exists(path).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::GONE,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => StatusCode::GONE,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    builder.status(status).body(Body::empty()).unwrap()
+}
+
+/// The error of an entry that does not exist.
+///
+/// Entries that were removed and still have a tombstone are gone instead of unknown.
+async fn missing_entry(
+    state: &SharedState,
+    connection: &mut RedisConnection,
+    raw_id: &str,
+    id_path: &str,
+) -> IhaCdnError {
+    if state.config.tombstone.enable
+        && let Some(tombstone) = load_tombstone(connection, &state.config.key_prefix, raw_id).await
     {
-        Ok(Some(data)) => {
-            let parsed_data = match serde_json::from_str::<CDNData>(&data) {
-                Ok(parsed_data) => parsed_data,
+        return IhaCdnError::Gone {
+            id_path: id_path.to_string(),
+            tombstone: Some(tombstone),
+        };
+    }
+    IhaCdnError::not_found(id_path)
+}
+
+/// Get and parse an entry from the primary.
+async fn load_entry(
+    state: &SharedState,
+    connection: &mut RedisConnection,
+    raw_id: &str,
+    id_path: &str,
+) -> Result<CDNData, IhaCdnError> {
+    let data = redis::cmd("GET")
+        .arg(entry_key(&state.config.key_prefix, raw_id))
+        .query_async::<Option<Vec<u8>>>(connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: id_path.to_string(),
+            source,
+        })?;
+
+    match data {
+        Some(data) => {
+            let data = CDNData::decode(&data).map_err(IhaCdnError::ParseData)?;
+            state.breaker.remember(raw_id, &data);
+            Ok(data)
+        }
+        None => Err(missing_entry(state, connection, raw_id, id_path).await),
+    }
+}
+
+/// Get a public entry from a replica.
+///
+/// Replicas may lag behind, so anything they don't have is looked up on the primary, and so is
+/// every entry moderation, its ACL or a view limit applies to.
+async fn load_replicated(state: &SharedState, raw_id: &str, id_path: &str) -> Option<CDNData> {
+    let mut replica = state.replicas.make_connection().await?;
+    let data = redis::cmd("GET")
+        .arg(entry_key(&state.config.key_prefix, raw_id))
+        .query_async::<Option<Vec<u8>>>(&mut replica)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::warn!("Failed to read {} from a Redis replica: {}", id_path, err);
+            None
+        })?;
+    let data = CDNData::decode(&data).ok()?;
+    if data.is_held() || !data.is_public(chrono::Utc::now().timestamp()) {
+        return None;
+    }
+    state.breaker.remember(raw_id, &data);
+    Some(data)
+}
+
+/// The connection to the primary of a request, only opened once it is needed.
+enum PrimaryConnection {
+    Open(RedisConnection),
+    /// The entry was read from a replica and nothing needed the primary yet.
+    Deferred,
+    /// Redis is down, the entry is served from memory.
+    Unavailable,
+}
+
+impl PrimaryConnection {
+    async fn get(&mut self, state: &SharedState) -> Option<&mut RedisConnection> {
+        if let PrimaryConnection::Deferred = self {
+            *self = match state.make_connection().await {
+                Ok(connection) => PrimaryConnection::Open(connection),
                 Err(err) => {
-                    tracing::error!("Failed to parse data: {}", err);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse data")
-                        .into_response();
+                    tracing::warn!("Failed to connect to Redis: {}", err);
+                    PrimaryConnection::Unavailable
                 }
             };
+        }
+        match self {
+            PrimaryConnection::Open(connection) => Some(connection),
+            _ => None,
+        }
+    }
+}
 
-            match parsed_data {
-                CDNData::Code {
-                    is_admin: _,
-                    path,
-                    mimetype,
-                    time_added: _,
-                } => {
-                    let actual_mimetype = match mime_guess::from_ext(&mimetype)
-                        .first()
-                        .map(|m| m.essence_str().to_string())
-                    {
-                        Some(mime) => mime,
-                        None => "text/plain".to_string(),
-                    };
-
-                    if method == axum::http::Method::HEAD {
-                        // Peek file if exists
-                        let mut builder = axum::http::Response::builder();
-                        let headers = builder.headers_mut().unwrap();
-
-                        headers.insert(
-                            axum::http::header::CONTENT_TYPE,
-                            actual_mimetype.parse().unwrap(),
-                        );
-
-                        match tokio::fs::try_exists(path).await {
-                            Ok(true) => {
-                                return builder
-                                    .status(axum::http::StatusCode::OK)
-                                    .body(Body::empty())
-                                    .unwrap()
-                                    .into_response();
-                            }
-                            Ok(false) => {
-                                return builder
-                                    .status(axum::http::StatusCode::GONE)
-                                    .body(Body::empty())
-                                    .unwrap()
-                                    .into_response();
-                            }
-                            Err(err) => {
-                                if err.kind() == std::io::ErrorKind::NotFound {
-                                    return builder
-                                        .status(axum::http::StatusCode::GONE)
-                                        .body(Body::empty())
-                                        .unwrap()
-                                        .into_response();
-                                } else {
-                                    return builder
-                                        .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                                        .body(Body::empty())
-                                        .unwrap()
-                                        .into_response();
-                                };
-                            }
-                        }
-                    };
-
-                    // send as attachment data
-                    match tokio::fs::read_to_string(&path).await {
-                        Ok(content) => {
-                            let builder = axum::http::Response::builder()
-                                .header(
-                                    axum::http::header::CONTENT_DISPOSITION,
-                                    format!(
-                                        "attachment; filename=\"{}\"",
-                                        path.file_name().unwrap_or_default().to_string_lossy()
-                                    ),
-                                )
-                                .header(axum::http::header::CONTENT_LENGTH, content.len())
-                                .header(axum::http::header::CONTENT_TYPE, actual_mimetype)
-                                .body(Body::from(content))
-                                .unwrap();
-                            builder.into_response()
-                        }
-                        Err(err) => {
-                            if err.kind() == std::io::ErrorKind::NotFound {
-                                tracing::warn!("File not found: {}", path.display());
-                                let missing_key =
-                                    DELETED_ERROR.to_string().replace("{{ FN }}", &id_path);
-                                (StatusCode::GONE, missing_key).into_response()
-                            } else {
-                                tracing::error!("Failed to read file: {}", err);
-                                let read_error =
-                                    READ_FILE_ERROR.to_string().replace("{{ FN }}", &id_path);
-                                (StatusCode::INTERNAL_SERVER_ERROR, read_error).into_response()
-                            }
-                        }
-                    }
-                }
-                CDNData::File { .. } => {
-                    let missing_key = DELETED_ERROR.to_string().replace("{{ FN }}", &id_path);
-                    (StatusCode::NOT_FOUND, missing_key).into_response()
-                }
-                CDNData::Short { .. } => {
-                    let missing_key = DELETED_ERROR.to_string().replace("{{ FN }}", &id_path);
-                    (StatusCode::NOT_FOUND, missing_key).into_response()
-                }
+/// Get an entry, from a replica if it has it or else from the primary.
+///
+/// While Redis is down, an entry read recently is served without a connection instead.
+async fn connect_and_load(
+    state: &SharedState,
+    raw_id: &str,
+    id_path: &str,
+) -> Result<(PrimaryConnection, CDNData), IhaCdnError> {
+    if let Some(data) = load_replicated(state, raw_id, id_path).await {
+        return Ok((PrimaryConnection::Deferred, data));
+    }
+
+    match state.make_connection().await {
+        Ok(mut connection) => {
+            let data = load_entry(state, &mut connection, raw_id, id_path).await?;
+            Ok((PrimaryConnection::Open(connection), data))
+        }
+        Err(err) => match state.breaker.cached(raw_id) {
+            Some(data) => {
+                tracing::warn!(
+                    "Serving {} from memory, Redis is unavailable: {}",
+                    id_path,
+                    err
+                );
+                Ok((PrimaryConnection::Unavailable, data))
             }
+            None => Err(IhaCdnError::RedisConnection(err)),
+        },
+    }
+}
+
+/// Count a view of an entry, entries served from memory while Redis is down aren't counted.
+async fn count_served_view(
+    state: &SharedState,
+    connection: Option<&mut RedisConnection>,
+    raw_id: &str,
+) -> Option<u64> {
+    count_view(connection?, &state.config.key_prefix, raw_id).await
+}
+
+/// Fetch an entry that can be served, quarantined entries and entries the viewer is not
+/// allowed to see are refused.
+async fn fetch_entry(
+    state: &SharedState,
+    viewer: &Viewer,
+    raw_id: &str,
+    id_path: &str,
+) -> Result<CDNData, IhaCdnError> {
+    let (_, parsed_data) = connect_and_load(state, raw_id, id_path).await?;
+    if parsed_data.is_quarantined() {
+        return Err(IhaCdnError::quarantined(id_path));
+    }
+    viewer.check(&parsed_data, id_path)?;
+    Ok(parsed_data)
+}
+
+/// Fetch an entry to serve something made from its content, e.g. a thumbnail.
+///
+/// Entries with a view limit are refused, these routes don't count views.
+async fn fetch_derived_entry(
+    state: &SharedState,
+    viewer: &Viewer,
+    raw_id: &str,
+    id_path: &str,
+) -> Result<CDNData, IhaCdnError> {
+    let parsed_data = fetch_entry(state, viewer, raw_id, id_path).await?;
+    if parsed_data.burns_after_read() || parsed_data.max_views().is_some() {
+        return Err(IhaCdnError::not_found(id_path));
+    }
+    Ok(parsed_data)
+}
+
+/// Serve the poster frame of a video.
+pub async fn file_reader_poster(
+    State(state): State<Arc<SharedState>>,
+    viewer: Viewer,
+    Path(id_path): Path<String>,
+) -> Result<Response, IhaCdnError> {
+    let (raw_id, _) = split_id_path(&id_path).ok_or_else(|| IhaCdnError::not_found(&id_path))?;
+    let parsed_data = fetch_derived_entry(&state, &viewer, &raw_id, &id_path).await?;
+
+    let CDNData::File {
+        poster: Some(poster),
+        ..
+    } = &parsed_data
+    else {
+        return Err(IhaCdnError::not_found(&id_path));
+    };
+
+    // Poster frames are small enough to be read at once
+    let poster = &state.config.resolve_path(poster).await;
+    let content = tokio::fs::read(poster)
+        .await
+        .map_err(|err| IhaCdnError::file(err, &id_path))?;
+    let mut response = (
+        [(axum::http::header::CONTENT_TYPE, "image/jpeg")],
+        Body::from(content),
+    )
+        .into_response();
+    apply_cache_control(
+        response.headers_mut(),
+        cache_policy(
+            cache_override(&parsed_data),
+            state.config.cache.image.as_deref(),
+        ),
+    );
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct ThumbParams {
+    /// The largest width, in pixels.
+    w: Option<u32>,
+    /// The largest height, in pixels.
+    h: Option<u32>,
+    /// `png`, `jpeg` or `webp`, the format of the image by default.
+    format: Option<String>,
+}
+
+/// Serve a resized copy of an image, made on the first request and kept with the entry.
+pub async fn file_reader_thumb(
+    State(state): State<Arc<SharedState>>,
+    viewer: Viewer,
+    Path(id_path): Path<String>,
+    Query(params): Query<ThumbParams>,
+) -> Result<Response, IhaCdnError> {
+    let config = &state.config.thumbnails;
+    if !config.enable {
+        return Err(IhaCdnError::not_found(&id_path));
+    }
+    let (raw_id, _) = split_id_path(&id_path).ok_or_else(|| IhaCdnError::not_found(&id_path))?;
+    if params.w.is_none() && params.h.is_none() {
+        return Err(IhaCdnError::BadRequest(
+            "Give the width `w` or the height `h` of the thumbnail".to_string(),
+        ));
+    }
+    if [params.w, params.h]
+        .into_iter()
+        .flatten()
+        .any(|size| !(1..=config.max_dimension).contains(&size))
+    {
+        return Err(IhaCdnError::BadRequest(format!(
+            "The thumbnail size must be between 1 and {} pixels",
+            config.max_dimension
+        )));
+    }
+    let parsed_data = fetch_derived_entry(&state, &viewer, &raw_id, &id_path).await?;
+
+    let CDNData::File {
+        path,
+        mimetype,
+        thumbnails,
+        ..
+    } = &parsed_data
+    else {
+        return Err(IhaCdnError::not_found(&id_path));
+    };
+    let format = match (
+        ThumbnailFormat::of_mimetype(mimetype),
+        params.format.as_deref(),
+    ) {
+        (None, _) => {
+            return Err(IhaCdnError::BadRequest(
+                "Thumbnails are only made of PNG, JPEG, GIF and WebP images".to_string(),
+            ));
+        }
+        (Some(_), Some(format)) => ThumbnailFormat::parse(format).ok_or_else(|| {
+            IhaCdnError::BadRequest(format!(
+                "Unknown thumbnail format {format}, use png, jpeg or webp"
+            ))
+        })?,
+        (Some(format), None) => format,
+    };
+
+    let source = state.config.resolve_path(path).await;
+    let output = thumbnail_path(&source, params.w, params.h, format);
+    // Matched by name, the thumbnails follow their image when it moves to another volume
+    let known = thumbnails
+        .iter()
+        .any(|thumbnail| thumbnail.file_name() == output.file_name());
+    let mut thumbnail = state.config.resolve_path(&output).await;
+    if !known || !tokio::fs::try_exists(&thumbnail).await.unwrap_or(false) {
+        if !known && thumbnails.len() >= config.max_per_entry {
+            return Err(IhaCdnError::BadRequest(format!(
+                "No more thumbnail sizes can be made of {id_path}"
+            )));
         }
-        Ok(None) => {
-            tracing::warn!("No data found for ID: {}", raw_id);
-            let missing_key = DELETED_ERROR.to_string().replace("{{ FN }}", &id_path);
-            (StatusCode::NOT_FOUND, missing_key).into_response()
+        let size = tokio::fs::metadata(&source)
+            .await
+            .map_err(|err| IhaCdnError::file(err, &id_path))?
+            .len();
+        if size > config.max_source_size * 1024 {
+            return Err(IhaCdnError::BadRequest(format!(
+                "{id_path} is too large to make thumbnails of"
+            )));
         }
-        Err(err) => {
-            tracing::error!("Failed to get data from Redis: {}", err);
-            let fetch_error = REDIS_GET_ERROR.to_string().replace("{{ FN }}", &id_path);
-            (StatusCode::INTERNAL_SERVER_ERROR, fetch_error).into_response()
+
+        // Only made when it can be recorded, otherwise purge would never find it
+        let mut connection = state
+            .make_connection()
+            .await
+            .map_err(IhaCdnError::RedisConnection)?;
+        make_thumbnail(source, output.clone(), params.w, params.h, format)
+            .await
+            .map_err(|reason| IhaCdnError::TaskFailed {
+                task: "thumbnail",
+                reason,
+            })?;
+        let recorded = record_thumbnail(&state, &mut connection, &raw_id, &output)
+            .await
+            .map_err(IhaCdnError::RedisSave)?;
+        if !recorded {
+            // The entry was deleted while the thumbnail was made
+            let _ = tokio::fs::remove_file(&output).await;
+            return Err(IhaCdnError::not_found(&id_path));
         }
+        thumbnail = output;
+    }
+
+    // Thumbnails are small enough to be read at once
+    let content = tokio::fs::read(&thumbnail)
+        .await
+        .map_err(|err| IhaCdnError::file(err, &id_path))?;
+    let mut response = (
+        [(axum::http::header::CONTENT_TYPE, format.mimetype())],
+        Body::from(content),
+    )
+        .into_response();
+    apply_cache_control(
+        response.headers_mut(),
+        cache_policy(
+            cache_override(&parsed_data),
+            state.config.cache.image.as_deref(),
+        ),
+    );
+    Ok(response)
+}
+
+/// Serve a previous version of an edited paste as plain text, `0` is the newest.
+pub async fn file_reader_revision(
+    method: axum::http::Method,
+    State(state): State<Arc<SharedState>>,
+    viewer: Viewer,
+    Path((id_path, index)): Path<(String, usize)>,
+) -> Result<Response, IhaCdnError> {
+    let (raw_id, _) = split_id_path(&id_path).ok_or_else(|| IhaCdnError::not_found(&id_path))?;
+    let parsed_data = fetch_entry(&state, &viewer, &raw_id, &id_path).await?;
+
+    let CDNData::Code {
+        revisions,
+        headers: custom_headers,
+        ..
+    } = &parsed_data
+    else {
+        return Err(IhaCdnError::not_found(&id_path));
+    };
+    let revision = revisions
+        .get(index)
+        .ok_or_else(|| IhaCdnError::not_found(&id_path))?;
+    let last_view = consume_view(&state, &method, &raw_id, &id_path, &parsed_data).await?;
+
+    stream_file(
+        &method,
+        &id_path,
+        &state.config.resolve_path(&revision.path).await,
+        "text/plain; charset=utf-8",
+        "inline",
+        cache_policy(
+            cache_override(&parsed_data),
+            state.config.cache.paste.as_deref(),
+        ),
+        custom_headers,
+        None,
+        None,
+    )
+    .await
+    .map(|response| last_view.attach(response))
+}
+
+/// Public information about an entry.
+pub async fn file_info(
+    State(state): State<Arc<SharedState>>,
+    viewer: Viewer,
+    Path(id_path): Path<String>,
+) -> Response {
+    entry_info(&state, &viewer, &id_path)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn entry_info(
+    state: &SharedState,
+    viewer: &Viewer,
+    id_path: &str,
+) -> Result<Response, IhaCdnError> {
+    let (raw_id, _) = split_id_path(id_path).ok_or_else(|| IhaCdnError::not_found(id_path))?;
+    let parsed_data = fetch_entry(state, viewer, &raw_id, id_path).await?;
+
+    let mut info = serde_json::Map::new();
+    info.insert("id".to_string(), raw_id.clone().into());
+    info.insert("type".to_string(), parsed_data.kind().into());
+    match &parsed_data {
+        CDNData::Short {
+            target, time_added, ..
+        } => {
+            info.insert("url".to_string(), state.config.make_url(&raw_id).into());
+            info.insert("target".to_string(), target.clone().into());
+            if let Some(time_added) = time_added {
+                info.insert("time_added".to_string(), (*time_added).into());
+            }
+        }
+        CDNData::File {
+            path,
+            mimetype,
+            time_added,
+            poster,
+            transcode,
+            burn_after_read,
+            ..
+        } => {
+            if *burn_after_read {
+                info.insert("burn_after_read".to_string(), true.into());
+            }
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            info.insert(
+                "url".to_string(),
+                state.config.make_content_url(&file_name).into(),
+            );
+            info.insert("mimetype".to_string(), mimetype.clone().into());
+            info.insert("time_added".to_string(), (*time_added).into());
+            if let Ok(metadata) = tokio::fs::metadata(state.config.resolve_path(path).await).await {
+                info.insert("size".to_string(), metadata.len().into());
+            }
+            if poster.is_some() {
+                info.insert(
+                    "poster".to_string(),
+                    state
+                        .config
+                        .make_content_url(&format!("{raw_id}/poster"))
+                        .into(),
+                );
+            }
+            if let Some(transcode) = transcode {
+                info.insert(
+                    "transcode".to_string(),
+                    serde_json::json!({
+                        "status": transcode.status,
+                        "mimetype": transcode.mimetype,
+                        "original": state.config.make_content_url(&format!("{raw_id}/raw")),
+                    }),
+                );
+            }
+        }
+        CDNData::Code {
+            path,
+            mimetype,
+            time_added,
+            encrypted,
+            parent_id,
+            revisions,
+            language,
+            ..
+        } => {
+            info.insert("url".to_string(), state.config.make_url(&raw_id).into());
+            info.insert(
+                "language".to_string(),
+                language.as_ref().unwrap_or(mimetype).clone().into(),
+            );
+            info.insert("time_added".to_string(), (*time_added).into());
+            info.insert("encrypted".to_string(), (*encrypted).into());
+            if let Some(parent_id) = parent_id {
+                info.insert("parent_id".to_string(), parent_id.clone().into());
+            }
+            let revisions = revisions
+                .iter()
+                .enumerate()
+                .map(|(index, revision)| {
+                    serde_json::json!({
+                        "url": state.config.make_content_url(&format!("{raw_id}/revisions/{index}")),
+                        "time_replaced": revision.time_replaced,
+                    })
+                })
+                .collect::<Vec<_>>();
+            info.insert("revisions".to_string(), revisions.into());
+            if let Ok(metadata) = tokio::fs::metadata(state.config.resolve_path(path).await).await {
+                info.insert("size".to_string(), metadata.len().into());
+            }
+        }
+    }
+
+    if parsed_data.is_restricted() {
+        info.insert("restricted".to_string(), true.into());
+    }
+    if parsed_data.is_listed() {
+        info.insert("listed".to_string(), true.into());
+    }
+    if let Some(metadata) = parsed_data.metadata() {
+        info.insert(
+            "metadata".to_string(),
+            serde_json::to_value(metadata).unwrap(),
+        );
+    }
+    let (not_before, not_after) = parsed_data.availability();
+    if let Some(not_before) = not_before {
+        info.insert("not_before".to_string(), not_before.into());
+    }
+    if let Some(not_after) = not_after {
+        info.insert("not_after".to_string(), not_after.into());
+    }
+
+    let file_size = info.get("size").and_then(|size| size.as_u64()).unwrap_or(0);
+    if let Some(expires_at) = parsed_data.expires_at(&state.config, file_size) {
+        info.insert("expires_at".to_string(), expires_at.into());
+    }
+
+    if let Ok(mut connection) = state.make_connection().await {
+        if let Some(views) = get_views(&mut connection, &state.config.key_prefix, &raw_id).await {
+            info.insert("views".to_string(), views.into());
+        }
+        if let Some(max_views) = parsed_data.max_views() {
+            info.insert("max_views".to_string(), max_views.into());
+            let remaining = redis::cmd("GET")
+                .arg(remaining_views_key(&state.config.key_prefix, &raw_id))
+                .query_async::<Option<u64>>(&mut connection)
+                .await;
+            if let Ok(Some(remaining)) = remaining {
+                info.insert("views_remaining".to_string(), remaining.into());
+            }
+        }
+    }
+
+    Ok((StatusCode::OK, Json(serde_json::Value::Object(info))).into_response())
+}
+
+/// The `Cache-Control` policy an entry needs regardless of its content class.
+fn cache_override(cdn_data: &CDNData) -> Option<&'static str> {
+    if cdn_data.is_restricted() || cdn_data.burns_after_read() || cdn_data.max_views().is_some() {
+        Some(PRIVATE_CACHE)
+    } else if cdn_data.availability() != (None, None) {
+        Some(WINDOWED_CACHE)
+    } else {
+        None
+    }
+}
+
+/// The `Cache-Control` policy of an entry, the override of the entry wins over the class.
+fn cache_policy<'a>(
+    cache_override: Option<&'static str>,
+    policy: Option<&'a str>,
+) -> Option<&'a str> {
+    cache_override.or(policy)
+}
+
+/// Whether the `Accept` header of a request explicitly asks for a mimetype, wildcards only
+/// count within its type.
+fn accepts_mimetype(headers: &HeaderMap, mimetype: &str) -> bool {
+    let any_subtype = mimetype
+        .split_once('/')
+        .map(|(kind, _)| format!("{kind}/*"));
+    headers
+        .get_all(axum::http::header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let essence = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            !refused
+                && (essence.eq_ignore_ascii_case(mimetype)
+                    || any_subtype
+                        .as_deref()
+                        .is_some_and(|any| essence.eq_ignore_ascii_case(any)))
+        })
+}
+
+/// Whether a client may revalidate its copy of an entry, every serve of an entry with a view
+/// limit must be counted instead.
+fn is_revalidable(cdn_data: &CDNData) -> bool {
+    !cdn_data.burns_after_read() && cdn_data.max_views().is_none()
+}
+
+/// The conditional headers of a request, with the hash of the content it asks for.
+struct Conditional<'a> {
+    request: &'a HeaderMap,
+    content_hash: Option<&'a str>,
+}
+
+/// The `ETag` and `Last-Modified` of a stored file.
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Validators {
+    fn new(content_hash: Option<&str>, metadata: &std::fs::Metadata) -> Self {
+        Self {
+            etag: content_hash.map(|hash| format!("\"{hash}\"")),
+            last_modified: metadata.modified().ok().map(chrono::DateTime::from),
+        }
+    }
+
+    /// Whether the copy the client has is still current, `If-None-Match` wins over
+    /// `If-Modified-Since` like RFC 9110 asks.
+    fn is_fresh(&self, request: &HeaderMap) -> bool {
+        if let Some(if_none_match) = request.get(axum::http::header::IF_NONE_MATCH) {
+            let Some(etag) = &self.etag else {
+                return false;
+            };
+            return if_none_match.to_str().is_ok_and(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag.as_str())
+            });
+        }
+
+        let since = request
+            .get(axum::http::header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| chrono::DateTime::parse_from_rfc2822(value).ok());
+        match (since, self.last_modified) {
+            (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    /// Whether an `If-Range` names the current copy, dates must match exactly.
+    fn matches_if_range(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') {
+            return self.etag.as_deref() == Some(if_range);
+        }
+        match (
+            chrono::DateTime::parse_from_rfc2822(if_range),
+            self.last_modified,
+        ) {
+            (Ok(since), Some(last_modified)) => since.timestamp() == last_modified.timestamp(),
+            _ => false,
+        }
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = &self.etag
+            && let Ok(value) = HeaderValue::from_str(etag)
+        {
+            headers.insert(axum::http::header::ETAG, value);
+        }
+        if let Some(last_modified) = self.last_modified
+            && let Ok(value) = HeaderValue::from_str(
+                &last_modified
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string(),
+            )
+        {
+            headers.insert(axum::http::header::LAST_MODIFIED, value);
+        }
+    }
+}
+
+/// Answer a request whose copy is still current, with the headers it would have been served.
+fn not_modified(
+    validators: &Validators,
+    cache_policy: Option<&str>,
+    custom_headers: &BTreeMap<String, String>,
+) -> Response {
+    let mut builder = axum::http::Response::builder();
+    let headers = builder.headers_mut().unwrap();
+    apply_cache_control(headers, cache_policy);
+    apply_custom_headers(headers, custom_headers);
+    validators.apply(headers);
+    builder
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Apply the configured `Cache-Control` policy of a content class.
+fn apply_cache_control(headers: &mut HeaderMap, policy: Option<&str>) {
+    if let Some(policy) = policy {
+        match HeaderValue::from_str(policy) {
+            Ok(value) => {
+                headers.insert(axum::http::header::CACHE_CONTROL, value);
+            }
+            Err(_) => tracing::warn!("Invalid Cache-Control policy: {}", policy),
+        }
+    }
+}
+
+/// A header built from a stored value, an invalid value fails the request instead of panicking.
+fn header_value(name: HeaderName, value: &str) -> Result<HeaderValue, IhaCdnError> {
+    HeaderValue::from_str(value).map_err(|source| IhaCdnError::InvalidHeader { name, source })
+}
+
+/// Apply the per-entry custom headers, skipping anything that is no longer a valid header.
+fn apply_custom_headers(headers: &mut HeaderMap, custom_headers: &BTreeMap<String, String>) {
+    for (name, value) in custom_headers {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => tracing::warn!("Skipping invalid custom header: {}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcoded_file(burn_after_read: bool) -> CDNData {
+        serde_json::from_value(serde_json::json!({
+            "type": "file",
+            "is_admin": false,
+            "path": "uploads/abcdef.mkv",
+            "mimetype": "video/x-matroska",
+            "time_added": 1735689600,
+            "transcode": {
+                "status": "done",
+                "path": "uploads/abcdef.webm",
+                "mimetype": "video/webm",
+            },
+            "burn_after_read": burn_after_read,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn burned_files_have_no_raw_original() {
+        assert!(serves_original(&transcoded_file(false)));
+        assert!(!serves_original(&transcoded_file(true)));
+    }
+    #[tokio::test]
+    async fn last_view_is_destroyed_once_the_body_was_sent() {
+        let destroyed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let last_view = {
+            let destroyed = Arc::clone(&destroyed);
+            LastView(Some(Box::new(move || {
+                destroyed.store(true, std::sync::atomic::Ordering::SeqCst)
+            })))
+        };
+
+        let response = last_view.attach(Response::new(Body::from("secret")));
+        assert!(!destroyed.load(std::sync::atomic::Ordering::SeqCst));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "secret");
+        assert!(destroyed.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    /// Needs a Redis database given with `IHACDN_TEST_REDIS`, passes without doing anything
+    /// otherwise.
+    #[tokio::test]
+    async fn views_are_taken_until_none_are_left() {
+        let Ok(url) = std::env::var("IHACDN_TEST_REDIS") else {
+            return;
+        };
+        let client = redis::Client::open(url).unwrap();
+        let mut connection =
+            RedisConnection::from(client.get_multiplexed_async_connection().await.unwrap());
+        let key = format!("ihacdn-test:{}:views", crate::state::generate_token());
+
+        // Entries without a counter have no views left
+        assert_eq!(take_view(&mut connection, &key).await.unwrap(), -1);
+
+        redis::cmd("SET")
+            .arg(&key)
+            .arg(2)
+            .exec_async(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(take_view(&mut connection, &key).await.unwrap(), 1);
+        assert_eq!(take_view(&mut connection, &key).await.unwrap(), 0);
+        assert_eq!(take_view(&mut connection, &key).await.unwrap(), -1);
+
+        redis::cmd("DEL")
+            .arg(&key)
+            .exec_async(&mut connection)
+            .await
+            .unwrap();
     }
 }
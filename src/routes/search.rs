@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{
+    error::{ErrorFormat, IhaCdnError},
+    index::{read_indexed_content, search_paste_words, tokenize},
+    routes::reader::Viewer,
+    state::{CDNData, SharedState, entry_key},
+};
+
+/// The default amount of search results.
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+/// The maximum amount of search results.
+const MAX_SEARCH_LIMIT: usize = 100;
+/// The maximum length of the excerpt of a result, in characters.
+const MAX_EXCERPT_LENGTH: usize = 200;
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    /// The words to search for, all of them must be in the paste.
+    q: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Full-text search over the content of pastes, newest first.
+///
+/// Admins search every paste, other viewers only the restricted pastes their access token opens.
+pub async fn search_pastes(
+    State(state): State<Arc<SharedState>>,
+    viewer: Viewer,
+    Query(params): Query<SearchParams>,
+) -> Response {
+    search(&state, &viewer, params)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn search(
+    state: &SharedState,
+    viewer: &Viewer,
+    params: SearchParams,
+) -> Result<Response, IhaCdnError> {
+    if !state.config.search.enable {
+        return Err(IhaCdnError::not_found("api/search"));
+    }
+    if !viewer.is_authenticated() {
+        return Err(IhaCdnError::Unauthorized);
+    }
+
+    let query = params.q.unwrap_or_default();
+    let words = tokenize(&query);
+    if words.is_empty() {
+        return Err(IhaCdnError::BadRequest(
+            "The query has no searchable words".to_string(),
+        ));
+    }
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let ids = search_paste_words(&mut connection, &state.config.key_prefix, &query)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: "api/search".to_string(),
+            source,
+        })?;
+    let values = if ids.is_empty() {
+        vec![]
+    } else {
+        redis::cmd("MGET")
+            .arg(
+                ids.iter()
+                    .map(|id| entry_key(&state.config.key_prefix, id))
+                    .collect::<Vec<_>>(),
+            )
+            .query_async::<Vec<Option<Vec<u8>>>>(&mut connection)
+            .await
+            .map_err(|source| IhaCdnError::RedisGet {
+                id_path: "api/search".to_string(),
+                source,
+            })?
+    };
+
+    let mut found = ids
+        .into_iter()
+        .zip(values)
+        .filter_map(|(id, value)| {
+            let cdn_data = CDNData::decode(&value?).ok()?;
+            (matches!(cdn_data, CDNData::Code { .. }) && viewer.may_find(&cdn_data))
+                .then_some((id, cdn_data))
+        })
+        .collect::<Vec<_>>();
+    found.sort_by_key(|(_, cdn_data)| std::cmp::Reverse(cdn_data.time_added()));
+    let total = found.len();
+
+    let mut results = vec![];
+    for (id, cdn_data) in found.into_iter().take(limit) {
+        let CDNData::Code {
+            path,
+            mimetype,
+            language,
+            time_added,
+            ..
+        } = &cdn_data
+        else {
+            continue;
+        };
+
+        let mut result = serde_json::Map::new();
+        result.insert("id".to_string(), id.clone().into());
+        result.insert("url".to_string(), state.config.make_url(&id).into());
+        result.insert(
+            "language".to_string(),
+            language.as_ref().unwrap_or(mimetype).clone().into(),
+        );
+        result.insert("time_added".to_string(), (*time_added).into());
+        if let Some(metadata) = cdn_data.metadata() {
+            result.insert(
+                "metadata".to_string(),
+                serde_json::to_value(metadata).unwrap(),
+            );
+        }
+        if let Ok(content) = read_indexed_content(&state.config, path).await
+            && let Some(excerpt) = excerpt(&content, &words)
+        {
+            result.insert("excerpt".to_string(), excerpt.into());
+        }
+        results.push(serde_json::Value::Object(result));
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "total": total,
+            "results": results,
+        })),
+    )
+        .into_response())
+}
+
+/// The first line of the content containing one of the searched words.
+fn excerpt(content: &str, words: &std::collections::BTreeSet<String>) -> Option<String> {
+    let line = content.lines().find(|line| {
+        let line = line.to_lowercase();
+        words.iter().any(|word| line.contains(word.as_str()))
+    })?;
+    Some(line.trim().chars().take(MAX_EXCERPT_LENGTH).collect())
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    build_info::{BUILD_TIMESTAMP, GIT_SHA, VERSION},
+    config::AdminRole,
+    error::{ErrorFormat, IhaCdnError},
+    index,
+    routes::admin::authorize,
+    state::{SharedState, humanize_bytes},
+    stats::{DailyStats, load_summary},
+    store,
+    tasks::{self, TASKS},
+    templating::{HtmlTemplate, TemplateLegal, TemplateStats, TemplateStatsPeriod},
+};
+
+/// The version and build of the running server.
+pub async fn version() -> Json<serde_json::Value> {
+    let build_date = BUILD_TIMESTAMP
+        .parse::<i64>()
+        .ok()
+        .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+        .map(|datetime| datetime.to_rfc3339());
+
+    Json(serde_json::json!({
+        "version": VERSION,
+        "git_sha": GIT_SHA,
+        "build_date": build_date,
+    }))
+}
+
+/// The runtime status of the server, for monitoring.
+pub async fn status(State(state): State<Arc<SharedState>>, headers: HeaderMap) -> Response {
+    collect_status(&state, &headers)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Json))
+}
+
+async fn collect_status(state: &SharedState, headers: &HeaderMap) -> Result<Response, IhaCdnError> {
+    if !state.config.status.public {
+        authorize(state, headers, AdminRole::Superadmin, "status", "").await?;
+    }
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let redis_error = |source| IhaCdnError::RedisGet {
+        id_path: "_/status".to_string(),
+        source,
+    };
+
+    let ping_started = std::time::Instant::now();
+    redis::cmd("PING")
+        .exec_async(&mut connection)
+        .await
+        .map_err(redis_error)?;
+    let redis_latency = ping_started.elapsed();
+
+    let entries = index::count_by_type(&mut connection, &state.config.key_prefix)
+        .await
+        .map_err(redis_error)?
+        .into_iter()
+        .map(|(kind, count)| (kind.to_string(), count.into()))
+        .collect::<serde_json::Map<_, _>>();
+
+    let disks = state
+        .config
+        .upload_paths()
+        .map(|path| {
+            serde_json::json!({
+                "path": path,
+                "total": fs4::total_space(path).ok(),
+                "available": fs4::available_space(path).ok(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut task_runs = serde_json::Map::new();
+    for task in TASKS {
+        let run = tasks::last_run(&mut connection, &state.config.key_prefix, task)
+            .await
+            .map_err(redis_error)?;
+        task_runs.insert(task.to_string(), serde_json::json!(run));
+    }
+    let next_run_in = state
+        .scheduler
+        .clone()
+        .time_till_next_job()
+        .await
+        .ok()
+        .flatten()
+        .map(|duration| duration.as_secs());
+
+    Ok(Json(serde_json::json!({
+        "version": VERSION,
+        "git_sha": GIT_SHA,
+        "uptime": state.started_at.elapsed().as_secs(),
+        "redis_latency_ms": redis_latency.as_secs_f64() * 1000.0,
+        "entries": entries,
+        "disks": disks,
+        "scheduler": {
+            "running": state.scheduler.inited().await,
+            "next_run_in": next_run_in,
+            "tasks": task_runs,
+        },
+    }))
+    .into_response())
+}
+
+/// The latency and errors of Redis commands, in the Prometheus text format.
+pub async fn metrics(State(state): State<Arc<SharedState>>, headers: HeaderMap) -> Response {
+    if !state.config.status.public
+        && let Err(err) = authorize(&state, &headers, AdminRole::Superadmin, "metrics", "").await
+    {
+        return err.respond(ErrorFormat::Text);
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        store::render_metrics(),
+    )
+        .into_response()
+}
+
+/// The public page of the aggregated upload statistics.
+pub async fn stats_page(State(state): State<Arc<SharedState>>) -> Response {
+    render_stats(&state)
+        .await
+        .unwrap_or_else(|err| err.respond(ErrorFormat::Html))
+}
+
+async fn render_stats(state: &SharedState) -> Result<Response, IhaCdnError> {
+    if !state.config.stats.public {
+        return Err(IhaCdnError::not_found("stats"));
+    }
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+    let summary = load_summary(
+        &mut connection,
+        &state.config.key_prefix,
+        state.config.stats.top_mimetypes,
+    )
+    .await
+    .map_err(|source| IhaCdnError::RedisGet {
+        id_path: "stats".to_string(),
+        source,
+    })?;
+
+    let period = |stats: &DailyStats| TemplateStatsPeriod {
+        uploads: stats.uploads,
+        files: stats.files,
+        pastes: stats.pastes,
+        size: humanize_bytes(stats.bytes),
+    };
+    Ok(HtmlTemplate::new(TemplateStats {
+        instance_name: state.config.instance.name.clone(),
+        periods: vec![
+            ("Last day", period(&summary.last_day.unwrap_or_default())),
+            ("Last 7 days", period(&summary.last_week)),
+            ("Total", period(&summary.total)),
+        ],
+        top_mimetypes: summary
+            .last_week
+            .top_mimetypes
+            .into_iter()
+            .map(|mimetype| (mimetype.mimetype, mimetype.count))
+            .collect(),
+        legal: TemplateLegal::new(&state.config.instance),
+    })
+    .into_response())
+}
@@ -1,23 +1,58 @@
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+};
 
 use axum::{
-    Form,
-    extract::{Multipart, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    Form, Json,
+    body::Body,
+    extract::{Multipart, Path, Query, State, multipart::MultipartError},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
 };
+use base64::{Engine, prelude::BASE64_STANDARD};
 use rand::seq::IteratorRandom;
-use redis::aio::MultiplexedConnection;
-use serde::Deserialize;
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::io::AsyncWriteExt;
 
 use crate::{
-    notifier::{extract_ip_address, notify_discord},
+    access_log::LoggedEntry,
+    admin_keys,
+    antivirus::{ScanVerdict, scan_file},
+    audit,
+    config::{AdminIdentity, AdminRole, IhaCdnConfig, TypeMismatchPolicy},
+    deadline::BodyDeadline,
+    error::{ErrorFormat, IhaCdnError},
+    events::{CdnEvent, publish_event},
+    filters::FilterSubject,
+    hooks::{HookSubject, HookVerdict, run_post_upload},
+    idempotency::{
+        self, IDEMPOTENCY_KEY_HEADER, IDEMPOTENT_REPLAYED_HEADER, Idempotency, StoredResult,
+    },
+    index::{add_paste_words, index_entry, index_paste_content},
+    media::{extract_poster, needs_faststart},
+    moderation::{ModerationSubject, ModerationVerdict},
+    network,
+    notifier::{extract_ip_address, notify_discord, notify_infected, notify_moderation},
+    nsfw::classify_image,
+    policy::{PolicyVerdict, ShortenPolicySubject, UploadPolicySubject},
+    purge::destroy_entry,
+    sanitize::{
+        is_false_field, parse_bool_field, parse_expiry_field, parse_timestamp_field,
+        sanitize_extension, sanitize_file_name, sanitize_language, sanitize_metadata,
+        split_id_path, validate_custom_headers, validate_slug,
+    },
+    signed_upload::{self, UploadGrant},
     state::{
-        BLOCKED_EXTENSION, CDNData, CREATE_FILE_ERROR, CUSTOM_NAME_GENERATION_ERROR,
-        INVALID_URL_FORMAT, MISSING_FIELD, PAYLOAD_TOO_LARGE, PREFIX, REDIS_CONNECTION_ERROR,
-        REDIS_SAVE_ERROR, SAVE_FILE_ERROR, SharedState, humanize_bytes,
+        CDNData, EntryMetadata, PasteRevision, SharedState, TranscodeState, UploaderInfo,
+        entry_key, generate_token, hash_token, remaining_views_key,
     },
+    store::{RedisConnection, replace_unchanged},
+    tombstone::{TombstoneReason, tombstone_key},
+    words::WordFilter,
 };
 
 enum ErrorState {
@@ -27,27 +62,272 @@ enum ErrorState {
 
 struct FileState {
     // skip debug
-    chunks: Vec<u8>,
+    partial: PartialFile,
+    /// The size of the received file, in bytes.
+    size: u64,
+    /// The SHA-256 of the received file.
+    sha256: Vec<u8>,
+    /// The start of the file, up to the size indexed for search.
+    head: Vec<u8>,
+    /// The whole file looks like a base64 blob.
+    base64: bool,
     mime_types: String,
+    /// The `Content-Type` sent with the file, kept if it doesn't match [`FileState::mime_types`].
+    declared_mimetype: Option<String>,
     extension: String,
     real_extension: String,
     file_name: String,
+    original_name: String,
 }
 
 impl std::fmt::Debug for FileState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("FileState")
-            .field("chunks", &"***")
+            .field("partial", &self.partial.path)
+            .field("size", &self.size)
             .field("mime_types", &self.mime_types)
+            .field("declared_mimetype", &self.declared_mimetype)
             .field("extension", &self.extension)
             .field("file_name", &self.file_name)
+            .field("original_name", &self.original_name)
             .finish()
     }
 }
 
+impl FileState {
+    fn sha256_hex(&self) -> String {
+        self.sha256.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// A file being received, removed when dropped unless it was persisted.
+struct PartialFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl PartialFile {
+    async fn create(path: PathBuf) -> std::io::Result<(Self, tokio::fs::File)> {
+        let file = tokio::fs::File::create_new(&path).await?;
+        Ok((
+            Self {
+                path,
+                persisted: false,
+            },
+            file,
+        ))
+    }
+
+    /// Move the file to its final place, copying it over when that is on another volume.
+    async fn persist(mut self, target: &FsPath) -> std::io::Result<()> {
+        match tokio::fs::rename(&self.path, target).await {
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                // Copy next to the target first so a partial copy is never served
+                let copy = target.with_extension("partial");
+                let copied = async {
+                    tokio::fs::copy(&self.path, &copy).await?;
+                    tokio::fs::rename(&copy, target).await
+                }
+                .await;
+                if copied.is_err() {
+                    let _ = tokio::fs::remove_file(&copy).await;
+                }
+                copied?;
+                let _ = tokio::fs::remove_file(&self.path).await;
+            }
+            result => result?,
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Extra options sent as text fields alongside the uploaded file.
+#[derive(Debug, Default)]
+struct UploadOptions {
+    /// The file is a client-side encrypted paste blob.
+    encrypted: bool,
+    /// Custom response headers as a JSON object, only honored for admin uploads.
+    headers: Option<String>,
+    /// The paste this upload is a fork of.
+    parent: Option<String>,
+    /// The syntax highlighting language of a paste.
+    lang: Option<String>,
+    /// Not served before this UNIX timestamp.
+    not_before: Option<i64>,
+    /// Not served after this UNIX timestamp.
+    not_after: Option<i64>,
+    /// Delete the file after its first complete download.
+    burn: bool,
+    /// Delete the entry after this many views.
+    max_views: Option<u64>,
+    /// Delete the entry at this UNIX timestamp.
+    expires: Option<i64>,
+    /// Title, description, tags and source of the upload.
+    metadata: Option<EntryMetadata>,
+    /// Show the upload in the public gallery, sent as `unlisted=false`.
+    listed: bool,
+    /// The ID requested instead of a random one.
+    slug: Option<String>,
+}
+
+/// The options that make an upload different from another of the same file, in a fixed order.
+#[derive(Serialize)]
+struct OptionsFingerprint<'a> {
+    encrypted: bool,
+    headers: Option<BTreeMap<String, String>>,
+    parent: Option<&'a str>,
+    lang: Option<&'a str>,
+    not_before: Option<i64>,
+    not_after: Option<i64>,
+    burn: bool,
+    max_views: Option<u64>,
+    expires: Option<i64>,
+    metadata: Option<&'a EntryMetadata>,
+    listed: bool,
+    slug: Option<&'a str>,
+}
+
+impl UploadOptions {
+    /// The SHA-256 of the options as canonical JSON, for the dedup fingerprint.
+    fn fingerprint(&self) -> String {
+        let fingerprint = OptionsFingerprint {
+            encrypted: self.encrypted,
+            // Invalid headers fail the upload later, they never reach the fingerprint
+            headers: self
+                .headers
+                .as_deref()
+                .and_then(|headers| serde_json::from_str(headers).ok()),
+            parent: self.parent.as_deref().map(str::trim),
+            lang: self.lang.as_deref(),
+            not_before: self.not_before,
+            not_after: self.not_after,
+            burn: self.burn,
+            max_views: self.max_views,
+            expires: self.expires,
+            metadata: self.metadata.as_ref(),
+            listed: self.listed,
+            slug: self.slug.as_deref().map(str::trim),
+        };
+        hash_token(&serde_json::to_string(&fingerprint).unwrap())
+    }
+}
+
+/// The header carrying the delete token of a paste.
+const DELETE_TOKEN_HEADER: &str = "x-delete-token";
+/// The header carrying the expiry of an upload, as an UNIX timestamp.
+const EXPIRES_AT_HEADER: &str = "x-expires-at";
+/// The maximum size of the metadata JSON of an upload, in bytes.
+const MAX_METADATA_SIZE: usize = 8 * 1024;
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    /// Only check the upload and report the outcome, without storing anything.
+    validate: Option<String>,
+    /// The signature of a signed upload URL.
+    signature: Option<String>,
+    /// `json` to answer with [`StoredResult`] as JSON instead of the bare URL.
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ShortenParams {
+    /// `json` to answer with [`StoredResult`] as JSON instead of the bare URL.
+    format: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct ShortenForm {
     url: String,
+    max_views: Option<u64>,
+    expires: Option<String>,
+    slug: Option<String>,
+}
+
+/// The declared `Content-Type` of a file, without parameters, [`None`] if it says nothing.
+fn declared_type(content_type: &str) -> Option<String> {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    (!essence.is_empty() && essence != "application/octet-stream").then_some(essence)
+}
+
+/// Whether a declared type agrees with the detected one.
+///
+/// Detection only tells text apart from binary for most text formats, and can't tell every
+/// binary format apart, so those are given the benefit of the doubt.
+fn types_match(declared: &str, detected: &str) -> bool {
+    declared == detected
+        || detected == "application/octet-stream"
+        || detected == "text/plain"
+        || (declared.starts_with("text/") && detected.starts_with("text/"))
+}
+
+/// Check if the content looks like a base64 blob.
+fn is_base64_payload(content: &[u8]) -> bool {
+    !content.is_empty()
+        && content.iter().all(|&b| {
+            b.is_ascii_alphanumeric()
+                || matches!(b, b'+' | b'/' | b'=' | b'-' | b'_')
+                || b.is_ascii_whitespace()
+        })
+}
+
+/// The SHA-256 of the file sent by the client, from `X-Content-Sha256` (hex) or `Repr-Digest` (`sha-256=:base64:`).
+fn expected_sha256(headers: &HeaderMap) -> Result<Option<Vec<u8>>, IhaCdnError> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default().trim())
+    };
+
+    if let Some(value) = header("x-content-sha256") {
+        let digest = (value.len() == 64)
+            .then(|| {
+                (0..value.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+                    .collect::<Option<Vec<_>>>()
+            })
+            .flatten();
+        return digest.map(Some).ok_or_else(|| {
+            IhaCdnError::BadRequest("X-Content-Sha256 must be a hex SHA-256".to_string())
+        });
+    }
+
+    let Some(value) = header("repr-digest") else {
+        return Ok(None);
+    };
+    // Other algorithms are ignored
+    let Some(digest) = value.split(',').find_map(|member| {
+        let (algorithm, digest) = member.split_once('=')?;
+        algorithm
+            .trim()
+            .eq_ignore_ascii_case("sha-256")
+            .then(|| digest.trim())
+    }) else {
+        return Ok(None);
+    };
+    digest
+        .strip_prefix(':')
+        .and_then(|digest| digest.strip_suffix(':'))
+        .and_then(|digest| BASE64_STANDARD.decode(digest).ok())
+        .filter(|digest| digest.len() == 32)
+        .map(Some)
+        .ok_or_else(|| {
+            IhaCdnError::BadRequest("Repr-Digest has an invalid sha-256 digest".to_string())
+        })
 }
 
 fn randomize_file_name(amount: usize) -> String {
@@ -61,14 +341,27 @@ fn randomize_file_name(amount: usize) -> String {
     file_name
 }
 
+/// How many random names are tried before giving up, only reached when most names of
+/// `filename_length` are taken or blocked.
+const MAX_NAME_ATTEMPTS: usize = 64;
+
 async fn generate_file_name(
     amount: usize,
-    engine: &mut MultiplexedConnection,
+    words: &WordFilter,
+    engine: &mut RedisConnection,
+    prefix: &str,
 ) -> Result<String, String> {
-    loop {
+    for _ in 0..MAX_NAME_ATTEMPTS {
         let file_name = randomize_file_name(amount);
+        if !words.is_allowed(&file_name) {
+            continue;
+        }
+
+        // IDs with a tombstone are still taken, so old links never point to new content
         let key_exist = match redis::cmd("EXISTS")
-            .arg(format!("{PREFIX}{}", file_name))
+            .arg(entry_key(prefix, &file_name))
+            .arg(tombstone_key(prefix, &file_name))
+            .arg(slug_claim_key(prefix, &file_name))
             .query_async::<i64>(engine)
             .await
         {
@@ -83,80 +376,515 @@ async fn generate_file_name(
             return Ok(file_name);
         }
     }
+
+    tracing::error!(
+        "No unused name of {} characters found after {} attempts",
+        amount,
+        MAX_NAME_ATTEMPTS
+    );
+    Err("Unable to find an unused name".to_string())
+}
+
+/// How long a slug stays reserved for the request that claimed it, in seconds.
+///
+/// The reservation is released once the request is done, the expiry only covers crashes.
+const SLUG_CLAIM_TTL: u64 = 3600;
+
+fn slug_claim_key(prefix: &str, slug: &str) -> String {
+    format!("{prefix}:slug-claim:{slug}")
+}
+
+/// Reserve an ID for a single request, `false` if it is used, was used or is reserved.
+async fn reserve_id(connection: &mut RedisConnection, prefix: &str, id: &str) -> RedisResult<bool> {
+    // Reserved before the check, two requests for the same slug can't both pass it
+    let claim_key = slug_claim_key(prefix, id);
+    let reserved = redis::cmd("SET")
+        .arg(&claim_key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(SLUG_CLAIM_TTL)
+        .query_async::<Option<String>>(connection)
+        .await?;
+    if reserved.is_none() {
+        return Ok(false);
+    }
+
+    // Like generated IDs, slugs with a tombstone are never given to new content
+    let taken = redis::cmd("EXISTS")
+        .arg(entry_key(prefix, id))
+        .arg(tombstone_key(prefix, id))
+        .query_async::<i64>(connection)
+        .await;
+    if !matches!(taken, Ok(0)) {
+        redis::cmd("DEL")
+            .arg(&claim_key)
+            .exec_async(connection)
+            .await?;
+    }
+    Ok(taken? == 0)
+}
+
+/// A slug reserved by the request being processed, released once dropped.
+///
+/// Nothing is written under the slug before it is reserved, so a colliding request never touches
+/// the file or the view counter of another entry.
+struct SlugClaim {
+    state: Arc<SharedState>,
+    key: String,
+}
+
+impl Drop for SlugClaim {
+    fn drop(&mut self) {
+        let state = Arc::clone(&self.state);
+        let key = std::mem::take(&mut self.key);
+        tokio::spawn(async move {
+            let released = match state.make_connection().await {
+                Ok(mut connection) => {
+                    redis::cmd("DEL")
+                        .arg(&key)
+                        .exec_async(&mut connection)
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = released {
+                tracing::error!("Failed to release slug claim: {}", err);
+            }
+        });
+    }
+}
+
+/// Check that the uploader may use a requested slug and that it was never used, then reserve it.
+async fn claim_slug(
+    state: &Arc<SharedState>,
+    connection: &mut RedisConnection,
+    slug: &str,
+    is_admin: bool,
+) -> Result<(String, SlugClaim), IhaCdnError> {
+    if !is_admin && !state.config.slugs.public {
+        return Err(IhaCdnError::BadRequest(
+            "Custom slugs are only available to admins".to_string(),
+        ));
+    }
+    let slug =
+        validate_slug(slug, state.config.slugs.min_length).map_err(IhaCdnError::BadRequest)?;
+    if !state.words.is_allowed(&slug) {
+        return Err(IhaCdnError::BadRequest(format!(
+            "The slug {slug} is reserved or not allowed"
+        )));
+    }
+
+    let reserved = reserve_id(connection, &state.config.key_prefix, &slug)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: slug.clone(),
+            source,
+        })?;
+    if !reserved {
+        return Err(IhaCdnError::SlugTaken(slug));
+    }
+    let claim = SlugClaim {
+        state: Arc::clone(state),
+        key: slug_claim_key(&state.config.key_prefix, &slug),
+    };
+    Ok((slug, claim))
+}
+
+/// Check that an availability window is not empty.
+pub(crate) fn validate_availability(
+    not_before: Option<i64>,
+    not_after: Option<i64>,
+) -> Result<(), IhaCdnError> {
+    match (not_before, not_after) {
+        (Some(start), Some(end)) if start >= end => Err(IhaCdnError::BadRequest(
+            "not_after must be later than not_before".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a view limit allows at least one view.
+fn validate_max_views(max_views: Option<u64>) -> Result<Option<u64>, IhaCdnError> {
+    match max_views {
+        Some(0) => Err(IhaCdnError::BadRequest(
+            "max_views must be at least 1".to_string(),
+        )),
+        max_views => Ok(max_views),
+    }
+}
+
+/// Parse the expiry chosen by the uploader, it must be in the future.
+fn validate_expires(value: &str) -> Result<i64, IhaCdnError> {
+    let now = chrono::Utc::now().timestamp();
+    match parse_expiry_field(value, now) {
+        Some(expires) if expires > now => Ok(expires),
+        Some(_) => Err(IhaCdnError::BadRequest(
+            "expires must be in the future".to_string(),
+        )),
+        None => Err(IhaCdnError::BadRequest(format!(
+            "Invalid expiry, expected a duration (e.g. 1h, 7d) or a timestamp: {value}"
+        ))),
+    }
+}
+
+/// Start the view counter of an entry with a view limit.
+async fn save_remaining_views(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+    max_views: Option<u64>,
+) -> Result<(), IhaCdnError> {
+    let Some(max_views) = max_views else {
+        return Ok(());
+    };
+    redis::cmd("SET")
+        .arg(remaining_views_key(prefix, id))
+        .arg(max_views)
+        .exec_async(connection)
+        .await
+        .map_err(IhaCdnError::RedisSave)
+}
+
+/// The part of a paste that is indexed for the full-text search.
+fn indexed_content(config: &IhaCdnConfig, content: &[u8]) -> String {
+    let length = content.len().min(config.search.max_size as usize);
+    String::from_utf8_lossy(&content[..length]).into_owned()
+}
+
+/// Describe what an upload would become, for `?validate=1` requests.
+fn validation_report(cdn_data: &CDNData, size: u64, expires_at: Option<i64>) -> Response {
+    let mut report = serde_json::Map::new();
+    report.insert("valid".to_string(), true.into());
+    report.insert("type".to_string(), cdn_data.kind().into());
+    match cdn_data {
+        CDNData::File {
+            mimetype,
+            nsfw_score,
+            transcode,
+            declared_mimetype,
+            ..
+        } => {
+            report.insert("mimetype".to_string(), mimetype.clone().into());
+            if let Some(declared_mimetype) = declared_mimetype {
+                report.insert(
+                    "declared_mimetype".to_string(),
+                    declared_mimetype.clone().into(),
+                );
+            }
+            if let Some(nsfw_score) = nsfw_score {
+                report.insert("nsfw_score".to_string(), (*nsfw_score).into());
+            }
+            report.insert("transcode".to_string(), transcode.is_some().into());
+        }
+        CDNData::Code {
+            mimetype, language, ..
+        } => {
+            report.insert(
+                "language".to_string(),
+                language.as_ref().unwrap_or(mimetype).clone().into(),
+            );
+        }
+        CDNData::Short { .. } => {}
+    }
+    report.insert("size".to_string(), size.into());
+    report.insert("quarantined".to_string(), cdn_data.is_quarantined().into());
+    report.insert("expires_at".to_string(), expires_at.into());
+
+    (StatusCode::OK, Json(serde_json::Value::Object(report))).into_response()
+}
+
+/// The longest user agent kept with an entry, in bytes.
+const MAX_UPLOADER_AGENT: usize = 256;
+
+/// Who is uploading, as kept with the entry, [`None`] if recording is disabled.
+fn uploader_info(
+    config: &IhaCdnConfig,
+    headers: &HeaderMap,
+    admin: Option<&AdminIdentity>,
+) -> Option<UploaderInfo> {
+    if !config.uploader.record {
+        return None;
+    }
+    let ip = extract_ip_address(headers)
+        .first()
+        .map(|ip| config.uploader.identify_ip(ip));
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .map(str::trim)
+        .filter(|agent| !agent.is_empty())
+        .map(|agent| {
+            let mut end = agent.len().min(MAX_UPLOADER_AGENT);
+            while !agent.is_char_boundary(end) {
+                end -= 1;
+            }
+            agent[..end].to_string()
+        });
+    Some(UploaderInfo {
+        ip,
+        user_agent,
+        key: admin.map(|admin| admin.name.clone()),
+    })
+}
+
+/// Check that a fork parent is an existing plain paste, returning its ID.
+async fn resolve_parent(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    parent: &str,
+) -> Option<String> {
+    let (parent_id, _) = split_id_path(parent)?;
+    let data = redis::cmd("GET")
+        .arg(entry_key(prefix, &parent_id))
+        .query_async::<Option<Vec<u8>>>(connection)
+        .await
+        .inspect_err(|err| tracing::error!("Failed to get fork parent from Redis: {}", err))
+        .ok()??;
+
+    match CDNData::decode(&data) {
+        Ok(CDNData::Code {
+            encrypted: false, ..
+        }) => Some(parent_id),
+        _ => {
+            tracing::warn!("Ignoring invalid fork parent: {}", parent_id);
+            None
+        }
+    }
+}
+
+/// The header declaring the type of the uploaded file ahead of the body, e.g. `image/png`.
+const DECLARED_TYPE_HEADER: &str = "x-upload-content-type";
+
+/// Check what the headers of an upload declare, before any of its body is read.
+///
+/// `100 Continue` is only sent once the body is polled, so clients sending
+/// `Expect: 100-continue` never send the body of an upload rejected here.
+fn check_declared_upload(
+    config: &IhaCdnConfig,
+    headers: &HeaderMap,
+    grant: Option<&UploadGrant>,
+    file_size_limit: Option<u64>,
+) -> Result<(), IhaCdnError> {
+    if let Some(expect) = headers.get(header::EXPECT)
+        && !expect
+            .to_str()
+            .is_ok_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+    {
+        return Err(IhaCdnError::ExpectationFailed(
+            String::from_utf8_lossy(expect.as_bytes()).into_owned(),
+        ));
+    }
+
+    // Leaving room for the other fields
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if let (Some(content_length), Some(limit)) = (content_length, file_size_limit)
+        && content_length > limit + config.storage.body_limit_slack * 1024
+    {
+        tracing::error!("Declared upload size too large: {}", content_length);
+        return Err(IhaCdnError::PayloadTooLarge {
+            file_name: "file".to_string(),
+            limit: file_size_limit,
+        });
+    }
+
+    // The content is still sniffed once it arrives, this only spares the body of known rejects
+    if let Some(declared) = headers
+        .get(DECLARED_TYPE_HEADER)
+        .and_then(|declared| declared.to_str().ok())
+        .and_then(declared_type)
+        && (!config.is_filetype_allowed(&declared)
+            || grant.is_some_and(|grant| !grant.allows_type(&declared)))
+    {
+        return Err(IhaCdnError::BlockedType(declared));
+    }
+    Ok(())
+}
+
+/// A broken file field, a body cut off by the body limit is too large rather than malformed.
+fn multipart_error(err: MultipartError, file_name: &str, limit: Option<u64>) -> IhaCdnError {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        IhaCdnError::PayloadTooLarge {
+            file_name: file_name.to_string(),
+            limit,
+        }
+    } else {
+        IhaCdnError::BadRequest(err.body_text())
+    }
 }
 
 pub(crate) async fn uploads_file(
     State(state): State<Arc<SharedState>>,
+    Query(params): Query<UploadParams>,
     headers: HeaderMap,
     mut multipart: Multipart,
-) -> impl IntoResponse {
+) -> Result<Response, IhaCdnError> {
+    let deadline = BodyDeadline::start(state.config.timeouts.upload, state.config.timeouts.idle);
+    network::check_upload(&state, &headers).await?;
+    let dry_run = params.validate.as_deref().is_some_and(parse_bool_field);
+    let json = wants_json(&headers, params.format.as_deref());
     // get field "file"
     let secret = match headers.get("x-admin-key") {
         Some(key) => key.to_str().unwrap_or_default(),
         None => "",
     };
 
-    let is_admin = state.config.verify_admin_password(secret);
-    let mut connection = match state.make_connection().await {
-        Ok(connection) => connection,
-        Err(err) => {
-            tracing::error!("Failed to connect to Redis: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, REDIS_CONNECTION_ERROR).into_response();
-        }
+    let admin = admin_keys::identify(&state, secret).await?;
+    let is_admin = admin.is_some();
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+
+    // A dry run must not use up the signed URL, a rejected upload gives it back
+    let grant_claim = match params.signature.as_deref() {
+        Some(signature) => Some(
+            signed_upload::claim(&state, &mut connection, signature, !dry_run)
+                .await
+                .map_err(|source| IhaCdnError::RedisGet {
+                    id_path: "signed upload".to_string(),
+                    source,
+                })?
+                .ok_or(IhaCdnError::InvalidSignature)?,
+        ),
+        None => None,
     };
+    let grant = grant_claim.as_ref().map(|claim| &claim.grant);
+    if let Some(grant) = &grant {
+        tracing::info!("Upload with a URL signed by {}", grant.signed_by);
+    }
+
+    let expected_sha256 = expected_sha256(&headers)?;
+    let file_size_limit = match &grant {
+        Some(grant) if !is_admin => grant.max_size.or(state.config.get_limit(false)),
+        _ => state.config.get_limit(is_admin),
+    };
+    check_declared_upload(&state.config, &headers, grant, file_size_limit)?;
 
     let mut file_state = None;
-    while let Ok(Some(mut field)) = multipart.next_field().await {
-        let field_name = field.name().unwrap_or_default();
-        if field_name == "file" {
-            let file_name =
-                match generate_file_name(state.config.filename_length, &mut connection).await {
-                    Ok(file_name) => file_name,
-                    Err(err) => {
-                        let error = CUSTOM_NAME_GENERATION_ERROR
-                            .to_string()
-                            .replace("{{ REASON }}", &err);
-                        return (StatusCode::INTERNAL_SERVER_ERROR, error).into_response();
-                    }
-                };
+    let mut options = UploadOptions::default();
+    while let Ok(Some(mut field)) = deadline.wait(multipart.next_field()).await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        if field_name == "encrypted" {
+            options.encrypted =
+                parse_bool_field(&deadline.wait(field.text()).await?.unwrap_or_default());
+        } else if field_name == "unlisted" {
+            options.listed =
+                is_false_field(&deadline.wait(field.text()).await?.unwrap_or_default());
+        } else if field_name == "burn" {
+            options.burn =
+                parse_bool_field(&deadline.wait(field.text()).await?.unwrap_or_default());
+        } else if field_name == "headers" {
+            options.headers = deadline.wait(field.text()).await?.ok();
+        } else if field_name == "parent" {
+            options.parent = deadline.wait(field.text()).await?.ok();
+        } else if field_name == "slug" {
+            options.slug = deadline
+                .wait(field.text())
+                .await?
+                .ok()
+                .filter(|slug| !slug.trim().is_empty());
+        } else if field_name == "lang" {
+            options.lang = deadline
+                .wait(field.text())
+                .await?
+                .ok()
+                .and_then(|lang| sanitize_language(&lang));
+        } else if field_name == "metadata" {
+            let value = deadline.wait(field.text()).await?.unwrap_or_default();
+            if value.len() > MAX_METADATA_SIZE {
+                return Err(IhaCdnError::BadRequest(format!(
+                    "The metadata is larger than {MAX_METADATA_SIZE} bytes"
+                )));
+            }
+            let metadata = serde_json::from_str::<EntryMetadata>(&value)
+                .map_err(|err| IhaCdnError::BadRequest(format!("Invalid metadata: {err}")))?;
+            options.metadata = sanitize_metadata(metadata).map_err(IhaCdnError::BadRequest)?;
+        } else if field_name == "max_views" {
+            let value = deadline.wait(field.text()).await?.unwrap_or_default();
+            let max_views = value.trim().parse::<u64>().map_err(|_| {
+                IhaCdnError::BadRequest(format!("Invalid number for max_views: {value}"))
+            })?;
+            options.max_views = validate_max_views(Some(max_views))?;
+        } else if field_name == "expires" {
+            let value = deadline.wait(field.text()).await?.unwrap_or_default();
+            options.expires = Some(validate_expires(&value)?);
+        } else if field_name == "not_before" || field_name == "not_after" {
+            let value = deadline.wait(field.text()).await?.unwrap_or_default();
+            let timestamp = parse_timestamp_field(&value).ok_or_else(|| {
+                IhaCdnError::BadRequest(format!("Invalid timestamp for {field_name}: {value}"))
+            })?;
+            if field_name == "not_before" {
+                options.not_before = Some(timestamp);
+            } else {
+                options.not_after = Some(timestamp);
+            }
+        } else if field_name == "file" && file_state.is_none() {
+            let file_name = generate_file_name(
+                state.config.filename_length,
+                &state.words,
+                &mut connection,
+                &state.config.key_prefix,
+            )
+            .await
+            .map_err(IhaCdnError::NameGeneration)?;
 
             let file_type = field.content_type().unwrap_or_default();
-            let file_name_orig = field.file_name().unwrap_or_default();
+            let declared_mimetype = declared_type(file_type);
+            let file_name_orig = sanitize_file_name(field.file_name().unwrap_or_default());
             // Split at last dot
-            let file_extension = file_name_orig.split('.').next_back();
+            let file_extension = sanitize_extension(&file_name_orig);
+            let unnamed = file_extension.is_none();
 
             // Check if file type is allowed
             if !state.config.is_filetype_allowed(file_type) {
-                tracing::error!("File type not allowed: {}", file_type);
-                let blocked_ext = BLOCKED_EXTENSION
-                    .to_string()
-                    .replace("{{ FILE_TYPE }}", file_type);
-                return (StatusCode::UNSUPPORTED_MEDIA_TYPE, blocked_ext).into_response();
+                return Err(IhaCdnError::BlockedType(file_type.to_string()));
             }
             let file_ext_actual = match file_extension {
                 Some(ext) => {
-                    if !state.config.is_extension_allowed(ext) {
+                    if !state.config.is_extension_allowed(&ext) {
                         drop(file_state);
-                        tracing::error!("File extension not allowed: {}", ext);
-                        let blocked_ext = BLOCKED_EXTENSION
-                            .to_string()
-                            .replace("{{ FILE_TYPE }}", ext);
-                        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, blocked_ext).into_response();
+                        return Err(IhaCdnError::BlockedType(ext));
                     }
                     ext
                 }
-                None => "bin",
-            }
-            .to_string();
+                None => "bin".to_string(),
+            };
 
             let file_name_actual = format!("{}.{}", file_name, file_ext_actual);
-            let file_size_limit = state.config.get_limit(is_admin);
+
+            // Stream the file to disk, it's moved into place once every check passed
+            let partial_path = state
+                .config
+                .get_staging_path(is_admin)
+                .map_err(|source| IhaCdnError::CreateFile {
+                    file_name: file_name_actual.clone(),
+                    source,
+                })?
+                .join(format!(".{}.partial", file_name));
+            let (partial, mut partial_file) =
+                PartialFile::create(partial_path).await.map_err(|source| {
+                    IhaCdnError::CreateFile {
+                        file_name: file_name_actual.clone(),
+                        source,
+                    }
+                })?;
 
             let mut initial_read = false;
-            let mut consumed_length = vec![];
+            let mut size = 0u64;
+            let mut hasher = Sha256::new();
+            let mut head = vec![];
+            let mut base64 = true;
             let mut blocked_state = None;
             let mut guess_type = None;
-            while let Ok(Some(chunk)) = field.chunk().await {
+            while let Some(chunk) = deadline
+                .wait(field.chunk())
+                .await?
+                .map_err(|err| multipart_error(err, &file_name_actual, file_size_limit))?
+            {
                 let consumed_u8 = chunk.as_ref();
                 if !initial_read {
                     // read mimetype via magic number
@@ -170,222 +898,1364 @@ pub(crate) async fn uploads_file(
                 }
 
                 // Check if file size is too large
-                if let Some(file_size_limit) = file_size_limit {
-                    let expected_length = consumed_length.len() as u64 + chunk.len() as u64;
-                    if expected_length > file_size_limit {
-                        blocked_state = Some(ErrorState::FileTooLarge(expected_length));
-                        break;
-                    }
+                let expected_length = size + chunk.len() as u64;
+                if let Some(file_size_limit) = file_size_limit
+                    && expected_length > file_size_limit
+                {
+                    blocked_state = Some(ErrorState::FileTooLarge(expected_length));
+                    break;
                 }
 
-                consumed_length.extend_from_slice(chunk.as_ref());
+                partial_file
+                    .write_all(consumed_u8)
+                    .await
+                    .map_err(|source| IhaCdnError::SaveFile {
+                        file_name: file_name_actual.clone(),
+                        reason: format!("Unable to write a chunk of {} bytes", chunk.len()),
+                        source,
+                    })?;
+                size = expected_length;
+                hasher.update(consumed_u8);
+                base64 = base64 && (chunk.is_empty() || is_base64_payload(consumed_u8));
+                let head_room = (state.config.search.max_size as usize).saturating_sub(head.len());
+                head.extend_from_slice(&consumed_u8[..head_room.min(chunk.len())]);
             }
 
             if let Some(blocked_state) = blocked_state {
-                drop(consumed_length);
+                drop(partial_file);
+                drop(partial);
 
                 match blocked_state {
                     ErrorState::BlockedExt(ext) => {
-                        tracing::error!("File extension not allowed: {}", ext);
-                        let blocked_ext = BLOCKED_EXTENSION
-                            .to_string()
-                            .replace("{{ FILE_TYPE }}", &ext);
-                        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, blocked_ext).into_response();
+                        return Err(IhaCdnError::BlockedType(ext));
                     }
                     ErrorState::FileTooLarge(size) => {
                         tracing::error!("File size too large: {}", size);
-                        let error_msg = PAYLOAD_TOO_LARGE
-                            .to_string()
-                            .replace("{{ FS }}", &humanize_bytes(file_size_limit.unwrap()))
-                            .replace("{{ FN }}", &file_name_actual);
-                        // TODO: This will break the connection and browser is fucking dumb and would return NETWORK_ERROR instead of actually the content body
-                        return (StatusCode::PAYLOAD_TOO_LARGE, error_msg).into_response();
+                        return Err(IhaCdnError::PayloadTooLarge {
+                            file_name: file_name_actual,
+                            limit: file_size_limit,
+                        });
                     }
                 }
             }
 
+            partial_file
+                .flush()
+                .await
+                .map_err(|source| IhaCdnError::SaveFile {
+                    file_name: file_name_actual.clone(),
+                    reason: format!("Unable to flush file contents of {} bytes", size),
+                    source,
+                })?;
+            // close file to release the lock
+            drop(partial_file);
+
             let guessed_type = guess_type.unwrap_or("application/octet-stream".to_string());
-            let guessed_ext = match mime_guess::get_mime_extensions_str(&guessed_type) {
-                Some(exts) => match exts.first() {
-                    Some(&ext) => {
-                        if ext == "bin" {
-                            file_ext_actual.to_string()
-                        } else {
-                            ext.to_string()
-                        }
-                    }
-                    None => file_ext_actual.to_string(),
-                },
-                None => file_ext_actual.to_string(),
+            let guessed_ext = state
+                .config
+                .storage
+                .extension_for(&guessed_type)
+                .unwrap_or_else(|| file_ext_actual.to_string());
+            // Files sent without an extension are stored with the one of their content
+            let file_ext_actual = if unnamed && guessed_ext != file_ext_actual {
+                if !state.config.is_extension_allowed(&guessed_ext) {
+                    return Err(IhaCdnError::BlockedType(guessed_ext));
+                }
+                guessed_ext.clone()
+            } else {
+                file_ext_actual
             };
 
             file_state = Some(FileState {
-                chunks: consumed_length,
+                partial,
+                size,
+                sha256: hasher.finalize().to_vec(),
+                head,
+                base64: base64 && size > 0,
+                declared_mimetype: declared_mimetype
+                    .filter(|declared| !types_match(declared, &guessed_type)),
                 mime_types: guessed_type,
                 extension: guessed_ext,
                 real_extension: file_ext_actual,
                 file_name,
+                original_name: file_name_orig,
             });
-            break;
         }
     }
 
-    if file_state.is_none() {
-        tracing::error!("No file found in the request");
-        return (StatusCode::BAD_REQUEST, MISSING_FIELD).into_response();
+    let mut file_state = file_state.ok_or(IhaCdnError::MissingField)?;
+    // The slug may be sent after the file, which was received under a random name
+    let _slug_claim = match &options.slug {
+        Some(slug) => {
+            let (slug, claim) = claim_slug(&state, &mut connection, slug, is_admin).await?;
+            file_state.file_name = slug;
+            Some(claim)
+        }
+        None => None,
+    };
+    if let Some(declared) = &file_state.declared_mimetype {
+        match state.config.blocklist.type_mismatch {
+            TypeMismatchPolicy::Warn => tracing::warn!(
+                "{} was sent as {} but looks like {}",
+                file_state.original_name,
+                declared,
+                file_state.mime_types
+            ),
+            TypeMismatchPolicy::PreferSniffed => {
+                tracing::warn!(
+                    "{} was sent as {} but looks like {}, storing it as .{}",
+                    file_state.original_name,
+                    declared,
+                    file_state.mime_types,
+                    file_state.extension
+                );
+                if !state.config.is_extension_allowed(&file_state.extension) {
+                    return Err(IhaCdnError::BlockedType(file_state.extension));
+                }
+                file_state.real_extension = file_state.extension.clone();
+            }
+            TypeMismatchPolicy::Reject => {
+                return Err(IhaCdnError::TypeMismatch {
+                    declared: declared.clone(),
+                    detected: file_state.mime_types,
+                });
+            }
+        }
+    }
+    if let Some(expected_sha256) = expected_sha256
+        && file_state.sha256 != expected_sha256
+    {
+        return Err(IhaCdnError::ChecksumMismatch);
+    }
+    if let Some(grant) = &grant
+        && !grant.allows_type(&file_state.mime_types)
+    {
+        return Err(IhaCdnError::BlockedType(file_state.mime_types));
+    }
+    validate_availability(options.not_before, options.not_after)?;
+    if options.encrypted && !file_state.base64 {
+        return Err(IhaCdnError::InvalidEncryptedPayload);
+    }
+    let is_code = options.encrypted || file_state.mime_types.starts_with("text/");
+
+    // Catch double submits, requests with an `Idempotency-Key` are already covered
+    let uploader = idempotency::uploader(admin.as_ref(), &headers);
+    let recent_fingerprint = uploader
+        .clone()
+        .filter(|_| {
+            !dry_run
+                && state.config.idempotency.dedup_window > 0
+                && !headers.contains_key(IDEMPOTENCY_KEY_HEADER)
+        })
+        .map(|uploader| {
+            format!(
+                "{uploader}:{}:{}",
+                file_state.sha256_hex(),
+                options.fingerprint()
+            )
+        });
+    if let Some(fingerprint) = &recent_fingerprint
+        && let Some(result) =
+            idempotency::recent_upload(&mut connection, &state.config.key_prefix, fingerprint)
+                .await?
+    {
+        tracing::info!("Returning the identical recent upload {}", result.url);
+        return Ok(replay_response(result, json));
     }
 
-    let file_state = file_state.unwrap();
-    let is_code = file_state.mime_types.starts_with("text/");
+    let custom_headers = match options.headers {
+        Some(headers) if is_admin => serde_json::from_str::<BTreeMap<String, String>>(&headers)
+            .map_err(|err| err.to_string())
+            .and_then(validate_custom_headers)
+            .map_err(IhaCdnError::InvalidCustomHeaders)?,
+        Some(_) => {
+            tracing::warn!("Ignoring custom headers from non-admin upload");
+            BTreeMap::new()
+        }
+        None => BTreeMap::new(),
+    };
     tracing::info!("File state: {:?}", &file_state);
 
-    // Store to disk
-    let base_dir = state.config.get_path(is_admin);
-    let file_name_actual = format!("{}.{}", &file_state.file_name, &file_state.real_extension);
-    let file_path = base_dir.join(&file_name_actual);
+    let claim = match idempotency::header_key(&headers)? {
+        Some(key) if !dry_run => {
+            let fingerprint = format!("upload:{}", file_state.sha256_hex());
+            match idempotency::claim(
+                &state,
+                &mut connection,
+                uploader.as_deref(),
+                &key,
+                fingerprint,
+            )
+            .await?
+            {
+                Idempotency::Replay(result) => return Ok(replay_response(result, json)),
+                Idempotency::Claimed(claim) => Some(claim),
+            }
+        }
+        _ => None,
+    };
 
-    // Write content to disk
-    let mut file = match tokio::fs::File::create(&file_path).await {
-        Ok(file) => file,
-        Err(err) => {
-            tracing::error!("Failed to create file: {}", err);
-            let error = CREATE_FILE_ERROR
-                .to_string()
-                .replace("{{ FN }}", &file_name_actual);
-            return (StatusCode::INTERNAL_SERVER_ERROR, error).into_response();
+    let parent_id = match options.parent {
+        Some(parent) if is_code && !options.encrypted => {
+            resolve_parent(&mut connection, &state.config.key_prefix, parent.trim()).await
         }
+        _ => None,
+    };
+
+    let ip_address = extract_ip_address(&headers);
+    let mut verdict = if is_admin {
+        ModerationVerdict::default()
+    } else {
+        let sha256 = state
+            .moderation
+            .needs_hash()
+            .then(|| file_state.sha256_hex());
+        state.moderation.evaluate(&ModerationSubject {
+            mimetype: &file_state.mime_types,
+            extension: &file_state.real_extension,
+            size: file_state.size,
+            ip_address: &ip_address,
+            sha256: sha256.as_deref(),
+            file_name: &file_state.original_name,
+        })
+    };
+    if let Some(rule) = verdict.rejected_by {
+        return Err(IhaCdnError::ModerationRejected(rule));
+    }
+    let policy_verdict = state
+        .policy
+        .check_upload(&UploadPolicySubject {
+            kind: if is_code { "code" } else { "file" },
+            mimetype: &file_state.mime_types,
+            extension: &file_state.real_extension,
+            size: file_state.size,
+            original_name: &file_state.original_name,
+            is_admin,
+            ip_address: &ip_address,
+        })
+        .await;
+    match policy_verdict {
+        PolicyVerdict::Allow => (),
+        PolicyVerdict::Reject(reason) => return Err(IhaCdnError::PolicyRejected(reason)),
+        PolicyVerdict::Quarantine => verdict.quarantine = true,
+    }
+    if let Some(retention_days) = grant.as_ref().and_then(|grant| grant.retention_days) {
+        verdict.retention_days = Some(
+            verdict
+                .retention_days
+                .map_or(retention_days, |days| days.min(retention_days)),
+        );
+    }
+
+    let sha256 = file_state.sha256_hex();
+    let filtered = state
+        .filters
+        .evaluate(
+            &FilterSubject {
+                kind: if is_code { "code" } else { "file" },
+                mimetype: &file_state.mime_types,
+                extension: &file_state.real_extension,
+                size: file_state.size,
+                original_name: &file_state.original_name,
+                sha256: &sha256,
+                is_admin,
+                ip_address: &ip_address,
+            },
+            &file_state.partial.path,
+        )
+        .await;
+    if let Some(filter) = filtered.rejected_by {
+        return Err(IhaCdnError::FilterRejected(filter));
+    }
+    verdict.quarantine |= filtered.quarantine;
+    if let Some(retention_days) = filtered.retention_days {
+        verdict.retention_days = Some(
+            verdict
+                .retention_days
+                .map_or(retention_days, |days| days.min(retention_days)),
+        );
+    }
+    options.listed &= !filtered.unlisted;
+
+    let nsfw_score = if is_code {
+        None
+    } else {
+        classify_image(
+            &state.config.nsfw,
+            &state.config.user_agent(),
+            &file_state.mime_types,
+            &file_state.partial.path,
+        )
+        .await
     };
-    if let Err(err) = file.write_all(&file_state.chunks).await {
-        tracing::error!("Failed to write file: {}", err);
-        let error = SAVE_FILE_ERROR
-            .to_string()
-            .replace("{{ FN }}", &file_name_actual)
-            .replace(
-                "{{ REASON }}",
-                &format!(
-                    "Unable to write file contents of {} bytes",
-                    file_state.chunks.len()
-                ),
+    let nsfw_quarantine = !is_admin
+        && nsfw_score
+            .zip(state.config.nsfw.quarantine_threshold)
+            .is_some_and(|(score, threshold)| score >= threshold);
+    if nsfw_quarantine {
+        tracing::warn!("Quarantining upload with NSFW score {:?}", nsfw_score);
+    }
+
+    // Malware never reaches the upload directory
+    match scan_file(&state.config.antivirus, &file_state.partial.path).await {
+        ScanVerdict::Clean => (),
+        ScanVerdict::Infected(signature) => {
+            tracing::warn!(
+                "Refusing {} infected with {}",
+                file_state.original_name,
+                signature
+            );
+            notify_infected(
+                &file_state.original_name,
+                &signature,
+                &state.config,
+                ip_address,
             );
-        return (StatusCode::INTERNAL_SERVER_ERROR, error).into_response();
-    }
-    if let Err(err) = file.flush().await {
-        tracing::error!("Failed to flush file: {}", err);
-        let error = SAVE_FILE_ERROR
-            .to_string()
-            .replace("{{ FN }}", &file_name_actual)
-            .replace(
-                "{{ REASON }}",
-                &format!(
-                    "Unable to flush file contents of {} bytes",
-                    file_state.chunks.len()
-                ),
+            return Err(IhaCdnError::Infected(signature));
+        }
+        ScanVerdict::Failed(reason) if state.config.antivirus.fail_closed => {
+            return Err(IhaCdnError::TaskFailed {
+                task: "malware scan",
+                reason,
+            });
+        }
+        ScanVerdict::Failed(reason) => {
+            tracing::error!(
+                "Failed to scan {} for malware: {}",
+                file_state.original_name,
+                reason
             );
-        return (StatusCode::INTERNAL_SERVER_ERROR, error).into_response();
+        }
     }
 
-    // close file to release the lock
-    drop(file);
+    // Where the file will be stored
+    let file_name_actual = format!("{}.{}", &file_state.file_name, &file_state.real_extension);
+    let base_dir = state
+        .config
+        .get_path(is_admin, is_code, &file_state.mime_types)
+        .map_err(|source| IhaCdnError::SaveFile {
+            file_name: file_name_actual.clone(),
+            reason: "Unable to resolve the upload directory".to_string(),
+            source,
+        })?;
+    let file_path = base_dir.join(&file_name_actual);
+
+    let needs_faststart = !is_code
+        && state.config.media.faststart
+        && file_state.mime_types == "video/mp4"
+        && needs_faststart(&file_state.partial.path).await;
+    let transcode = (!is_code
+        && (needs_faststart
+            || state.config.media.should_transcode(
+                &file_state.mime_types,
+                &file_state.real_extension,
+                file_state.size,
+            )))
+    .then(TranscodeState::queued);
+    let should_transcode = transcode.is_some();
 
     let current_time = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs() as i64;
 
+    // The token is only returned once, we only keep its hash
+    let delete_token = generate_token();
+
     // Then we create the handle in Redis
-    let cdn_data = if is_code {
+    let mut cdn_data = if is_code {
         CDNData::Code {
             is_admin,
-            path: file_path,
-            mimetype: file_state.real_extension,
+            path: file_path.clone(),
+            mimetype: file_state.real_extension.clone(),
             time_added: current_time,
+            encrypted: options.encrypted,
+            headers: custom_headers,
+            quarantined: verdict.quarantine,
+            retention_days: verdict.retention_days,
+            parent_id,
+            language: options.lang.filter(|_| !options.encrypted),
+            delete_token: Some(hash_token(&delete_token)),
+            revisions: vec![],
+            expires_at: None,
+            acl: vec![],
+            not_before: options.not_before,
+            not_after: options.not_after,
+            max_views: options.max_views,
+            expires: options.expires,
+            metadata: options.metadata,
+            declared_mimetype: file_state.declared_mimetype.clone(),
+            content_hash: Some(file_state.sha256_hex()),
+            // Nobody could read an encrypted paste in the gallery
+            listed: options.listed && !options.encrypted,
+            uploader: uploader_info(&state.config, &headers, admin.as_ref()),
+            legal_hold: false,
         }
     } else {
         CDNData::File {
             is_admin,
-            path: file_path,
-            mimetype: file_state.mime_types,
+            path: file_path.clone(),
+            mimetype: file_state.mime_types.clone(),
             time_added: current_time,
+            headers: custom_headers,
+            quarantined: verdict.quarantine || nsfw_quarantine,
+            retention_days: verdict.retention_days,
+            nsfw_score,
+            poster: None,
+            transcode,
+            thumbnails: vec![],
+            burn_after_read: options.burn,
+            delete_token: Some(hash_token(&delete_token)),
+            expires_at: None,
+            acl: vec![],
+            not_before: options.not_before,
+            not_after: options.not_after,
+            max_views: options.max_views,
+            expires: options.expires,
+            metadata: options.metadata,
+            declared_mimetype: file_state.declared_mimetype.clone(),
+            content_hash: Some(file_state.sha256_hex()),
+            listed: options.listed,
+            uploader: uploader_info(&state.config, &headers, admin.as_ref()),
+            legal_hold: false,
         }
     };
+    let expires_at = cdn_data.persist_expiry(&state.config, file_state.size);
 
-    // Set to redis
-    match redis::cmd("SET")
-        .arg(format!("{PREFIX}{}", file_state.file_name))
-        .arg(serde_json::to_string(&cdn_data).unwrap())
-        .exec_async(&mut connection)
+    if dry_run {
+        return Ok(validation_report(&cdn_data, file_state.size, expires_at));
+    }
+
+    // Move the content into place
+    file_state
+        .partial
+        .persist(&file_path)
         .await
+        .map_err(|source| IhaCdnError::SaveFile {
+            file_name: file_name_actual.clone(),
+            reason: format!(
+                "Unable to move file contents of {} bytes into place",
+                file_state.size
+            ),
+            source,
+        })?;
+
+    if let CDNData::File {
+        poster, mimetype, ..
+    } = &mut cdn_data
     {
-        Ok(_) => (),
-        Err(err) => {
-            tracing::error!("Failed to set key in Redis: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, REDIS_SAVE_ERROR).into_response();
+        *poster = extract_poster(&state.config.media, mimetype, &file_path).await;
+    }
+
+    let hook_verdict = run_post_upload(
+        &state.config.hooks,
+        &HookSubject {
+            id: &file_state.file_name,
+            path: &file_path,
+            kind: if is_code { "code" } else { "file" },
+            mimetype: &file_state.mime_types,
+            extension: &file_state.real_extension,
+            size: file_state.size,
+            original_name: &file_state.original_name,
+            is_admin,
+            ip_address: &ip_address,
+        },
+    )
+    .await;
+    match hook_verdict {
+        HookVerdict::Accept => (),
+        HookVerdict::Reject => {
+            tracing::warn!("The post-upload hook rejected {}", file_name_actual);
+            let _ = tokio::fs::remove_file(&file_path).await;
+            if let CDNData::File {
+                poster: Some(poster),
+                ..
+            } = &cdn_data
+            {
+                let _ = tokio::fs::remove_file(poster).await;
+            }
+            return Err(IhaCdnError::HookRejected);
+        }
+        HookVerdict::Quarantine => {
+            tracing::warn!("The post-upload hook quarantined {}", file_name_actual);
+            cdn_data.set_quarantined(true);
         }
     }
 
-    let ip_address = extract_ip_address(&headers);
-    let final_url = state.config.make_url(&file_name_actual);
+    // Set to redis, the view counter first so the entry is never served without it
+    save_remaining_views(
+        &mut connection,
+        &state.config.key_prefix,
+        &file_state.file_name,
+        options.max_views,
+    )
+    .await?;
+    let mut save = redis::cmd("SET");
+    save.arg(entry_key(&state.config.key_prefix, &file_state.file_name))
+        .arg(cdn_data.encode(state.config.storage.entry_encoding));
+    // Never replaces another entry, even if the claim of the slug expired in between
+    if options.slug.is_some() {
+        save.arg("NX");
+    }
+    let saved = save
+        .query_async::<Option<String>>(&mut connection)
+        .await
+        .map_err(IhaCdnError::RedisSave)?;
+    if saved.is_none() {
+        cdn_data.delete_file(&state.config).await;
+        return Err(IhaCdnError::SlugTaken(file_state.file_name));
+    }
+    if let Some(grant_claim) = grant_claim {
+        grant_claim.accept();
+    }
+    if let Some(admin) = &admin {
+        audit::record(&state, admin, "upload", &file_state.file_name, true).await;
+    }
+
+    if should_transcode {
+        state
+            .transcoder
+            .enqueue(
+                &mut connection,
+                &state.config.key_prefix,
+                &file_state.file_name,
+            )
+            .await;
+    }
+
+    let final_url = if is_code {
+        state.config.make_url(&file_name_actual)
+    } else {
+        state.config.make_content_url(&file_name_actual)
+    };
+
+    index_entry(
+        &mut connection,
+        &state.config.key_prefix,
+        &file_state.file_name,
+        &cdn_data,
+    )
+    .await;
+    if is_code && !options.encrypted && state.config.search.enable {
+        add_paste_words(
+            &mut connection,
+            &state.config.key_prefix,
+            &file_state.file_name,
+            &indexed_content(&state.config, &file_state.head),
+        )
+        .await;
+    }
+    publish_event(&state, CdnEvent::Upload, &file_state.file_name, &cdn_data);
+    if !verdict.notify.is_empty() {
+        notify_moderation(
+            &final_url,
+            verdict.notify,
+            &state.config,
+            ip_address.clone(),
+        );
+    }
 
     notify_discord(&final_url, cdn_data, &state.config, ip_address);
-    (StatusCode::OK, final_url).into_response()
+    let raw_url = if is_code {
+        state
+            .config
+            .make_content_url(&format!("{file_name_actual}/raw"))
+    } else {
+        final_url.clone()
+    };
+    let result = StoredResult {
+        url: final_url,
+        id: file_state.file_name.clone(),
+        raw_url: Some(raw_url),
+        mimetype: Some(file_state.mime_types.clone()),
+        size: Some(file_state.size),
+        delete_token: Some(delete_token),
+        expires_at,
+    };
+    if let Some(claim) = claim {
+        claim.complete(&mut connection, result.clone()).await;
+    }
+    if let Some(fingerprint) = recent_fingerprint {
+        idempotency::remember_upload(
+            &mut connection,
+            &state.config.key_prefix,
+            &fingerprint,
+            &file_state.file_name,
+            &result,
+            state.config.idempotency.dedup_window,
+        )
+        .await;
+    }
+    Ok(upload_response(result, json))
 }
 
-pub(crate) async fn shorten_url(
+/// Whether the client asked for a JSON response, with `?format=json` or the `Accept` header.
+fn wants_json(headers: &HeaderMap, format: Option<&str>) -> bool {
+    match format {
+        Some(format) => format.eq_ignore_ascii_case("json"),
+        None => headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(ErrorFormat::from_accept)
+            .is_some_and(|format| matches!(format, ErrorFormat::Json)),
+    }
+}
+
+/// The response of a successful upload, with its delete token and expiry if any.
+///
+/// Plain text is only the URL, JSON has every field of the result.
+fn upload_response(result: StoredResult, json: bool) -> Response {
+    let mut response = if json {
+        Json(serde_json::json!({
+            "url": result.url,
+            "raw_url": result.raw_url,
+            "id": result.id,
+            "mimetype": result.mimetype,
+            "size": result.size,
+            "delete_token": result.delete_token,
+            "expires_at": result.expires_at,
+        }))
+        .into_response()
+    } else {
+        (StatusCode::OK, result.url).into_response()
+    };
+    if let Some(delete_token) = result.delete_token
+        && let Ok(value) = delete_token.parse()
+    {
+        response.headers_mut().insert(DELETE_TOKEN_HEADER, value);
+    }
+    if let Some(expires_at) = result.expires_at {
+        response
+            .headers_mut()
+            .insert(EXPIRES_AT_HEADER, HeaderValue::from(expires_at));
+    }
+    response.extensions_mut().insert(LoggedEntry(result.id));
+    response
+}
+
+/// Answer a retried request with the result of the original one.
+fn replay_response(result: StoredResult, json: bool) -> Response {
+    let mut response = upload_response(result, json);
+    response
+        .headers_mut()
+        .insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+/// Check that a request may change an entry, with its delete token or a moderator admin key.
+async fn authorize_owner(
+    state: &SharedState,
+    headers: &HeaderMap,
+    cdn_data: &CDNData,
+    token: &str,
+    raw_id: &str,
+    id_path: &str,
+    action: &str,
+) -> Result<(), IhaCdnError> {
+    if cdn_data.verify_delete_token(token) {
+        return Ok(());
+    }
+
+    // Only moderators may edit or delete the entries of others
+    let admin_key = headers
+        .get("x-admin-key")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let Some(admin) = admin_keys::identify(state, admin_key).await? else {
+        return Err(IhaCdnError::InvalidDeleteToken {
+            id_path: id_path.to_string(),
+        });
+    };
+    let allowed = admin.role >= AdminRole::Moderator;
+    audit::record(state, &admin, action, raw_id, allowed).await;
+    if !allowed {
+        return Err(IhaCdnError::InsufficientRole(AdminRole::Moderator));
+    }
+    Ok(())
+}
+
+async fn load_editable_paste(
+    state: &SharedState,
+    headers: &HeaderMap,
+    id_path: &str,
+) -> Result<(String, RedisConnection, Vec<u8>, CDNData), IhaCdnError> {
+    let (raw_id, _) = split_id_path(id_path).ok_or_else(|| IhaCdnError::not_found(id_path))?;
+
+    if !state.config.paste.editable {
+        return Err(IhaCdnError::EditingDisabled);
+    }
+
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+
+    let data = redis::cmd("GET")
+        .arg(entry_key(&state.config.key_prefix, &raw_id))
+        .query_async::<Option<Vec<u8>>>(&mut connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: id_path.to_string(),
+            source,
+        })?
+        .ok_or_else(|| IhaCdnError::not_found(id_path))?;
+    let cdn_data = match CDNData::decode(&data).map_err(IhaCdnError::ParseData)? {
+        cdn_data @ CDNData::Code { .. } => cdn_data,
+        _ => return Err(IhaCdnError::not_found(id_path)),
+    };
+
+    let token = headers
+        .get(DELETE_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    authorize_owner(
+        state,
+        headers,
+        &cdn_data,
+        token,
+        &raw_id,
+        id_path,
+        "modify_paste",
+    )
+    .await?;
+    check_editable(&cdn_data, id_path)?;
+
+    Ok((raw_id, connection, data, cdn_data))
+}
+
+/// Check that the content of a paste may change.
+///
+/// Pastes under legal hold are refused, an edit would prune the revisions under investigation.
+fn check_editable(cdn_data: &CDNData, id_path: &str) -> Result<(), IhaCdnError> {
+    if cdn_data.is_held() {
+        return Err(IhaCdnError::LegalHold {
+            id_path: id_path.to_string(),
+        });
+    }
+    if cdn_data.is_quarantined() {
+        return Err(IhaCdnError::quarantined(id_path));
+    }
+    Ok(())
+}
+
+/// Replace the content of a paste, keeping the previous version as a revision.
+///
+/// Authenticated with the delete token returned on upload, or the admin key.
+pub(crate) async fn edit_paste(
     State(state): State<Arc<SharedState>>,
     headers: HeaderMap,
-    Form(form): Form<ShortenForm>,
-) -> impl IntoResponse {
-    let mut connection = match state.make_connection().await {
-        Ok(connection) => connection,
-        Err(err) => {
-            tracing::error!("Failed to connect to Redis: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, REDIS_CONNECTION_ERROR).into_response();
+    Path(id_path): Path<String>,
+    body: Body,
+) -> Result<Response, IhaCdnError> {
+    let (raw_id, mut connection, data, mut cdn_data) =
+        load_editable_paste(&state, &headers, &id_path).await?;
+
+    let file_size_limit = state.config.get_limit(cdn_data.is_admin());
+    let deadline = BodyDeadline::start(state.config.timeouts.paste, state.config.timeouts.idle);
+    let content = deadline
+        .read_body(
+            body,
+            file_size_limit.map_or(usize::MAX, |limit| limit as usize),
+        )
+        .await?
+        .map_err(|err| {
+            tracing::error!("Failed to read paste edit: {}", err);
+            IhaCdnError::PayloadTooLarge {
+                file_name: id_path.clone(),
+                limit: file_size_limit,
+            }
+        })?;
+
+    let CDNData::Code {
+        path,
+        encrypted,
+        revisions,
+        content_hash,
+        ..
+    } = &mut cdn_data
+    else {
+        unreachable!();
+    };
+
+    let is_encrypted = *encrypted;
+    if is_encrypted {
+        if !is_base64_payload(&content) {
+            return Err(IhaCdnError::InvalidEncryptedPayload);
+        }
+    } else {
+        let gtype = tika_magic::from_u8(&content);
+        if !gtype.starts_with("text/") || !state.config.is_filetype_allowed(gtype) {
+            return Err(IhaCdnError::BlockedType(gtype.to_string()));
         }
+    }
+
+    // The new version is written next to the current one, e.g. `abc.1700000000123.rs`, so a
+    // concurrent edit that loses the compare-and-set below leaves the paste untouched
+    let current_path = state.config.resolve_path(path).await;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    let new_path = {
+        let stem = current_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let extension = current_path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        current_path.with_file_name(format!("{stem}.{}.{extension}", now.as_millis()))
+    };
+    let file_name = new_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let written = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&new_path)
+            .await?;
+        file.write_all(&content).await?;
+        file.flush().await
+    }
+    .await;
+    if let Err(source) = written {
+        let _ = tokio::fs::remove_file(&new_path).await;
+        return Err(IhaCdnError::SaveFile {
+            file_name,
+            reason: format!("Unable to write file contents of {} bytes", content.len()),
+            source,
+        });
+    }
+
+    // Keep the previous version as a revision
+    let max_revisions = state.config.paste.max_revisions;
+    revisions.insert(
+        0,
+        PasteRevision {
+            path: current_path,
+            time_replaced: now.as_secs() as i64,
+        },
+    );
+    *path = new_path.clone();
+    *content_hash = Some(
+        Sha256::digest(&content)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    );
+
+    let dropped = if revisions.len() > max_revisions {
+        revisions.split_off(max_revisions)
+    } else {
+        vec![]
     };
 
-    let file_name = match generate_file_name(state.config.filename_length, &mut connection).await {
-        Ok(file_name) => file_name,
-        Err(err) => {
-            let error = CUSTOM_NAME_GENERATION_ERROR
-                .to_string()
-                .replace("{{ REASON }}", &err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, error).into_response();
+    // Only replaced if nobody else changed the paste meanwhile, keeping the expiry of the entry
+    let saved = replace_unchanged(
+        &mut connection,
+        &entry_key(&state.config.key_prefix, &raw_id),
+        &data,
+        &cdn_data.encode(state.config.storage.entry_encoding),
+    )
+    .await;
+    if !matches!(saved, Ok(true)) {
+        let _ = tokio::fs::remove_file(&new_path).await;
+        return Err(match saved {
+            Err(err) => IhaCdnError::RedisSave(err),
+            _ => IhaCdnError::EntryChanged { id_path },
+        });
+    }
+    state.breaker.forget(&raw_id);
+
+    for revision in dropped {
+        if let Err(err) = tokio::fs::remove_file(&revision.path).await {
+            tracing::warn!("Failed to delete old paste revision: {}", err);
         }
+    }
+    if !is_encrypted && state.config.search.enable {
+        index_paste_content(
+            &mut connection,
+            &state.config.key_prefix,
+            &raw_id,
+            &indexed_content(&state.config, &content),
+        )
+        .await;
+    }
+
+    tracing::info!("Edited paste {}", raw_id);
+    Ok((StatusCode::OK, state.config.make_url(&raw_id)).into_response())
+}
+
+/// Append to the end of a paste, e.g. to live-stream a log that viewers follow.
+///
+/// Authenticated with the delete token returned on upload, or the admin key.
+pub(crate) async fn append_paste(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id_path): Path<String>,
+    body: Body,
+) -> Result<Response, IhaCdnError> {
+    let (raw_id, mut connection, data, mut cdn_data) =
+        load_editable_paste(&state, &headers, &id_path).await?;
+
+    let CDNData::Code {
+        path, encrypted, ..
+    } = &cdn_data
+    else {
+        unreachable!();
+    };
+    if *encrypted {
+        return Err(IhaCdnError::BadRequest(
+            "Encrypted pastes can't be appended to".to_string(),
+        ));
+    }
+
+    let path = state.config.resolve_path(path).await;
+    let current_size = tokio::fs::metadata(&path)
+        .await
+        .map_err(|err| IhaCdnError::file(err, &id_path))?
+        .len();
+
+    let file_size_limit = state.config.get_limit(cdn_data.is_admin());
+    let remaining = file_size_limit.map_or(usize::MAX, |limit| {
+        limit.saturating_sub(current_size) as usize
+    });
+    let deadline = BodyDeadline::start(state.config.timeouts.paste, state.config.timeouts.idle);
+    let content = deadline.read_body(body, remaining).await?.map_err(|err| {
+        tracing::error!("Failed to read paste append: {}", err);
+        IhaCdnError::PayloadTooLarge {
+            file_name: id_path.clone(),
+            limit: file_size_limit,
+        }
+    })?;
+
+    // A paste must stay text, chunks are too small to sniff reliably so only reject binary data
+    if content.contains(&0) {
+        return Err(IhaCdnError::BlockedType(
+            "application/octet-stream".to_string(),
+        ));
+    }
+
+    // The hash no longer matches, it isn't worth hashing the whole paste on every append
+    if let CDNData::Code { content_hash, .. } = &mut cdn_data
+        && content_hash.take().is_some()
+    {
+        let saved = replace_unchanged(
+            &mut connection,
+            &entry_key(&state.config.key_prefix, &raw_id),
+            &data,
+            &cdn_data.encode(state.config.storage.entry_encoding),
+        )
+        .await
+        .map_err(IhaCdnError::RedisSave)?;
+        if !saved {
+            return Err(IhaCdnError::EntryChanged { id_path });
+        }
+        state.breaker.forget(&raw_id);
+    }
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let result = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+        file.write_all(&content).await?;
+        file.flush().await
+    }
+    .await;
+    result.map_err(|source| IhaCdnError::SaveFile {
+        file_name: file_name.to_string(),
+        reason: format!("Unable to append {} bytes", content.len()),
+        source,
+    })?;
+    if state.config.search.enable && current_size < state.config.search.max_size {
+        add_paste_words(
+            &mut connection,
+            &state.config.key_prefix,
+            &raw_id,
+            &indexed_content(&state.config, &content),
+        )
+        .await;
+    }
+
+    Ok((StatusCode::OK, state.config.make_url(&raw_id)).into_response())
+}
+
+/// Delete an entry with the delete token in the `x-delete-token` header, or the admin key.
+pub(crate) async fn delete_entry(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path(id_path): Path<String>,
+) -> Result<Response, IhaCdnError> {
+    let token = headers
+        .get(DELETE_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    remove_entry(&state, &headers, &id_path, token).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Delete an entry with the delete token in the URL, for clients that can only open a link.
+pub(crate) async fn delete_entry_link(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+    Path((id_path, token)): Path<(String, String)>,
+) -> Result<Response, IhaCdnError> {
+    remove_entry(&state, &headers, &id_path, &token).await?;
+    Ok((StatusCode::OK, format!("Deleted {id_path}")).into_response())
+}
+
+async fn remove_entry(
+    state: &Arc<SharedState>,
+    headers: &HeaderMap,
+    id_path: &str,
+    token: &str,
+) -> Result<(), IhaCdnError> {
+    let (raw_id, _) = split_id_path(id_path).ok_or_else(|| IhaCdnError::not_found(id_path))?;
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+
+    let data = redis::cmd("GET")
+        .arg(entry_key(&state.config.key_prefix, &raw_id))
+        .query_async::<Option<Vec<u8>>>(&mut connection)
+        .await
+        .map_err(|source| IhaCdnError::RedisGet {
+            id_path: id_path.to_string(),
+            source,
+        })?
+        .ok_or_else(|| IhaCdnError::not_found(id_path))?;
+    let cdn_data = CDNData::decode(&data).map_err(IhaCdnError::ParseData)?;
+    authorize_owner(
+        state,
+        headers,
+        &cdn_data,
+        token,
+        &raw_id,
+        id_path,
+        "delete_entry",
+    )
+    .await?;
+
+    if !destroy_entry(state, &mut connection, &raw_id, TombstoneReason::Deleted).await {
+        return Err(if cdn_data.is_held() {
+            IhaCdnError::LegalHold {
+                id_path: id_path.to_string(),
+            }
+        } else {
+            IhaCdnError::not_found(id_path)
+        });
+    }
+    tracing::info!("Deleted {} on request", raw_id);
+    Ok(())
+}
+
+pub(crate) async fn shorten_url(
+    State(state): State<Arc<SharedState>>,
+    Query(params): Query<ShortenParams>,
+    headers: HeaderMap,
+    Form(form): Form<ShortenForm>,
+) -> Result<Response, IhaCdnError> {
+    network::check_upload(&state, &headers).await?;
+    let json = wants_json(&headers, params.format.as_deref());
+    let mut connection = state
+        .make_connection()
+        .await
+        .map_err(IhaCdnError::RedisConnection)?;
+
+    let secret = headers
+        .get("x-admin-key")
+        .and_then(|key| key.to_str().ok())
+        .unwrap_or_default();
+    let admin = admin_keys::identify(&state, secret).await?;
+    let is_admin = admin.is_some();
+    let slug = form.slug.as_deref().filter(|slug| !slug.trim().is_empty());
+    let (file_name, _slug_claim) = match slug {
+        Some(slug) => {
+            let (slug, claim) = claim_slug(&state, &mut connection, slug, is_admin).await?;
+            (slug, Some(claim))
+        }
+        None => (
+            generate_file_name(
+                state.config.filename_length,
+                &state.words,
+                &mut connection,
+                &state.config.key_prefix,
+            )
+            .await
+            .map_err(IhaCdnError::NameGeneration)?,
+            None,
+        ),
     };
 
     let form_url = form.url.trim().to_string();
     // parse as URL
-    let parsed_url = match url::Url::parse(&form_url) {
-        Ok(url) => url,
-        Err(err) => {
-            tracing::error!("Failed to parse URL: {}", err);
-            let error = INVALID_URL_FORMAT.replace("{{ URL }}", &form_url);
-            return (StatusCode::BAD_REQUEST, error).into_response();
+    let parsed_url = url::Url::parse(&form_url).map_err(|err| {
+        tracing::error!("Failed to parse URL: {}", err);
+        IhaCdnError::InvalidUrl(form_url.clone())
+    })?;
+    let short_links = &state.config.short_links;
+    if !short_links
+        .allowed_schemes
+        .iter()
+        .any(|scheme| scheme.eq_ignore_ascii_case(parsed_url.scheme()))
+    {
+        return Err(IhaCdnError::BlockedUrl(format!(
+            "The {} scheme is not allowed",
+            parsed_url.scheme()
+        )));
+    }
+    if let Some(host) = parsed_url.host_str()
+        && short_links.is_domain_blocked(host)
+    {
+        return Err(IhaCdnError::BlockedUrl(format!(
+            "Links to {host} are not allowed"
+        )));
+    }
+    if state.config.is_own_url(&parsed_url) {
+        return Err(IhaCdnError::BlockedUrl(
+            "Short links can't point back to this instance".to_string(),
+        ));
+    }
+
+    let ip_address = extract_ip_address(&headers);
+    if let PolicyVerdict::Reject(reason) = state
+        .policy
+        .check_short_link(&ShortenPolicySubject {
+            url: parsed_url.as_str(),
+            scheme: parsed_url.scheme(),
+            domain: parsed_url.host_str().unwrap_or_default(),
+            is_admin,
+            ip_address: &ip_address,
+        })
+        .await
+    {
+        return Err(IhaCdnError::PolicyRejected(reason));
+    }
+
+    // Then we create the handle in Redis
+    let max_views = validate_max_views(form.max_views)?;
+    let expires = form.expires.as_deref().map(validate_expires).transpose()?;
+
+    let claim = match idempotency::header_key(&headers)? {
+        Some(key) => {
+            let fingerprint = format!(
+                "short:{parsed_url}:{}:{}:{}",
+                max_views.unwrap_or_default(),
+                form.expires.as_deref().unwrap_or_default(),
+                slug.unwrap_or_default()
+            );
+            let uploader = idempotency::uploader(admin.as_ref(), &headers);
+            match idempotency::claim(
+                &state,
+                &mut connection,
+                uploader.as_deref(),
+                &key,
+                fingerprint,
+            )
+            .await?
+            {
+                Idempotency::Replay(result) => return Ok(replay_response(result, json)),
+                Idempotency::Claimed(claim) => Some(claim),
+            }
         }
+        None => None,
     };
 
-    // Then we create the handle in Redis
+    let delete_token = generate_token();
     let cdn_data = CDNData::Short {
         target: parsed_url.to_string(),
+        time_added: Some(chrono::Utc::now().timestamp()),
+        max_views,
+        expires,
+        delete_token: Some(hash_token(&delete_token)),
+        uploader: uploader_info(&state.config, &headers, admin.as_ref()),
+        legal_hold: false,
     };
 
-    // Set to redis
-    match redis::cmd("SET")
-        .arg(format!("{PREFIX}{}", file_name))
-        .arg(serde_json::to_string(&cdn_data).unwrap())
-        .exec_async(&mut connection)
+    // Set to redis, the view counter first so the entry is never served without it
+    save_remaining_views(
+        &mut connection,
+        &state.config.key_prefix,
+        &file_name,
+        max_views,
+    )
+    .await?;
+    let saved = redis::cmd("SET")
+        .arg(entry_key(&state.config.key_prefix, &file_name))
+        .arg(cdn_data.encode(state.config.storage.entry_encoding))
+        .arg("NX")
+        .query_async::<Option<String>>(&mut connection)
         .await
-    {
-        Ok(_) => (),
-        Err(err) => {
-            tracing::error!("Failed to set key in Redis: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, REDIS_SAVE_ERROR).into_response();
-        }
+        .map_err(IhaCdnError::RedisSave)?;
+    if saved.is_none() {
+        return Err(IhaCdnError::SlugTaken(file_name));
     }
 
-    let ip_address = extract_ip_address(&headers);
     let final_url = state.config.make_url(&file_name);
 
+    index_entry(
+        &mut connection,
+        &state.config.key_prefix,
+        &file_name,
+        &cdn_data,
+    )
+    .await;
+    publish_event(&state, CdnEvent::Upload, &file_name, &cdn_data);
+
     notify_discord(&final_url, cdn_data, &state.config, ip_address);
-    (StatusCode::OK, final_url).into_response()
+    let result = StoredResult {
+        url: final_url,
+        id: file_name,
+        raw_url: Some(parsed_url.to_string()),
+        mimetype: None,
+        size: None,
+        delete_token: Some(delete_token),
+        expires_at: expires,
+    };
+    if let Some(claim) = claim {
+        claim.complete(&mut connection, result.clone()).await;
+    }
+    Ok(upload_response(result, json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declared_headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn declared_uploads_are_checked_before_the_body() {
+        let mut config = IhaCdnConfig::default();
+        config.blocklist.content_types = vec!["application/x-msdownload".to_string()];
+        let limit = Some(1024 * 1024);
+        let too_large = (1024 * 1024 + config.storage.body_limit_slack * 1024 + 1).to_string();
+
+        let accepted = declared_headers(&[
+            ("expect", "100-continue"),
+            ("content-length", "2048"),
+            (DECLARED_TYPE_HEADER, "image/png"),
+        ]);
+        assert!(check_declared_upload(&config, &accepted, None, limit).is_ok());
+
+        let unknown_expectation = declared_headers(&[("expect", "202-accepted")]);
+        assert!(matches!(
+            check_declared_upload(&config, &unknown_expectation, None, limit),
+            Err(IhaCdnError::ExpectationFailed(_))
+        ));
+
+        let oversized =
+            declared_headers(&[("expect", "100-continue"), ("content-length", &too_large)]);
+        assert!(matches!(
+            check_declared_upload(&config, &oversized, None, limit),
+            Err(IhaCdnError::PayloadTooLarge { .. })
+        ));
+
+        let blocked = declared_headers(&[
+            ("expect", "100-continue"),
+            (DECLARED_TYPE_HEADER, "application/x-msdownload"),
+        ]);
+        assert!(matches!(
+            check_declared_upload(&config, &blocked, None, limit),
+            Err(IhaCdnError::BlockedType(_))
+        ));
+    }
+
+    fn paste(legal_hold: bool) -> CDNData {
+        serde_json::from_value(serde_json::json!({
+            "type": "code",
+            "is_admin": false,
+            "path": "uploads_code/abcdef.txt",
+            "mimetype": "txt",
+            "time_added": 1735689600,
+            "legal_hold": legal_hold,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn held_pastes_are_not_editable() {
+        assert!(matches!(
+            check_editable(&paste(true), "abcdef.txt"),
+            Err(IhaCdnError::LegalHold { .. })
+        ));
+        assert!(check_editable(&paste(false), "abcdef.txt").is_ok());
+    }
+
+    /// A Redis database for the tests that need one, given with `IHACDN_TEST_REDIS`, e.g.
+    /// `redis://127.0.0.1/15`. These tests pass without doing anything if it is not set.
+    async fn test_connection() -> Option<(RedisConnection, String)> {
+        let url = std::env::var("IHACDN_TEST_REDIS").ok()?;
+        let client = redis::Client::open(url).unwrap();
+        let connection = client.get_multiplexed_async_connection().await.unwrap();
+        // Keeps the keys of every run apart
+        let prefix = format!("ihacdn-test:{}:", generate_token());
+        Some((connection.into(), prefix))
+    }
+
+    #[tokio::test]
+    async fn colliding_slugs_are_reserved_once() {
+        let Some((mut connection, prefix)) = test_connection().await else {
+            return;
+        };
+
+        let mut other = connection.clone();
+        let (first, second) = tokio::join!(
+            reserve_id(&mut connection, &prefix, "release-notes"),
+            reserve_id(&mut other, &prefix, "release-notes"),
+        );
+        assert!(first.unwrap() ^ second.unwrap());
+
+        redis::cmd("DEL")
+            .arg(slug_claim_key(&prefix, "release-notes"))
+            .exec_async(&mut connection)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn slugs_of_existing_entries_are_refused() {
+        let Some((mut connection, prefix)) = test_connection().await else {
+            return;
+        };
+
+        redis::cmd("SET")
+            .arg(entry_key(&prefix, "taken"))
+            .arg("{}")
+            .exec_async(&mut connection)
+            .await
+            .unwrap();
+        assert!(!reserve_id(&mut connection, &prefix, "taken").await.unwrap());
+        // The refused request leaves no claim behind
+        let claimed = redis::cmd("EXISTS")
+            .arg(slug_claim_key(&prefix, "taken"))
+            .query_async::<bool>(&mut connection)
+            .await
+            .unwrap();
+        assert!(!claimed);
+
+        redis::cmd("DEL")
+            .arg(entry_key(&prefix, "taken"))
+            .exec_async(&mut connection)
+            .await
+            .unwrap();
+    }
 }
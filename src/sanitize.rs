@@ -0,0 +1,399 @@
+use std::collections::BTreeMap;
+
+use axum::http::{HeaderName, HeaderValue};
+
+use crate::state::EntryMetadata;
+
+/// The maximum length of a sanitized file name, in bytes.
+const MAX_FILE_NAME_LENGTH: usize = 255;
+/// The maximum length of a sanitized extension.
+const MAX_EXTENSION_LENGTH: usize = 16;
+/// The maximum length of a syntax highlighting language name.
+const MAX_LANGUAGE_LENGTH: usize = 32;
+/// The maximum length of a requested ID.
+const MAX_ID_LENGTH: usize = 64;
+/// The maximum amount of custom headers per entry.
+const MAX_CUSTOM_HEADERS: usize = 16;
+/// The maximum length of a metadata title, in characters.
+const MAX_TITLE_LENGTH: usize = 200;
+/// The maximum length of a metadata description, in characters.
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+/// The maximum length of a metadata source, in characters.
+const MAX_SOURCE_LENGTH: usize = 2048;
+/// The maximum amount of tags per entry.
+const MAX_TAGS: usize = 20;
+/// The maximum length of a tag, in characters.
+const MAX_TAG_LENGTH: usize = 50;
+
+/// Headers that are controlled by the server and can't be overridden per entry.
+const RESTRICTED_HEADERS: [&str; 11] = [
+    "connection",
+    "content-disposition",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "keep-alive",
+    "location",
+    "set-cookie",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Parse a boolean form field or query parameter.
+pub fn parse_bool_field(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
+/// Check if a boolean form field is explicitly false, for flags that default to true.
+pub fn is_false_field(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "0" | "false" | "no" | "off"
+    )
+}
+
+/// Parse a timestamp form field, either an UNIX timestamp or an RFC 3339 date.
+pub fn parse_timestamp_field(value: &str) -> Option<i64> {
+    let value = value.trim();
+    value.parse::<i64>().ok().or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|datetime| datetime.timestamp())
+    })
+}
+
+/// Parse an expiry form field, either a duration from `now` (`30m`, `1h`, `7d`, `2w`) or a timestamp.
+pub fn parse_expiry_field(value: &str, now: i64) -> Option<i64> {
+    let value = value.trim();
+    let unit = match value.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        'w' => 7 * 86400,
+        _ => return parse_timestamp_field(value),
+    };
+    let amount = value[..value.len() - 1].parse::<i64>().ok()?;
+    amount
+        .checked_mul(unit)
+        .and_then(|duration| now.checked_add(duration))
+}
+
+/// Sanitize a user-supplied file name.
+///
+/// This strips any directory component (both `/` and `\`), null bytes and control characters,
+/// and truncates the result to [`MAX_FILE_NAME_LENGTH`] bytes on a character boundary.
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let base_name = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+
+    let mut sanitized = String::with_capacity(base_name.len().min(MAX_FILE_NAME_LENGTH));
+    for c in base_name.chars().filter(|c| !c.is_control()) {
+        if sanitized.len() + c.len_utf8() > MAX_FILE_NAME_LENGTH {
+            break;
+        }
+        sanitized.push(c);
+    }
+
+    // Avoid names that resolve to the current or parent directory.
+    if sanitized.chars().all(|c| c == '.') {
+        return String::new();
+    }
+    sanitized
+}
+
+/// Extract a safe extension from a sanitized file name.
+///
+/// Only lowercase ASCII alphanumeric extensions up to [`MAX_EXTENSION_LENGTH`] characters are kept,
+/// anything else returns [`None`].
+pub fn sanitize_extension(file_name: &str) -> Option<String> {
+    let (stem, ext) = file_name.rsplit_once('.')?;
+    if stem.is_empty() || ext.is_empty() || ext.len() > MAX_EXTENSION_LENGTH {
+        return None;
+    }
+
+    if !ext.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    Some(ext.to_ascii_lowercase())
+}
+
+/// Sanitize a syntax highlighting language name, e.g. `python` or `objective-c`.
+///
+/// Returns [`None`] for anything that is not a short ASCII identifier.
+pub fn sanitize_language(language: &str) -> Option<String> {
+    let language = language.trim();
+    if language.is_empty() || language.len() > MAX_LANGUAGE_LENGTH {
+        return None;
+    }
+
+    if !language
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+
+    Some(language.to_ascii_lowercase())
+}
+
+/// Check and normalize the metadata of an upload.
+///
+/// Text fields are trimmed and dropped if empty, tags are lowercased and deduplicated.
+/// Returns [`None`] if nothing is left.
+pub fn sanitize_metadata(metadata: EntryMetadata) -> Result<Option<EntryMetadata>, String> {
+    fn text_field(
+        value: Option<String>,
+        name: &str,
+        limit: usize,
+    ) -> Result<Option<String>, String> {
+        let Some(value) = value.map(|value| value.trim().to_string()) else {
+            return Ok(None);
+        };
+        if value.chars().count() > limit {
+            return Err(format!(
+                "The metadata {name} is longer than {limit} characters"
+            ));
+        }
+        if value.chars().any(|c| c.is_control() && c != '\n') {
+            return Err(format!("The metadata {name} contains control characters"));
+        }
+        Ok(Some(value).filter(|value| !value.is_empty()))
+    }
+
+    let mut tags = vec![];
+    for tag in metadata.tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH || tag.chars().any(|c| c.is_control() || c == ',') {
+            return Err(format!("Invalid tag: {tag}"));
+        }
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("At most {MAX_TAGS} tags are allowed"));
+    }
+
+    let metadata = EntryMetadata {
+        title: text_field(metadata.title, "title", MAX_TITLE_LENGTH)?
+            .map(|title| title.replace('\n', " ")),
+        description: text_field(metadata.description, "description", MAX_DESCRIPTION_LENGTH)?,
+        tags,
+        source: text_field(metadata.source, "source", MAX_SOURCE_LENGTH)?,
+    };
+    Ok(Some(metadata).filter(|metadata| !metadata.is_empty()))
+}
+
+/// Split a requested `{id}.{ext}` path into the ID and extension.
+///
+/// Returns [`None`] if either part is obviously invalid, so the caller can respond with a 404
+/// without querying Redis.
+pub fn split_id_path(id_path: &str) -> Option<(String, String)> {
+    let (id, ext) = match id_path.rsplit_once('.') {
+        Some((id, ext)) => (id, ext),
+        None => (id_path, ""),
+    };
+
+    if id.is_empty() || id.len() > MAX_ID_LENGTH {
+        return None;
+    }
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+
+    if ext.len() > MAX_EXTENSION_LENGTH || !ext.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    Some((id.to_string(), ext.to_string()))
+}
+
+/// Validate an ID requested by the uploader, it must be usable as-is in a URL.
+pub fn validate_slug(slug: &str, min_length: usize) -> Result<String, String> {
+    let slug = slug.trim();
+    if slug.len() < min_length || slug.len() > MAX_ID_LENGTH {
+        return Err(format!(
+            "The slug must be between {min_length} and {MAX_ID_LENGTH} characters long"
+        ));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("The slug may only contain letters, digits, - and _".to_string());
+    }
+    Ok(slug.to_string())
+}
+
+/// Build a `Content-Disposition` header value.
+///
+/// Non-ASCII or quote-unsafe file names get an ASCII fallback in `filename` and the full name
+/// encoded in `filename*` following RFC 5987.
+pub fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let is_plain = file_name
+        .chars()
+        .all(|c| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\');
+
+    if is_plain {
+        return format!("{disposition}; filename=\"{file_name}\"");
+    }
+
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!(
+        "{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        encode_rfc5987(file_name)
+    )
+}
+
+fn encode_rfc5987(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Validate user-supplied custom response headers.
+///
+/// Header names are normalized to lowercase, on failure the offending header name is returned.
+pub fn validate_custom_headers(
+    headers: BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, String> {
+    if headers.len() > MAX_CUSTOM_HEADERS {
+        return Err(format!("more than {MAX_CUSTOM_HEADERS} headers"));
+    }
+
+    let mut validated = BTreeMap::new();
+    for (name, value) in headers {
+        let header_name = match HeaderName::from_bytes(name.trim().as_bytes()) {
+            Ok(header_name) => header_name,
+            Err(_) => return Err(name),
+        };
+        if RESTRICTED_HEADERS.contains(&header_name.as_str()) {
+            return Err(name);
+        }
+        if HeaderValue::from_str(value.trim()).is_err() {
+            return Err(name);
+        }
+        validated.insert(header_name.as_str().to_string(), value.trim().to_string());
+    }
+    Ok(validated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_file_names_are_quoted_as_is() {
+        assert_eq!(
+            content_disposition("inline", "photo 1.png"),
+            "inline; filename=\"photo 1.png\""
+        );
+    }
+
+    #[test]
+    fn quotes_and_backslashes_are_encoded() {
+        assert_eq!(
+            content_disposition("attachment", "a\"b\\c.txt"),
+            "attachment; filename=\"a_b_c.txt\"; filename*=UTF-8''a%22b%5Cc.txt"
+        );
+    }
+
+    #[test]
+    fn line_breaks_never_reach_the_header() {
+        let value = content_disposition("attachment", "evil\r\nSet-Cookie: a=b.txt");
+        assert!(!value.contains(['\r', '\n']));
+        assert_eq!(
+            value,
+            "attachment; filename=\"evil__Set-Cookie: a=b.txt\"; \
+             filename*=UTF-8''evil%0D%0ASet-Cookie%3A%20a%3Db.txt"
+        );
+    }
+
+    #[test]
+    fn non_ascii_names_get_an_ascii_fallback() {
+        assert_eq!(
+            content_disposition("inline", "ねこ.png"),
+            "inline; filename=\"__.png\"; filename*=UTF-8''%E3%81%AD%E3%81%93.png"
+        );
+    }
+
+    #[test]
+    fn rfc5987_keeps_attr_chars_and_escapes_the_rest() {
+        assert_eq!(encode_rfc5987("a-Z_0.9~!#$&+^`|"), "a-Z_0.9~!#$&+^`|");
+        assert_eq!(
+            encode_rfc5987("100% (1);*'="),
+            "100%25%20%281%29%3B%2A%27%3D"
+        );
+        assert_eq!(encode_rfc5987("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn id_paths_split_into_id_and_extension() {
+        assert_eq!(
+            split_id_path("abcdef.png"),
+            Some(("abcdef".to_string(), "png".to_string()))
+        );
+        assert_eq!(
+            split_id_path("release-notes"),
+            Some(("release-notes".to_string(), String::new()))
+        );
+        // Only the last dot separates the extension, the rest must be a valid ID
+        assert_eq!(split_id_path("archive.tar.gz"), None);
+    }
+
+    #[test]
+    fn invalid_id_paths_are_rejected() {
+        assert_eq!(split_id_path(""), None);
+        assert_eq!(split_id_path(".png"), None);
+        assert_eq!(split_id_path("abcdef/"), None);
+        assert_eq!(split_id_path("abcdef.png/"), None);
+        assert_eq!(split_id_path(".."), None);
+        assert_eq!(split_id_path("../abcdef"), None);
+        assert_eq!(split_id_path("abcdef.p%6Eg"), None);
+        assert_eq!(split_id_path(&"a".repeat(MAX_ID_LENGTH + 1)), None);
+    }
+}
@@ -0,0 +1,115 @@
+use std::{sync::Arc, time::Duration};
+
+use redis::RedisResult;
+use tokio_cron_scheduler::{Job, JobSchedulerError};
+
+use crate::{
+    error::IhaCdnError, purge::destroy_entry, state::SharedState, store::RedisConnection,
+    tombstone::TombstoneReason,
+};
+
+/// The Redis sorted set of pending deletions, scored by their UNIX timestamp.
+pub fn scheduled_deletions_key(prefix: &str) -> String {
+    format!("{prefix}:scheduled:delete")
+}
+
+/// Persist a deletion of `id` at `at` and register its job in the scheduler.
+///
+/// Scheduling an entry again replaces the previous time, the job of the old time
+/// notices it is stale and does nothing.
+pub async fn schedule_deletion(
+    state: &Arc<SharedState>,
+    connection: &mut RedisConnection,
+    id: &str,
+    at: i64,
+) -> Result<(), IhaCdnError> {
+    redis::cmd("ZADD")
+        .arg(scheduled_deletions_key(&state.config.key_prefix))
+        .arg(at)
+        .arg(id)
+        .exec_async(connection)
+        .await
+        .map_err(IhaCdnError::RedisSave)?;
+    let job = deletion_job(state, id, at).map_err(|e| IhaCdnError::Scheduler(e.to_string()))?;
+    state
+        .scheduler
+        .add(job)
+        .await
+        .map_err(|e| IhaCdnError::Scheduler(e.to_string()))?;
+    Ok(())
+}
+
+/// Drop the pending deletion of `id`, returns whether there was one.
+pub async fn cancel_deletion(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+) -> RedisResult<bool> {
+    redis::cmd("ZREM")
+        .arg(scheduled_deletions_key(prefix))
+        .arg(id)
+        .query_async::<i64>(connection)
+        .await
+        .map(|removed| removed > 0)
+}
+
+/// The time `id` is scheduled to be deleted at, if any.
+pub async fn scheduled_deletion(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+) -> RedisResult<Option<i64>> {
+    redis::cmd("ZSCORE")
+        .arg(scheduled_deletions_key(prefix))
+        .arg(id)
+        .query_async::<Option<i64>>(connection)
+        .await
+}
+
+/// Register the jobs of every persisted deletion, used on startup.
+///
+/// Deletions that came due while the server was down run right away.
+pub async fn restore_scheduled_deletions(
+    state: &Arc<SharedState>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut connection = state.make_connection().await?;
+    let pending = redis::cmd("ZRANGE")
+        .arg(scheduled_deletions_key(&state.config.key_prefix))
+        .arg(0)
+        .arg(-1)
+        .arg("WITHSCORES")
+        .query_async::<Vec<(String, i64)>>(&mut connection)
+        .await?;
+
+    for (id, at) in &pending {
+        state.scheduler.add(deletion_job(state, id, *at)?).await?;
+    }
+    Ok(pending.len())
+}
+
+fn deletion_job(state: &Arc<SharedState>, id: &str, at: i64) -> Result<Job, JobSchedulerError> {
+    let delay = (at - chrono::Utc::now().timestamp()).max(0) as u64;
+    let state = Arc::clone(state);
+    let id = id.to_string();
+    Job::new_one_shot_async(Duration::from_secs(delay), move |_uuid, _lock| {
+        let state = Arc::clone(&state);
+        let id = id.clone();
+        Box::pin(async move {
+            if let Err(e) = run_deletion(&state, &id, at).await {
+                tracing::error!("Scheduled deletion of {} failed: {}", id, e);
+            }
+        })
+    })
+}
+
+async fn run_deletion(state: &Arc<SharedState>, id: &str, at: i64) -> RedisResult<()> {
+    let mut connection = state.make_connection().await?;
+    // Rescheduled or cancelled since this job was registered
+    if scheduled_deletion(&mut connection, &state.config.key_prefix, id).await? != Some(at) {
+        return Ok(());
+    }
+
+    cancel_deletion(&mut connection, &state.config.key_prefix, id).await?;
+    destroy_entry(state, &mut connection, id, TombstoneReason::Deleted).await;
+    Ok(())
+}
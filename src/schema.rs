@@ -0,0 +1,63 @@
+//! Versions of the layout of stored entries.
+//!
+//! Every entry is written with the current [`SCHEMA_VERSION`]. Older entries are upgraded in
+//! memory when they are read and stored in the new layout the next time they are saved, the
+//! `migrate-entries` command upgrades all of them at once.
+
+use redis::Client;
+
+use crate::{config::IhaCdnConfig, encoding::rewrite_entries};
+
+/// The layout version written with every entry.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// The field holding the layout version of an entry.
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+/// Upgrades an entry to the next version, `MIGRATIONS[n]` takes version `n` to `n + 1`.
+type Migration = fn(&mut serde_json::Map<String, serde_json::Value>);
+
+/// Add a migration here and bump [`SCHEMA_VERSION`] when a field can't be read from older
+/// entries as is, e.g. a renamed field or one without a sensible default.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [from_unversioned];
+
+/// Entries written before versions were recorded, every field added until then has a default.
+fn from_unversioned(_entry: &mut serde_json::Map<String, serde_json::Value>) {}
+
+/// The layout version of a stored entry, `0` for entries written before versions were recorded.
+pub fn version_of(entry: &serde_json::Value) -> u64 {
+    entry
+        .get(SCHEMA_VERSION_FIELD)
+        .and_then(|version| version.as_u64())
+        .unwrap_or(0)
+}
+
+/// Upgrade an entry to the current layout, removing its version field.
+///
+/// Entries written by a newer version are read as they are, after a downgrade unknown fields
+/// are ignored.
+pub fn upgrade(entry: &mut serde_json::Value) {
+    let version = version_of(entry);
+    let Some(fields) = entry.as_object_mut() else {
+        return;
+    };
+    fields.remove(SCHEMA_VERSION_FIELD);
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        migration(fields);
+    }
+}
+
+/// Rewrite every entry with an older layout version, used by the `migrate-entries` command.
+pub async fn migrate_entries(
+    config: &IhaCdnConfig,
+    redis: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    tracing::info!(
+        "🧬 Upgrading entries to layout version {}...",
+        SCHEMA_VERSION
+    );
+    let rewritten =
+        rewrite_entries(config, redis, |entry| version_of(entry) < SCHEMA_VERSION).await?;
+    tracing::info!("🧬 Upgraded {} entries", rewritten.entries);
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Signed upload URLs, each allowing a single upload with the constraints it was signed with.
+//!
+//! The signature is a random token, its grant is kept in Redis until it is used or expires.
+
+use std::{sync::Arc, time::Instant};
+
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    moderation::mimetype_matches,
+    state::{SharedState, generate_token},
+    store::RedisConnection,
+};
+
+/// What the upload of a signed URL may be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadGrant {
+    /// The maximum file size in bytes, the regular limit if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// The allowed mimetypes, e.g. `image/*`, anything if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_types: Vec<String>,
+    /// Delete the upload after this many days.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u64>,
+    /// The name of the admin key that signed the URL.
+    pub signed_by: String,
+}
+
+impl UploadGrant {
+    pub fn allows_type(&self, mimetype: &str) -> bool {
+        self.allowed_types.is_empty()
+            || self
+                .allowed_types
+                .iter()
+                .any(|pattern| mimetype_matches(pattern, mimetype))
+    }
+}
+
+fn grant_key(prefix: &str, signature: &str) -> String {
+    format!("{prefix}:signed:{signature}")
+}
+
+/// Store a grant for `ttl` seconds, returns its signature.
+pub async fn sign(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    grant: &UploadGrant,
+    ttl: u64,
+) -> RedisResult<String> {
+    let signature = generate_token();
+    redis::cmd("SET")
+        .arg(grant_key(prefix, &signature))
+        .arg(serde_json::to_string(grant).unwrap())
+        .arg("EX")
+        .arg(ttl)
+        .exec_async(connection)
+        .await?;
+    Ok(signature)
+}
+
+/// Takes a grant with its remaining time to live in milliseconds, deleting it if `ARGV[1]` is 1.
+const CLAIM_SCRIPT: &str = r#"
+local grant = redis.call("GET", KEYS[1])
+if not grant then
+    return false
+end
+local ttl = redis.call("PTTL", KEYS[1])
+if ARGV[1] == "1" then
+    redis.call("DEL", KEYS[1])
+end
+return {grant, ttl}
+"#;
+
+/// A grant taken by the request being processed, given back if the upload is not accepted.
+pub struct GrantClaim {
+    pub grant: UploadGrant,
+    /// Where and for how long the grant is given back, [`None`] if it was not used up.
+    restore: Option<(Arc<SharedState>, String, String, i64)>,
+    claimed_at: Instant,
+}
+
+/// The grant of a signature, used up unless `consume` is false (e.g. for a dry run).
+///
+/// A used up grant is given back when the claim is dropped before [`GrantClaim::accept`], so a
+/// rejected upload can be retried with the same URL.
+pub async fn claim(
+    state: &Arc<SharedState>,
+    connection: &mut RedisConnection,
+    signature: &str,
+    consume: bool,
+) -> RedisResult<Option<GrantClaim>> {
+    let key = grant_key(&state.config.key_prefix, signature);
+    let claimed = redis::cmd("EVAL")
+        .arg(CLAIM_SCRIPT)
+        .arg(1)
+        .arg(&key)
+        .arg(if consume { 1 } else { 0 })
+        .query_async::<Option<(String, i64)>>(connection)
+        .await?;
+    let Some((value, ttl)) = claimed else {
+        return Ok(None);
+    };
+    let Ok(grant) = serde_json::from_str(&value) else {
+        return Ok(None);
+    };
+    Ok(Some(GrantClaim {
+        grant,
+        restore: consume.then(|| (Arc::clone(state), key, value, ttl)),
+        claimed_at: Instant::now(),
+    }))
+}
+
+impl GrantClaim {
+    /// The upload was stored, the grant stays used up.
+    pub fn accept(mut self) {
+        self.restore = None;
+    }
+}
+
+impl Drop for GrantClaim {
+    fn drop(&mut self) {
+        let Some((state, key, value, ttl)) = self.restore.take() else {
+            return;
+        };
+        // Grants always have an expiry, the time spent on the upload is taken off it
+        let remaining = ttl - self.claimed_at.elapsed().as_millis() as i64;
+        if remaining <= 0 {
+            return;
+        }
+        tokio::spawn(async move {
+            let restored = match state.make_connection().await {
+                Ok(mut connection) => {
+                    redis::cmd("SET")
+                        .arg(&key)
+                        .arg(&value)
+                        .arg("PX")
+                        .arg(remaining)
+                        .arg("NX")
+                        .exec_async(&mut connection)
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = restored {
+                tracing::error!("Failed to give back a signed upload grant: {}", err);
+            }
+        });
+    }
+}
@@ -1,42 +1,379 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
 
-use redis::{RedisResult, aio::MultiplexedConnection};
+use redis::RedisResult;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_cron_scheduler::JobScheduler;
 
-use crate::config::IhaCdnConfig;
+use crate::{
+    access_log::AccessLog,
+    breaker::RedisBreaker,
+    config::{IhaCdnConfig, IhaCdnShortRetentionConfig},
+    events::EventQueue,
+    filters::UploadFilters,
+    media::TranscodeQueue,
+    moderation::ModerationEngine,
+    network::NetworkFilter,
+    policy::PolicyScript,
+    store::RedisConnection,
+    words::WordFilter,
+};
 
 pub struct SharedState {
     pub config: Arc<IhaCdnConfig>,
     pub redis: Arc<redis::Client>,
+    pub replicas: Arc<RedisReplicas>,
+    /// Fails fast while Redis is down, with the entries that can still be served.
+    pub breaker: Arc<RedisBreaker>,
+    pub words: Arc<WordFilter>,
+    pub moderation: Arc<ModerationEngine>,
+    pub filters: Arc<UploadFilters>,
+    pub policy: Arc<PolicyScript>,
+    pub network: Arc<NetworkFilter>,
+    pub transcoder: Arc<TranscodeQueue>,
+    pub events: Arc<EventQueue>,
+    pub scheduler: JobScheduler,
+    pub access_log: Arc<AccessLog>,
+    /// When the server was started.
+    pub started_at: std::time::Instant,
 }
 
 impl SharedState {
-    pub async fn make_connection(&self) -> RedisResult<MultiplexedConnection> {
-        self.redis.get_multiplexed_async_connection().await
+    pub async fn make_connection(&self) -> RedisResult<RedisConnection> {
+        if self.breaker.is_open() {
+            return Err((redis::ErrorKind::IoError, "Redis is considered down").into());
+        }
+        let connection = self.redis.get_multiplexed_async_connection().await;
+        self.breaker.record(connection.is_ok());
+        connection.map(|connection| {
+            RedisConnection::from(connection).with_breaker(Arc::clone(&self.breaker))
+        })
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// How long an unreachable replica is skipped, in seconds.
+const REPLICA_RETRY_DELAY: u64 = 30;
+
+/// The read-only replicas of the Redis database, used in turn.
+pub struct RedisReplicas {
+    clients: Vec<redis::Client>,
+    /// When each replica may be tried again after failing, as an UNIX timestamp.
+    retry_at: Vec<AtomicU64>,
+    next: AtomicUsize,
+}
+
+impl RedisReplicas {
+    pub fn open(urls: &[String]) -> RedisResult<Self> {
+        let clients = urls
+            .iter()
+            .map(|url| redis::Client::open(url.as_str()))
+            .collect::<RedisResult<Vec<_>>>()?;
+        Ok(Self {
+            retry_at: clients.iter().map(|_| AtomicU64::new(0)).collect(),
+            clients,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Connect to the next reachable replica, [`None`] if there is none.
+    pub async fn make_connection(&self) -> Option<RedisConnection> {
+        if self.clients.is_empty() {
+            return None;
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.clients.len() {
+            let index = (start + offset) % self.clients.len();
+            if self.retry_at[index].load(Ordering::Relaxed) > now {
+                continue;
+            }
+            match self.clients[index].get_multiplexed_async_connection().await {
+                Ok(connection) => return Some(connection.into()),
+                Err(err) => {
+                    tracing::warn!("Redis replica #{} is unreachable: {}", index, err);
+                    self.retry_at[index].store(now + REPLICA_RETRY_DELAY, Ordering::Relaxed);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscodeStatus {
+    Queued,
+    Processing,
+    Done,
+    Failed,
+}
+
+/// The state of the browser-playable copy of a video, or of the video copy of a GIF.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranscodeState {
+    pub status: TranscodeStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
+}
+
+impl TranscodeState {
+    pub fn queued() -> Self {
+        Self {
+            status: TranscodeStatus::Queued,
+            path: None,
+            mimetype: None,
+        }
+    }
+
+    /// The path and mimetype of the finished copy.
+    pub fn output(&self) -> Option<(&PathBuf, &str)> {
+        match (self.status, &self.path, &self.mimetype) {
+            (TranscodeStatus::Done, Some(path), Some(mimetype)) => Some((path, mimetype)),
+            _ => None,
+        }
+    }
+}
+
+/// Who uploaded an entry, kept for moderation.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UploaderInfo {
+    /// The IP address, or its salted hash in privacy mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// The name of the admin key used to upload, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// A previous version of an edited paste.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PasteRevision {
+    pub path: PathBuf,
+    /// When this version was replaced, as an UNIX timestamp.
+    pub time_replaced: i64,
+}
+
+/// Descriptive fields attached to an upload by its uploader.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct EntryMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Lowercase tags, without duplicates.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Where the content comes from, usually an URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl EntryMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.description.is_none()
+            && self.tags.is_empty()
+            && self.source.is_none()
+    }
+
+    /// Check if any field contains `query`, ignoring case.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [&self.title, &self.description, &self.source]
+            .into_iter()
+            .flatten()
+            .chain(&self.tags)
+            .any(|field| field.to_lowercase().contains(&query))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum CDNData {
     Short {
         target: String,
+        /// Unset for short links created before it was recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        time_added: Option<i64>,
+        /// Removed after this many views, the remaining views are counted in Redis.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_views: Option<u64>,
+        /// Removed at this UNIX timestamp as asked by the uploader, regardless of the retention.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<i64>,
+        /// SHA-256 hash of the delete token returned on upload.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delete_token: Option<String>,
+        /// The uploader IP and user agent, unset for entries added before it was kept.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uploader: Option<UploaderInfo>,
+        /// Under legal hold, nothing deletes the entry until an admin releases it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        legal_hold: bool,
     },
     File {
         is_admin: bool,
         path: PathBuf,
         mimetype: String,
         time_added: i64,
+        /// Extra response headers sent when serving this file.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+        /// Quarantined by moderation, not served until released by an admin.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        quarantined: bool,
+        /// Retention forced by moderation, in days.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retention_days: Option<u64>,
+        /// The NSFW confidence score from the classification hook.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nsfw_score: Option<f64>,
+        /// The poster frame extracted from a video.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        poster: Option<PathBuf>,
+        /// The browser-playable copy of a video, served instead of the original.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transcode: Option<TranscodeState>,
+        /// The resized copies of an image made for `/{id}/thumb`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        thumbnails: Vec<PathBuf>,
+        /// Deleted after the first complete download.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        burn_after_read: bool,
+        /// SHA-256 hash of the delete token returned on upload.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delete_token: Option<String>,
+        /// The expiry computed on upload, as an UNIX timestamp.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<i64>,
+        /// SHA-256 hashes of the access tokens allowed to view this entry, empty if public.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        acl: Vec<String>,
+        /// Not served before this UNIX timestamp.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        not_before: Option<i64>,
+        /// Not served after this UNIX timestamp.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        not_after: Option<i64>,
+        /// Removed after this many views, the remaining views are counted in Redis.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_views: Option<u64>,
+        /// Removed at this UNIX timestamp as asked by the uploader, regardless of the retention.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<i64>,
+        /// Title, description, tags and source given on upload.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<EntryMetadata>,
+        /// The `Content-Type` sent by the uploader, if it differs from the detected one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        declared_mimetype: Option<String>,
+        /// Hex SHA-256 of the content, the strong `ETag` it is served with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_hash: Option<String>,
+        /// Shown in the public gallery.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        listed: bool,
+        /// The uploader IP and user agent, unset for entries added before it was kept.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uploader: Option<UploaderInfo>,
+        /// Under legal hold, nothing deletes the entry until an admin releases it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        legal_hold: bool,
     },
     Code {
         is_admin: bool,
         path: PathBuf,
         mimetype: String,
         time_added: i64,
+        /// The content is an opaque client-side encrypted blob, never rendered by the server.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        encrypted: bool,
+        /// Extra response headers sent when serving this paste.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+        /// Quarantined by moderation, not served until released by an admin.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        quarantined: bool,
+        /// Retention forced by moderation, in days.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retention_days: Option<u64>,
+        /// The paste this one was forked from.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parent_id: Option<String>,
+        /// The syntax highlighting language chosen on upload, instead of the one from `mimetype`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+        /// SHA-256 hash of the delete token that allows editing and deleting this paste.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delete_token: Option<String>,
+        /// Previous versions of the paste, newest first.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        revisions: Vec<PasteRevision>,
+        /// The expiry computed on upload, as an UNIX timestamp.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<i64>,
+        /// SHA-256 hashes of the access tokens allowed to view this entry, empty if public.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        acl: Vec<String>,
+        /// Not served before this UNIX timestamp.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        not_before: Option<i64>,
+        /// Not served after this UNIX timestamp.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        not_after: Option<i64>,
+        /// Removed after this many views, the remaining views are counted in Redis.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_views: Option<u64>,
+        /// Removed at this UNIX timestamp as asked by the uploader, regardless of the retention.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<i64>,
+        /// Title, description, tags and source given on upload.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<EntryMetadata>,
+        /// The `Content-Type` sent by the uploader, if it differs from the detected one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        declared_mimetype: Option<String>,
+        /// Hex SHA-256 of the content, the strong `ETag` it is served with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_hash: Option<String>,
+        /// Shown in the public gallery.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        listed: bool,
+        /// The uploader IP and user agent, unset for entries added before it was kept.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uploader: Option<UploaderInfo>,
+        /// Under legal hold, nothing deletes the entry until an admin releases it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        legal_hold: bool,
     },
 }
 
 impl CDNData {
+    /// The type of the entry as stored in the `type` tag.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CDNData::Short { .. } => "short",
+            CDNData::File { .. } => "file",
+            CDNData::Code { .. } => "code",
+        }
+    }
+
     pub fn is_admin(&self) -> bool {
         match self {
             CDNData::Short { .. } => false,
@@ -45,14 +382,391 @@ impl CDNData {
         }
     }
 
-    pub async fn is_expired(&self, config: &Arc<IhaCdnConfig>) -> bool {
+    /// The custom response headers of this entry.
+    pub fn custom_headers(&self) -> Option<&BTreeMap<String, String>> {
+        match self {
+            CDNData::Short { .. } => None,
+            CDNData::File { headers, .. } => Some(headers),
+            CDNData::Code { headers, .. } => Some(headers),
+        }
+    }
+
+    /// Replace the custom response headers of this entry.
+    ///
+    /// Returns `false` if the entry does not support custom headers.
+    pub fn set_custom_headers(&mut self, new_headers: BTreeMap<String, String>) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File { headers, .. } | CDNData::Code { headers, .. } => {
+                *headers = new_headers;
+                true
+            }
+        }
+    }
+
+    /// Check a delete token against the stored hash.
+    ///
+    /// Entries without a delete token never match.
+    pub fn verify_delete_token(&self, token: &str) -> bool {
+        match self {
+            CDNData::Short {
+                delete_token: Some(hash),
+                ..
+            }
+            | CDNData::File {
+                delete_token: Some(hash),
+                ..
+            }
+            | CDNData::Code {
+                delete_token: Some(hash),
+                ..
+            } => !token.is_empty() && *hash == hash_token(token),
+            _ => false,
+        }
+    }
+
+    /// Check if viewing the entry is restricted to the access tokens of its ACL.
+    pub fn is_restricted(&self) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File { acl, .. } | CDNData::Code { acl, .. } => !acl.is_empty(),
+        }
+    }
+
+    /// Check an access token against the ACL, public entries are visible to everyone.
+    pub fn is_visible_to(&self, token: Option<&str>) -> bool {
+        match self {
+            CDNData::Short { .. } => true,
+            CDNData::File { acl, .. } | CDNData::Code { acl, .. } => {
+                acl.is_empty()
+                    || token
+                        .is_some_and(|token| !token.is_empty() && acl.contains(&hash_token(token)))
+            }
+        }
+    }
+
+    /// Replace the access tokens allowed to view the entry, an empty list makes it public.
+    ///
+    /// Returns `false` if the entry does not support an ACL.
+    pub fn set_acl(&mut self, tokens: &[String]) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File { acl, .. } | CDNData::Code { acl, .. } => {
+                *acl = tokens.iter().map(|token| hash_token(token)).collect();
+                acl.sort();
+                acl.dedup();
+                true
+            }
+        }
+    }
+
+    /// The amount of views the entry is removed after.
+    pub fn max_views(&self) -> Option<u64> {
+        match self {
+            CDNData::Short { max_views, .. }
+            | CDNData::File { max_views, .. }
+            | CDNData::Code { max_views, .. } => *max_views,
+        }
+    }
+
+    /// The expiry chosen by the uploader, as an UNIX timestamp.
+    pub fn expires(&self) -> Option<i64> {
+        match self {
+            CDNData::Short { expires, .. }
+            | CDNData::File { expires, .. }
+            | CDNData::Code { expires, .. } => *expires,
+        }
+    }
+
+    /// Check if the expiry chosen by the uploader has passed.
+    pub fn has_expired(&self, now: i64) -> bool {
+        self.expires().is_some_and(|expires| now >= expires)
+    }
+
+    /// The descriptive fields given on upload.
+    pub fn metadata(&self) -> Option<&EntryMetadata> {
+        match self {
+            CDNData::Short { .. } => None,
+            CDNData::File { metadata, .. } | CDNData::Code { metadata, .. } => metadata.as_ref(),
+        }
+    }
+
+    /// Who uploaded the entry, unknown for entries added before it was kept.
+    pub fn uploader(&self) -> Option<&UploaderInfo> {
+        match self {
+            CDNData::Short { uploader, .. }
+            | CDNData::File { uploader, .. }
+            | CDNData::Code { uploader, .. } => uploader.as_ref(),
+        }
+    }
+
+    /// Forget who uploaded the entry, returning whether anything was kept.
+    pub fn forget_uploader(&mut self) -> bool {
+        match self {
+            CDNData::Short { uploader, .. }
+            | CDNData::File { uploader, .. }
+            | CDNData::Code { uploader, .. } => uploader.take().is_some(),
+        }
+    }
+
+    /// The hash of the stored content, unknown for entries uploaded before it was kept.
+    pub fn content_hash(&self) -> Option<&str> {
+        match self {
+            CDNData::Short { .. } => None,
+            CDNData::File { content_hash, .. } | CDNData::Code { content_hash, .. } => {
+                content_hash.as_deref()
+            }
+        }
+    }
+
+    /// Check if the uploader asked for the entry to be shown in the public gallery.
+    pub fn is_listed(&self) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File { listed, .. } | CDNData::Code { listed, .. } => *listed,
+        }
+    }
+
+    /// Check if anyone can view the entry right now, without a token or a limit on its views.
+    pub fn is_public(&self, now: i64) -> bool {
+        let (not_before, not_after) = self.availability();
+        !self.is_quarantined()
+            && !self.is_restricted()
+            && !self.burns_after_read()
+            && self.max_views().is_none()
+            && not_before.is_none_or(|not_before| now >= not_before)
+            && not_after.is_none_or(|not_after| now < not_after)
+    }
+
+    /// The time the entry was added, as an UNIX timestamp.
+    pub fn time_added(&self) -> Option<i64> {
+        match self {
+            CDNData::Short { time_added, .. } => *time_added,
+            CDNData::File { time_added, .. } | CDNData::Code { time_added, .. } => {
+                Some(*time_added)
+            }
+        }
+    }
+
+    /// Check if the entry is deleted after its first complete download.
+    pub fn burns_after_read(&self) -> bool {
+        matches!(
+            self,
+            CDNData::File {
+                burn_after_read: true,
+                ..
+            }
+        )
+    }
+
+    /// The time window the entry is served in, as UNIX timestamps.
+    pub fn availability(&self) -> (Option<i64>, Option<i64>) {
+        match self {
+            CDNData::Short { .. } => (None, None),
+            CDNData::File {
+                not_before,
+                not_after,
+                ..
+            }
+            | CDNData::Code {
+                not_before,
+                not_after,
+                ..
+            } => (*not_before, *not_after),
+        }
+    }
+
+    /// Replace the time window the entry is served in.
+    ///
+    /// Returns `false` if the entry does not support a time window.
+    pub fn set_availability(&mut self, start: Option<i64>, end: Option<i64>) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File {
+                not_before,
+                not_after,
+                ..
+            }
+            | CDNData::Code {
+                not_before,
+                not_after,
+                ..
+            } => {
+                *not_before = start;
+                *not_after = end;
+                true
+            }
+        }
+    }
+
+    /// Check if the entry is quarantined by moderation.
+    pub fn is_quarantined(&self) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File { quarantined, .. } => *quarantined,
+            CDNData::Code { quarantined, .. } => *quarantined,
+        }
+    }
+
+    /// Check if the entry is under legal hold, kept regardless of any deletion.
+    pub fn is_held(&self) -> bool {
+        match self {
+            CDNData::Short { legal_hold, .. }
+            | CDNData::File { legal_hold, .. }
+            | CDNData::Code { legal_hold, .. } => *legal_hold,
+        }
+    }
+
+    /// Put the entry under legal hold or release it.
+    pub fn set_legal_hold(&mut self, value: bool) {
+        match self {
+            CDNData::Short { legal_hold, .. }
+            | CDNData::File { legal_hold, .. }
+            | CDNData::Code { legal_hold, .. } => *legal_hold = value,
+        }
+    }
+
+    /// Quarantine or release the entry.
+    ///
+    /// Returns `false` if the entry can't be quarantined.
+    pub fn set_quarantined(&mut self, value: bool) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File { quarantined, .. } | CDNData::Code { quarantined, .. } => {
+                *quarantined = value;
+                true
+            }
+        }
+    }
+
+    /// Check if the retention forced by moderation has passed.
+    fn is_forced_retention_expired(&self, now_time: i64) -> bool {
+        let (retention_days, time_added) = match self {
+            CDNData::Short { .. } => return false,
+            CDNData::File {
+                retention_days,
+                time_added,
+                ..
+            } => (retention_days, time_added),
+            CDNData::Code {
+                retention_days,
+                time_added,
+                ..
+            } => (retention_days, time_added),
+        };
+
+        match retention_days {
+            Some(days) => now_time.saturating_sub(*time_added) > (*days as i64) * 86400,
+            None => false,
+        }
+    }
+
+    /// The expiry persisted on upload, entries from before it was persisted have none.
+    fn persisted_expiry(&self) -> Option<i64> {
+        match self {
+            CDNData::Short { .. } => None,
+            CDNData::File { expires_at, .. } | CDNData::Code { expires_at, .. } => *expires_at,
+        }
+    }
+
+    /// Compute the expiry from the current retention config and persist it in the entry.
+    ///
+    /// Returns the persisted expiry, [`None`] if the entry never expires.
+    pub fn persist_expiry(&mut self, config: &Arc<IhaCdnConfig>, file_size: u64) -> Option<i64> {
+        let computed = self.compute_expiry(config, file_size);
+        match self {
+            CDNData::Short { .. } => None,
+            CDNData::File { expires_at, .. } | CDNData::Code { expires_at, .. } => {
+                *expires_at = computed;
+                computed
+            }
+        }
+    }
+
+    /// The time this entry is expected to expire, as an UNIX timestamp.
+    ///
+    /// The expiry persisted on upload is used if there is one, so later retention config changes
+    /// don't shorten the lifetime promised to the uploader.
+    ///
+    /// Returns [`None`] if the entry never expires.
+    pub fn expires_at(&self, config: &Arc<IhaCdnConfig>, file_size: u64) -> Option<i64> {
+        if self.is_retention_exempt(config) {
+            return self.compute_expiry(config, file_size);
+        }
+        self.persisted_expiry()
+            .or_else(|| self.compute_expiry(config, file_size))
+    }
+
+    /// Whether the extension or mimetype of the entry is exempt from the retention policy.
+    ///
+    /// Exempt entries still expire with a retention forced by moderation.
+    fn is_retention_exempt(&self, config: &IhaCdnConfig) -> bool {
+        match self {
+            CDNData::Short { .. } => false,
+            CDNData::File { path, mimetype, .. } | CDNData::Code { path, mimetype, .. } => {
+                config.retention.is_exempt(path, mimetype)
+            }
+        }
+    }
+
+    fn compute_expiry(&self, config: &Arc<IhaCdnConfig>, file_size: u64) -> Option<i64> {
+        let (is_admin, time_added, retention_days) = match self {
+            CDNData::Short { expires, .. } => return *expires,
+            CDNData::File {
+                is_admin,
+                time_added,
+                retention_days,
+                ..
+            }
+            | CDNData::Code {
+                is_admin,
+                time_added,
+                retention_days,
+                ..
+            } => (*is_admin, *time_added, *retention_days),
+        };
+
+        let forced = retention_days.map(|days| time_added + (days as i64) * 86400);
+        let retention = if config.retention.enable && !is_admin && !self.is_retention_exempt(config)
+        {
+            let max_age = calculate_retention_file(file_size, config, is_admin);
+            (max_age != -1).then(|| time_added + max_age.saturating_mul(86400))
+        } else {
+            None
+        };
+
+        [forced, retention, self.expires()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Check if the entry should be removed by the purge task.
+    ///
+    /// `last_clicked` is the last time a short link was followed, see [`last_clicked_key`].
+    pub async fn is_expired(&self, config: &Arc<IhaCdnConfig>, last_clicked: Option<i64>) -> bool {
         let now_time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
+        if self.has_expired(now_time) || self.is_forced_retention_expired(now_time) {
+            return true;
+        }
+
+        // The persisted expiry may predate the exemption
+        if self.is_retention_exempt(config) {
+            return false;
+        }
+
+        if let Some(expires_at) = self.persisted_expiry() {
+            return now_time >= expires_at;
+        }
+
         match self {
-            CDNData::Short { .. } => false,
+            CDNData::Short { time_added, .. } => {
+                is_short_expired(&config.short_retention, *time_added, last_clicked, now_time)
+            }
             CDNData::File {
                 is_admin,
                 time_added,
@@ -62,7 +776,8 @@ impl CDNData {
                 if *is_admin {
                     false
                 } else {
-                    let file_size = match tokio::fs::metadata(path).await {
+                    let file_size = match tokio::fs::metadata(config.resolve_path(path).await).await
+                    {
                         Ok(metadata) => metadata.len(),
                         Err(err) => return err.kind() == std::io::ErrorKind::NotFound,
                     };
@@ -85,7 +800,8 @@ impl CDNData {
                 if *is_admin {
                     false
                 } else {
-                    let file_size = match tokio::fs::metadata(path).await {
+                    let file_size = match tokio::fs::metadata(config.resolve_path(path).await).await
+                    {
                         Ok(metadata) => metadata.len(),
                         Err(err) => return err.kind() == std::io::ErrorKind::NotFound,
                     };
@@ -102,42 +818,179 @@ impl CDNData {
         }
     }
 
-    pub async fn delete_file(&self) {
+    pub async fn delete_file(&self, config: &IhaCdnConfig) {
         let path = match self {
             CDNData::Short { .. } => None,
             CDNData::File { path, .. } => Some(path),
             CDNData::Code { path, .. } => Some(path),
         };
 
-        if let Some(path) = path {
-            if let Err(err) = tokio::fs::remove_file(path).await {
-                tracing::error!("Failed to delete file: {}", err);
+        if let Some(path) = path
+            && let Err(err) = tokio::fs::remove_file(config.resolve_path(path).await).await
+        {
+            tracing::error!("Failed to delete file: {}", err);
+        }
+
+        if let CDNData::Code { revisions, .. } = self {
+            for revision in revisions {
+                if let Err(err) =
+                    tokio::fs::remove_file(config.resolve_path(&revision.path).await).await
+                {
+                    tracing::error!("Failed to delete paste revision: {}", err);
+                }
             }
         }
+
+        if let CDNData::File {
+            poster: Some(poster),
+            ..
+        } = self
+            && let Err(err) = tokio::fs::remove_file(config.resolve_path(poster).await).await
+        {
+            tracing::error!("Failed to delete poster: {}", err);
+        }
+
+        if let CDNData::File { thumbnails, .. } = self {
+            for thumbnail in thumbnails {
+                if let Err(err) = tokio::fs::remove_file(config.resolve_path(thumbnail).await).await
+                {
+                    tracing::error!("Failed to delete thumbnail: {}", err);
+                }
+            }
+        }
+
+        if let CDNData::File {
+            transcode:
+                Some(TranscodeState {
+                    path: Some(transcoded),
+                    ..
+                }),
+            ..
+        } = self
+            && let Err(err) = tokio::fs::remove_file(config.resolve_path(transcoded).await).await
+        {
+            tracing::error!("Failed to delete transcoded file: {}", err);
+        }
     }
 }
 
-fn calculate_retention_file(file_size: u64, config: &Arc<IhaCdnConfig>, is_admin: bool) -> i64 {
+/// Check a short link against the short link retention policy.
+///
+/// Short links that were never clicked are idle since they were created.
+fn is_short_expired(
+    retention: &IhaCdnShortRetentionConfig,
+    time_added: Option<i64>,
+    last_clicked: Option<i64>,
+    now_time: i64,
+) -> bool {
+    if !retention.enable {
+        return false;
+    }
+
+    let too_old = retention
+        .max_age
+        .zip(time_added)
+        .is_some_and(|(max_age, time_added)| now_time - time_added > (max_age as i64) * 86400);
+    let too_idle = retention
+        .max_idle
+        .zip(last_clicked.or(time_added))
+        .is_some_and(|(max_idle, last_active)| now_time - last_active > (max_idle as i64) * 86400);
+    too_old || too_idle
+}
+
+/// How many days a file of this size is kept, `-1` if uploads are unlimited.
+///
+/// `min_age + (min_age - max_age) * (file_size / limit - 1) ^ 5`, empty files are kept for
+/// `max_age` days and files at the size limit for `min_age` days.
+pub(crate) fn calculate_retention_file(
+    file_size: u64,
+    config: &Arc<IhaCdnConfig>,
+    is_admin: bool,
+) -> i64 {
     let ret = &config.retention;
     let limit = config.get_limit(is_admin);
     match limit {
         Some(limit) => {
-            let min_age = ret.min_age as i64;
-            let max_age = ret.max_age as i64;
-            let fsize = file_size as f64;
-            let ilimit = limit as f64;
-
-            let fs_div = (fsize / ilimit).floor().min(0.0) as i64;
-            let age_calc = -max_age.saturating_add(min_age);
+            let min_age = ret.min_age as f64;
+            let max_age = ret.max_age as f64;
+            // Files over the limit (e.g. admin uploads) are kept as long as the largest ones
+            let ratio = if limit == 0 {
+                1.0
+            } else {
+                (file_size as f64 / limit as f64).clamp(0.0, 1.0)
+            };
 
-            let rhs = (age_calc.saturating_mul(fs_div)).saturating_pow(5);
-            min_age.saturating_add(rhs)
+            let days = min_age + (min_age - max_age) * (ratio - 1.0).powi(5);
+            days.clamp(min_age.min(max_age), min_age.max(max_age))
+                .round() as i64
         }
         None => -1,
     }
 }
 
-pub const PREFIX: &str = "ihacdn";
+/// How long files are kept from empty up to the size limit, as `(size, days)`.
+///
+/// Empty if uploads are unlimited.
+pub(crate) fn retention_table(config: &Arc<IhaCdnConfig>) -> Vec<(String, i64)> {
+    match config.get_limit(false) {
+        Some(limit) => [0, 25, 50, 75, 100]
+            .into_iter()
+            .map(|percent| {
+                let size = limit * percent / 100;
+                (
+                    humanize_bytes(size),
+                    calculate_retention_file(size, config, false),
+                )
+            })
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// The Redis key of an entry.
+pub fn entry_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}{id}")
+}
+
+/// The Redis key of the view counter of an entry.
+pub fn views_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}:views:{id}")
+}
+
+/// The Redis key of the remaining views of an entry with a view limit.
+pub fn remaining_views_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}:remaining:{id}")
+}
+
+/// The Redis key of the last time a short link was followed, as an UNIX timestamp.
+pub fn last_clicked_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}:clicked:{id}")
+}
+
+/// The Redis key claiming the only download of a burn-after-read file.
+pub fn burn_claim_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}:claim:{id}")
+}
+
+/// Generate a new random delete token.
+pub fn generate_token() -> String {
+    use rand::distr::{Alphanumeric, SampleString};
+
+    Alphanumeric.sample_string(&mut rand::rng(), 32)
+}
+
+/// Hash a delete token for storage, the token itself is only known to the uploader.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Get the entry ID of a Redis key, other keys sharing the prefix (e.g. stats) return [`None`].
+pub fn entry_id<'a>(prefix: &str, key: &'a str) -> Option<&'a str> {
+    key.strip_prefix(prefix).filter(|id| !id.contains(':'))
+}
 
 pub const DELETED_ERROR: &str = r#"System.IO.FileNotFoundException: Could not find file '{{ FN }}' in server filesystem.
 File name: '{{ FN }}'
@@ -224,6 +1077,57 @@ stack backtrace:
              at /rustc/1.86.0/library/std/src/rt.rs:165
 "#;
 
+pub const INVALID_ENCRYPTED_PAYLOAD: &str = r#"Traceback (most recent call last):
+  File "paste_vault.py", line 58, in store_encrypted
+    blob = base64.b64decode(payload, validate=True)
+binascii.Error: Encrypted paste payload must be base64 encoded
+"#;
+
+pub const INVALID_CUSTOM_HEADERS: &str = r#"System.ArgumentException: The header '{{ HEADER }}' cannot be set by the client.
+Parameter name: headers
+   at System.Net.WebHeaderCollection.ThrowOnRestrictedHeader(String headerName)
+   at System.Net.WebHeaderCollection.Add(String name, String value)
+   at ihaCDN.Routes.UploadRoutes.ApplyHeaders(Dictionary`2 headers) in UploadRoutes.cs:line 88
+"#;
+
+pub const MODERATION_REJECTED: &str = r#"PermissionError: [Errno 13] Upload rejected by moderation policy: '{{ RULE }}'
+  File "/srv/ihacdn/moderation/engine.py", line 121, in evaluate
+    raise PermissionError(errno.EACCES, "Upload rejected by moderation policy", rule.name)
+"#;
+
+pub const INFECTED_UPLOAD: &str = r#"Scanning upload.bin...
+upload.bin: {{ SIGNATURE }} FOUND
+
+----------- SCAN SUMMARY -----------
+Infected files: 1
+Action taken: upload refused, go scan your computer
+"#;
+
+pub const INVALID_DELETE_TOKEN: &str = r#"Traceback (most recent call last):
+  File "/srv/ihacdn/paste/editor.py", line 33, in edit_paste
+    raise PermissionError("Invalid delete token for '{{ FN }}'")
+PermissionError: Invalid delete token for '{{ FN }}'
+"#;
+
+pub const ACCESS_DENIED: &str = r#"java.security.AccessControlException: access denied ("ihacdn.EntryPermission" "{{ FN }}" "read")
+    at java.security.AccessControlContext.checkPermission(AccessControlContext.java:472)
+    at com.ihacdn.FileHandler.checkAcl(FileHandler.java:97)
+    at com.ihacdn.Main.main(Main.java:12)
+"#;
+
+pub const NOT_YET_AVAILABLE: &str = r#"Traceback (most recent call last):
+  File "/srv/ihacdn/serve.py", line 71, in serve_file
+    raise EmbargoError('{{ FN }}', not_before='{{ TIME }}')
+EmbargoError: '{{ FN }}' is embargoed until {{ TIME }}
+"#;
+
+pub const QUARANTINED_ERROR: &str = r#"HTTP/1.1 451 Unavailable For Legal Reasons
+Traceback (most recent call last):
+  File "/srv/ihacdn/serve.py", line 64, in serve_file
+    raise ContentQuarantined('{{ FN }}')
+ContentQuarantined: '{{ FN }}' is quarantined pending moderator review
+"#;
+
 pub const CUSTOM_NAME_GENERATION_ERROR: &str = r#"Error: Failed to generate custom name: {{ REASON }}
     at generateCustomName (customNameGenerator.js:45:15)
     at processRequest (requestHandler.js:32:10)
@@ -251,14 +1155,7 @@ pub fn humanize_bytes(bytes: u64) -> String {
         let mut once = true;
         let extra = format!("{:.2}", units);
         let trimmed = extra
-            .trim_end_matches(|_| {
-                if once {
-                    once = false;
-                    true
-                } else {
-                    false
-                }
-            })
+            .trim_end_matches(|_| std::mem::take(&mut once))
             .trim_end_matches("0")
             .trim_end_matches(".");
 
@@ -268,3 +1165,60 @@ pub fn humanize_bytes(bytes: u64) -> String {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retention_config(limit: Option<u64>) -> Arc<IhaCdnConfig> {
+        let mut config = IhaCdnConfig::default();
+        config.retention.enable = true;
+        config.retention.min_age = 30;
+        config.retention.max_age = 180;
+        config.storage.filesize_limit = limit;
+        Arc::new(config)
+    }
+
+    #[test]
+    fn retention_curve_follows_the_file_size() {
+        // 100 MiB
+        let config = retention_config(Some(102400));
+        let limit = config.get_limit(false).unwrap();
+
+        assert_eq!(calculate_retention_file(0, &config, false), 180);
+        // 30 + 150 * 0.5 ^ 5
+        assert_eq!(calculate_retention_file(limit / 2, &config, false), 35);
+        assert_eq!(calculate_retention_file(limit, &config, false), 30);
+    }
+
+    #[test]
+    fn retention_curve_is_clamped() {
+        let config = retention_config(Some(102400));
+        let limit = config.get_limit(false).unwrap();
+
+        assert_eq!(calculate_retention_file(limit * 4, &config, false), 30);
+        assert_eq!(calculate_retention_file(u64::MAX, &config, false), 30);
+    }
+
+    #[test]
+    fn retention_table_decreases_with_size() {
+        let config = retention_config(Some(102400));
+        let days: Vec<i64> = retention_table(&config)
+            .into_iter()
+            .map(|(_, days)| days)
+            .collect();
+
+        assert_eq!(days.len(), 5);
+        assert_eq!(days.first(), Some(&180));
+        assert_eq!(days.last(), Some(&30));
+        assert!(days.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(days[1] > days[2]);
+    }
+
+    #[test]
+    fn retention_curve_without_limit() {
+        let config = retention_config(None);
+
+        assert_eq!(calculate_retention_file(1024, &config, false), -1);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{Days, NaiveDate, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    index,
+    notifier::notify_stats_digest,
+    state::{CDNData, SharedState, entry_key},
+    store::RedisConnection,
+};
+
+/// How many days of rollups make up the last week.
+const WEEK_DAYS: u64 = 7;
+
+/// How many entries are fetched at once.
+const MGET_BATCH: usize = 500;
+
+/// The Redis key of the rollup of a day (`YYYY-MM-DD`).
+fn daily_key(prefix: &str, date: &str) -> String {
+    format!("{prefix}:stats:daily:{date}")
+}
+
+/// The Redis hash of the totals of every rollup.
+fn totals_key(prefix: &str) -> String {
+    format!("{prefix}:stats:totals")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MimetypeCount {
+    pub mimetype: String,
+    pub count: u64,
+}
+
+/// The upload statistics of a single day.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DailyStats {
+    pub date: String,
+    pub uploads: u64,
+    pub files: u64,
+    pub pastes: u64,
+    pub bytes: u64,
+    pub top_mimetypes: Vec<MimetypeCount>,
+}
+
+/// The aggregated statistics shown on the public stats page.
+#[derive(Debug, Default)]
+pub struct StatsSummary {
+    /// The totals of every rollup, the `date` is unused.
+    pub total: DailyStats,
+    /// The most recent rollup, of the previous day.
+    pub last_day: Option<DailyStats>,
+    /// The rollups of the last week summed, with their merged top mimetypes.
+    pub last_week: DailyStats,
+}
+
+/// Roll up the statistics of the previous day (UTC) into Redis.
+pub async fn stats_task(state: Arc<SharedState>) -> Result<(), Box<dyn std::error::Error>> {
+    tracing::info!("Running stats rollup task...");
+
+    let date = Utc::now()
+        .date_naive()
+        .checked_sub_days(Days::new(1))
+        .ok_or("Failed to compute the previous day")?;
+    let stats = collect_daily_stats(&state, date).await?;
+    tracing::info!(
+        "Rolled up {} uploads ({} bytes) for {}",
+        stats.uploads,
+        stats.bytes,
+        stats.date
+    );
+
+    let mut connection = state.make_connection().await?;
+    let mut cmd = redis::cmd("SET");
+    cmd.arg(daily_key(&state.config.key_prefix, &stats.date))
+        .arg(serde_json::to_string(&stats)?)
+        .arg("GET");
+    if let Some(keep_days) = state.config.stats.keep_days {
+        cmd.arg("EX").arg(keep_days * 86400);
+    }
+    let previous = cmd.query_async::<Option<String>>(&mut connection).await?;
+
+    // A rerun of the same day replaces its previous rollup in the totals
+    let previous = previous
+        .and_then(|previous| serde_json::from_str::<DailyStats>(&previous).ok())
+        .unwrap_or_default();
+    let totals_key = totals_key(&state.config.key_prefix);
+    redis::pipe()
+        .atomic()
+        .hincr(
+            &totals_key,
+            "uploads",
+            delta(stats.uploads, previous.uploads),
+        )
+        .hincr(&totals_key, "files", delta(stats.files, previous.files))
+        .hincr(&totals_key, "pastes", delta(stats.pastes, previous.pastes))
+        .hincr(&totals_key, "bytes", delta(stats.bytes, previous.bytes))
+        .exec_async(&mut connection)
+        .await?;
+
+    if state.config.stats.digest {
+        notify_stats_digest(&stats, &state.config);
+    }
+
+    Ok(())
+}
+
+fn delta(current: u64, previous: u64) -> i64 {
+    current as i64 - previous as i64
+}
+
+/// Load the totals and the rollups of the last week.
+pub async fn load_summary(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    top_mimetypes: usize,
+) -> redis::RedisResult<StatsSummary> {
+    let totals: HashMap<String, u64> = connection.hgetall(totals_key(prefix)).await?;
+    let total = |field: &str| totals.get(field).copied().unwrap_or_default();
+
+    let today = Utc::now().date_naive();
+    let keys = (1..=WEEK_DAYS)
+        .filter_map(|days| today.checked_sub_days(Days::new(days)))
+        .map(|date| daily_key(prefix, &date.format("%Y-%m-%d").to_string()))
+        .collect::<Vec<_>>();
+    let rollups = redis::cmd("MGET")
+        .arg(&keys)
+        .query_async::<Vec<Option<String>>>(connection)
+        .await?
+        .into_iter()
+        .map(|rollup| rollup.and_then(|rollup| serde_json::from_str::<DailyStats>(&rollup).ok()))
+        .collect::<Vec<_>>();
+
+    let mut last_week = DailyStats::default();
+    let mut mimetypes: HashMap<String, u64> = HashMap::new();
+    for rollup in rollups.iter().flatten() {
+        last_week.uploads += rollup.uploads;
+        last_week.files += rollup.files;
+        last_week.pastes += rollup.pastes;
+        last_week.bytes += rollup.bytes;
+        for mimetype in &rollup.top_mimetypes {
+            *mimetypes.entry(mimetype.mimetype.clone()).or_default() += mimetype.count;
+        }
+    }
+    last_week.top_mimetypes = sorted_mimetypes(mimetypes, top_mimetypes);
+
+    Ok(StatsSummary {
+        total: DailyStats {
+            uploads: total("uploads"),
+            files: total("files"),
+            pastes: total("pastes"),
+            bytes: total("bytes"),
+            ..Default::default()
+        },
+        last_day: rollups.into_iter().next().flatten(),
+        last_week,
+    })
+}
+
+fn sorted_mimetypes(mimetypes: HashMap<String, u64>, limit: usize) -> Vec<MimetypeCount> {
+    let mut top_mimetypes = mimetypes
+        .into_iter()
+        .map(|(mimetype, count)| MimetypeCount { mimetype, count })
+        .collect::<Vec<_>>();
+    top_mimetypes.sort_by(|a, b| b.count.cmp(&a.count).then(a.mimetype.cmp(&b.mimetype)));
+    top_mimetypes.truncate(limit);
+    top_mimetypes
+}
+
+/// Collect the statistics of the entries added on `date`.
+///
+/// The entries are read from the `added` index by their time, never by scanning the keyspace.
+async fn collect_daily_stats(
+    state: &SharedState,
+    date: NaiveDate,
+) -> Result<DailyStats, Box<dyn std::error::Error>> {
+    let start = date
+        .and_hms_opt(0, 0, 0)
+        .ok_or("Invalid date")?
+        .and_utc()
+        .timestamp();
+    let end = start + 86400;
+
+    let mut connection = state.make_connection().await?;
+    let mut stats = DailyStats {
+        date: date.format("%Y-%m-%d").to_string(),
+        ..Default::default()
+    };
+    let mut mimetypes: HashMap<String, u64> = HashMap::new();
+
+    let ids = index::added_between(&mut connection, &state.config.key_prefix, start, end).await?;
+    for ids in ids.chunks(MGET_BATCH) {
+        let keys = ids
+            .iter()
+            .map(|id| entry_key(&state.config.key_prefix, id))
+            .collect::<Vec<_>>();
+        let values = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async::<Vec<Option<Vec<u8>>>>(&mut connection)
+            .await?;
+
+        for value in values.into_iter().flatten() {
+            let cdn_data = match CDNData::decode(&value) {
+                Ok(cdn_data) => cdn_data,
+                Err(e) => {
+                    tracing::warn!("Skipping unparseable entry in stats rollup: {}", e);
+                    continue;
+                }
+            };
+
+            // Short links are not uploads
+            let (path, time_added, mimetype, is_file) = match &cdn_data {
+                CDNData::Short { .. } => continue,
+                CDNData::File {
+                    path,
+                    time_added,
+                    mimetype,
+                    ..
+                } => (path, *time_added, mimetype.clone(), true),
+                CDNData::Code {
+                    path,
+                    time_added,
+                    mimetype,
+                    ..
+                } => {
+                    let mimetype = mime_guess::from_ext(mimetype)
+                        .first_or_text_plain()
+                        .essence_str()
+                        .to_string();
+                    (path, *time_added, mimetype, false)
+                }
+            };
+
+            if time_added < start || time_added >= end {
+                continue;
+            }
+
+            if is_file {
+                stats.files += 1;
+            } else {
+                stats.pastes += 1;
+            }
+            stats.uploads += 1;
+            if let Ok(metadata) = tokio::fs::metadata(state.config.resolve_path(path).await).await {
+                stats.bytes += metadata.len();
+            }
+            *mimetypes.entry(mimetype).or_default() += 1;
+        }
+    }
+
+    stats.top_mimetypes = sorted_mimetypes(mimetypes, state.config.stats.top_mimetypes);
+
+    Ok(stats)
+}
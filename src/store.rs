@@ -0,0 +1,270 @@
+//! The connection to the metadata store, timing every Redis command for `/_/metrics`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, LazyLock, Mutex},
+    time::Instant,
+};
+
+use redis::{
+    Arg, Cmd, Pipeline, RedisFuture, RedisResult, Value,
+    aio::{ConnectionLike, MultiplexedConnection},
+};
+
+use crate::breaker::RedisBreaker;
+
+/// The upper bounds of the latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// The timings of every command sent by this process, shared by all tenants.
+static METRICS: LazyLock<Mutex<BTreeMap<String, CommandStats>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Default)]
+struct CommandStats {
+    /// The commands that took at most each of [`LATENCY_BUCKETS`].
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    /// The total time spent, in seconds.
+    sum: f64,
+    errors: u64,
+}
+
+/// A connection to Redis recording the latency and errors of its commands.
+///
+/// Pipelines are recorded as a single `PIPELINE` command.
+#[derive(Clone)]
+pub struct RedisConnection {
+    connection: MultiplexedConnection,
+    /// Told whether Redis could be reached for every command, see [`RedisConnection::with_breaker`].
+    breaker: Option<Arc<RedisBreaker>>,
+}
+
+impl From<MultiplexedConnection> for RedisConnection {
+    fn from(connection: MultiplexedConnection) -> Self {
+        Self {
+            connection,
+            breaker: None,
+        }
+    }
+}
+
+impl RedisConnection {
+    /// Count the commands that could not reach Redis as failures of `breaker`.
+    pub fn with_breaker(mut self, breaker: Arc<RedisBreaker>) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    fn report<T>(&self, result: &RedisResult<T>) {
+        let Some(breaker) = &self.breaker else {
+            return;
+        };
+        match result {
+            Ok(_) => breaker.record(true),
+            // Errors sent back by Redis mean it is up
+            Err(err)
+                if err.is_io_error()
+                    || err.is_connection_dropped()
+                    || err.is_connection_refusal()
+                    || err.is_timeout() =>
+            {
+                breaker.record(false)
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+/// The name of a command, anything unexpected is grouped to keep the label set small.
+fn command_name(cmd: &Cmd) -> String {
+    match cmd.args_iter().next() {
+        Some(Arg::Simple(name))
+            if !name.is_empty() && name.len() <= 32 && name.iter().all(u8::is_ascii_alphabetic) =>
+        {
+            String::from_utf8_lossy(name).to_ascii_uppercase()
+        }
+        _ => "OTHER".to_string(),
+    }
+}
+
+fn record<T>(command: String, started: Instant, result: &RedisResult<T>) {
+    let elapsed = started.elapsed().as_secs_f64();
+    let mut metrics = METRICS.lock().unwrap();
+    let stats = metrics.entry(command).or_default();
+    for (bucket, bound) in stats.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+        if elapsed <= bound {
+            *bucket += 1;
+        }
+    }
+    stats.count += 1;
+    stats.sum += elapsed;
+    if result.is_err() {
+        stats.errors += 1;
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.connection.req_packed_command(cmd).await;
+            record(command_name(cmd), started, &result);
+            self.report(&result);
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self
+                .connection
+                .req_packed_commands(cmd, offset, count)
+                .await;
+            record("PIPELINE".to_string(), started, &result);
+            self.report(&result);
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.connection.get_db()
+    }
+}
+
+/// Replace `KEYS[1]` with `ARGV[2]` if it is still `ARGV[1]`, keeping its TTL.
+const REPLACE_UNCHANGED_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    redis.call("SET", KEYS[1], ARGV[2], "KEEPTTL")
+    return 1
+end
+return 0
+"#;
+
+/// Replace the value of `key` with `new` only if it is still `current`, the value it was read
+/// with. The expiry of the key is kept, returns whether it was replaced.
+pub async fn replace_unchanged(
+    connection: &mut impl ConnectionLike,
+    key: &str,
+    current: &[u8],
+    new: &[u8],
+) -> RedisResult<bool> {
+    redis::cmd("EVAL")
+        .arg(REPLACE_UNCHANGED_SCRIPT)
+        .arg(1)
+        .arg(key)
+        .arg(current)
+        .arg(new)
+        .query_async::<i64>(connection)
+        .await
+        .map(|replaced| replaced == 1)
+}
+
+/// The command metrics in the Prometheus text format.
+pub fn render_metrics() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut output = String::new();
+
+    output
+        .push_str("# HELP ihacdn_redis_command_duration_seconds The latency of Redis commands.\n");
+    output.push_str("# TYPE ihacdn_redis_command_duration_seconds histogram\n");
+    for (command, stats) in metrics.iter() {
+        for (bucket, bound) in stats.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                output,
+                "ihacdn_redis_command_duration_seconds_bucket{{command=\"{command}\",le=\"{bound}\"}} {bucket}"
+            );
+        }
+        let _ = writeln!(
+            output,
+            "ihacdn_redis_command_duration_seconds_bucket{{command=\"{command}\",le=\"+Inf\"}} {}",
+            stats.count
+        );
+        let _ = writeln!(
+            output,
+            "ihacdn_redis_command_duration_seconds_sum{{command=\"{command}\"}} {}",
+            stats.sum
+        );
+        let _ = writeln!(
+            output,
+            "ihacdn_redis_command_duration_seconds_count{{command=\"{command}\"}} {}",
+            stats.count
+        );
+    }
+
+    output.push_str("# HELP ihacdn_redis_command_errors_total The Redis commands that failed.\n");
+    output.push_str("# TYPE ihacdn_redis_command_errors_total counter\n");
+    for (command, stats) in metrics.iter() {
+        let _ = writeln!(
+            output,
+            "ihacdn_redis_command_errors_total{{command=\"{command}\"}} {}",
+            stats.errors
+        );
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Needs a Redis database given with `IHACDN_TEST_REDIS`, passes without doing anything
+    /// otherwise.
+    #[tokio::test]
+    async fn changed_values_are_not_replaced_and_the_ttl_is_kept() {
+        let Ok(url) = std::env::var("IHACDN_TEST_REDIS") else {
+            return;
+        };
+        let client = redis::Client::open(url).unwrap();
+        let mut connection =
+            RedisConnection::from(client.get_multiplexed_async_connection().await.unwrap());
+        let key = format!("ihacdn-test:{}:entry", crate::state::generate_token());
+
+        redis::cmd("SET")
+            .arg(&key)
+            .arg("first")
+            .arg("EX")
+            .arg(600)
+            .exec_async(&mut connection)
+            .await
+            .unwrap();
+        assert!(
+            !replace_unchanged(&mut connection, &key, b"other", b"second")
+                .await
+                .unwrap()
+        );
+        assert!(
+            replace_unchanged(&mut connection, &key, b"first", b"second")
+                .await
+                .unwrap()
+        );
+
+        let value = redis::cmd("GET")
+            .arg(&key)
+            .query_async::<String>(&mut connection)
+            .await
+            .unwrap();
+        let ttl = redis::cmd("TTL")
+            .arg(&key)
+            .query_async::<i64>(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(value, "second");
+        assert!(ttl > 0);
+
+        redis::cmd("DEL")
+            .arg(&key)
+            .exec_async(&mut connection)
+            .await
+            .unwrap();
+    }
+}
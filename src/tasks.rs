@@ -0,0 +1,72 @@
+//! The outcome of the scheduled tasks, kept in Redis for the status endpoint.
+
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{state::SharedState, store::RedisConnection};
+
+/// The tasks whose runs are recorded.
+pub const TASKS: [&str; 2] = ["purge", "stats"];
+
+/// The last run of a scheduled task.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskRun {
+    /// UNIX timestamp.
+    pub started_at: i64,
+    /// UNIX timestamp.
+    pub finished_at: i64,
+    /// The error of a failed run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn task_run_key(prefix: &str, task: &str) -> String {
+    format!("{prefix}:task:{task}")
+}
+
+/// Run a scheduled task and record its outcome, returns what the task reported or why it failed.
+pub async fn run_recorded<T, F>(state: &SharedState, task: &str, run: F) -> Result<T, String>
+where
+    F: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let started_at = chrono::Utc::now().timestamp();
+    let outcome = run.await.map_err(|e| e.to_string());
+    let error = outcome.as_ref().err().cloned();
+    if let Some(error) = &error {
+        tracing::error!("The {} task failed: {}", task, error);
+    }
+
+    let record = TaskRun {
+        started_at,
+        finished_at: chrono::Utc::now().timestamp(),
+        error,
+    };
+    let result = match state.make_connection().await {
+        Ok(mut connection) => {
+            redis::cmd("SET")
+                .arg(task_run_key(&state.config.key_prefix, task))
+                .arg(serde_json::to_string(&record).unwrap())
+                .exec_async(&mut connection)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        tracing::error!("Failed to record the run of the {} task: {}", task, e);
+    }
+    outcome
+}
+
+/// The last run of a task, if it ever ran.
+pub async fn last_run(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    task: &str,
+) -> redis::RedisResult<Option<TaskRun>> {
+    let data = redis::cmd("GET")
+        .arg(task_run_key(prefix, task))
+        .query_async::<Option<String>>(connection)
+        .await?;
+    Ok(data.and_then(|data| serde_json::from_str(&data).ok()))
+}
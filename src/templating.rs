@@ -1,20 +1,58 @@
 use askama::Template;
 use axum::{http::StatusCode, response::IntoResponse};
 
+use crate::config::IhaCdnInstanceConfig;
+
 pub struct TemplateIndexRetention {
     pub min_age: String,
     pub max_age: String,
+    /// How long files are kept at some sizes, as `(size, days)`.
+    pub table: Vec<(String, i64)>,
+}
+
+/// The contact and policy links of the instance, shown in the footer of pages.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateLegal {
+    /// The text and URL of each link.
+    pub links: Vec<(String, String)>,
+}
+
+impl TemplateLegal {
+    pub fn new(instance: &IhaCdnInstanceConfig) -> Self {
+        let mut links = vec![];
+        if let Some(contact) = &instance.abuse_contact {
+            let url = if contact.contains('@') && !contact.contains("://") {
+                format!("mailto:{contact}")
+            } else {
+                contact.clone()
+            };
+            links.push((format!("Report abuse: {contact}"), url));
+        }
+        if let Some(terms_url) = &instance.terms_url {
+            links.push(("Terms of Service".to_string(), terms_url.clone()));
+        }
+        if let Some(privacy_url) = &instance.privacy_url {
+            links.push(("Privacy Policy".to_string(), privacy_url.clone()));
+        }
+        Self { links }
+    }
 }
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct TemplateIndex {
-    pub https_mode: bool,
-    pub hostname: String,
+    /// The URL of the instance without the trailing slash, e.g. `https://p.ihateani.me`.
+    pub base_url: String,
+    /// A ShareX custom uploader for this instance, as JSON.
+    pub sharex_config: String,
     pub filesize_limit: Option<String>,
     pub blacklist_extensions: Vec<String>,
     pub blacklist_ctypes: Vec<String>,
     pub file_retention: Option<TemplateIndexRetention>,
+    pub instance_name: String,
+    pub legal: TemplateLegal,
+    pub version: &'static str,
+    pub git_sha: &'static str,
 }
 
 #[derive(Template)]
@@ -23,6 +61,131 @@ pub struct TemplatePaste {
     pub code_type: String,
     pub code_data: String,
     pub file_id: String,
+    pub file_size: String,
+    pub created_at: String,
+    /// UNIX timestamp used by the countdown.
+    pub expires_at: Option<i64>,
+    pub expires_at_display: Option<String>,
+    pub views: Option<u64>,
+    pub edited_at: Option<String>,
+    /// The fork parents of the paste, nearest first.
+    pub parents: Vec<String>,
+    /// From the upload metadata.
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// The page link unfurlers get instead of an uploaded file, with its OpenGraph and Twitter Card
+/// tags.
+#[derive(Template)]
+#[template(path = "preview.html")]
+pub struct TemplatePreview {
+    pub file_id: String,
+    /// The metadata title, or the file name.
+    pub title: String,
+    pub description: String,
+    pub instance_name: String,
+    /// The URL the link was shared with.
+    pub page_url: String,
+    /// The URL of the file bytes.
+    pub raw_url: String,
+    pub mimetype: String,
+    pub file_size: String,
+    pub created_at: String,
+    /// The `og:type`, `website` or `video.other`.
+    pub og_type: &'static str,
+    /// The `twitter:card`, `summary` or `summary_large_image`.
+    pub card: &'static str,
+    /// The image shown in the embed, the file itself or the poster frame of a video.
+    pub image: Option<String>,
+    /// The URL of a video browsers can play.
+    pub video: Option<String>,
+    pub is_image: bool,
+}
+
+#[derive(Template)]
+#[template(path = "encrypted.html")]
+pub struct TemplateEncryptedPaste {
+    pub file_id: String,
+}
+
+#[derive(Template)]
+#[template(path = "sandboxed.html")]
+pub struct TemplateSandboxedPaste {
+    pub file_id: String,
+    /// The HTML rendered in the sandboxed iframe.
+    pub content: String,
+}
+
+pub struct TemplateExploreItem {
+    pub url: String,
+    /// The metadata title, or the ID.
+    pub title: String,
+    pub tags: Vec<String>,
+    /// The image shown for image uploads.
+    pub image: Option<String>,
+    /// The first lines of a paste.
+    pub snippet: Option<String>,
+    pub added: String,
+}
+
+#[derive(Template)]
+#[template(path = "explore.html")]
+pub struct TemplateExplore {
+    pub items: Vec<TemplateExploreItem>,
+    pub page: usize,
+    pub previous_page: Option<usize>,
+    pub next_page: Option<usize>,
+}
+
+/// The uploads of a period on the stats page.
+pub struct TemplateStatsPeriod {
+    pub uploads: u64,
+    pub files: u64,
+    pub pastes: u64,
+    pub size: String,
+}
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+pub struct TemplateStats {
+    pub instance_name: String,
+    /// The uploads of the last day, the last week and in total, with their label.
+    pub periods: Vec<(&'static str, TemplateStatsPeriod)>,
+    /// The most uploaded mimetypes of the last week, as `(mimetype, count)`.
+    pub top_mimetypes: Vec<(String, u64)>,
+    pub legal: TemplateLegal,
+}
+
+/// The error page, shared by every HTML error.
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct TemplateError {
+    /// The entry the error is about, shown in the page title.
+    pub file_id: Option<String>,
+    pub status: u16,
+    pub title: String,
+    pub message: String,
+    pub legal: TemplateLegal,
+}
+
+impl TemplateError {
+    fn badge_color(&self) -> &'static str {
+        match self.status {
+            404 => "#f9e2af",
+            451 => "#fab387",
+            _ => "#f38ba8",
+        }
+    }
+}
+
+/// Format an UNIX timestamp for display in templates.
+pub fn format_timestamp(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(datetime) => datetime.format("%Y-%m-%d %H:%M UTC").to_string(),
+        None => "Unknown".to_string(),
+    }
 }
 
 pub struct HtmlTemplate<T>(T);
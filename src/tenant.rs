@@ -0,0 +1,63 @@
+//! Serving several instances from one process, each request goes to the tenant of its `Host`.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::header,
+    response::Response,
+};
+use tower_service::Service;
+
+use crate::config::IhaCdnConfig;
+
+/// The routers of every tenant by hostname.
+pub struct Tenants {
+    /// The router of the main config, serving unknown hostnames.
+    default: Router,
+    hosts: HashMap<String, Router>,
+}
+
+impl Tenants {
+    pub fn new(default: Router) -> Self {
+        Self {
+            default,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Serve the hostname and content hostname of a tenant with its router.
+    pub fn add(&mut self, config: &IhaCdnConfig, router: Router) {
+        for hostname in std::iter::once(&config.hostname).chain(config.content_hostname.as_ref()) {
+            self.hosts
+                .insert(hostname.to_ascii_lowercase(), router.clone());
+        }
+    }
+
+    fn router(&self, host: Option<&str>) -> Router {
+        host.and_then(|host| self.hosts.get(&host.to_ascii_lowercase()))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// Pass a request to the router of its tenant.
+pub async fn dispatch(State(tenants): State<Arc<Tenants>>, request: Request) -> Response {
+    // HTTP/2 requests carry the host in the URI instead
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+        });
+    let mut router = tenants.router(host);
+    match router.call(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
@@ -0,0 +1,179 @@
+//! Resized copies of uploaded images, served at `/{id}/thumb`.
+
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, ImageFormat, ImageReader, imageops::FilterType};
+
+use crate::{
+    state::{CDNData, SharedState, entry_key},
+    store::RedisConnection,
+};
+
+/// The formats thumbnails are encoded in.
+#[derive(Debug, Clone, Copy)]
+pub enum ThumbnailFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ThumbnailFormat {
+    /// Parse the `format` query parameter.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "png" => Some(ThumbnailFormat::Png),
+            "jpg" | "jpeg" => Some(ThumbnailFormat::Jpeg),
+            "webp" => Some(ThumbnailFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// The default format of the thumbnails of an image, [`None`] if none can be made of it.
+    ///
+    /// Thumbnails of GIFs are a still PNG of their first frame.
+    pub fn of_mimetype(mimetype: &str) -> Option<Self> {
+        match mimetype {
+            "image/png" | "image/gif" => Some(ThumbnailFormat::Png),
+            "image/jpeg" => Some(ThumbnailFormat::Jpeg),
+            "image/webp" => Some(ThumbnailFormat::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Png => "png",
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+        }
+    }
+
+    pub fn mimetype(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Png => "image/png",
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            ThumbnailFormat::Png => ImageFormat::Png,
+            ThumbnailFormat::Jpeg => ImageFormat::Jpeg,
+            ThumbnailFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// Where a thumbnail of a stored image is kept, in `thumbs/` next to its `uploads/` directory.
+pub fn thumbnail_path(
+    path: &Path,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: ThumbnailFormat,
+) -> PathBuf {
+    let dir = path
+        .parent()
+        .and_then(Path::parent)
+        .unwrap_or(Path::new("."))
+        .join("thumbs");
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let size = |size: Option<u32>| size.map(|size| size.to_string()).unwrap_or_default();
+    dir.join(format!(
+        "{stem}.{}x{}.{}",
+        size(width),
+        size(height),
+        format.extension()
+    ))
+}
+
+/// Resize an image to fit in `width` by `height` and save it to `output`.
+///
+/// Images are never enlarged, a missing width or height leaves that side unbounded.
+pub async fn make_thumbnail(
+    source: PathBuf,
+    output: PathBuf,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: ThumbnailFormat,
+) -> Result<(), String> {
+    // Decoding and resizing a big image takes a while, keep it off the async workers
+    tokio::task::spawn_blocking(move || render(&source, &output, width, height, format))
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+fn render(
+    source: &Path,
+    output: &Path,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: ThumbnailFormat,
+) -> Result<(), String> {
+    let image = ImageReader::open(source)
+        .map_err(|err| err.to_string())?
+        .with_guessed_format()
+        .map_err(|err| err.to_string())?
+        .decode()
+        .map_err(|err| err.to_string())?;
+
+    let bound_width = width.map_or(image.width(), |width| width.min(image.width()));
+    let bound_height = height.map_or(image.height(), |height| height.min(image.height()));
+    let image = if bound_width < image.width() || bound_height < image.height() {
+        image.resize(bound_width, bound_height, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    // JPEG has no alpha channel, the WebP encoder only takes 8-bit pixels
+    let image = match format {
+        ThumbnailFormat::Png => image,
+        ThumbnailFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        ThumbnailFormat::Webp => DynamicImage::ImageRgba8(image.to_rgba8()),
+    };
+
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+    // Saved aside first so a half-written thumbnail is never served
+    let partial = output.with_extension("partial");
+    image
+        .save_with_format(&partial, format.image_format())
+        .map_err(|err| err.to_string())?;
+    std::fs::rename(&partial, output).map_err(|err| err.to_string())
+}
+
+/// Keep a new thumbnail with its entry so it is deleted along with it.
+///
+/// Returns `false` if the entry is gone, the thumbnail should be removed then.
+pub async fn record_thumbnail(
+    state: &SharedState,
+    connection: &mut RedisConnection,
+    id: &str,
+    thumbnail: &Path,
+) -> redis::RedisResult<bool> {
+    let key = entry_key(&state.config.key_prefix, id);
+    let data = redis::cmd("GET")
+        .arg(&key)
+        .query_async::<Option<Vec<u8>>>(connection)
+        .await?;
+    let Some(mut cdn_data) = data.and_then(|data| CDNData::decode(&data).ok()) else {
+        return Ok(false);
+    };
+    let CDNData::File { thumbnails, .. } = &mut cdn_data else {
+        return Ok(false);
+    };
+    if thumbnails.iter().any(|known| known == thumbnail) {
+        return Ok(true);
+    }
+    thumbnails.push(thumbnail.to_path_buf());
+
+    // KEEPTTL so we don't drop the expiry of the entry
+    redis::cmd("SET")
+        .arg(&key)
+        .arg(cdn_data.encode(state.config.storage.entry_encoding))
+        .arg("XX")
+        .arg("KEEPTTL")
+        .query_async::<Option<String>>(connection)
+        .await
+        .map(|reply| reply.is_some())
+}
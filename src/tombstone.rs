@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::IhaCdnTombstoneConfig, state::CDNData, store::RedisConnection,
+    templating::format_timestamp,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TombstoneReason {
+    /// Removed by the purge task.
+    Expired,
+    /// Removed by the uploader or an admin.
+    Deleted,
+    /// Removed after its only download.
+    Burned,
+    /// Removed after its last allowed view.
+    ViewLimit,
+}
+
+/// The small record kept in place of a removed entry.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tombstone {
+    /// The type of the removed entry.
+    pub kind: String,
+    pub reason: TombstoneReason,
+    /// When the entry was removed, as an UNIX timestamp.
+    pub deleted_at: i64,
+}
+
+/// The Redis key of the tombstone of an entry.
+pub fn tombstone_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}:tombstone:{id}")
+}
+
+/// Keep a tombstone for a removed entry, if enabled.
+pub async fn record_tombstone(
+    config: &IhaCdnTombstoneConfig,
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+    cdn_data: &CDNData,
+    reason: TombstoneReason,
+) {
+    if !config.enable {
+        return;
+    }
+
+    let tombstone = Tombstone {
+        kind: cdn_data.kind().to_string(),
+        reason,
+        deleted_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64,
+    };
+
+    let mut cmd = redis::cmd("SET");
+    cmd.arg(tombstone_key(prefix, id))
+        .arg(serde_json::to_string(&tombstone).unwrap());
+    if let Some(keep_days) = config.keep_days {
+        cmd.arg("EX").arg(keep_days * 86400);
+    }
+    if let Err(e) = cmd.exec_async(connection).await {
+        tracing::error!("Failed to save tombstone of {}: {}", id, e);
+    }
+}
+
+/// Get the tombstone of a removed entry.
+pub async fn load_tombstone(
+    connection: &mut RedisConnection,
+    prefix: &str,
+    id: &str,
+) -> Option<Tombstone> {
+    let data = redis::cmd("GET")
+        .arg(tombstone_key(prefix, id))
+        .query_async::<Option<String>>(connection)
+        .await
+        .inspect_err(|e| tracing::error!("Failed to get tombstone of {}: {}", id, e))
+        .ok()??;
+
+    serde_json::from_str::<Tombstone>(&data)
+        .inspect_err(|e| tracing::error!("Failed to parse tombstone of {}: {}", id, e))
+        .ok()
+}
+
+impl Tombstone {
+    /// Describe the removal for humans, e.g. "This paste expired on 2025-01-01 00:00 UTC."
+    pub fn message(&self) -> String {
+        let noun = match self.kind.as_str() {
+            "code" => "paste",
+            "short" => "short link",
+            _ => "file",
+        };
+        let verb = match self.reason {
+            TombstoneReason::Expired => "expired",
+            TombstoneReason::Deleted => "was deleted",
+            TombstoneReason::Burned => "was downloaded and destroyed",
+            TombstoneReason::ViewLimit => "reached its view limit",
+        };
+        format!(
+            "This {noun} {verb} on {}.",
+            format_timestamp(self.deleted_at)
+        )
+    }
+}
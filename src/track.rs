@@ -1,6 +1,43 @@
 use std::{net::IpAddr, sync::Arc};
 
-use crate::{config::IhaCdnConfig, state::CDNData};
+use crate::{
+    config::IhaCdnConfig,
+    state::{CDNData, last_clicked_key, views_key},
+    store::RedisConnection,
+};
+
+/// Increment the view counter of an entry, returning the new count.
+pub async fn count_view(connection: &mut RedisConnection, prefix: &str, id: &str) -> Option<u64> {
+    redis::cmd("INCR")
+        .arg(views_key(prefix, id))
+        .query_async::<u64>(connection)
+        .await
+        .inspect_err(|e| tracing::error!("Failed to count view of {}: {}", id, e))
+        .ok()
+}
+
+/// Remember when a short link was last followed, for the short link retention.
+pub async fn record_click(connection: &mut RedisConnection, prefix: &str, id: &str, now: i64) {
+    if let Err(e) = redis::cmd("SET")
+        .arg(last_clicked_key(prefix, id))
+        .arg(now)
+        .exec_async(connection)
+        .await
+    {
+        tracing::error!("Failed to record click of {}: {}", id, e);
+    }
+}
+
+/// Get the view counter of an entry.
+pub async fn get_views(connection: &mut RedisConnection, prefix: &str, id: &str) -> Option<u64> {
+    redis::cmd("GET")
+        .arg(views_key(prefix, id))
+        .query_async::<Option<u64>>(connection)
+        .await
+        .inspect_err(|e| tracing::error!("Failed to get views of {}: {}", id, e))
+        .ok()
+        .map(Option::unwrap_or_default)
+}
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct PlausibleEvent {
@@ -59,9 +96,7 @@ pub fn report_to_plausible(
         interactive: false,
     };
 
-    let user_agent: String = user_agent.unwrap_or_else(|| {
-        "ihacdn-rs/0.1.0 (+https://github.com/ihateani-me/ihacdn-server-rs)".to_string()
-    });
+    let user_agent: String = user_agent.unwrap_or_else(|| config.user_agent());
 
     tokio::spawn(async move {
         let ip_addresses = ip_address
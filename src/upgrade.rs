@@ -0,0 +1,90 @@
+//! Binary upgrades without dropping connections.
+//!
+//! On `SIGUSR2` the running server starts the current executable again and hands it the
+//! listening socket. Once the new process is serving it asks the old one to stop, which then
+//! finishes its in-flight requests before exiting.
+
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+
+/// The inherited listening socket of an upgraded process.
+const LISTEN_FD_ENV: &str = "IHACDN_LISTEN_FD";
+/// The process to stop once the upgraded process is serving.
+const UPGRADE_PARENT_ENV: &str = "IHACDN_UPGRADE_PARENT";
+
+/// Take over the listening socket handed over by the previous process, if any.
+pub fn inherited_listener() -> Option<std::net::TcpListener> {
+    let fd = std::env::var(LISTEN_FD_ENV).ok()?;
+    let fd = match fd.parse::<RawFd>() {
+        Ok(fd) if fd > 2 => fd,
+        _ => {
+            tracing::error!("🔁💥 Invalid inherited socket: {}", fd);
+            return None;
+        }
+    };
+    // The previous process made sure this is the listening socket it served on
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if let Err(err) = listener.set_nonblocking(true) {
+        tracing::error!("🔁💥 Unable to use inherited socket: {}", err);
+        return None;
+    }
+    set_cloexec(fd, true);
+    Some(listener)
+}
+
+/// Ask the previous process to drain and exit now that we are serving.
+pub fn notify_parent() {
+    let Ok(parent) = std::env::var(UPGRADE_PARENT_ENV) else {
+        return;
+    };
+
+    match parent.parse::<libc::pid_t>() {
+        Ok(pid) if pid > 1 => {
+            tracing::info!("🔁 Asking previous process {} to shut down...", pid);
+            if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+                tracing::error!(
+                    "🔁💥 Failed to stop previous process: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        _ => tracing::error!("🔁💥 Invalid previous process: {}", parent),
+    }
+}
+
+/// Start a new instance of the current executable serving on the same socket.
+pub fn spawn_upgrade(listener: &impl AsRawFd) -> std::io::Result<()> {
+    let fd = listener.as_raw_fd();
+    let executable = std::env::current_exe()?;
+
+    // The socket must survive the exec, but only for this child
+    set_cloexec(fd, false);
+    let result = std::process::Command::new(&executable)
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_ENV, fd.to_string())
+        .env(UPGRADE_PARENT_ENV, std::process::id().to_string())
+        .spawn();
+    set_cloexec(fd, true);
+
+    let child = result?;
+    tracing::info!(
+        "🔁 Started {} as process {}, waiting for it to take over...",
+        executable.display(),
+        child.id()
+    );
+    Ok(())
+}
+
+fn set_cloexec(fd: RawFd, enable: bool) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return;
+        }
+        let flags = if enable {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        libc::fcntl(fd, libc::F_SETFD, flags);
+    }
+}
@@ -0,0 +1,78 @@
+//! Preloading the most viewed recent entries on startup, so a restart doesn't begin cold.
+
+use std::sync::Arc;
+
+use crate::{
+    index,
+    state::{CDNData, SharedState, entry_key, views_key},
+};
+
+/// Preload the entries in the background, failures only leave the cache cold.
+pub async fn preload(state: Arc<SharedState>) {
+    tracing::info!("🔥 Preloading the most viewed entries...");
+    match preload_entries(&state).await {
+        Ok((entries, pastes)) => {
+            tracing::info!("🔥 Preloaded {} entries and {} pastes", entries, pastes)
+        }
+        Err(e) => tracing::error!("🔥💥 Failed to preload the entries: {}", e),
+    }
+}
+
+async fn preload_entries(state: &SharedState) -> redis::RedisResult<(usize, usize)> {
+    let config = &state.config.warmup;
+    let prefix = &state.config.key_prefix;
+    let mut connection = state.make_connection().await?;
+
+    let ids = index::newest(&mut connection, prefix, config.candidates).await?;
+    if ids.is_empty() {
+        return Ok((0, 0));
+    }
+    let (values, views) = redis::pipe()
+        .cmd("MGET")
+        .arg(
+            ids.iter()
+                .map(|id| entry_key(prefix, id))
+                .collect::<Vec<_>>(),
+        )
+        .cmd("MGET")
+        .arg(
+            ids.iter()
+                .map(|id| views_key(prefix, id))
+                .collect::<Vec<_>>(),
+        )
+        .query_async::<(Vec<Option<Vec<u8>>>, Vec<Option<u64>>)>(&mut connection)
+        .await?;
+
+    let mut entries = ids
+        .into_iter()
+        .zip(values)
+        .zip(views)
+        .filter_map(|((id, value), views)| {
+            let cdn_data = CDNData::decode(&value?).ok()?;
+            Some((id, cdn_data, views.unwrap_or_default()))
+        })
+        .collect::<Vec<_>>();
+    // Stable, the newest of equally viewed entries come first
+    entries.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+    entries.truncate(config.entries);
+
+    let mut pastes = 0;
+    for (id, cdn_data, _) in &entries {
+        state.breaker.remember(id, cdn_data);
+        if let CDNData::Code {
+            path,
+            encrypted: false,
+            ..
+        } = cdn_data
+        {
+            let path = state.config.resolve_path(path).await;
+            let small = tokio::fs::metadata(&path)
+                .await
+                .is_ok_and(|metadata| metadata.len() <= config.paste_max_size);
+            if small && tokio::fs::read(&path).await.is_ok() {
+                pastes += 1;
+            }
+        }
+    }
+    Ok((entries.len(), pastes))
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use crate::config::IhaCdnBlocklistConfig;
+
+/// Top-level route names that can never be used as an ID.
+const RESERVED_ROUTES: [&str; 9] = [
+    "_", "admin", "api", "favicon", "health", "raw", "short", "static", "upload",
+];
+
+/// Words that are not allowed to appear anywhere in a generated ID.
+const DEFAULT_BLOCKED_WORDS: [&str; 16] = [
+    "anal", "bitch", "cock", "cum", "cunt", "dick", "fag", "fuck", "nazi", "nigg", "porn", "rape",
+    "sex", "shit", "slut", "whore",
+];
+
+/// Filter for generated IDs and custom slugs.
+///
+/// Reserved route names are matched exactly, while blocked words are matched anywhere in the ID.
+#[derive(Debug, Clone)]
+pub struct WordFilter {
+    reserved: HashSet<String>,
+    blocked: Vec<String>,
+}
+
+impl WordFilter {
+    /// Load the filter from the built-in lists and the optional words file.
+    ///
+    /// The words file contains one word per line, empty lines and lines starting with `#` are ignored.
+    pub fn load(config: &IhaCdnBlocklistConfig) -> std::io::Result<Self> {
+        let reserved = RESERVED_ROUTES.iter().map(|s| s.to_string()).collect();
+        let mut blocked: Vec<String> = DEFAULT_BLOCKED_WORDS
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        if let Some(words_file) = &config.words_file {
+            let contents = std::fs::read_to_string(words_file)?;
+            blocked.extend(
+                contents
+                    .lines()
+                    .map(|line| line.trim().to_lowercase())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#')),
+            );
+        }
+
+        blocked.sort();
+        blocked.dedup();
+        Ok(Self { reserved, blocked })
+    }
+
+    /// Check if the ID can be used.
+    pub fn is_allowed(&self, id: &str) -> bool {
+        let id = id.to_lowercase();
+        if self.reserved.contains(&id) {
+            return false;
+        }
+
+        !self.blocked.iter().any(|word| id.contains(word.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin() -> WordFilter {
+        WordFilter::load(&IhaCdnBlocklistConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn reserved_routes_are_matched_exactly() {
+        let words = builtin();
+        assert!(!words.is_allowed("upload"));
+        assert!(!words.is_allowed("Static"));
+        assert!(words.is_allowed("uploads"));
+        assert!(words.is_allowed("rawr"));
+    }
+
+    #[test]
+    fn blocked_words_are_matched_anywhere() {
+        let words = builtin();
+        assert!(!words.is_allowed("xxfuckxx"));
+        assert!(!words.is_allowed("ShItty"));
+        assert!(words.is_allowed("abcdefgh"));
+    }
+
+    #[test]
+    fn words_file_extends_the_builtin_list() {
+        let path = std::env::temp_dir().join(format!("ihacdn-words-{}", std::process::id()));
+        std::fs::write(&path, "# comment\n\n  Banana \nkiwi\n").unwrap();
+        let config = IhaCdnBlocklistConfig {
+            words_file: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let words = WordFilter::load(&config);
+        std::fs::remove_file(&path).unwrap();
+        let words = words.unwrap();
+
+        assert!(!words.is_allowed("xbananax"));
+        assert!(!words.is_allowed("kiwis"));
+        assert!(!words.is_allowed("porn"));
+        assert!(words.is_allowed("comment"));
+    }
+
+    #[test]
+    fn missing_words_file_is_an_error() {
+        let config = IhaCdnBlocklistConfig {
+            words_file: Some("/nonexistent/ihacdn-words".to_string()),
+            ..Default::default()
+        };
+        assert!(WordFilter::load(&config).is_err());
+    }
+}